@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 
 use crate::core;
@@ -21,6 +21,8 @@ struct Cli {
     command: Command,
 }
 
+// Pack のオプションが多いが、起動時に1つ作るだけなので Box にしない
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Command {
     /// テーブル一覧とスキーマを表示
@@ -35,34 +37,225 @@ enum Command {
     /// プレイリスト一覧を表示
     ListPlaylists,
 
+    /// 再生履歴の一覧を表示
+    ListHistories,
+
     /// プレイリストの全関連データと音声ファイルを .rkp にパック
     Pack {
-        /// 出力先 .rkp ファイルパス
-        output: String,
+        /// 出力先 .rkp ファイルパス (--dry-run では不要)
+        #[arg(required_unless_present = "dry_run")]
+        output: Option<String>,
+
+        /// パックするプレイリスト名 (複数指定可。フォルダの場合は配下をすべてパック)
+        #[arg(
+            long,
+            required_unless_present_any = [
+                "playlist_id",
+                "filter",
+                "history",
+                "hot_cue_banklist",
+                "sampler",
+                "related_tracks",
+            ]
+        )]
+        playlist: Vec<String>,
 
-        /// パックするプレイリスト名
+        /// パックするプレイリスト ID (複数指定可)
         #[arg(long)]
-        playlist: String,
+        playlist_id: Vec<String>,
+
+        /// プレイリストの代わりに条件でトラックを選ぶ
+        /// (例: "genre=House,bpm=124..128,mytag=Warmup"。
+        /// 使えるキー: artist, genre, key, bpm, rating, color, mytag, added。
+        /// key は "Am" のほか Camelot 表記 "8A" でも指定できる)
+        #[arg(long, conflicts_with_all = ["playlist", "playlist_id"])]
+        filter: Vec<String>,
+
+        /// パックする再生履歴の ID または日付 YYYY-MM-DD (複数指定可)
+        #[arg(long, conflicts_with_all = ["playlist", "playlist_id", "filter"])]
+        history: Vec<String>,
 
-        /// 音声ファイルのディレクトリ構造を維持する
+        /// パックするホットキューバンクリスト名 (複数指定可。キューの取得元トラックも含める)
+        #[arg(
+            long,
+            conflicts_with_all = ["playlist", "playlist_id", "filter", "history"]
+        )]
+        hot_cue_banklist: Vec<String>,
+
+        /// パックするサンプラーリスト名 (複数指定可)
+        #[arg(
+            long,
+            conflicts_with_all = ["playlist", "playlist_id", "filter", "history", "hot_cue_banklist"]
+        )]
+        sampler: Vec<String>,
+
+        /// パックする関連トラックリスト名 (複数指定可)
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "playlist",
+                "playlist_id",
+                "filter",
+                "history",
+                "hot_cue_banklist",
+                "sampler",
+            ]
+        )]
+        related_tracks: Vec<String>,
+
+        /// --filter で作成するプレイリストの名前 (省略時は条件式)
+        #[arg(long, requires = "filter")]
+        name: Option<String>,
+
+        /// 音声ファイルのディレクトリ構造を維持する (すべての音声ファイルに共通するフォルダより下)
         #[arg(long)]
         keep_structure: bool,
+
+        /// スマートプレイリストを固定リストにせず、条件を保ったまま移行する
+        #[arg(long)]
+        keep_smart: bool,
+
+        /// 音声ファイルを含めない (受け取り側が持っているトラックにキュー等を適用する)
+        #[arg(long)]
+        no_audio: bool,
+
+        /// --no-audio でも分析データファイルを含める
+        #[arg(long, requires = "no_audio")]
+        with_analysis: bool,
+
+        /// 以前の .rkp を基準に、変更のある音声と行だけを含む差分パックを作る
+        #[arg(long)]
+        base: Option<String>,
+
+        /// 音声ファイル等の元の絶対パスと端末・DB 固有の値を pack.json に残す
+        /// (既定ではパック内の相対パスに置き換える)
+        #[arg(long)]
+        keep_source_paths: bool,
+
+        /// .rkp を作らず、テーブルの行数・音声と分析データのサイズ・推定サイズと、
+        /// 見つからないファイル・分析されていないトラックを表示する
+        #[arg(long)]
+        dry_run: bool,
+
+        /// FolderPath の音声ファイルが見つからないとき、ファイル名 (NFC) とサイズが一致するファイルを
+        /// 探すフォルダ (複数指定可)。見つかったファイルをパックし、移動先を表示する
+        #[arg(long, conflicts_with = "no_audio")]
+        search_root: Vec<String>,
+
+        /// --search-root で、--base の .rkp に記録された SHA-256 と一致するファイルを探す
+        /// (名前が変わったファイルも見つかる)
+        #[arg(long, requires_all = ["search_root", "base"])]
+        search_by_hash: bool,
+
+        /// --search-root で、指定した以前の .rkp に記録された SHA-256 と一致するファイルを探す
+        /// (--base と違い差分パックにはしない)
+        #[arg(long, requires = "search_root", conflicts_with = "search_by_hash")]
+        hash_pack: Option<String>,
+
+        /// WAV/AIFF を FLAC に可逆圧縮して格納する (アンパック時に元のファイルへ戻す)
+        #[arg(long, conflicts_with = "no_audio")]
+        flac: bool,
+
+        /// 格納する音声ファイルのコピーに、タイトル・アーティスト・アルバム・ジャンル・キー・BPM・
+        /// コメント・レーティング・レーベル・ISRC とホットキュー (Serato 形式) をタグとして書き込む
+        /// (元のファイルは変更しない)。--flac と併用すると、WAV/AIFF には変換前に ID3 タグを書き込む
+        #[arg(long, conflicts_with = "no_audio")]
+        embed_tags: bool,
+
+        /// WAV/AIFF・分析データの圧縮方式 (deflate, zstd, store)。MP3 などの圧縮済み音声は常に無圧縮
+        #[arg(long, default_value = "deflate")]
+        compression: core::PackCompression,
+
+        /// 指定サイズごとに name.rkp.001, .002, ... へ分割する (例: 4G, 700M。K/M/G は 1000 単位)
+        #[arg(long, value_parser = core::parse_split_size)]
+        split_size: Option<u64>,
+
+        /// pack.json に署名する秘密鍵ファイル (keygen で作成)
+        #[arg(long)]
+        sign_key: Option<String>,
+
+        /// パスフレーズで暗号化する (環境変数 RKPACK_PASSPHRASE がなければ入力を求める)
+        #[arg(long)]
+        encrypt: bool,
     },
 
     /// パックされた .rkp を別DBにインポート
     Unpack {
-        /// パック .rkp ファイルのパス
+        /// パック .rkp ファイルのパス (分割パックは最初のボリューム name.rkp.001)
         pack_path: String,
 
         /// 音声ファイルの配置先ディレクトリ
         #[arg(long)]
         dest_dir: String,
+
+        /// プレイリストを作成する親フォルダの ID (省略時はルート)
+        #[arg(long)]
+        parent_id: Option<String>,
+
+        /// 再生履歴を履歴ではなく通常のプレイリストとして作成する
+        #[arg(long)]
+        history_as_playlist: bool,
+
+        /// 差分パックの基準の .rkp (基準をこの DB にインポート済みならその記録を優先し、
+        /// 省略時は同じフォルダの基準を使う)
+        #[arg(long)]
+        base: Option<String>,
+
+        /// 元の DB の UUID をそのまま使う (既定では新しい UUID を振り、uuidIDMap に登録する)
+        #[arg(long)]
+        keep_uuids: bool,
+
+        /// --flac でパックされた音声を WAV/AIFF に戻さず FLAC のまま配置する
+        #[arg(long)]
+        keep_flac: bool,
+    },
+    /// .rkp の全エントリのチェックサム・行の参照・署名を検証
+    Verify {
+        /// 検証する .rkp ファイルのパス
+        pack_path: String,
+
+        /// 差分パックの基準の .rkp (基準をこの DB にインポート済みならその記録を優先し、
+        /// 省略時は同じフォルダの基準を使う)
+        #[arg(long)]
+        base: Option<String>,
+
+        /// 信頼する公開鍵 (.pub) またはそのフォルダ (複数指定可)。指定時は署名がなければ失敗する
+        #[arg(long)]
+        trusted_keys: Vec<String>,
+    },
+
+    /// パック署名用の鍵ペアを作成 (秘密鍵を OUTPUT、公開鍵を OUTPUT.pub に書く)
+    Keygen {
+        /// 秘密鍵の出力先
+        output: String,
+
+        /// 署名者名 (プレビューや verify で表示される)
+        #[arg(long)]
+        name: String,
+    },
+
+    /// pack.json の JSON Schema を出力 (他のツールで .rkp を読み書きする場合に使う)
+    Schema {
+        /// 出力先
+        output: String,
     },
 }
 
 pub fn run_cli() -> Result<()> {
     let cli = Cli::parse();
 
+    // 鍵の作成に DB は不要
+    if let Command::Keygen { output, name } = &cli.command {
+        return core::generate_key(output, name, &|msg| tracing::info!("{}", msg));
+    }
+    if let Command::Schema { output } = &cli.command {
+        let schema = serde_json::to_string_pretty(&core::pack_schema())? + "\n";
+        std::fs::write(output, schema)
+            .with_context(|| format!("スキーマの書き込みに失敗: {}", output))?;
+        tracing::info!("スキーマ: {}", output);
+        return Ok(());
+    }
+
     let db_path = match cli.db_path {
         Some(p) => PathBuf::from(p),
         None => core::default_db_path()?,
@@ -73,7 +266,11 @@ pub fn run_cli() -> Result<()> {
 
     let read_only = matches!(
         cli.command,
-        Command::ListTables | Command::ListPlaylists | Command::Pack { .. }
+        Command::ListTables
+            | Command::ListPlaylists
+            | Command::ListHistories
+            | Command::Pack { .. }
+            | Command::Verify { .. }
     );
     let conn = core::open_rekordbox_db(&db_path, key, read_only)?;
 
@@ -87,22 +284,113 @@ pub fn run_cli() -> Result<()> {
         Command::ListPlaylists => {
             core::list_playlists(&conn)?;
         }
+        Command::ListHistories => {
+            core::list_histories(&conn)?;
+        }
         Command::Pack {
             output,
             playlist,
+            playlist_id,
+            filter,
+            history,
+            hot_cue_banklist,
+            sampler,
+            related_tracks,
+            name,
             keep_structure,
+            keep_smart,
+            no_audio,
+            with_analysis,
+            base,
+            keep_source_paths,
+            dry_run,
+            search_root,
+            search_by_hash,
+            hash_pack,
+            flac,
+            embed_tags,
+            compression,
+            split_size,
+            sign_key,
+            encrypt,
         } => {
-            core::pack_playlist(&conn, &output, &playlist, keep_structure, &|msg| tracing::info!("{}", msg))?;
+            let output = output.unwrap_or_default();
+            let passphrase = if encrypt && !dry_run {
+                Some(read_passphrase(true)?)
+            } else {
+                None
+            };
+            let options = core::PackOptions {
+                keep_structure,
+                keep_smart_lists: keep_smart,
+                no_audio,
+                with_analysis,
+                base,
+                compression,
+                split_size,
+                sign_key,
+                passphrase,
+                keep_source_paths,
+                dry_run,
+                search_roots: search_root,
+                search_by_hash,
+                hash_pack,
+                flac,
+                embed_tags,
+            };
+            if !related_tracks.is_empty() {
+                core::pack_related_tracks(&conn, &output, &related_tracks, &options, &|msg| {
+                    tracing::info!("{}", msg)
+                })?;
+            } else if !sampler.is_empty() {
+                core::pack_samplers(&conn, &output, &sampler, &options, &|msg| {
+                    tracing::info!("{}", msg)
+                })?;
+            } else if !hot_cue_banklist.is_empty() {
+                core::pack_hot_cue_banklists(
+                    &conn,
+                    &output,
+                    &hot_cue_banklist,
+                    &options,
+                    &|msg| tracing::info!("{}", msg),
+                )?;
+            } else if !history.is_empty() {
+                core::pack_histories(&conn, &output, &history, &options, &|msg| {
+                    tracing::info!("{}", msg)
+                })?;
+            } else if filter.is_empty() {
+                core::pack_playlists(&conn, &output, &playlist, &playlist_id, &options, &|msg| {
+                    tracing::info!("{}", msg)
+                })?;
+            } else {
+                let expr = filter.join(",");
+                let track_filter = core::TrackFilter::parse(&expr)?;
+                core::pack_filtered_tracks(
+                    &conn,
+                    &output,
+                    &track_filter,
+                    name.as_deref().unwrap_or(&expr),
+                    &options,
+                    &|msg| tracing::info!("{}", msg),
+                )?;
+            }
         }
         Command::Unpack {
             pack_path,
             dest_dir,
+            parent_id,
+            history_as_playlist,
+            base,
+            keep_uuids,
+            keep_flac,
         } => {
             let confirm = |info: &core::DuplicateInfo| -> bool {
                 eprintln!("重複トラックが見つかりました:");
                 eprintln!(
                     "  元のトラック名: {} メモリーキュー: {}個 ホットキュー: {}個",
-                    info.existing_title, info.existing_memory_cue_count, info.existing_hot_cue_count
+                    info.existing_title,
+                    info.existing_memory_cue_count,
+                    info.existing_hot_cue_count
                 );
                 eprintln!(
                     "  新しいトラック名: {} メモリーキュー: {}個 ホットキュー: {}個",
@@ -114,9 +402,88 @@ pub fn run_cli() -> Result<()> {
                 std::io::stdin().read_line(&mut input).unwrap_or(0);
                 input.trim().eq_ignore_ascii_case("y")
             };
-            core::unpack_playlist(&conn, &pack_path, &dest_dir, &|msg| tracing::info!("{}", msg), &confirm)?;
+            let passphrase = passphrase_if_encrypted(&pack_path)?;
+            core::unpack_playlist(
+                &conn,
+                &pack_path,
+                &dest_dir,
+                &core::UnpackOptions {
+                    parent_id,
+                    histories_as_playlists: history_as_playlist,
+                    base_path: base,
+                    passphrase,
+                    keep_uuids,
+                    keep_flac,
+                },
+                &|msg| tracing::info!("{}", msg),
+                &confirm,
+            )?;
+        }
+        Command::Keygen { .. } | Command::Schema { .. } => unreachable!(),
+        Command::Verify {
+            pack_path,
+            base,
+            trusted_keys,
+        } => {
+            let passphrase = passphrase_if_encrypted(&pack_path)?;
+            let report = core::verify_pack(
+                &conn,
+                &pack_path,
+                base.as_deref(),
+                passphrase.as_deref(),
+                &trusted_keys,
+                &|msg| tracing::info!("{}", msg),
+            )?;
+            tracing::info!("{}", report.signature);
+            if let Some(base_signature) = &report.base_signature {
+                tracing::info!("基準パック: {}", base_signature);
+            }
+            for warning in &report.warnings {
+                tracing::warn!("{}", warning);
+            }
+            for problem in &report.problems {
+                tracing::error!("{}", problem);
+            }
+            if !report.problems.is_empty() {
+                anyhow::bail!(
+                    "検証に失敗しました: {} 件の問題 ({} エントリを検証)",
+                    report.problems.len(),
+                    report.checked_entries
+                );
+            }
+            tracing::info!("検証OK: {} エントリ", report.checked_entries);
         }
     }
 
     Ok(())
 }
+
+/// パスフレーズを環境変数 RKPACK_PASSPHRASE か端末から読む
+fn read_passphrase(confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var("RKPACK_PASSPHRASE")
+        && !passphrase.is_empty()
+    {
+        return Ok(passphrase);
+    }
+    let prompt = |label: &str| {
+        rpassword::prompt_password(label)
+            .context("パスフレーズを入力できません (環境変数 RKPACK_PASSPHRASE でも指定できます)")
+    };
+    let passphrase = prompt("パスフレーズ: ")?;
+    if passphrase.is_empty() {
+        anyhow::bail!("パスフレーズが空です");
+    }
+    if confirm && prompt("パスフレーズ (確認): ")? != passphrase {
+        anyhow::bail!("パスフレーズが一致しません");
+    }
+    Ok(passphrase)
+}
+
+/// 暗号化パックならパスフレーズを読む
+fn passphrase_if_encrypted(pack_path: &str) -> Result<Option<String>> {
+    if core::is_encrypted_pack(pack_path)? {
+        Ok(Some(read_passphrase(false)?))
+    } else {
+        Ok(None)
+    }
+}
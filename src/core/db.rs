@@ -5,8 +5,7 @@ use anyhow::{Context, Result};
 use rusqlite::{Connection, OpenFlags};
 use unicode_normalization::UnicodeNormalization;
 
-pub const DEFAULT_KEY: &str =
-    "402fd482c38817c35ffa8ffb8c7d93143b749e7d315df7a81732a1ff43608497";
+pub const DEFAULT_KEY: &str = "402fd482c38817c35ffa8ffb8c7d93143b749e7d315df7a81732a1ff43608497";
 
 pub fn default_db_path() -> Result<PathBuf> {
     let candidates = if cfg!(target_os = "macos") {
@@ -17,10 +16,12 @@ pub fn default_db_path() -> Result<PathBuf> {
         ]
     } else if cfg!(target_os = "windows") {
         let appdata = std::env::var("APPDATA").context("%APPDATA% が設定されていません")?;
-        vec![PathBuf::from(&appdata)
-            .join("Pioneer")
-            .join("rekordbox")
-            .join("master.db")]
+        vec![
+            PathBuf::from(&appdata)
+                .join("Pioneer")
+                .join("rekordbox")
+                .join("master.db"),
+        ]
     } else {
         let home = std::env::var("HOME").context("$HOME が設定されていません")?;
         vec![PathBuf::from(&home).join(".Pioneer/rekordbox/master.db")]
@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, params};
+
+use super::smart_list::{
+    LogicalOperator, OP_CONTAINS, OP_EQUAL, OP_IN_RANGE, SmartCondition, SmartNode,
+};
+
+/// プレイリストを使わずにトラックを選ぶための条件。指定した条件はすべて AND で結合する
+#[derive(Clone, Default, Debug)]
+pub struct TrackFilter {
+    /// アーティスト名 (部分一致)
+    pub artist: Option<String>,
+    /// ジャンル名 (完全一致)
+    pub genre: Option<String>,
+    /// キー (例: "Am", "F#m")。Camelot 表記 ("8A") や異名同音 ("Gbm") でも同じキーに一致する
+    pub key: Option<String>,
+    pub bpm_min: Option<f64>,
+    pub bpm_max: Option<f64>,
+    pub rating_min: Option<i64>,
+    pub rating_max: Option<i64>,
+    /// カラーの ID または名前
+    pub color: Option<String>,
+    /// MyTag 名
+    pub my_tags: Vec<String>,
+    /// 追加日 (YYYY-MM-DD, 両端を含む)
+    pub added_from: Option<String>,
+    pub added_to: Option<String>,
+}
+
+/// Camelot 表記 → djmdKey.ScaleName の表記 (異名同音を含む)
+const CAMELOT_KEYS: &[(&str, &[&str])] = &[
+    ("1A", &["Abm", "G#m"]),
+    ("2A", &["Ebm", "D#m"]),
+    ("3A", &["Bbm", "A#m"]),
+    ("4A", &["Fm"]),
+    ("5A", &["Cm"]),
+    ("6A", &["Gm"]),
+    ("7A", &["Dm"]),
+    ("8A", &["Am"]),
+    ("9A", &["Em"]),
+    ("10A", &["Bm"]),
+    ("11A", &["F#m", "Gbm"]),
+    ("12A", &["C#m", "Dbm"]),
+    ("1B", &["B", "Cb"]),
+    ("2B", &["F#", "Gb"]),
+    ("3B", &["Db", "C#"]),
+    ("4B", &["Ab", "G#"]),
+    ("5B", &["Eb", "D#"]),
+    ("6B", &["Bb", "A#"]),
+    ("7B", &["F"]),
+    ("8B", &["C"]),
+    ("9B", &["G"]),
+    ("10B", &["D"]),
+    ("11B", &["A"]),
+    ("12B", &["E"]),
+];
+
+/// 指定されたキーと同じキーを表す ScaleName の候補。
+/// Camelot 表記と異名同音に展開し、表にないものはそのまま返す
+fn key_names(key: &str) -> Vec<String> {
+    let key = key.trim();
+    CAMELOT_KEYS
+        .iter()
+        .find(|(camelot, names)| camelot.eq_ignore_ascii_case(key) || names.contains(&key))
+        .map(|(camelot, names)| {
+            names
+                .iter()
+                .chain(std::iter::once(camelot))
+                .map(|name| name.to_string())
+                .collect()
+        })
+        .unwrap_or_else(|| vec![key.to_string()])
+}
+
+/// "a..b" / "a.." / "..b" / "a" を (下限, 上限) に分解する
+fn parse_range(value: &str) -> (Option<String>, Option<String>) {
+    let non_empty = |s: &str| Some(s.trim().to_string()).filter(|s| !s.is_empty());
+    match value.split_once("..") {
+        Some((from, to)) => (non_empty(from), non_empty(to)),
+        None => (non_empty(value), non_empty(value)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: Option<String>) -> Result<Option<T>> {
+    value
+        .map(|v| {
+            v.parse()
+                .map_err(|_| anyhow::anyhow!("{} の値が数値ではありません: {}", key, v))
+        })
+        .transpose()
+}
+
+impl TrackFilter {
+    /// `artist=Foo,bpm=124..128,mytag=Warmup` 形式の条件を解析する
+    pub fn parse(expr: &str) -> Result<Self> {
+        let mut filter = Self::default();
+        for term in expr.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (key, value) = term.split_once('=').with_context(|| {
+                format!("フィルタ条件は key=value で指定してください: {}", term)
+            })?;
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim().to_string();
+            match key.as_str() {
+                "artist" => filter.artist = Some(value),
+                "genre" => filter.genre = Some(value),
+                "key" => filter.key = Some(value),
+                "bpm" => {
+                    let (min, max) = parse_range(&value);
+                    filter.bpm_min = parse_number(&key, min)?;
+                    filter.bpm_max = parse_number(&key, max)?;
+                }
+                "rating" => {
+                    let (min, max) = parse_range(&value);
+                    filter.rating_min = parse_number(&key, min)?;
+                    filter.rating_max = parse_number(&key, max)?;
+                }
+                "color" => filter.color = Some(value),
+                "mytag" => filter.my_tags.push(value),
+                "added" => {
+                    let (from, to) = parse_range(&value);
+                    filter.added_from = from;
+                    filter.added_to = to;
+                }
+                _ => anyhow::bail!(
+                    "未対応のフィルタ条件です: {} (artist, genre, key, bpm, rating, color, mytag, added)",
+                    key
+                ),
+            }
+        }
+        if filter.is_empty() {
+            anyhow::bail!("フィルタ条件が指定されていません");
+        }
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.artist.is_none()
+            && self.genre.is_none()
+            && self.key.is_none()
+            && self.bpm_min.is_none()
+            && self.bpm_max.is_none()
+            && self.rating_min.is_none()
+            && self.rating_max.is_none()
+            && self.color.is_none()
+            && self.my_tags.is_empty()
+            && self.added_from.is_none()
+            && self.added_to.is_none()
+    }
+
+    /// スマートプレイリストと同じ評価器で扱えるよう条件に変換する。
+    /// カラー・MyTag の名前はここで ID に解決する
+    pub(crate) fn to_smart_node(&self, conn: &Connection) -> Result<SmartNode> {
+        let condition =
+            |property: &str, operator: i64, left: String, right: String| SmartCondition {
+                property: property.to_string(),
+                operator,
+                value_unit: String::new(),
+                value_left: left,
+                value_right: right,
+            };
+
+        let mut conditions = Vec::new();
+        let mut children = Vec::new();
+        if let Some(artist) = &self.artist {
+            conditions.push(condition(
+                "artist",
+                OP_CONTAINS,
+                artist.clone(),
+                String::new(),
+            ));
+        }
+        if let Some(genre) = &self.genre {
+            conditions.push(condition("genre", OP_EQUAL, genre.clone(), String::new()));
+        }
+        if let Some(key) = &self.key {
+            // 表記の違うキーのいずれかに一致すればよい
+            children.push(SmartNode {
+                logical_operator: LogicalOperator::Any,
+                conditions: key_names(key)
+                    .into_iter()
+                    .map(|name| condition("key", OP_EQUAL, name, String::new()))
+                    .collect(),
+                children: Vec::new(),
+            });
+        }
+        if self.bpm_min.is_some() || self.bpm_max.is_some() {
+            conditions.push(condition(
+                "bpm",
+                OP_IN_RANGE,
+                self.bpm_min.unwrap_or(0.0).to_string(),
+                self.bpm_max.unwrap_or(f64::from(u16::MAX)).to_string(),
+            ));
+        }
+        if self.rating_min.is_some() || self.rating_max.is_some() {
+            conditions.push(condition(
+                "rating",
+                OP_IN_RANGE,
+                self.rating_min.unwrap_or(0).to_string(),
+                self.rating_max.unwrap_or(5).to_string(),
+            ));
+        }
+        if let Some(color) = &self.color {
+            let color_id = find_color_id(conn, color)?;
+            conditions.push(condition("color", OP_EQUAL, color_id, String::new()));
+        }
+        for tag in &self.my_tags {
+            let tag_id = find_my_tag_id(conn, tag)?;
+            conditions.push(condition("myTag", OP_EQUAL, tag_id, String::new()));
+        }
+        if self.added_from.is_some() || self.added_to.is_some() {
+            conditions.push(condition(
+                "stockDate",
+                OP_IN_RANGE,
+                self.added_from
+                    .clone()
+                    .unwrap_or_else(|| "0001-01-01".to_string()),
+                self.added_to
+                    .clone()
+                    .unwrap_or_else(|| "9999-12-31".to_string()),
+            ));
+        }
+
+        Ok(SmartNode {
+            logical_operator: LogicalOperator::All,
+            conditions,
+            children,
+        })
+    }
+}
+
+fn find_color_id(conn: &Connection, color: &str) -> Result<String> {
+    conn.query_row(
+        "SELECT ID FROM djmdColor WHERE (ID = ?1 OR Commnt = ?1 COLLATE NOCASE) \
+         AND rb_local_deleted = 0 LIMIT 1",
+        params![color],
+        |row| row.get(0),
+    )
+    .with_context(|| format!("カラー '{}' が見つかりません", color))
+}
+
+fn find_my_tag_id(conn: &Connection, name: &str) -> Result<String> {
+    conn.query_row(
+        "SELECT ID FROM djmdMyTag WHERE Name = ? AND rb_local_deleted = 0 LIMIT 1",
+        params![name],
+        |row| row.get(0),
+    )
+    .with_context(|| format!("MyTag '{}' が見つかりません", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_camelot_and_enharmonic_keys() {
+        assert_eq!(key_names("8A"), vec!["Am", "8A"]);
+        assert_eq!(key_names("11a"), vec!["F#m", "Gbm", "11A"]);
+        assert_eq!(key_names("Gbm"), vec!["F#m", "Gbm", "11A"]);
+        assert_eq!(key_names("Xyz"), vec!["Xyz"]);
+    }
+}
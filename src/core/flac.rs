@@ -0,0 +1,1167 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use claxon::frame::FrameReader;
+use claxon::input::{BufferedReader, ReadBytes};
+use claxon::metadata::{MetadataBlock, MetadataBlockReader};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// 1フレームあたりのサンプル数 (チャンネルごと)
+const BLOCK_SIZE: usize = 4096;
+
+/// STREAMINFO に書けるサンプリング周波数の上限 (claxon の上限に合わせる)
+const MAX_SAMPLE_RATE: u32 = 655_350;
+
+/// METADATA_BLOCK の長さ (24bit) の上限
+pub(crate) const MAX_METADATA_BLOCK_LEN: usize = (1 << 24) - 1;
+
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 8;
+/// 4bit の Rice パラメータの上限 (15 はエスケープ)。超える場合は 5bit の RICE2 を使う
+const MAX_RICE_PARAMETER: u32 = 14;
+const MAX_RICE2_PARAMETER: u32 = 30;
+
+/// djmdContent.FileType の FLAC
+pub(crate) const FILE_TYPE_FLAC: i64 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Container {
+    Wav,
+    Aiff,
+}
+
+impl Container {
+    /// 元のチャンクを格納する APPLICATION ブロックの ID (flac --keep-foreign-metadata と同じ)
+    fn application_id(self) -> [u8; 4] {
+        match self {
+            Self::Wav => *b"riff",
+            Self::Aiff => *b"aiff",
+        }
+    }
+}
+
+/// WAV/AIFF の PCM の並び
+#[derive(Clone, Copy)]
+struct PcmFormat {
+    channels: u32,
+    bits_per_sample: u32,
+    sample_rate: u32,
+    little_endian: bool,
+    /// 8bit の WAV は符号なし
+    unsigned_8bit: bool,
+}
+
+impl PcmFormat {
+    /// FLAC の MD5 の計算に使う並び (符号付きリトルエンディアン)
+    fn md5_layout(bits_per_sample: u32) -> Self {
+        Self {
+            channels: 1,
+            bits_per_sample,
+            sample_rate: 0,
+            little_endian: true,
+            unsigned_8bit: false,
+        }
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        (self.bits_per_sample / 8) as usize
+    }
+
+    fn block_align(&self) -> usize {
+        self.bytes_per_sample() * self.channels as usize
+    }
+
+    fn decode_sample(&self, bytes: &[u8]) -> i32 {
+        match (self.bits_per_sample, self.little_endian) {
+            (8, _) if self.unsigned_8bit => bytes[0] as i32 - 128,
+            (8, _) => bytes[0] as i8 as i32,
+            (16, true) => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+            (16, false) => i16::from_be_bytes([bytes[0], bytes[1]]) as i32,
+            (_, true) => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8,
+            (_, false) => i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8,
+        }
+    }
+
+    fn encode_sample(&self, sample: i32, out: &mut Vec<u8>) {
+        match (self.bits_per_sample, self.little_endian) {
+            (8, _) if self.unsigned_8bit => out.push((sample + 128) as u8),
+            (8, _) => out.push(sample as i8 as u8),
+            (16, true) => out.extend_from_slice(&(sample as i16).to_le_bytes()),
+            (16, false) => out.extend_from_slice(&(sample as i16).to_be_bytes()),
+            (_, true) => out.extend_from_slice(&sample.to_le_bytes()[..3]),
+            (_, false) => out.extend_from_slice(&sample.to_be_bytes()[1..]),
+        }
+    }
+}
+
+/// 音声データより前のチャンクと PCM の形式
+struct ContainerHeader {
+    container: Container,
+    format: PcmFormat,
+    /// 音声データより前のチャンク (音声データのチャンクはヘッダ部分だけ)
+    chunks: Vec<Vec<u8>>,
+    /// 音声データのサンプル数 (チャンネルごと)
+    frames: u64,
+}
+
+/// WAV/AIFF の先頭から音声データの直前までを読む
+fn parse_header<R: Read>(r: &mut R) -> Result<ContainerHeader> {
+    let mut magic = [0u8; 12];
+    r.read_exact(&mut magic)
+        .context("WAV/AIFF のヘッダを読めません")?;
+    match (&magic[0..4], &magic[8..12]) {
+        (b"RIFF", b"WAVE") => parse_wav(r, magic),
+        (b"FORM", b"AIFF" | b"AIFC") => parse_aiff(r, magic),
+        _ => anyhow::bail!("WAV/AIFF ではありません"),
+    }
+}
+
+fn read_chunk<R: Read>(r: &mut R, header: [u8; 8], body_len: usize) -> Result<Vec<u8>> {
+    if body_len + header.len() > MAX_METADATA_BLOCK_LEN - 4 {
+        anyhow::bail!(
+            "{} チャンクが大きすぎます ({} バイト)",
+            String::from_utf8_lossy(&header[0..4]),
+            body_len
+        );
+    }
+    let mut chunk = header.to_vec();
+    chunk.resize(header.len() + body_len, 0);
+    r.read_exact(&mut chunk[header.len()..])
+        .context("チャンクが途中で切れています")?;
+    Ok(chunk)
+}
+
+fn check_format(format: &PcmFormat) -> Result<()> {
+    if !matches!(format.bits_per_sample, 8 | 16 | 24) {
+        anyhow::bail!("未対応のビット深度です: {}bit", format.bits_per_sample);
+    }
+    if !(1..=8).contains(&format.channels) {
+        anyhow::bail!("未対応のチャンネル数です: {}", format.channels);
+    }
+    if !(1..=MAX_SAMPLE_RATE).contains(&format.sample_rate) {
+        anyhow::bail!("未対応のサンプリング周波数です: {}Hz", format.sample_rate);
+    }
+    Ok(())
+}
+
+fn parse_wav<R: Read>(r: &mut R, magic: [u8; 12]) -> Result<ContainerHeader> {
+    let mut chunks = vec![magic.to_vec()];
+    let mut format = None;
+    loop {
+        let mut header = [0u8; 8];
+        r.read_exact(&mut header)
+            .context("data チャンクが見つかりません")?;
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        if &header[0..4] == b"data" {
+            let format: PcmFormat = format.context("fmt チャンクが見つかりません")?;
+            chunks.push(header.to_vec());
+            return Ok(ContainerHeader {
+                container: Container::Wav,
+                format,
+                chunks,
+                frames: size / format.block_align() as u64,
+            });
+        }
+        // 奇数長のチャンクには 1 バイトの詰め物がある
+        let chunk = read_chunk(r, header, (size + (size & 1)) as usize)?;
+        if &header[0..4] == b"fmt " {
+            format = Some(parse_wav_fmt(&chunk[8..])?);
+        }
+        chunks.push(chunk);
+    }
+}
+
+fn parse_wav_fmt(body: &[u8]) -> Result<PcmFormat> {
+    if body.len() < 16 {
+        anyhow::bail!("fmt チャンクが短すぎます");
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+    let mut tag = u16_at(0);
+    // WAVE_FORMAT_EXTENSIBLE はサブフォーマット GUID の先頭が形式
+    if tag == 0xFFFE && body.len() >= 40 {
+        tag = u16_at(24);
+    }
+    if tag != 1 {
+        anyhow::bail!("PCM 以外の WAV は未対応です (形式 0x{:04x})", tag);
+    }
+    let format = PcmFormat {
+        channels: u16_at(2) as u32,
+        bits_per_sample: u16_at(14) as u32,
+        sample_rate: u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+        little_endian: true,
+        unsigned_8bit: true,
+    };
+    check_format(&format)?;
+    if u16_at(12) as usize != format.block_align() {
+        anyhow::bail!("fmt チャンクの blockAlign が不正です");
+    }
+    Ok(format)
+}
+
+fn parse_aiff<R: Read>(r: &mut R, magic: [u8; 12]) -> Result<ContainerHeader> {
+    let aifc = &magic[8..12] == b"AIFC";
+    let mut chunks = vec![magic.to_vec()];
+    let mut comm = None;
+    loop {
+        let mut header = [0u8; 8];
+        r.read_exact(&mut header)
+            .context("SSND チャンクが見つかりません")?;
+        let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        if &header[0..4] == b"SSND" {
+            let (format, frames): (PcmFormat, u64) =
+                comm.context("COMM チャンクが SSND チャンクより前にありません")?;
+            // offset と blockSize の後に offset バイトの詰め物があり、その後が音声データ
+            let mut ssnd = [0u8; 8];
+            r.read_exact(&mut ssnd)
+                .context("SSND チャンクが途中で切れています")?;
+            let offset = u32::from_be_bytes([ssnd[0], ssnd[1], ssnd[2], ssnd[3]]) as u64;
+            if size < 8 + offset + frames * format.block_align() as u64 {
+                anyhow::bail!("SSND チャンクが COMM のサンプル数より短いです");
+            }
+            let mut chunk = read_chunk(r, header, offset as usize)?;
+            chunk.splice(8..8, ssnd);
+            chunks.push(chunk);
+            return Ok(ContainerHeader {
+                container: Container::Aiff,
+                format,
+                chunks,
+                frames,
+            });
+        }
+        let chunk = read_chunk(r, header, (size + (size & 1)) as usize)?;
+        if &header[0..4] == b"COMM" {
+            comm = Some(parse_aiff_comm(&chunk[8..], aifc)?);
+        }
+        chunks.push(chunk);
+    }
+}
+
+fn parse_aiff_comm(body: &[u8], aifc: bool) -> Result<(PcmFormat, u64)> {
+    if body.len() < 18 || (aifc && body.len() < 22) {
+        anyhow::bail!("COMM チャンクが短すぎます");
+    }
+    let little_endian = if aifc {
+        match &body[18..22] {
+            b"NONE" | b"twos" => false,
+            b"sowt" => true,
+            other => anyhow::bail!(
+                "圧縮された AIFC は未対応です ({})",
+                String::from_utf8_lossy(other)
+            ),
+        }
+    } else {
+        false
+    };
+    let sample_rate =
+        extended_to_u32(&body[8..18]).context("COMM のサンプリング周波数が不正です")?;
+    let format = PcmFormat {
+        channels: u16::from_be_bytes([body[0], body[1]]) as u32,
+        bits_per_sample: u16::from_be_bytes([body[6], body[7]]) as u32,
+        sample_rate,
+        little_endian,
+        unsigned_8bit: false,
+    };
+    check_format(&format)?;
+    let frames = u32::from_be_bytes([body[2], body[3], body[4], body[5]]) as u64;
+    Ok((format, frames))
+}
+
+/// AIFF のサンプリング周波数 (80bit 拡張精度浮動小数点) を整数にする
+fn extended_to_u32(bytes: &[u8]) -> Option<u32> {
+    let sign_exponent = u16::from_be_bytes([bytes[0], bytes[1]]);
+    if sign_exponent & 0x8000 != 0 {
+        return None;
+    }
+    let mantissa = u64::from_be_bytes(bytes[2..10].try_into().ok()?);
+    let shift = 16383 + 63 - (sign_exponent as i32);
+    if mantissa == 0 || !(0..64).contains(&shift) {
+        return None;
+    }
+    let value = mantissa >> shift;
+    // 小数部があるものは扱わない
+    if value << shift != mantissa {
+        return None;
+    }
+    u32::try_from(value).ok()
+}
+
+/// WAV/AIFF なら FLAC に変換できる見込みがある (形式の確認は encode_file で行う)
+pub(crate) fn is_pcm_container(path: &Path, file_type: Option<i64>) -> bool {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("wav" | "aif" | "aiff" | "aifc") => true,
+        Some(_) => false,
+        // 11=WAV, 12=AIFF
+        None => matches!(file_type, Some(11 | 12)),
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// 下位 n ビット (n <= 32) を書く
+    fn write(&mut self, value: u64, n: u32) {
+        let mask = (1u64 << n) - 1;
+        self.acc = (self.acc << n) | (value & mask);
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1u64 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, n: u32) {
+        self.write(value as u64, n);
+    }
+
+    fn write_rice(&mut self, value: u32, parameter: u32) {
+        let mut quotient = value >> parameter;
+        while quotient >= 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+        self.write(1, quotient + 1);
+        self.write(value as u64, parameter);
+    }
+
+    /// フレーム番号を UTF-8 と同じ可変長で書く
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+        let len = (2..=7u32)
+            .find(|&n| value < 1u64 << (5 * n + 1))
+            .unwrap_or(7);
+        let lead = (0xFF00u32 >> len) as u64 & 0xFF;
+        self.write(lead | (value >> (6 * (len - 1))), 8);
+        for i in (0..len - 1).rev() {
+            self.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |crc, &b| {
+        let mut crc = crc ^ b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |crc, &b| {
+        (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ b) as usize]
+    })
+}
+
+/// 残差の Rice 符号化の区分
+struct RicePlan {
+    partition_order: u32,
+    parameters: Vec<u32>,
+    rice2: bool,
+}
+
+enum SubframeKind {
+    Constant,
+    Verbatim,
+    Fixed {
+        order: usize,
+        /// 符号なしに変換した (zigzag) 残差
+        residual: Vec<u32>,
+        rice: RicePlan,
+    },
+}
+
+struct SubframePlan {
+    /// 見積もったビット数
+    bits: u64,
+    kind: SubframeKind,
+}
+
+/// 固定予測の残差。32bit に収まらない場合は None
+fn fixed_residual(x: &[i64], order: usize) -> Option<Vec<u32>> {
+    let mut residual = Vec::with_capacity(x.len() - order);
+    for i in order..x.len() {
+        let r = match order {
+            0 => x[i],
+            1 => x[i] - x[i - 1],
+            2 => x[i] - 2 * x[i - 1] + x[i - 2],
+            3 => x[i] - 3 * x[i - 1] + 3 * x[i - 2] - x[i - 3],
+            _ => x[i] - 4 * x[i - 1] + 6 * x[i - 2] - 4 * x[i - 3] + x[i - 4],
+        };
+        let r = i32::try_from(r).ok()?;
+        residual.push(((r as u32) << 1) ^ ((r >> 31) as u32));
+    }
+    Some(residual)
+}
+
+/// 区分の合計と件数から Rice パラメータとビット数を見積もる
+fn rice_parameter(sum: u64, len: u64) -> (u32, u64) {
+    if len == 0 {
+        return (0, 0);
+    }
+    let estimate = if sum > len { (sum / len).ilog2() } else { 0 };
+    (estimate.saturating_sub(1)..=(estimate + 1).min(MAX_RICE2_PARAMETER))
+        .map(|k| (k, len * (k as u64 + 1) + (sum >> k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, u64::MAX))
+}
+
+fn plan_rice(residual: &[u32], block_size: usize, order: usize) -> (u64, RicePlan) {
+    let mut prefix = Vec::with_capacity(residual.len() + 1);
+    prefix.push(0u64);
+    for &r in residual {
+        prefix.push(prefix[prefix.len() - 1] + r as u64);
+    }
+
+    let mut best: Option<(u64, RicePlan)> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1usize << partition_order;
+        // 区分は同じ長さで、最初の区分は予測の初期値の分だけ短い
+        if !block_size.is_multiple_of(partitions) || block_size / partitions <= order {
+            break;
+        }
+        let partition_len = block_size / partitions;
+        let mut bits = 0u64;
+        let mut parameters = Vec::with_capacity(partitions);
+        let mut start = 0;
+        for i in 0..partitions {
+            let len = if i == 0 {
+                partition_len - order
+            } else {
+                partition_len
+            };
+            let (parameter, partition_bits) =
+                rice_parameter(prefix[start + len] - prefix[start], len as u64);
+            parameters.push(parameter);
+            bits += partition_bits;
+            start += len;
+        }
+        let rice2 = parameters.iter().any(|&k| k > MAX_RICE_PARAMETER);
+        bits += 6 + partitions as u64 * if rice2 { 5 } else { 4 };
+        if best.as_ref().is_none_or(|(best_bits, _)| bits < *best_bits) {
+            best = Some((
+                bits,
+                RicePlan {
+                    partition_order,
+                    parameters,
+                    rice2,
+                },
+            ));
+        }
+    }
+    best.unwrap_or_else(|| {
+        (
+            u64::MAX,
+            RicePlan {
+                partition_order: 0,
+                parameters: vec![MAX_RICE2_PARAMETER],
+                rice2: true,
+            },
+        )
+    })
+}
+
+fn plan_subframe(samples: &[i64], bits_per_sample: u32) -> SubframePlan {
+    if samples.iter().all(|&s| s == samples[0]) {
+        return SubframePlan {
+            bits: 8 + bits_per_sample as u64,
+            kind: SubframeKind::Constant,
+        };
+    }
+    let mut best = SubframePlan {
+        bits: 8 + samples.len() as u64 * bits_per_sample as u64,
+        kind: SubframeKind::Verbatim,
+    };
+    for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+        let Some(residual) = fixed_residual(samples, order) else {
+            continue;
+        };
+        let (rice_bits, rice) = plan_rice(&residual, samples.len(), order);
+        let bits = (8 + order as u64 * bits_per_sample as u64).saturating_add(rice_bits);
+        if bits < best.bits {
+            best = SubframePlan {
+                bits,
+                kind: SubframeKind::Fixed {
+                    order,
+                    residual,
+                    rice,
+                },
+            };
+        }
+    }
+    best
+}
+
+fn write_subframe(w: &mut BitWriter, samples: &[i64], bits_per_sample: u32, plan: &SubframePlan) {
+    match &plan.kind {
+        SubframeKind::Constant => {
+            w.write(0x00, 8);
+            w.write_signed(samples[0], bits_per_sample);
+        }
+        SubframeKind::Verbatim => {
+            w.write(0x02, 8);
+            for &s in samples {
+                w.write_signed(s, bits_per_sample);
+            }
+        }
+        SubframeKind::Fixed {
+            order,
+            residual,
+            rice,
+        } => {
+            w.write(0x10 | ((*order as u64) << 1), 8);
+            for &s in &samples[..*order] {
+                w.write_signed(s, bits_per_sample);
+            }
+            w.write(rice.rice2 as u64, 2);
+            w.write(rice.partition_order as u64, 4);
+            let parameter_bits = if rice.rice2 { 5 } else { 4 };
+            let partition_len = samples.len() >> rice.partition_order;
+            let mut start = 0;
+            for (i, &parameter) in rice.parameters.iter().enumerate() {
+                let len = if i == 0 {
+                    partition_len - order
+                } else {
+                    partition_len
+                };
+                w.write(parameter as u64, parameter_bits);
+                for &r in &residual[start..start + len] {
+                    w.write_rice(r, parameter);
+                }
+                start += len;
+            }
+        }
+    }
+}
+
+fn block_size_code(block_size: usize) -> (u64, Option<(u64, u32)>) {
+    match block_size {
+        4096 => (0b1100, None),
+        n if n <= 256 => (0b0110, Some(((n - 1) as u64, 8))),
+        n => (0b0111, Some(((n - 1) as u64, 16))),
+    }
+}
+
+fn sample_rate_code(sample_rate: u32) -> (u64, Option<(u64, u32)>) {
+    let rate = sample_rate as u64;
+    match sample_rate {
+        88_200 => (0b0001, None),
+        176_400 => (0b0010, None),
+        192_000 => (0b0011, None),
+        8_000 => (0b0100, None),
+        16_000 => (0b0101, None),
+        22_050 => (0b0110, None),
+        24_000 => (0b0111, None),
+        32_000 => (0b1000, None),
+        44_100 => (0b1001, None),
+        48_000 => (0b1010, None),
+        96_000 => (0b1011, None),
+        r if r % 1000 == 0 && r / 1000 <= 255 => (0b1100, Some((rate / 1000, 8))),
+        r if r <= 65_535 => (0b1101, Some((rate, 16))),
+        r if r % 10 == 0 && r / 10 <= 65_535 => (0b1110, Some((rate / 10, 16))),
+        _ => (0b0000, None),
+    }
+}
+
+fn sample_size_code(bits_per_sample: u32) -> u64 {
+    match bits_per_sample {
+        8 => 0b001,
+        16 => 0b100,
+        _ => 0b110,
+    }
+}
+
+/// 1フレームを符号化する。ステレオは L/R・L/S・S/R・M/S のうち最も小さくなるものを使う
+fn encode_frame(channels: &[Vec<i64>], format: &PcmFormat, frame_number: u64) -> Vec<u8> {
+    let block_size = channels[0].len();
+    let bps = format.bits_per_sample;
+
+    let mut subframes: Vec<(Vec<i64>, u32, SubframePlan)> = Vec::new();
+    let assignment = if channels.len() == 2 {
+        let (left, right) = (&channels[0], &channels[1]);
+        let side: Vec<i64> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+        let mid: Vec<i64> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+        let left_plan = plan_subframe(left, bps);
+        let right_plan = plan_subframe(right, bps);
+        let side_plan = plan_subframe(&side, bps + 1);
+        let mid_plan = plan_subframe(&mid, bps);
+        let costs = [
+            left_plan.bits.saturating_add(right_plan.bits),
+            left_plan.bits.saturating_add(side_plan.bits),
+            side_plan.bits.saturating_add(right_plan.bits),
+            mid_plan.bits.saturating_add(side_plan.bits),
+        ];
+        let best = (0..costs.len()).min_by_key(|&i| costs[i]).unwrap_or(0);
+        match best {
+            0 => {
+                subframes.push((left.clone(), bps, left_plan));
+                subframes.push((right.clone(), bps, right_plan));
+                0b0001
+            }
+            1 => {
+                subframes.push((left.clone(), bps, left_plan));
+                subframes.push((side, bps + 1, side_plan));
+                0b1000
+            }
+            2 => {
+                subframes.push((side, bps + 1, side_plan));
+                subframes.push((right.clone(), bps, right_plan));
+                0b1001
+            }
+            _ => {
+                subframes.push((mid, bps, mid_plan));
+                subframes.push((side, bps + 1, side_plan));
+                0b1010
+            }
+        }
+    } else {
+        for channel in channels {
+            let plan = plan_subframe(channel, bps);
+            subframes.push((channel.clone(), bps, plan));
+        }
+        channels.len() as u64 - 1
+    };
+
+    let mut w = BitWriter::default();
+    // 同期コード + 固定ブロックサイズ
+    w.write(0xFFF8, 16);
+    let (block_size_code, block_size_extra) = block_size_code(block_size);
+    let (sample_rate_code, sample_rate_extra) = sample_rate_code(format.sample_rate);
+    w.write(block_size_code, 4);
+    w.write(sample_rate_code, 4);
+    w.write(assignment, 4);
+    w.write(sample_size_code(bps), 3);
+    w.write(0, 1);
+    w.write_utf8(frame_number);
+    for (value, bits) in block_size_extra.into_iter().chain(sample_rate_extra) {
+        w.write(value, bits);
+    }
+    let header_crc = crc8(&w.bytes);
+    w.write(header_crc as u64, 8);
+
+    for (samples, bits_per_sample, plan) in &subframes {
+        write_subframe(&mut w, samples, *bits_per_sample, plan);
+    }
+    w.align();
+    let frame_crc = crc16(&w.bytes);
+    w.write(frame_crc as u64, 16);
+    w.bytes
+}
+
+pub(crate) fn write_metadata_header<W: Write>(
+    out: &mut W,
+    block_type: u8,
+    last: bool,
+    len: usize,
+) -> Result<()> {
+    let len = len as u32;
+    out.write_all(&[
+        block_type | if last { 0x80 } else { 0 },
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ])?;
+    Ok(())
+}
+
+fn stream_info_bytes(
+    format: &PcmFormat,
+    frame_sizes: Option<(u32, u32)>,
+    total_samples: u64,
+    md5: &[u8],
+) -> Vec<u8> {
+    let (min_frame, max_frame) = frame_sizes.unwrap_or((0, 0));
+    let mut w = BitWriter::default();
+    w.write(BLOCK_SIZE as u64, 16);
+    w.write(BLOCK_SIZE as u64, 16);
+    w.write(min_frame as u64, 24);
+    w.write(max_frame as u64, 24);
+    w.write(format.sample_rate as u64, 20);
+    w.write(format.channels as u64 - 1, 3);
+    w.write(format.bits_per_sample as u64 - 1, 5);
+    w.write(total_samples >> 32, 4);
+    w.write(total_samples, 32);
+    w.bytes.extend_from_slice(md5);
+    w.bytes
+}
+
+/// WAV/AIFF を FLAC に可逆圧縮して dest に書く。音声データ以外のチャンクは
+/// APPLICATION ブロックにそのまま格納し、restore で元のファイルに戻せるようにする。
+/// 書いた FLAC を戻したものが source_sha256 と一致しなければエラーにする
+pub(crate) fn encode_file(source: &Path, dest: &Path, source_sha256: &str) -> Result<()> {
+    let mut input = BufReader::new(
+        fs::File::open(source)
+            .with_context(|| format!("ファイルを開けません: {}", source.display()))?,
+    );
+    let header = parse_header(&mut input)?;
+    let format = header.format;
+    let data_offset = input.stream_position()?;
+
+    // 音声データより後ろのチャンク
+    input.seek(SeekFrom::Start(
+        data_offset + header.frames * format.block_align() as u64,
+    ))?;
+    let mut trailer = Vec::new();
+    input.read_to_end(&mut trailer)?;
+    input.seek(SeekFrom::Start(data_offset))?;
+
+    let mut out = BufWriter::new(
+        fs::File::create(dest)
+            .with_context(|| format!("ファイルの作成に失敗: {}", dest.display()))?,
+    );
+    out.write_all(b"fLaC")?;
+    // STREAMINFO はフレームを書き終えてから書き直す
+    write_metadata_header(&mut out, 0, false, 34)?;
+    out.write_all(&[0u8; 34])?;
+    let foreign: Vec<&[u8]> = header
+        .chunks
+        .iter()
+        .map(|chunk| chunk.as_slice())
+        .chain(trailer.chunks(MAX_METADATA_BLOCK_LEN - 4))
+        .collect();
+    let application_id = header.container.application_id();
+    for (i, block) in foreign.iter().enumerate() {
+        write_metadata_header(&mut out, 2, i + 1 == foreign.len(), block.len() + 4)?;
+        out.write_all(&application_id)?;
+        out.write_all(block)?;
+    }
+
+    let align = format.block_align();
+    let bytes_per_sample = format.bytes_per_sample();
+    let md5_layout = PcmFormat::md5_layout(format.bits_per_sample);
+    let mut md5 = Md5::new();
+    let mut md5_bytes = Vec::with_capacity(BLOCK_SIZE * align);
+    let mut raw = vec![0u8; BLOCK_SIZE * align];
+    let mut channels: Vec<Vec<i64>> =
+        vec![Vec::with_capacity(BLOCK_SIZE); format.channels as usize];
+    let mut frame_sizes: Option<(u32, u32)> = None;
+    let mut remaining = header.frames;
+    let mut frame_number = 0u64;
+    while remaining > 0 {
+        let block_size = remaining.min(BLOCK_SIZE as u64) as usize;
+        let raw = &mut raw[..block_size * align];
+        input
+            .read_exact(raw)
+            .context("音声データが途中で切れています")?;
+        channels.iter_mut().for_each(|c| c.clear());
+        md5_bytes.clear();
+        for frame in raw.chunks_exact(align) {
+            for (channel, bytes) in channels
+                .iter_mut()
+                .zip(frame.chunks_exact(bytes_per_sample))
+            {
+                let sample = format.decode_sample(bytes);
+                channel.push(sample as i64);
+                md5_layout.encode_sample(sample, &mut md5_bytes);
+            }
+        }
+        md5.update(&md5_bytes);
+
+        let frame = encode_frame(&channels, &format, frame_number);
+        let size = frame.len() as u32;
+        frame_sizes = Some(match frame_sizes {
+            Some((min, max)) => (min.min(size), max.max(size)),
+            None => (size, size),
+        });
+        out.write_all(&frame)?;
+        frame_number += 1;
+        remaining -= block_size as u64;
+    }
+
+    out.seek(SeekFrom::Start(8))?;
+    out.write_all(&stream_info_bytes(
+        &format,
+        frame_sizes,
+        header.frames,
+        &md5.finalize(),
+    ))?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    let mut hasher = Sha256::new();
+    restore(BufReader::new(fs::File::open(dest)?), &mut hasher)
+        .context("変換した FLAC を元に戻せません")?;
+    if hex::encode(hasher.finalize()) != source_sha256 {
+        anyhow::bail!("変換した FLAC を戻したものが元のファイルと一致しません");
+    }
+    Ok(())
+}
+
+/// encode_file で変換した FLAC を元の WAV/AIFF のバイト列に戻して out に書く
+pub(crate) fn restore<R: Read, W: Write>(input: R, out: &mut W) -> Result<()> {
+    let mut reader = BufferedReader::new(input);
+    let mut marker = [0u8; 4];
+    reader.read_into(&mut marker)?;
+    if &marker != b"fLaC" {
+        anyhow::bail!("FLAC ではありません");
+    }
+
+    let mut stream_info = None;
+    let mut foreign = Vec::new();
+    for block in MetadataBlockReader::new(&mut reader) {
+        match block.context("FLAC のメタデータを読めません")? {
+            MetadataBlock::StreamInfo(info) => stream_info = Some(info),
+            MetadataBlock::Application { id, data }
+                if [Container::Wav, Container::Aiff]
+                    .iter()
+                    .any(|c| id == u32::from_be_bytes(c.application_id())) =>
+            {
+                foreign.extend_from_slice(&data);
+            }
+            _ => {}
+        }
+    }
+    let info = stream_info.context("FLAC に STREAMINFO がありません")?;
+
+    // 元のファイルの音声データより前の部分を読み直して PCM の形式を得る
+    let mut cursor = Cursor::new(foreign.as_slice());
+    let header = parse_header(&mut cursor).context("元の WAV/AIFF のチャンクがありません")?;
+    let header_len = cursor.position() as usize;
+    let format = header.format;
+    if info.channels != format.channels || info.bits_per_sample != format.bits_per_sample {
+        anyhow::bail!("FLAC と元の WAV/AIFF のチャンネル数・ビット深度が一致しません");
+    }
+    out.write_all(&foreign[..header_len])?;
+
+    let md5_layout = PcmFormat::md5_layout(format.bits_per_sample);
+    let mut md5 = Md5::new();
+    let mut md5_bytes = Vec::new();
+    let mut bytes = Vec::new();
+    let mut frames = FrameReader::new(&mut reader);
+    let mut buffer = Vec::new();
+    let mut written = 0u64;
+    while let Some(block) = frames
+        .read_next_or_eof(buffer)
+        .context("FLAC のフレームを読めません")?
+    {
+        bytes.clear();
+        md5_bytes.clear();
+        for i in 0..block.duration() {
+            for ch in 0..block.channels() {
+                let sample = block.sample(ch, i);
+                format.encode_sample(sample, &mut bytes);
+                md5_layout.encode_sample(sample, &mut md5_bytes);
+            }
+        }
+        out.write_all(&bytes)?;
+        md5.update(&md5_bytes);
+        written += block.duration() as u64;
+        buffer = block.into_buffer();
+    }
+    if written != header.frames {
+        anyhow::bail!(
+            "サンプル数が一致しません ({}, 元は {})",
+            written,
+            header.frames
+        );
+    }
+    if info.md5sum != [0u8; 16] && md5.finalize().as_slice() != info.md5sum {
+        anyhow::bail!("FLAC の MD5 が一致しません");
+    }
+
+    out.write_all(&foreign[header_len..])?;
+    Ok(())
+}
+
+/// FLAC ファイルの平均ビットレート (kbps)。djmdContent.BitRate に使う
+pub(crate) fn bit_rate(path: &Path) -> Result<i64> {
+    let reader = claxon::FlacReader::open(path)
+        .with_context(|| format!("FLAC を開けません: {}", path.display()))?;
+    let info = reader.streaminfo();
+    let size = fs::metadata(path)?.len();
+    let seconds = info.samples.unwrap_or(0) as f64 / info.sample_rate as f64;
+    if seconds <= 0.0 {
+        return Ok(0);
+    }
+    Ok((size as f64 * 8.0 / seconds / 1000.0).round() as i64)
+}
+
+/// FLAC を元の WAV/AIFF に戻して dest に書く。SHA-256 が一致しなければ dest を消してエラーにする
+pub(crate) fn restore_to_file<R: Read>(input: R, dest: &Path, expected_sha256: &str) -> Result<()> {
+    let file = fs::File::create(dest)
+        .with_context(|| format!("ファイルの作成に失敗: {}", dest.display()))?;
+    let mut out = HashingWriter {
+        inner: BufWriter::new(file),
+        hasher: Sha256::new(),
+    };
+    let result = restore(input, &mut out).and_then(|_| {
+        out.inner.flush()?;
+        if hex::encode(out.hasher.finalize_reset()) != expected_sha256 {
+            anyhow::bail!("元に戻した WAV/AIFF の SHA-256 が一致しません");
+        }
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(dest);
+    }
+    result
+}
+
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 最大値・最小値を含む、予測しにくい (圧縮しにくい) サンプル
+    fn test_samples(bits_per_sample: u32, channels: u32, frames: usize) -> Vec<i32> {
+        let max = (1i64 << (bits_per_sample - 1)) - 1;
+        let min = -(1i64 << (bits_per_sample - 1));
+        let mut state = 0x1234_5678u32;
+        (0..frames * channels as usize)
+            .map(|i| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let sample = match i % 97 {
+                    0 => max,
+                    1 => min,
+                    _ => {
+                        let wave = ((i as f64 / 20.0).sin() * max as f64 * 0.5) as i64;
+                        let noise = (state >> 8) as i64 % (max / 8 + 1);
+                        (wave + noise).clamp(min, max)
+                    }
+                };
+                sample as i32
+            })
+            .collect()
+    }
+
+    fn pcm_bytes(format: &PcmFormat, samples: &[i32]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &sample in samples {
+            format.encode_sample(sample, &mut bytes);
+        }
+        bytes
+    }
+
+    /// チャンク (奇数長なら詰め物を付ける)
+    fn chunk(id: &[u8; 4], body: &[u8], little_endian: bool) -> Vec<u8> {
+        let size = body.len() as u32;
+        let mut bytes = id.to_vec();
+        bytes.extend_from_slice(&if little_endian {
+            size.to_le_bytes()
+        } else {
+            size.to_be_bytes()
+        });
+        bytes.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn wav_format(bits_per_sample: u32, channels: u32) -> PcmFormat {
+        PcmFormat {
+            channels,
+            bits_per_sample,
+            sample_rate: 44_100,
+            little_endian: true,
+            unsigned_8bit: true,
+        }
+    }
+
+    fn aiff_format(bits_per_sample: u32, channels: u32) -> PcmFormat {
+        PcmFormat {
+            channels,
+            bits_per_sample,
+            sample_rate: 48_000,
+            little_endian: false,
+            unsigned_8bit: false,
+        }
+    }
+
+    fn wav(format: &PcmFormat, samples: &[i32], before: &[Vec<u8>], after: &[Vec<u8>]) -> Vec<u8> {
+        let mut fmt = Vec::new();
+        fmt.extend_from_slice(&1u16.to_le_bytes());
+        fmt.extend_from_slice(&(format.channels as u16).to_le_bytes());
+        fmt.extend_from_slice(&format.sample_rate.to_le_bytes());
+        fmt.extend_from_slice(&(format.sample_rate * format.block_align() as u32).to_le_bytes());
+        fmt.extend_from_slice(&(format.block_align() as u16).to_le_bytes());
+        fmt.extend_from_slice(&(format.bits_per_sample as u16).to_le_bytes());
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &fmt, true));
+        body.extend(before.concat());
+        body.extend(chunk(b"data", &pcm_bytes(format, samples), true));
+        body.extend(after.concat());
+        chunk(b"RIFF", &body, true)
+    }
+
+    /// 整数のサンプリング周波数を 80bit 拡張精度浮動小数点にする
+    fn extended(value: u32) -> [u8; 10] {
+        let shift = (value as u64).leading_zeros();
+        let exponent = (16383 + 63 - shift) as u16;
+        let mut bytes = [0u8; 10];
+        bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+        bytes[2..].copy_from_slice(&((value as u64) << shift).to_be_bytes());
+        bytes
+    }
+
+    fn aiff(format: &PcmFormat, samples: &[i32], before: &[Vec<u8>], after: &[Vec<u8>]) -> Vec<u8> {
+        let frames = samples.len() as u32 / format.channels;
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&(format.channels as u16).to_be_bytes());
+        comm.extend_from_slice(&frames.to_be_bytes());
+        comm.extend_from_slice(&(format.bits_per_sample as u16).to_be_bytes());
+        comm.extend_from_slice(&extended(format.sample_rate));
+
+        let mut ssnd = vec![0u8; 8];
+        ssnd.extend(pcm_bytes(format, samples));
+
+        let mut body = b"AIFF".to_vec();
+        body.extend(chunk(b"COMM", &comm, false));
+        body.extend(before.concat());
+        body.extend(chunk(b"SSND", &ssnd, false));
+        body.extend(after.concat());
+        chunk(b"FORM", &body, false)
+    }
+
+    /// FLAC に変換し、claxon でデコードしたサンプルと元に戻したバイト列を照合する
+    fn assert_round_trip(source_bytes: &[u8], format: &PcmFormat, samples: &[i32]) {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let dest = dir.path().join("encoded.flac");
+        fs::write(&source, source_bytes).unwrap();
+        let sha256 = hex::encode(Sha256::digest(source_bytes));
+        encode_file(&source, &dest, &sha256).unwrap();
+
+        let mut reader = claxon::FlacReader::open(&dest).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.channels, format.channels);
+        assert_eq!(info.bits_per_sample, format.bits_per_sample);
+        assert_eq!(info.sample_rate, format.sample_rate);
+        // STREAMINFO のサンプル数 0 は「不明」として読まれる
+        assert_eq!(
+            info.samples.unwrap_or(0),
+            samples.len() as u64 / format.channels as u64
+        );
+        let decoded: Vec<i32> = reader.samples().map(|s| s.unwrap()).collect();
+        assert_eq!(decoded, samples);
+
+        let mut restored = Vec::new();
+        restore(fs::File::open(&dest).unwrap(), &mut restored).unwrap();
+        assert_eq!(restored, source_bytes);
+    }
+
+    #[test]
+    fn round_trips_wav_bit_depths_and_channels() {
+        for bits in [8, 16, 24] {
+            for channels in [1, 2] {
+                let format = wav_format(bits, channels);
+                // 最後のフレームが BLOCK_SIZE に満たない長さ
+                let samples = test_samples(bits, channels, BLOCK_SIZE * 2 + 5);
+                assert_round_trip(&wav(&format, &samples, &[], &[]), &format, &samples);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_aiff_bit_depths_and_channels() {
+        for bits in [8, 16, 24] {
+            for channels in [1, 2] {
+                let format = aiff_format(bits, channels);
+                let samples = test_samples(bits, channels, BLOCK_SIZE + 17);
+                assert_round_trip(&aiff(&format, &samples, &[], &[]), &format, &samples);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_odd_length_and_trailing_chunks() {
+        let before = vec![chunk(b"LIST", b"odd", true), chunk(b"junk", &[7; 10], true)];
+        let after = vec![
+            chunk(b"id3 ", b"ID3 tag", true),
+            chunk(b"cue ", &[1; 4], true),
+        ];
+        // 8bit モノラルの奇数サンプルは data チャンクも奇数長になる
+        let format = wav_format(8, 1);
+        let samples = test_samples(8, 1, 1001);
+        assert_round_trip(&wav(&format, &samples, &before, &after), &format, &samples);
+
+        let before = vec![chunk(b"NAME", b"title", false)];
+        let after = vec![chunk(b"ID3 ", b"ID3 tag", false)];
+        let format = aiff_format(24, 2);
+        let samples = test_samples(24, 2, 300);
+        assert_round_trip(&aiff(&format, &samples, &before, &after), &format, &samples);
+    }
+
+    #[test]
+    fn round_trips_zero_frame_files() {
+        let format = wav_format(16, 2);
+        assert_round_trip(&wav(&format, &[], &[], &[]), &format, &[]);
+        let format = aiff_format(16, 1);
+        let after = vec![chunk(b"ANNO", b"empty", false)];
+        assert_round_trip(&aiff(&format, &[], &[], &after), &format, &[]);
+    }
+
+    #[test]
+    fn rejects_unsupported_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.wav");
+        let dest = dir.path().join("encoded.flac");
+        let format = PcmFormat {
+            bits_per_sample: 32,
+            ..wav_format(16, 2)
+        };
+        fs::write(&source, wav(&format, &[0, 0], &[], &[])).unwrap();
+        assert!(encode_file(&source, &dest, "").is_err());
+    }
+}
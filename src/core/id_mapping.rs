@@ -1,10 +1,123 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use anyhow::{Context, Result};
 use rusqlite::{Connection, params};
 
 pub(crate) type IdMap = HashMap<String, HashMap<String, String>>;
 
+/// 挿入する行の UUID (テーブル → 新しい ID → UUID)。
+/// 空なら元の DB の UUID をそのまま使う
+#[derive(Default)]
+pub(crate) struct UuidMap {
+    uuids: HashMap<String, HashMap<String, String>>,
+    /// 新しく UUID を振った ID (uuidIDMap に登録する)
+    generated: BTreeMap<String, Vec<String>>,
+}
+
+/// UUID で別のテーブルの行を指す列 (UUID の列, ID の列, 参照先テーブル)
+fn uuid_ref_columns_for_table(table: &str) -> Vec<(&'static str, &'static str, &'static str)> {
+    match table {
+        "djmdCue" | "djmdActiveCensor" => vec![("ContentUUID", "ContentID", "djmdContent")],
+        "djmdSongHotCueBanklist" => vec![(
+            "HotCueBanklistUUID",
+            "HotCueBanklistID",
+            "djmdHotCueBanklist",
+        )],
+        _ => vec![],
+    }
+}
+
+impl UuidMap {
+    /// id_map の新しい ID ごとに UUID を決める。既存の行に合流する ID はその行の UUID を使い、
+    /// それ以外は新しい UUID を振る
+    pub(crate) fn regenerate(conn: &Connection, id_map: &IdMap) -> Result<Self> {
+        let mut map = Self::default();
+        for (table, table_map) in id_map {
+            let mut new_ids: Vec<&String> = table_map.values().collect();
+            new_ids.sort_by_key(|id| (id.parse::<i64>().ok(), id.as_str()));
+            new_ids.dedup();
+
+            let mut existing: HashMap<String, Option<String>> = HashMap::new();
+            for chunk in new_ids.chunks(500) {
+                let placeholders: Vec<&str> = chunk.iter().map(|_| "?").collect();
+                let sql = format!(
+                    "SELECT ID, UUID FROM `{}` WHERE ID IN ({})",
+                    table,
+                    placeholders.join(",")
+                );
+                let mut stmt = conn.prepare(&sql)?;
+                let rows = stmt.query_map(rusqlite::params_from_iter(chunk), |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+                })?;
+                for row in rows {
+                    let (id, uuid) = row?;
+                    existing.insert(id, uuid);
+                }
+            }
+
+            let uuids = map.uuids.entry(table.clone()).or_default();
+            for id in new_ids {
+                match existing.get(id) {
+                    Some(Some(uuid)) => {
+                        uuids.insert(id.clone(), uuid.clone());
+                    }
+                    Some(None) => {}
+                    None => {
+                        uuids.insert(id.clone(), uuid::Uuid::new_v4().to_string());
+                        map.generated
+                            .entry(table.clone())
+                            .or_default()
+                            .push(id.clone());
+                    }
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    pub(crate) fn get(&self, table: &str, id: &str) -> Option<&str> {
+        self.uuids.get(table)?.get(id).map(|s| s.as_str())
+    }
+
+    /// 新しく UUID を振って実際に挿入された行を uuidIDMap に登録する。登録した行数を返す
+    pub(crate) fn insert_uuid_id_map(&self, conn: &Connection) -> Result<u32> {
+        let mut max_id = get_max_numeric_id(conn, "uuidIDMap")?;
+        let mut inserted = 0;
+        for (table, ids) in &self.generated {
+            let sql = format!(
+                "INSERT INTO uuidIDMap (ID, TableName, TargetUUID, CurrentID, UUID, \
+                 rb_data_status, rb_local_data_status, rb_local_deleted, rb_local_synced, \
+                 created_at, updated_at) \
+                 SELECT ?1, ?2, ?3, ?4, ?5, 0, 0, 0, 0, \
+                 strftime('%Y-%m-%d %H:%M:%f +00:00', 'now'), \
+                 strftime('%Y-%m-%d %H:%M:%f +00:00', 'now') \
+                 WHERE EXISTS (SELECT 1 FROM `{}` WHERE ID = ?4 AND UUID = ?3)",
+                table
+            );
+            for id in ids {
+                let target_uuid = &self.uuids[table][id];
+                let changed = conn
+                    .execute(
+                        &sql,
+                        params![
+                            (max_id + 1).to_string(),
+                            table,
+                            target_uuid,
+                            id,
+                            uuid::Uuid::new_v4().to_string()
+                        ],
+                    )
+                    .with_context(|| format!("uuidIDMap への挿入に失敗 ({} ID: {})", table, id))?;
+                if changed > 0 {
+                    max_id += 1;
+                    inserted += 1;
+                }
+            }
+        }
+        Ok(inserted)
+    }
+}
+
 pub(crate) fn get_max_numeric_id(conn: &Connection, table: &str) -> Result<i64> {
     let sql = format!("SELECT MAX(CAST(ID AS INTEGER)) FROM `{}`", table);
     let max_id: Option<i64> = conn.query_row(&sql, [], |row| row.get(0)).unwrap_or(None);
@@ -56,21 +169,27 @@ pub(crate) fn fk_columns_for_table(table: &str) -> Vec<(&'static str, &'static s
             ("MasterSongID", "djmdContent"),
         ],
         "djmdAlbum" => vec![("AlbumArtistID", "djmdArtist")],
-        "djmdSongPlaylist" => vec![
-            ("PlaylistID", "djmdPlaylist"),
+        "djmdPlaylist" => vec![("ParentID", "djmdPlaylist")],
+        "djmdHistory" => vec![("ParentID", "djmdHistory")],
+        "djmdHotCueBanklist" => vec![("ParentID", "djmdHotCueBanklist")],
+        "djmdSampler" => vec![("ParentID", "djmdSampler")],
+        "djmdSongSampler" => vec![("SamplerID", "djmdSampler"), ("ContentID", "djmdContent")],
+        "djmdRelatedTracks" => vec![("ParentID", "djmdRelatedTracks")],
+        "djmdSongRelatedTracks" => vec![
+            ("RelatedTracksID", "djmdRelatedTracks"),
             ("ContentID", "djmdContent"),
         ],
+        "djmdSongHistory" => vec![("HistoryID", "djmdHistory"), ("ContentID", "djmdContent")],
+        "djmdSongPlaylist" => vec![("PlaylistID", "djmdPlaylist"), ("ContentID", "djmdContent")],
         "djmdCue" => vec![("ContentID", "djmdContent")],
         "djmdActiveCensor" => vec![("ContentID", "djmdContent")],
         "djmdMixerParam" => vec![("ContentID", "djmdContent")],
-        "djmdSongMyTag" => vec![
-            ("MyTagID", "djmdMyTag"),
-            ("ContentID", "djmdContent"),
-        ],
+        "djmdSongMyTag" => vec![("MyTagID", "djmdMyTag"), ("ContentID", "djmdContent")],
         "djmdSongTagList" => vec![("ContentID", "djmdContent")],
         "djmdSongHotCueBanklist" => vec![
             ("HotCueBanklistID", "djmdHotCueBanklist"),
             ("ContentID", "djmdContent"),
+            ("CueID", "djmdCue"),
         ],
         "hotCueBanklistCue" => vec![("HotCueBanklistID", "djmdHotCueBanklist")],
         "contentCue" => vec![("ContentID", "djmdContent")],
@@ -80,6 +199,15 @@ pub(crate) fn fk_columns_for_table(table: &str) -> Vec<(&'static str, &'static s
     }
 }
 
+/// 属性のマスタ (トラックやアルバムが任意で参照するテーブル)。
+/// 参照先がパックにない場合は参照を外して取り込む
+pub(crate) fn is_attribute_master(table: &str) -> bool {
+    matches!(
+        table,
+        "djmdArtist" | "djmdAlbum" | "djmdGenre" | "djmdKey" | "djmdLabel" | "djmdColor"
+    )
+}
+
 pub(crate) fn insert_row(conn: &Connection, table: &str, row: &serde_json::Value) -> Result<()> {
     let obj = row
         .as_object()
@@ -98,23 +226,7 @@ pub(crate) fn insert_row(conn: &Connection, table: &str, row: &serde_json::Value
 
     let values: Vec<Box<dyn rusqlite::types::ToSql>> = columns
         .iter()
-        .map(|col| -> Box<dyn rusqlite::types::ToSql> {
-            match &obj[col.as_str()] {
-                serde_json::Value::Null => Box::new(Option::<String>::None),
-                serde_json::Value::Number(n) => {
-                    if let Some(i) = n.as_i64() {
-                        Box::new(i)
-                    } else if let Some(f) = n.as_f64() {
-                        Box::new(f)
-                    } else {
-                        Box::new(n.to_string())
-                    }
-                }
-                serde_json::Value::String(s) => Box::new(s.clone()),
-                serde_json::Value::Bool(b) => Box::new(*b as i32),
-                other => Box::new(other.to_string()),
-            }
-        })
+        .map(|col| sql_value(&obj[col.as_str()]))
         .collect();
 
     let params: Vec<&dyn rusqlite::types::ToSql> = values.iter().map(|v| v.as_ref()).collect();
@@ -122,10 +234,71 @@ pub(crate) fn insert_row(conn: &Connection, table: &str, row: &serde_json::Value
     Ok(())
 }
 
+/// 既存の行 (ID が同じもの) を行データの内容で更新する
+pub(crate) fn update_row(conn: &Connection, table: &str, row: &serde_json::Value) -> Result<()> {
+    let obj = row
+        .as_object()
+        .context("行データがオブジェクトではありません")?;
+    let id = obj
+        .get("ID")
+        .and_then(|v| v.as_str())
+        .context("行データに ID がありません")?;
+
+    let columns: Vec<&String> = obj.keys().filter(|c| c.as_str() != "ID").collect();
+    if columns.is_empty() {
+        return Ok(());
+    }
+    let assignments: Vec<String> = columns.iter().map(|c| format!("`{}` = ?", c)).collect();
+    let sql = format!(
+        "UPDATE `{}` SET {} WHERE ID = ?",
+        table,
+        assignments.join(", ")
+    );
+
+    let mut values: Vec<Box<dyn rusqlite::types::ToSql>> = columns
+        .iter()
+        .map(|col| sql_value(&obj[col.as_str()]))
+        .collect();
+    values.push(Box::new(id.to_string()));
+
+    let params: Vec<&dyn rusqlite::types::ToSql> = values.iter().map(|v| v.as_ref()).collect();
+    conn.execute(&sql, params.as_slice())?;
+    Ok(())
+}
+
+/// ID の行がテーブルにあるか (削除済みのものを含む)
+pub(crate) fn row_exists(conn: &Connection, table: &str, id: &str) -> bool {
+    conn.query_row(
+        &format!("SELECT 1 FROM `{}` WHERE ID = ?", table),
+        params![id],
+        |_| Ok(true),
+    )
+    .unwrap_or(false)
+}
+
+fn sql_value(value: &serde_json::Value) -> Box<dyn rusqlite::types::ToSql> {
+    match value {
+        serde_json::Value::Null => Box::new(Option::<String>::None),
+        serde_json::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Box::new(i)
+            } else if let Some(f) = n.as_f64() {
+                Box::new(f)
+            } else {
+                Box::new(n.to_string())
+            }
+        }
+        serde_json::Value::String(s) => Box::new(s.clone()),
+        serde_json::Value::Bool(b) => Box::new(*b as i32),
+        other => Box::new(other.to_string()),
+    }
+}
+
 pub(crate) fn apply_mapping(
     row: &serde_json::Value,
     table: &str,
     id_map: &IdMap,
+    uuids: &UuidMap,
 ) -> serde_json::Value {
     let mut row = row.clone();
     if let Some(obj) = row.as_object_mut() {
@@ -134,25 +307,61 @@ pub(crate) fn apply_mapping(
             .and_then(|v| v.as_str())
             .map(|s| s.to_string())
             && let Some(table_map) = id_map.get(table)
-                && let Some(new_id) = table_map.get(&old_id) {
-                    obj.insert(
-                        "ID".to_string(),
-                        serde_json::Value::String(new_id.clone()),
-                    );
-                }
+            && let Some(new_id) = table_map.get(&old_id)
+        {
+            obj.insert("ID".to_string(), serde_json::Value::String(new_id.clone()));
+        }
 
         for (fk_col, ref_table) in fk_columns_for_table(table) {
-            if let Some(old_fk) = obj
+            let Some(old_fk) = obj
                 .get(fk_col)
                 .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
                 .map(|s| s.to_string())
-                && let Some(ref_map) = id_map.get(ref_table)
-                    && let Some(new_fk) = ref_map.get(&old_fk) {
-                        obj.insert(
-                            fk_col.to_string(),
-                            serde_json::Value::String(new_fk.clone()),
-                        );
-                    }
+            else {
+                continue;
+            };
+            match id_map
+                .get(ref_table)
+                .and_then(|ref_map| ref_map.get(&old_fk))
+            {
+                Some(new_fk) => {
+                    obj.insert(
+                        fk_col.to_string(),
+                        serde_json::Value::String(new_fk.clone()),
+                    );
+                }
+                // パックにない属性のマスタを指したままにすると、手元の別の行を指してしまう
+                None if is_attribute_master(ref_table) => {
+                    obj.insert(fk_col.to_string(), serde_json::Value::Null);
+                }
+                None => {}
+            }
+        }
+
+        if let Some(new_uuid) = obj
+            .get("ID")
+            .and_then(|v| v.as_str())
+            .and_then(|id| uuids.get(table, id))
+            && obj.contains_key("UUID")
+        {
+            obj.insert(
+                "UUID".to_string(),
+                serde_json::Value::String(new_uuid.to_string()),
+            );
+        }
+        for (uuid_col, id_col, ref_table) in uuid_ref_columns_for_table(table) {
+            if let Some(ref_uuid) = obj
+                .get(id_col)
+                .and_then(|v| v.as_str())
+                .and_then(|id| uuids.get(ref_table, id))
+                && obj.contains_key(uuid_col)
+            {
+                obj.insert(
+                    uuid_col.to_string(),
+                    serde_json::Value::String(ref_uuid.to_string()),
+                );
+            }
         }
 
         for &sync_field in &[
@@ -161,18 +370,12 @@ pub(crate) fn apply_mapping(
             "rb_local_file_status",
         ] {
             if obj.contains_key(sync_field) {
-                obj.insert(
-                    sync_field.to_string(),
-                    serde_json::Value::Number(0.into()),
-                );
+                obj.insert(sync_field.to_string(), serde_json::Value::Number(0.into()));
             }
         }
         for &sync_field in &["rb_local_synced"] {
             if obj.contains_key(sync_field) {
-                obj.insert(
-                    sync_field.to_string(),
-                    serde_json::Value::Number(0.into()),
-                );
+                obj.insert(sync_field.to_string(), serde_json::Value::Number(0.into()));
             }
         }
         for &sync_field in &["usn", "rb_local_usn", "rb_insync_local_usn"] {
@@ -197,11 +400,7 @@ fn remap_json_blob_field(
     }
 }
 
-pub(crate) fn remap_json_blob(
-    row: &mut serde_json::Value,
-    table: &str,
-    id_map: &IdMap,
-) {
+pub(crate) fn remap_json_blob(row: &mut serde_json::Value, table: &str, id_map: &IdMap) {
     let (blob_field, ref_table) = match table {
         "contentCue" => ("Cues", "djmdCue"),
         "contentActiveCensor" => ("ActiveCensors", "djmdActiveCensor"),
@@ -212,7 +411,10 @@ pub(crate) fn remap_json_blob(
     let Some(obj) = row.as_object_mut() else {
         return;
     };
-    let Some(blob_str) = obj.get(blob_field).and_then(|v| v.as_str()).map(|s| s.to_string())
+    let Some(blob_str) = obj
+        .get(blob_field)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
     else {
         return;
     };
@@ -235,9 +437,6 @@ pub(crate) fn remap_json_blob(
     }
 
     if let Ok(new_blob) = serde_json::to_string(&arr) {
-        obj.insert(
-            blob_field.to_string(),
-            serde_json::Value::String(new_blob),
-        );
+        obj.insert(blob_field.to_string(), serde_json::Value::String(new_blob));
     }
 }
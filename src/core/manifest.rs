@@ -0,0 +1,481 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{BufRead, BufReader, Read};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::volume::RkpArchive;
+
+/// 現在の pack.json のバージョン
+pub(crate) const PACK_VERSION: u64 = 3;
+
+/// 行数が多く、メモリに読み込まずに1行ずつ処理するテーブル (トラックごとのキュー等)
+pub(crate) const STREAMED_TABLES: &[&str] = &[
+    "djmdCue",
+    "djmdActiveCensor",
+    "djmdMixerParam",
+    "djmdSongTagList",
+    "contentCue",
+    "contentActiveCensor",
+];
+
+/// テーブルの1行 (カラム名 → 値 の JSON オブジェクト)
+pub(crate) type Row = serde_json::Value;
+
+/// テーブル名 → 行
+pub(crate) type Tables = BTreeMap<String, Vec<Row>>;
+
+/// スキーマ上の行の型
+type RowSchema = serde_json::Map<String, serde_json::Value>;
+
+/// .rkp 内の pack.json
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[schemars(title = "rkpack pack.json")]
+pub(crate) struct PackManifest {
+    /// 形式のバージョン (2 は行を tables に直接持ち、3 は table_files のエントリに持つ)
+    #[schemars(extend("enum" = [2, 3]))]
+    pub version: u64,
+    pub metadata: PackMetadata,
+    /// 最上位のプレイリスト (djmdPlaylist の行)。子プレイリストは tables.djmdPlaylist に入る
+    #[serde(default)]
+    #[schemars(with = "Vec<RowSchema>")]
+    pub playlists: Vec<Row>,
+    /// 再生履歴 (djmdHistory の行)
+    #[serde(default)]
+    #[schemars(with = "Vec<RowSchema>")]
+    pub histories: Vec<Row>,
+    /// ホットキューバンクリスト (djmdHotCueBanklist の行)
+    #[serde(default)]
+    #[schemars(with = "Vec<RowSchema>")]
+    pub hot_cue_banklists: Vec<Row>,
+    /// サンプラー (djmdSampler の行)
+    #[serde(default)]
+    #[schemars(with = "Vec<RowSchema>")]
+    pub samplers: Vec<Row>,
+    /// 関連トラック (djmdRelatedTracks の行)
+    #[serde(default)]
+    #[schemars(with = "Vec<RowSchema>")]
+    pub related_tracks: Vec<Row>,
+    /// テーブル名 → 行。カラムは rekordbox の DB と同じ。
+    /// バージョン3 の pack.json にはなく、行は table_files のエントリにある
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schemars(with = "BTreeMap<String, Vec<RowSchema>>")]
+    pub tables: Tables,
+    /// テーブル名 → 行を格納した JSON Lines のエントリ。tables に同じテーブルがあればそちらを使う
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub table_files: BTreeMap<String, TableFile>,
+    #[serde(default)]
+    pub audio_files: Vec<AudioFile>,
+    /// 分析データ・アートワーク (PIONEER フォルダ以下のファイル)
+    #[serde(default)]
+    pub content_data_files: Vec<ContentDataFile>,
+}
+
+/// パック全体の情報
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub(crate) struct PackMetadata {
+    /// パックごとの一意な ID (差分パックが基準を識別するのに使う)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack_id: Option<String>,
+    /// 作成日時 (RFC 3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    /// 作成したツールとバージョン
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generator: Option<String>,
+    /// 音声ファイルを含まないパック
+    #[serde(default)]
+    pub lite: bool,
+    /// 差分パックの基準 (通常のパックにはない)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<PackBase>,
+}
+
+/// 差分パックの基準パック
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub(crate) struct PackBase {
+    pub pack_id: Option<String>,
+    pub file_name: String,
+    /// テーブル名 → 基準と結合した後の行 ID の並び。tables には基準から変わった行だけが入る
+    #[serde(default)]
+    pub table_ids: BTreeMap<String, Vec<String>>,
+}
+
+/// テーブルの行を格納した .rkp 内の JSON Lines (1行に1レコード)
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub(crate) struct TableFile {
+    /// .rkp 内のエントリ名 (tables/<テーブル名>.jsonl)
+    pub entry: String,
+    pub rows: u64,
+    pub size: u64,
+    /// エントリ内容の SHA-256 (16進)
+    pub sha256: String,
+}
+
+/// パックに格納した音声ファイル
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub(crate) struct AudioFile {
+    pub content_id: String,
+    /// 展開先の相対パス
+    pub relative_path: String,
+    /// .rkp 内のエントリ名 (ない場合は files/<relative_path>)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry: Option<String>,
+    /// ファイル内容の SHA-256 (16進)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// 更新日時 (UNIX 秒)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// 差分パックで、音声が基準パック側にある
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub in_base: bool,
+    /// WAV/AIFF を FLAC に変換して格納した場合の元のファイル (sha256・size はエントリの FLAC のもの)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flac_source: Option<FlacSource>,
+    /// パック時にタグを書き込んだコピー (sha256・size はコピーのもの。
+    /// FLAC に変換したものは、flac_source が ID3 タグを書き込んだ WAV/AIFF)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tagged: bool,
+}
+
+/// FLAC に変換する前の WAV/AIFF (アンパック時に元に戻して照合する)
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub(crate) struct FlacSource {
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl AudioFile {
+    /// 対応する .rkp 内のエントリ名。古いパックはハッシュではなく相対パスで格納している
+    pub(crate) fn entry_name(&self) -> String {
+        match &self.entry {
+            Some(entry) => entry.clone(),
+            None => format!("files/{}", self.relative_path.replace('\\', "/")),
+        }
+    }
+
+    /// 元の音声ファイルの SHA-256 (FLAC に変換したものは変換前)
+    pub(crate) fn source_sha256(&self) -> Option<&str> {
+        match &self.flac_source {
+            Some(source) => Some(source.sha256.as_str()),
+            None => self.sha256.as_deref(),
+        }
+    }
+}
+
+/// パックに格納した分析データ・アートワークのファイル
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub(crate) struct ContentDataFile {
+    /// 対応する contentFile の ID (アートワークの縮小画像にはない)
+    #[serde(default)]
+    pub content_file_id: Option<String>,
+    /// PIONEER フォルダからの相対パス
+    pub relative_path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// このファイルを指す ImagePath / imageFile.Path の元の値 (展開後の相対パスに書き換える)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_path: Option<String>,
+}
+
+impl ContentDataFile {
+    pub(crate) fn entry_name(&self) -> String {
+        format!("content_data/{}", self.relative_path.replace('\\', "/"))
+    }
+}
+
+impl PackManifest {
+    /// pack.json を読む。古いバージョンは現在の形式に変換する
+    pub(crate) fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_slice(bytes).context("pack.json の解析に失敗")?;
+        Self::from_value(value)
+    }
+
+    pub(crate) fn from_value(mut value: serde_json::Value) -> Result<Self> {
+        let version = value["version"].as_u64().unwrap_or(0);
+        match version {
+            1 => upgrade_v1(&mut value),
+            2 | PACK_VERSION => {}
+            _ => anyhow::bail!("未対応のパックバージョン: {}", version),
+        }
+        serde_json::from_value(value).context("pack.json の形式が不正です")
+    }
+
+    /// 差分パックかどうか
+    pub(crate) fn is_delta(&self) -> bool {
+        self.metadata.base.is_some()
+    }
+
+    /// テーブルの行 (テーブルがなければ空)
+    pub(crate) fn rows(&self, table: &str) -> &[Row] {
+        self.tables
+            .get(table)
+            .map(|r| r.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// パック内のすべてのテーブル名 (読み込み済みかどうかによらない)
+    pub(crate) fn table_names(&self) -> BTreeSet<String> {
+        self.tables
+            .keys()
+            .chain(self.table_files.keys())
+            .cloned()
+            .collect()
+    }
+
+    /// JSON Lines のテーブルのうち filter に一致するものを tables に読み込む
+    pub(crate) fn load_tables(
+        &mut self,
+        archive: &mut RkpArchive,
+        filter: impl Fn(&str) -> bool,
+    ) -> Result<()> {
+        for (table, file) in &self.table_files {
+            if self.tables.contains_key(table) || !filter(table) {
+                continue;
+            }
+            let mut rows = Vec::new();
+            read_table_rows(archive.by_name(&file.entry)?, &file.entry, |row| {
+                rows.push(row);
+                Ok(())
+            })?;
+            self.tables.insert(table.clone(), rows);
+        }
+        Ok(())
+    }
+
+    /// tables の外に置かれた最上位の行 (playlists / histories 等のキーで指定する)
+    pub(crate) fn top_level_rows(&self, key: &str) -> &[Row] {
+        match key {
+            "playlists" => &self.playlists,
+            "histories" => &self.histories,
+            "hot_cue_banklists" => &self.hot_cue_banklists,
+            "samplers" => &self.samplers,
+            "related_tracks" => &self.related_tracks,
+            _ => &[],
+        }
+    }
+}
+
+/// JSON Lines を1行ずつ解析して f に渡す (name はエラー表示用)
+pub(crate) fn read_table_rows(
+    reader: impl Read,
+    name: &str,
+    mut f: impl FnMut(Row) -> Result<()>,
+) -> Result<()> {
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line.with_context(|| format!("{} の読み込みに失敗", name))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = serde_json::from_str(&line)
+            .with_context(|| format!("{} の {} 行目の解析に失敗", name, i + 1))?;
+        f(row)?;
+    }
+    Ok(())
+}
+
+/// テーブルの行を1行ずつ読む。読み込み済みのテーブルはメモリから、
+/// それ以外は .rkp 内の JSON Lines から読む
+pub(crate) struct TableReader<'a> {
+    pack_data: &'a PackManifest,
+    archive: &'a mut RkpArchive,
+    /// JSON Lines から読むときに除くトラック (音声なしパックで照合できなかったもの)
+    excluded_contents: HashSet<String>,
+    /// 差分パックの基準 .rkp (STREAMED_TABLES は差分の行と基準の行を結合して読む)
+    base: Option<BaseTables>,
+}
+
+/// 差分パックの基準 .rkp。テーブルはメモリに読み込まず、必要なときに1行ずつ読む
+pub(crate) struct BaseTables {
+    pack_data: PackManifest,
+    archive: RkpArchive,
+}
+
+impl BaseTables {
+    pub(crate) fn new(pack_data: PackManifest, archive: RkpArchive) -> Self {
+        Self { pack_data, archive }
+    }
+
+    pub(crate) fn pack_data(&self) -> &PackManifest {
+        &self.pack_data
+    }
+
+    /// 基準のテーブルの行を順に f に渡す
+    pub(crate) fn for_each(
+        &mut self,
+        table: &str,
+        f: impl FnMut(&Row) -> Result<()>,
+    ) -> Result<()> {
+        if let Some(rows) = self.pack_data.tables.get(table) {
+            return rows.iter().try_for_each(f);
+        }
+        read_entry_rows(&self.pack_data, &mut self.archive, table, f)
+    }
+}
+
+impl<'a> TableReader<'a> {
+    pub(crate) fn new(pack_data: &'a PackManifest, archive: &'a mut RkpArchive) -> Self {
+        Self {
+            pack_data,
+            archive,
+            excluded_contents: HashSet::new(),
+            base: None,
+        }
+    }
+
+    pub(crate) fn excluding_contents(mut self, content_ids: HashSet<String>) -> Self {
+        self.excluded_contents = content_ids;
+        self
+    }
+
+    pub(crate) fn with_base(mut self, base: Option<BaseTables>) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// 読めるすべてのテーブル名 (基準と結合する場合は基準にだけ行がある STREAMED_TABLES も含む)
+    pub(crate) fn table_names(&self) -> BTreeSet<String> {
+        let mut names = self.pack_data.table_names();
+        names.extend(
+            STREAMED_TABLES
+                .iter()
+                .filter(|table| self.merged_base_ids(table).is_some())
+                .map(|table| table.to_string()),
+        );
+        names
+    }
+
+    /// 基準と結合して読む STREAMED_TABLES の、結合後の行 ID
+    fn merged_base_ids(&self, table: &str) -> Option<&'a Vec<String>> {
+        if self.base.is_none() || !STREAMED_TABLES.contains(&table) {
+            return None;
+        }
+        self.pack_data.metadata.base.as_ref()?.table_ids.get(table)
+    }
+
+    /// テーブルの行を順に f に渡す (テーブルがなければ何もしない)。
+    /// 基準と結合する STREAMED_TABLES は差分の行、差分にない基準の行の順に渡す
+    pub(crate) fn for_each(
+        &mut self,
+        table: &str,
+        mut f: impl FnMut(&Row) -> Result<()>,
+    ) -> Result<()> {
+        if let Some(rows) = self.pack_data.tables.get(table) {
+            return rows.iter().try_for_each(f);
+        }
+        let excluded = &self.excluded_contents;
+        let mut f = |row: &Row| {
+            let is_excluded = row
+                .get("ContentID")
+                .and_then(|v| v.as_str())
+                .is_some_and(|cid| excluded.contains(cid));
+            if is_excluded {
+                return Ok(());
+            }
+            f(row)
+        };
+        let base_ids = self.merged_base_ids(table);
+        let (Some(base), Some(base_ids)) = (self.base.as_mut(), base_ids) else {
+            return read_entry_rows(self.pack_data, self.archive, table, f);
+        };
+
+        let base_ids: HashSet<&str> = base_ids.iter().map(|id| id.as_str()).collect();
+        let mut changed = HashSet::new();
+        read_entry_rows(self.pack_data, self.archive, table, |row| {
+            if let Some(id) = row.get("ID").and_then(|v| v.as_str()) {
+                if !base_ids.contains(id) {
+                    return Ok(());
+                }
+                changed.insert(id.to_string());
+            }
+            f(row)
+        })?;
+        base.for_each(table, |row| {
+            let id = row.get("ID").and_then(|v| v.as_str()).unwrap_or_default();
+            if base_ids.contains(id) && !changed.contains(id) {
+                f(row)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// table_files のエントリからテーブルの行を読む (エントリがなければ何もしない)
+fn read_entry_rows(
+    pack_data: &PackManifest,
+    archive: &mut RkpArchive,
+    table: &str,
+    mut f: impl FnMut(&Row) -> Result<()>,
+) -> Result<()> {
+    let Some(file) = pack_data.table_files.get(table) else {
+        return Ok(());
+    };
+    read_table_rows(archive.by_name(&file.entry)?, &file.entry, |row| f(&row))
+}
+
+/// バージョン1 の pack.json をバージョン2 の形に直す。
+/// 1 は単一プレイリスト形式 ("playlist") で metadata がない。行は 2 と同じく tables に直接持つ
+fn upgrade_v1(value: &mut serde_json::Value) {
+    let Some(obj) = value.as_object_mut() else {
+        return;
+    };
+    if !obj.contains_key("playlists")
+        && let Some(playlist) = obj.remove("playlist")
+    {
+        obj.insert(
+            "playlists".to_string(),
+            serde_json::Value::Array(vec![playlist]),
+        );
+    }
+    obj.entry("metadata")
+        .or_insert_with(|| serde_json::json!({}));
+    obj.insert("version".to_string(), serde_json::json!(2));
+}
+
+/// pack.json の JSON Schema
+pub fn pack_schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(PackManifest)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrades_v1_single_playlist_pack() {
+        let pack = PackManifest::from_value(serde_json::json!({
+            "version": 1,
+            "playlist": {"ID": "10", "Name": "Set"},
+            "tables": {"djmdContent": [{"ID": "1"}]},
+            "audio_files": [{"content_id": "1", "relative_path": "a.mp3"}],
+            "content_data_files": [{"content_file_id": null, "relative_path": "x/a.DAT"}],
+        }))
+        .unwrap();
+        assert_eq!(pack.version, 2);
+        assert_eq!(
+            pack.playlists,
+            vec![serde_json::json!({"ID": "10", "Name": "Set"})]
+        );
+        assert_eq!(pack.rows("djmdContent").len(), 1);
+        assert_eq!(pack.audio_files[0].entry_name(), "files/a.mp3");
+        assert!(pack.metadata.pack_id.is_none() && !pack.is_delta());
+    }
+
+    #[test]
+    fn committed_schema_matches_pack_schema() {
+        let committed: serde_json::Value =
+            serde_json::from_str(include_str!("../../schemas/pack.schema.json")).unwrap();
+        assert_eq!(
+            committed,
+            pack_schema(),
+            "schemas/pack.schema.json を `rkpack schema` で作り直してください"
+        );
+    }
+}
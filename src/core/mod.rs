@@ -1,16 +1,34 @@
 mod db;
+mod filter;
+mod flac;
 mod id_mapping;
+mod manifest;
 mod pack;
 mod query;
+mod relocate;
+mod sign;
+mod smart_list;
+mod tags;
 mod unpack;
+mod verify;
+mod volume;
 
 pub use db::{DEFAULT_KEY, default_db_path, export_decrypted, open_rekordbox_db};
-pub use pack::{pack_playlist, pack_playlist_by_id};
+pub use filter::TrackFilter;
+pub use manifest::pack_schema;
+pub use pack::{
+    PackCompression, PackEstimate, PackOptions, estimate_playlists, pack_filtered_tracks,
+    pack_histories, pack_hot_cue_banklists, pack_playlists, pack_related_tracks, pack_samplers,
+};
 pub use query::{
-    PlaylistInfo, TrackInfo, get_playlist_tracks, get_playlists, list_playlists, list_tables,
+    PlaylistInfo, TrackInfo, get_playlist_tracks, get_playlists, list_histories, list_playlists,
+    list_tables,
 };
+pub use sign::{SignatureStatus, generate_key};
 pub use unpack::{
-    DuplicateDecision, DuplicateInfo, DuplicateMatch, UnpackDecisions, UnpackPreviewData,
-    check_content_id_duplicate, load_unpack_preview, unpack_playlist,
+    DuplicateDecision, DuplicateInfo, DuplicateMatch, UnpackDecisions, UnpackOptions,
+    UnpackPreviewData, check_content_id_duplicate, load_unpack_preview, unpack_playlist,
     unpack_playlist_with_decisions,
 };
+pub use verify::verify_pack;
+pub use volume::{PassphraseError, is_encrypted_pack, parse_split_size};
@@ -1,27 +1,390 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::{self, Write as _};
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
+use rayon::prelude::*;
 use rusqlite::Connection;
 use serde_json::json;
-use zip::ZipWriter;
-use zip::write::SimpleFileOptions;
+use sha2::{Digest, Sha256};
+use zip::write::{FileOptions, SimpleFileOptions};
+use zip::{AesMode, ZipArchive, ZipWriter};
 
 use super::db::to_nfc;
-use super::query::{collect_ids_from_column, query_by_content_ids, query_by_ids, query_table_rows};
+use super::filter::TrackFilter;
+use super::flac;
+use super::manifest::{
+    AudioFile, ContentDataFile, FlacSource, PACK_VERSION, PackBase, PackManifest, PackMetadata,
+    Row, STREAMED_TABLES, TableFile, TableReader,
+};
+use super::query::{
+    collect_ids_from_column, for_each_row_by_content_ids, query_by_content_ids, query_by_ids,
+    query_table_rows,
+};
+use super::relocate::{ExpectedAudio, Relocation, SearchIndex, relocate_missing_audio};
+use super::sign::{SIGNATURE_ENTRY, load_signing_key, sign_manifest};
+use super::smart_list::{
+    evaluate_smart_list, parse_smart_list, referenced_my_tag_ids, smart_list_to_xml,
+};
+use super::tags::{self, HotCue, TrackTags};
+use super::unpack::{get_share_dir, load_pack_data, read_pack_json};
+use super::volume::{VolumeWriter, open_rkp};
+
+/// パック時のオプション
+#[derive(Clone, Default)]
+pub struct PackOptions {
+    /// 音声ファイルのディレクトリ構造を維持する (すべての音声ファイルに共通するフォルダより下)
+    pub keep_structure: bool,
+    /// スマートプレイリストを固定リストに変換せず、条件ごと移行する
+    pub keep_smart_lists: bool,
+    /// 音声ファイルを含めずテーブルデータだけをパックする (受け取り側の手持ちのトラックに適用する)
+    pub no_audio: bool,
+    /// 音声なしパックでも分析データファイル (content_data/) を含める
+    pub with_analysis: bool,
+    /// 差分パックの基準にする以前の .rkp (変更のない音声と行を省く)
+    pub base: Option<String>,
+    /// 圧縮できるエントリの圧縮方式
+    pub compression: PackCompression,
+    /// 指定したサイズごとに `name.rkp.001`, `.002`, ... へ分割する (FAT32 のメディア向け)
+    pub split_size: Option<u64>,
+    /// pack.json に署名する秘密鍵ファイル (keygen で作成)
+    pub sign_key: Option<String>,
+    /// 指定すると pack.json・音声・分析データを AES-256 で暗号化する
+    pub passphrase: Option<String>,
+    /// 音声ファイル等の元の絶対パスや端末・DB 固有の値を pack.json にそのまま残す
+    /// (既定ではパック内の相対パスに置き換え、端末・DB 固有の値は消す)
+    pub keep_source_paths: bool,
+    /// .rkp を作らず、行数・ファイルサイズの見積もりと見つからないファイルを表示する
+    pub dry_run: bool,
+    /// FolderPath のファイルが見つからないとき、ファイル名とサイズで探すフォルダ
+    pub search_roots: Vec<String>,
+    /// search_roots では基準パック (base) に記録された SHA-256 で照合する (名前が変わったファイルも見つかる)
+    pub search_by_hash: bool,
+    /// search_roots で SHA-256 を照合する以前の .rkp (base と違い差分パックにはしない)
+    pub hash_pack: Option<String>,
+    /// WAV/AIFF を FLAC に可逆圧縮して格納する (アンパック時に元のファイルに戻す)
+    pub flac: bool,
+    /// 格納する音声ファイルのコピーに djmdContent のタイトル・アーティスト等とホットキューを
+    /// タグとして書き込む (元のファイルは変更しない)
+    pub embed_tags: bool,
+}
+
+/// パックの見積もり (--dry-run)。差分パックの基準は考慮せず、基準と同じ音声も数える
+#[derive(Clone, Debug, Default)]
+pub struct PackEstimate {
+    /// テーブル名 → 行数
+    pub table_rows: BTreeMap<String, u64>,
+    pub audio_files: usize,
+    pub audio_bytes: u64,
+    /// 分析データ・アートワーク・画像のファイル
+    pub data_files: usize,
+    pub data_bytes: u64,
+    /// .rkp の推定サイズ (圧縮しない場合の目安)
+    pub estimated_size: u64,
+    /// 音声ファイルが見つからないトラック
+    pub missing_audio: Vec<MissingFile>,
+    /// search_roots で見つけた音声ファイルの移動先
+    pub relocated: Vec<Relocation>,
+    /// contentFile・画像のファイルが見つからないもの
+    pub missing_data_files: Vec<MissingFile>,
+    /// 分析されていない (AnalysisDataPath のない) トラック
+    pub unanalyzed: Vec<String>,
+}
+
+/// 見積もりで見つからなかったファイル
+#[derive(Clone, Debug)]
+pub struct MissingFile {
+    /// トラック名 (画像は参照しているパス)
+    pub label: String,
+    pub path: String,
+}
+
+impl PackEstimate {
+    /// 見積もりを表示用の行にする (CLI と GUI で同じ内容を表示する)
+    pub fn summary_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut table_summary = String::from("テーブルデータ:");
+        for (name, rows) in &self.table_rows {
+            if *rows > 0 {
+                table_summary.push_str(&format!(" {}={}行", name, rows));
+            }
+        }
+        lines.push(table_summary);
+        lines.push(format!(
+            "音声ファイル: {} 件 ({})",
+            self.audio_files,
+            format_bytes(self.audio_bytes)
+        ));
+        lines.push(format!(
+            "データファイル(artwork/分析): {} 件 ({})",
+            self.data_files,
+            format_bytes(self.data_bytes)
+        ));
+        lines.push(format!(
+            "推定サイズ: {} (圧縮前の目安)",
+            format_bytes(self.estimated_size)
+        ));
+        if !self.relocated.is_empty() {
+            lines.push(format!(
+                "移動先で見つかったトラック: {} 件",
+                self.relocated.len()
+            ));
+            for relocation in &self.relocated {
+                lines.push(format!(
+                    "  {}: {} → {}",
+                    relocation.label, relocation.from, relocation.to
+                ));
+            }
+        }
+        for (heading, missing) in [
+            ("音声ファイルが見つからないトラック", &self.missing_audio),
+            ("データファイルが見つからないもの", &self.missing_data_files),
+        ] {
+            if missing.is_empty() {
+                continue;
+            }
+            lines.push(format!("{}: {} 件", heading, missing.len()));
+            for file in missing {
+                lines.push(format!("  {}: {}", file.label, file.path));
+            }
+        }
+        if !self.unanalyzed.is_empty() {
+            lines.push(format!(
+                "分析されていないトラック: {} 件",
+                self.unanalyzed.len()
+            ));
+            for title in &self.unanalyzed {
+                lines.push(format!("  {}", title));
+            }
+        }
+        lines
+    }
+}
+
+/// 1000 単位 (--split-size と同じ) のサイズ表記
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1_000_000_000 => format!("{:.2} GB", b as f64 / 1e9),
+        b if b >= 1_000_000 => format!("{:.1} MB", b as f64 / 1e6),
+        b if b >= 1_000 => format!("{:.1} KB", b as f64 / 1e3),
+        b => format!("{} B", b),
+    }
+}
+
+/// WAV/AIFF・分析データ・pack.json など圧縮の効くエントリの圧縮方式。
+/// MP3 などの圧縮済み音声や画像は効果がないため常に無圧縮で格納する
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum PackCompression {
+    #[default]
+    Deflate,
+    Zstd,
+    Store,
+}
+
+impl std::str::FromStr for PackCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "deflate" => Ok(Self::Deflate),
+            "zstd" => Ok(Self::Zstd),
+            "store" => Ok(Self::Store),
+            _ => anyhow::bail!("未対応の圧縮方式です: {} (deflate, zstd, store)", s),
+        }
+    }
+}
+
+impl PackCompression {
+    fn method(self) -> zip::CompressionMethod {
+        match self {
+            Self::Deflate => zip::CompressionMethod::Deflated,
+            Self::Zstd => zip::CompressionMethod::Zstd,
+            Self::Store => zip::CompressionMethod::Stored,
+        }
+    }
+
+    /// エントリごとの圧縮方式
+    fn method_for(self, path: &std::path::Path, file_type: Option<i64>) -> zip::CompressionMethod {
+        if is_precompressed(path, file_type) {
+            zip::CompressionMethod::Stored
+        } else {
+            self.method()
+        }
+    }
+}
+
+/// 既に圧縮されている形式か (拡張子で判別できなければ djmdContent.FileType で判断する)
+fn is_precompressed(path: &std::path::Path, file_type: Option<i64>) -> bool {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match ext.as_deref() {
+        Some(
+            "mp3" | "m4a" | "aac" | "mp4" | "flac" | "alac" | "ogg" | "opus" | "jpg" | "jpeg"
+            | "png",
+        ) => true,
+        Some("wav" | "aif" | "aiff") => false,
+        // 1=MP3, 4=M4A, 5=FLAC
+        _ => matches!(file_type, Some(1 | 4 | 5)),
+    }
+}
+
+/// 差分パックの基準にする以前のパック
+struct BasePack {
+    pack_id: Option<String>,
+    file_name: String,
+    pack_data: PackManifest,
+    /// テーブル名 → 行 ID → 行の SHA-256 (行を比較するのに使う)
+    row_digests: HashMap<String, HashMap<String, [u8; 32]>>,
+    /// files/ エントリ名 → サイズ (サイズ・更新日時を記録していない古いパック用)
+    entry_sizes: HashMap<String, u64>,
+}
+
+fn row_digest(row: &Row) -> Result<[u8; 32]> {
+    Ok(Sha256::digest(serde_json::to_vec(row)?).into())
+}
+
+impl BasePack {
+    fn load(path: &str, passphrase: Option<&str>) -> Result<Self> {
+        let mut archive = open_rkp(std::path::Path::new(path), passphrase)
+            .with_context(|| format!("基準の .rkp ファイルを読めません: {}", path))?;
+        let pack_data = load_pack_data(&mut archive)?;
+        if pack_data.is_delta() {
+            anyhow::bail!("差分パックは基準にできません: {}", path);
+        }
+
+        let mut entry_sizes = HashMap::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index_raw(i)?;
+            if entry.name().starts_with("files/") {
+                entry_sizes.insert(entry.name().to_string(), entry.size());
+            }
+        }
+
+        let mut row_digests: HashMap<String, HashMap<String, [u8; 32]>> = HashMap::new();
+        let mut reader = TableReader::new(&pack_data, &mut archive);
+        for table in pack_data.table_names() {
+            let digests = row_digests.entry(table.clone()).or_default();
+            reader.for_each(&table, |row| {
+                if let Some(id) = row.get("ID").and_then(|v| v.as_str()) {
+                    digests.insert(id.to_string(), row_digest(row)?);
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(Self {
+            pack_id: pack_data.metadata.pack_id.clone(),
+            file_name: PathBuf::from(path)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            pack_data,
+            row_digests,
+            entry_sizes,
+        })
+    }
+
+    /// 基準パックに同じ音声ファイルが入っていればその記録を返す (FLAC に変換したものは変換前と比べる)。
+    /// ハッシュを記録していない古いパックはサイズと更新日時で判定する
+    fn unchanged_audio(
+        &self,
+        content_id: &str,
+        size: u64,
+        mtime: u64,
+        sha256: &str,
+    ) -> Option<&AudioFile> {
+        let audio_files = &self.pack_data.audio_files;
+        if let Some(entry) = audio_files
+            .iter()
+            .find(|af| af.source_sha256() == Some(sha256))
+        {
+            return Some(entry);
+        }
+        let entry = audio_files.iter().find(|af| af.content_id == content_id)?;
+        if entry.sha256.is_some() {
+            return None;
+        }
+        let same = match entry.size {
+            Some(base_size) => base_size == size && entry.mtime.is_none_or(|m| m == mtime),
+            None => self.entry_sizes.get(&entry.entry_name()) == Some(&size),
+        };
+        same.then_some(entry)
+    }
+
+    /// 基準パックに同じ ID・同じ内容の行がある
+    fn is_unchanged_row(&self, table: &str, row: &Row) -> Result<bool> {
+        let Some(base_digest) = row
+            .get("ID")
+            .and_then(|v| v.as_str())
+            .and_then(|id| self.row_digests.get(table)?.get(id))
+        else {
+            return Ok(false);
+        };
+        Ok(*base_digest == row_digest(row)?)
+    }
+}
+
+/// ファイル内容の SHA-256 (16進)
+pub(crate) fn sha256_file(path: &std::path::Path) -> Result<String> {
+    let mut f = fs::File::open(path)
+        .with_context(|| format!("ファイルを開けません: {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut f, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// pack.json に記録する SHA-256 とサイズ
+fn file_digest(path: &std::path::Path) -> Result<(String, u64)> {
+    let size = fs::metadata(path)
+        .with_context(|| format!("ファイルを開けません: {}", path.display()))?
+        .len();
+    Ok((sha256_file(path)?, size))
+}
+
+/// 音声ファイルは内容のハッシュをエントリ名にして格納する (同名ファイルの衝突と重複を避ける)
+fn content_addressed_entry_name(sha256: &str, source_path: &std::path::Path) -> String {
+    match source_path.extension() {
+        Some(ext) => format!("files/{}.{}", sha256, ext.to_string_lossy().to_lowercase()),
+        None => format!("files/{}", sha256),
+    }
+}
+
+/// ファイルのサイズと更新日時 (UNIX 秒)
+fn file_size_and_mtime(path: &std::path::Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some((metadata.len(), mtime))
+}
+
+/// エントリの書き込みオプション。パスフレーズがあれば AES-256 で暗号化する
+fn entry_options(method: zip::CompressionMethod, passphrase: Option<&str>) -> FileOptions<'_, ()> {
+    let options = SimpleFileOptions::default().compression_method(method);
+    match passphrase {
+        Some(passphrase) => options.with_aes_encryption(AesMode::Aes256, passphrase),
+        None => options,
+    }
+}
 
 pub(crate) fn add_file_to_rkp<W: io::Write + io::Seek>(
     writer: &mut ZipWriter<W>,
     entry_name: &str,
     source_path: &std::path::Path,
+    method: zip::CompressionMethod,
+    passphrase: Option<&str>,
 ) -> Result<()> {
-    let options =
-        SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
-    writer.start_file(entry_name, options)?;
     let mut f = fs::File::open(source_path)
         .with_context(|| format!("ファイルを開けません: {}", source_path.display()))?;
+    writer.start_file(
+        entry_name,
+        entry_options(method, passphrase).large_file(true),
+    )?;
     io::copy(&mut f, writer)?;
     Ok(())
 }
@@ -53,9 +416,20 @@ fn find_playlist(
     Ok(playlists.into_iter().next().unwrap())
 }
 
+#[derive(Default)]
 struct PackData {
-    playlist: serde_json::Value,
+    playlists: Vec<serde_json::Value>,
+    child_playlists: Vec<serde_json::Value>,
     song_playlists: Vec<serde_json::Value>,
+    histories: Vec<serde_json::Value>,
+    history_folders: Vec<serde_json::Value>,
+    song_histories: Vec<serde_json::Value>,
+    /// 単独でパックしたホットキューバンクリスト (親→子の順)
+    hot_cue_banklist_tree: Vec<serde_json::Value>,
+    sampler_tree: Vec<serde_json::Value>,
+    song_samplers: Vec<serde_json::Value>,
+    related_tracks_tree: Vec<serde_json::Value>,
+    song_related_tracks: Vec<serde_json::Value>,
     contents: Vec<serde_json::Value>,
     artists: Vec<serde_json::Value>,
     albums: Vec<serde_json::Value>,
@@ -63,40 +437,262 @@ struct PackData {
     keys: Vec<serde_json::Value>,
     labels: Vec<serde_json::Value>,
     colors: Vec<serde_json::Value>,
-    cues: Vec<serde_json::Value>,
-    active_censors: Vec<serde_json::Value>,
-    mixer_params: Vec<serde_json::Value>,
     my_tags: Vec<serde_json::Value>,
     song_my_tags: Vec<serde_json::Value>,
-    song_tag_lists: Vec<serde_json::Value>,
     hot_cue_banklists: Vec<serde_json::Value>,
     song_hot_cue_banklists: Vec<serde_json::Value>,
     hot_cue_banklist_cues: Vec<serde_json::Value>,
-    content_cues: Vec<serde_json::Value>,
-    content_active_censors: Vec<serde_json::Value>,
     content_files: Vec<serde_json::Value>,
+    /// 音声・分析データと一緒に格納する画像の imageFile の行
+    image_files: Vec<serde_json::Value>,
+    /// パックするトラック。STREAMED_TABLES の行は書き込むときに DB から1行ずつ読む
+    content_ids: HashSet<String>,
+}
+
+impl PackData {
+    /// tables/ に書くテーブルと行 (STREAMED_TABLES 以外)
+    fn tables(&self) -> [(&'static str, &[serde_json::Value]); 20] {
+        [
+            ("djmdPlaylist", &self.child_playlists),
+            ("djmdSongPlaylist", &self.song_playlists),
+            ("djmdHistory", &self.history_folders),
+            ("djmdSongHistory", &self.song_histories),
+            ("djmdSongSampler", &self.song_samplers),
+            ("djmdSongRelatedTracks", &self.song_related_tracks),
+            ("djmdContent", &self.contents),
+            ("djmdArtist", &self.artists),
+            ("djmdAlbum", &self.albums),
+            ("djmdGenre", &self.genres),
+            ("djmdKey", &self.keys),
+            ("djmdLabel", &self.labels),
+            ("djmdColor", &self.colors),
+            ("djmdMyTag", &self.my_tags),
+            ("djmdSongMyTag", &self.song_my_tags),
+            ("djmdHotCueBanklist", &self.hot_cue_banklists),
+            ("djmdSongHotCueBanklist", &self.song_hot_cue_banklists),
+            ("hotCueBanklistCue", &self.hot_cue_banklist_cues),
+            ("contentFile", &self.content_files),
+            ("imageFile", &self.image_files),
+        ]
+    }
+}
+
+/// ParentID を辿って配下のフォルダ/リストを親→子の順にすべて取得する
+fn collect_descendants(
+    conn: &Connection,
+    table: &str,
+    root_id: &str,
+) -> Result<Vec<serde_json::Value>> {
+    let mut descendants = Vec::new();
+    let mut visited: HashSet<String> = HashSet::from([root_id.to_string()]);
+    let mut queue = vec![root_id.to_string()];
+
+    while let Some(parent_id) = queue.pop() {
+        let children = query_table_rows(
+            conn,
+            &format!(
+                "SELECT * FROM `{}` WHERE ParentID = ? AND rb_local_deleted = 0 ORDER BY Seq",
+                table
+            ),
+            &[&parent_id],
+        )?;
+        for child in children {
+            let Some(child_id) = child["ID"].as_str() else {
+                continue;
+            };
+            // 循環参照があっても無限ループしないように
+            if visited.insert(child_id.to_string()) {
+                queue.push(child_id.to_string());
+                descendants.push(child);
+            }
+        }
+    }
+
+    Ok(descendants)
 }
 
 fn collect_pack_data(
     conn: &Connection,
-    playlist: serde_json::Value,
+    playlists: Vec<serde_json::Value>,
+    options: &PackOptions,
     progress: &dyn Fn(&str),
 ) -> Result<PackData> {
-    let playlist_id = playlist["ID"]
-        .as_str()
-        .context("プレイリストのIDが取得できません")?;
-    let playlist_name = playlist["Name"].as_str().unwrap_or("?");
-    progress(&format!("プレイリスト: {} (ID: {})", playlist_name, playlist_id));
+    let mut trees = Vec::new();
+    for playlist in playlists {
+        let playlist_id = playlist["ID"]
+            .as_str()
+            .context("プレイリストのIDが取得できません")?
+            .to_string();
+        let descendants = collect_descendants(conn, "djmdPlaylist", &playlist_id)?;
+        trees.push((playlist_id, playlist, descendants));
+    }
 
-    let song_playlists = query_table_rows(
-        conn,
-        "SELECT * FROM djmdSongPlaylist WHERE PlaylistID = ? AND rb_local_deleted = 0",
-        &[&playlist_id],
-    )?;
-    let content_ids = collect_ids_from_column(&song_playlists, "ContentID");
+    // 他の選択フォルダの配下にあるもの・重複指定されたものはルートとして扱わない
+    let nested_ids: HashSet<String> = trees
+        .iter()
+        .flat_map(|(_, _, descendants)| collect_ids_from_column(descendants, "ID"))
+        .collect();
+    let mut seen_roots = HashSet::new();
+    let mut roots = Vec::new();
+    let mut child_playlists = Vec::new();
+    let mut song_playlists = Vec::new();
+
+    for (playlist_id, playlist, descendants) in trees {
+        let playlist_name = playlist["Name"].as_str().unwrap_or("?").to_string();
+        if nested_ids.contains(&playlist_id) || !seen_roots.insert(playlist_id.clone()) {
+            progress(&format!(
+                "プレイリスト {} (ID: {}) は他の選択と重複するためまとめてパックします",
+                playlist_name, playlist_id
+            ));
+            continue;
+        }
+        progress(&format!(
+            "プレイリスト: {} (ID: {})",
+            playlist_name, playlist_id
+        ));
+        if !descendants.is_empty() {
+            progress(&format!(
+                "配下のフォルダ/プレイリスト数: {}",
+                descendants.len()
+            ));
+        }
+
+        let playlist_ids = std::iter::once(playlist_id.as_str())
+            .chain(descendants.iter().filter_map(|p| p["ID"].as_str()));
+        for pid in playlist_ids {
+            song_playlists.extend(query_table_rows(
+                conn,
+                "SELECT * FROM djmdSongPlaylist WHERE PlaylistID = ? AND rb_local_deleted = 0",
+                &[&pid],
+            )?);
+        }
+        roots.push(playlist);
+        child_playlists.extend(descendants);
+    }
+
+    let mut content_ids = collect_ids_from_column(&song_playlists, "ContentID");
+    let mut smart_my_tag_ids = HashSet::new();
+    for playlist in roots.iter_mut().chain(child_playlists.iter_mut()) {
+        let matched = materialize_smart_playlist(
+            conn,
+            playlist,
+            options.keep_smart_lists,
+            &mut song_playlists,
+            &mut smart_my_tag_ids,
+            progress,
+        )?;
+        content_ids.extend(matched);
+    }
+
+    // 複数のリストに含まれるトラックも1回だけ収集される
     progress(&format!("トラック数: {}", content_ids.len()));
 
-    let contents = query_by_ids(conn, "djmdContent", "ID", &content_ids)?;
+    let mut data = collect_content_data(conn, &content_ids)?;
+    // 条件で参照されている MyTag は曲に付いていなくても移行する
+    let packed_my_tag_ids = collect_ids_from_column(&data.my_tags, "ID");
+    smart_my_tag_ids.retain(|id| !packed_my_tag_ids.contains(id));
+    data.my_tags
+        .extend(query_by_ids(conn, "djmdMyTag", "ID", &smart_my_tag_ids)?);
+
+    Ok(PackData {
+        playlists: roots,
+        child_playlists,
+        song_playlists,
+        ..data
+    })
+}
+
+/// スマートプレイリストの条件を評価し、一致したトラックの ContentID を返す。
+/// keep_smart が false の場合は通常のプレイリストに変換し、一致したトラックを
+/// djmdSongPlaylist の行として追加する。
+/// 条件を解析できないリストは空のプレイリストに、評価できない (未対応の条件を含む) リストは
+/// トラックを含めず条件だけを移行し、パック全体は失敗させない
+fn materialize_smart_playlist(
+    conn: &Connection,
+    playlist: &mut serde_json::Value,
+    keep_smart: bool,
+    song_playlists: &mut Vec<serde_json::Value>,
+    my_tag_ids: &mut HashSet<String>,
+    progress: &dyn Fn(&str),
+) -> Result<Vec<String>> {
+    let Some(smart_list) = playlist["SmartList"]
+        .as_str()
+        .filter(|s| !s.trim().is_empty())
+    else {
+        return Ok(Vec::new());
+    };
+    let playlist_id = playlist["ID"].as_str().unwrap_or("?").to_string();
+    let playlist_name = playlist["Name"].as_str().unwrap_or("?").to_string();
+
+    let node = match parse_smart_list(smart_list) {
+        Ok(node) => node,
+        Err(e) => {
+            progress(&format!(
+                "警告: スマートプレイリスト {} の条件を解析できないため空のプレイリストとして移行します: {:#}",
+                playlist_name, e
+            ));
+            if let Some(obj) = playlist.as_object_mut() {
+                obj.insert("Attribute".to_string(), json!(0));
+                obj.insert("SmartList".to_string(), serde_json::Value::Null);
+            }
+            return Ok(Vec::new());
+        }
+    };
+    let matched = match evaluate_smart_list(conn, &node) {
+        Ok(matched) => matched,
+        Err(e) => {
+            progress(&format!(
+                "警告: スマートプレイリスト {} の条件を評価できないため、トラックを含めず条件だけを移行します: {:#}",
+                playlist_name, e
+            ));
+            my_tag_ids.extend(referenced_my_tag_ids(&node));
+            return Ok(Vec::new());
+        }
+    };
+
+    if keep_smart {
+        my_tag_ids.extend(referenced_my_tag_ids(&node));
+        progress(&format!(
+            "スマートプレイリスト: {} (条件に一致するトラック {} 件を含めます)",
+            playlist_name,
+            matched.len()
+        ));
+        return Ok(matched);
+    }
+
+    let created_at = playlist["created_at"].clone();
+    let updated_at = playlist["updated_at"].clone();
+    for (i, content_id) in matched.iter().enumerate() {
+        song_playlists.push(json!({
+            "ID": format!("smart-{}-{}", playlist_id, i + 1),
+            "PlaylistID": playlist_id,
+            "ContentID": content_id,
+            "TrackNo": i + 1,
+            "UUID": serde_json::Value::Null,
+            "rb_data_status": 0,
+            "rb_local_data_status": 0,
+            "rb_local_deleted": 0,
+            "rb_local_synced": 0,
+            "usn": serde_json::Value::Null,
+            "rb_local_usn": serde_json::Value::Null,
+            "created_at": created_at,
+            "updated_at": updated_at,
+        }));
+    }
+    if let Some(obj) = playlist.as_object_mut() {
+        obj.insert("Attribute".to_string(), json!(0));
+        obj.insert("SmartList".to_string(), serde_json::Value::Null);
+    }
+    progress(&format!(
+        "スマートプレイリスト: {} を {} 曲のプレイリストとして固定します",
+        playlist_name,
+        matched.len()
+    ));
+    Ok(matched)
+}
+
+fn collect_content_data(conn: &Connection, content_ids: &HashSet<String>) -> Result<PackData> {
+    let contents = query_by_ids(conn, "djmdContent", "ID", content_ids)?;
 
     let mut artist_ids = HashSet::new();
     artist_ids.extend(collect_ids_from_column(&contents, "ArtistID"));
@@ -119,25 +715,15 @@ fn collect_pack_data(
     let labels = query_by_ids(conn, "djmdLabel", "ID", &label_ids)?;
     let colors = query_by_ids(conn, "djmdColor", "ID", &color_ids)?;
 
-    let cues = query_by_content_ids(conn, "djmdCue", &content_ids)?;
-    let active_censors = query_by_content_ids(conn, "djmdActiveCensor", &content_ids)?;
-    let mixer_params = query_by_content_ids(conn, "djmdMixerParam", &content_ids)?;
-    let song_my_tags = query_by_content_ids(conn, "djmdSongMyTag", &content_ids)?;
-    let song_tag_lists = query_by_content_ids(conn, "djmdSongTagList", &content_ids)?;
-    let song_hot_cue_banklists =
-        query_by_content_ids(conn, "djmdSongHotCueBanklist", &content_ids)?;
-    let content_cues = query_by_content_ids(conn, "contentCue", &content_ids)?;
-    let content_active_censors =
-        query_by_content_ids(conn, "contentActiveCensor", &content_ids)?;
-    let content_files = query_by_content_ids(conn, "contentFile", &content_ids)?;
+    let song_my_tags = query_by_content_ids(conn, "djmdSongMyTag", content_ids)?;
+    let song_hot_cue_banklists = query_by_content_ids(conn, "djmdSongHotCueBanklist", content_ids)?;
+    let content_files = query_by_content_ids(conn, "contentFile", content_ids)?;
 
     let my_tag_ids = collect_ids_from_column(&song_my_tags, "MyTagID");
     let my_tags = query_by_ids(conn, "djmdMyTag", "ID", &my_tag_ids)?;
 
-    let hot_cue_banklist_ids =
-        collect_ids_from_column(&song_hot_cue_banklists, "HotCueBanklistID");
-    let hot_cue_banklists =
-        query_by_ids(conn, "djmdHotCueBanklist", "ID", &hot_cue_banklist_ids)?;
+    let hot_cue_banklist_ids = collect_ids_from_column(&song_hot_cue_banklists, "HotCueBanklistID");
+    let hot_cue_banklists = query_by_ids(conn, "djmdHotCueBanklist", "ID", &hot_cue_banklist_ids)?;
     let hot_cue_banklist_cues = query_by_ids(
         conn,
         "hotCueBanklistCue",
@@ -146,8 +732,6 @@ fn collect_pack_data(
     )?;
 
     Ok(PackData {
-        playlist,
-        song_playlists,
         contents,
         artists,
         albums,
@@ -155,38 +739,258 @@ fn collect_pack_data(
         keys,
         labels,
         colors,
-        cues,
-        active_censors,
-        mixer_params,
         my_tags,
         song_my_tags,
-        song_tag_lists,
         hot_cue_banklists,
         song_hot_cue_banklists,
         hot_cue_banklist_cues,
-        content_cues,
-        content_active_censors,
         content_files,
+        content_ids: content_ids.clone(),
+        ..Default::default()
     })
 }
 
+#[derive(Default)]
 struct FileCopyStats {
     success: u32,
     skip: u32,
     fail: u32,
+    /// 差分パックで基準パックと同一だったもの
+    unchanged: u32,
+    /// 同じ内容のファイルが既に格納済みだったもの
+    shared: u32,
+}
+
+/// 格納するエントリ1つ分
+struct EntryJob {
+    entry_name: String,
+    source_path: PathBuf,
+    method: zip::CompressionMethod,
+}
+
+/// エントリを一時ファイルの zip に圧縮する (ワーカースレッドで実行する)
+fn compress_entry(job: &EntryJob, passphrase: Option<&str>) -> Result<ZipArchive<fs::File>> {
+    let mut temp = ZipWriter::new(tempfile::tempfile().context("一時ファイルの作成に失敗")?);
+    add_file_to_rkp(
+        &mut temp,
+        &job.entry_name,
+        &job.source_path,
+        job.method,
+        passphrase,
+    )?;
+    Ok(ZipArchive::new(temp.finish()?)?)
+}
+
+/// 圧縮しない (Stored) エントリを writer へ直接書く。途中で失敗したら書きかけのエントリを取り除く
+fn write_stored_entry<W: io::Write + io::Seek>(
+    writer: &mut ZipWriter<W>,
+    job: &EntryJob,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let result = add_file_to_rkp(
+        writer,
+        &job.entry_name,
+        &job.source_path,
+        job.method,
+        passphrase,
+    );
+    if result.is_err() && writer.is_writing_file() {
+        writer.abort_file()?;
+    }
+    result
+}
+
+/// エントリを並列に圧縮し、圧縮済みのデータを jobs の順に writer へ追記する。
+/// 圧縮しないエントリは一時ファイルを介さず、順番が来たときに writer へ直接書く。
+/// 一時ファイルが増えすぎないよう、jobs は1バッチ分 (スレッド数の2倍程度) にする。
+/// done (前のバッチまでのトラック数) と total は進捗表示用。結果は jobs と同じ順に返す
+fn write_entries_parallel<W: io::Write + io::Seek>(
+    writer: &mut ZipWriter<W>,
+    jobs: &[EntryJob],
+    passphrase: Option<&str>,
+    done: usize,
+    total: usize,
+    progress: &dyn Fn(&str),
+) -> Vec<Result<()>> {
+    let compressed: Vec<Option<Result<ZipArchive<fs::File>>>> = jobs
+        .par_iter()
+        .map(|job| {
+            (job.method != zip::CompressionMethod::Stored).then(|| compress_entry(job, passphrase))
+        })
+        .collect();
+    let mut results = Vec::with_capacity(jobs.len());
+    for (job, entry) in jobs.iter().zip(compressed) {
+        progress(&format!(
+            "音声ファイル ({}/{}) {}",
+            done + results.len() + 1,
+            total,
+            job.source_path
+                .file_name()
+                .map(|n| n.to_string_lossy())
+                .unwrap_or_default()
+        ));
+        results.push(match entry {
+            Some(entry) => entry.and_then(|mut archive| {
+                writer.raw_copy_file(archive.by_index_raw(0)?)?;
+                Ok(())
+            }),
+            None => write_stored_entry(writer, job, passphrase),
+        });
+    }
+    results
+}
+
+/// 一時フォルダに書き出した音声ファイル (FLAC に変換したもの・タグを書き込んだコピー)
+struct TempAudio {
+    path: PathBuf,
+    sha256: String,
+    size: u64,
+}
+
+/// 格納する前に一時フォルダで作った音声ファイル
+#[derive(Default)]
+struct PreparedAudio {
+    /// タグを書き込んだコピー (FLAC に変換するものは変換前の WAV/AIFF に ID3 タグを書き込んだもの)
+    tagged: Option<Result<TempAudio>>,
+    /// FLAC に変換したもの (タグを書き込む場合は Vorbis コメントも書き込み済み)
+    flac: Option<Result<TempAudio>>,
+}
+
+/// input のコピーを dest に作ってタグを書き込む
+fn tagged_temp_copy(
+    input: &std::path::Path,
+    dest: &std::path::Path,
+    file_type: Option<i64>,
+    track_tags: &TrackTags,
+) -> Result<TempAudio> {
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    tags::write_tagged_copy(input, dest, file_type, track_tags)?;
+    let (sha256, size) = file_digest(dest)?;
+    Ok(TempAudio {
+        path: dest.to_path_buf(),
+        sha256,
+        size,
+    })
+}
+
+/// トラックごとに、タグの書き込みと FLAC への変換を一時フォルダで行う。結果は sources と同じ順に返す。
+/// FLAC に変換する WAV/AIFF は変換前に ID3 タグを書き込み (アンパックで WAV/AIFF に戻してもタグが残る)、
+/// 変換後の FLAC にも Vorbis コメントを書き込む。基準パックと同じ WAV/AIFF は変換しない
+fn prepare_audio_sources(
+    sources: &[(&str, PathBuf, String, Option<i64>)],
+    hashes: &[Result<String>],
+    to_flac: bool,
+    tags: Option<&HashMap<String, TrackTags>>,
+    base: Option<&BasePack>,
+    temp_dir: &std::path::Path,
+) -> Vec<PreparedAudio> {
+    sources
+        .par_iter()
+        .zip(hashes)
+        .enumerate()
+        .map(|(idx, ((content_id, source_path, _, file_type), sha256))| {
+            let mut prepared = PreparedAudio::default();
+            let Ok(sha256) = sha256 else {
+                return prepared;
+            };
+            // 一時ファイルは元の名前にして、格納時の進捗表示に元の名前が出るようにする
+            let dir = temp_dir.join(idx.to_string());
+            let file_name = source_path.file_name().unwrap_or_default();
+            let track_tags = tags.and_then(|tags| tags.get(*content_id));
+            if let Some(track_tags) = track_tags {
+                let dest = dir.join("tags").join(file_name);
+                prepared.tagged =
+                    Some(tagged_temp_copy(source_path, &dest, *file_type, track_tags));
+            }
+            if !to_flac || !flac::is_pcm_container(source_path, *file_type) {
+                return prepared;
+            }
+
+            // タグを書き込めなかったものは元のファイルを変換する
+            let (input, input_sha256, tagged) = match &prepared.tagged {
+                Some(Ok(copy)) => (&copy.path, &copy.sha256, true),
+                _ => (source_path, sha256, false),
+            };
+            let (size, mtime) = file_size_and_mtime(source_path).unwrap_or_default();
+            if base.is_some_and(|b| {
+                b.unchanged_audio(content_id, size, mtime, input_sha256)
+                    .is_some()
+            }) {
+                return prepared;
+            }
+            let stem = source_path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let path = dir.join("flac").join(format!("{}.flac", stem));
+            let mut encoded = fs::create_dir_all(dir.join("flac"))
+                .map_err(anyhow::Error::from)
+                .and_then(|_| flac::encode_file(input, &path, input_sha256))
+                .and_then(|_| file_digest(&path))
+                .map(|(flac_sha256, size)| TempAudio {
+                    path: path.clone(),
+                    sha256: flac_sha256,
+                    size,
+                });
+            if let (Ok(_), Some(track_tags)) = (&encoded, track_tags.filter(|_| tagged)) {
+                let dest = dir.join("flac_tags").join(format!("{}.flac", stem));
+                encoded = tagged_temp_copy(&path, &dest, Some(flac::FILE_TYPE_FLAC), track_tags);
+            }
+            prepared.flac = Some(encoded);
+            prepared
+        })
+        .collect()
+}
+
+/// ディレクトリ構造を維持する場合の相対パス。すべての音声ファイルに共通する親フォルダより下を残す
+/// (送り手のユーザー名等が分かる上位のフォルダは残さない)
+fn structured_relative_paths(folder_paths: &[&str]) -> Vec<String> {
+    let segments: Vec<Vec<&str>> = folder_paths
+        .iter()
+        .map(|path| path.split(['/', '\\']).filter(|s| !s.is_empty()).collect())
+        .collect();
+    // 共通の親フォルダの深さ (ファイル名は含めない)
+    let common = segments.first().map_or(0, |first| {
+        segments
+            .iter()
+            .fold(first.len().saturating_sub(1), |depth, s| {
+                let dirs = &s[..s.len().saturating_sub(1)];
+                first[..depth]
+                    .iter()
+                    .zip(dirs)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            })
+    });
+    segments
+        .iter()
+        .map(|s| {
+            let rest = &s[common.min(s.len())..];
+            // ドライブが異なる場合もドライブ名は残さない
+            let rest = match rest.first() {
+                Some(drive) if drive.ends_with(':') => &rest[1..],
+                _ => rest,
+            };
+            rest.join("/")
+        })
+        .collect()
 }
 
 fn pack_audio_files<W: io::Write + io::Seek>(
     writer: &mut ZipWriter<W>,
     contents: &[serde_json::Value],
-    keep_structure: bool,
+    options: &PackOptions,
+    track_tags: Option<&HashMap<String, TrackTags>>,
+    base: Option<&BasePack>,
     progress: &dyn Fn(&str),
-) -> Result<(Vec<serde_json::Value>, FileCopyStats)> {
-    let mut audio_files: Vec<serde_json::Value> = Vec::new();
-    let mut stats = FileCopyStats { success: 0, skip: 0, fail: 0 };
-    let total_contents = contents.len();
+) -> Result<(Vec<AudioFile>, FileCopyStats)> {
+    let mut stats = FileCopyStats::default();
 
-    for (idx, content) in contents.iter().enumerate() {
+    // (ContentID, 元ファイル, 相対パス, FileType)
+    let mut sources: Vec<(&str, PathBuf, String, Option<i64>)> = Vec::new();
+    for content in contents {
         let content_id = match content["ID"].as_str() {
             Some(id) => id,
             None => continue,
@@ -200,40 +1004,87 @@ fn pack_audio_files<W: io::Write + io::Seek>(
         };
 
         let source_path = PathBuf::from(folder_path);
+        if !source_path.exists() {
+            progress(&format!(
+                "警告: 音声ファイルが見つかりません: {}",
+                source_path.display()
+            ));
+            stats.skip += 1;
+            continue;
+        }
 
-        let file_name = source_path
-            .file_name()
-            .map(|n| n.to_string_lossy().to_string())
-            .unwrap_or_default();
-
-        progress(&format!(
-            "音声ファイル ({}/{}) {}",
-            idx + 1,
-            total_contents,
-            file_name
+        let relative = if options.keep_structure {
+            folder_path.to_string()
+        } else {
+            source_path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        sources.push((
+            content_id,
+            source_path,
+            relative,
+            content["FileType"].as_i64(),
         ));
+    }
+    if options.keep_structure {
+        let folder_paths: Vec<&str> = sources.iter().map(|(_, _, p, _)| p.as_str()).collect();
+        let relatives = structured_relative_paths(&folder_paths);
+        for (source, relative) in sources.iter_mut().zip(relatives) {
+            source.2 = relative;
+        }
+    }
 
-        let relative = if keep_structure {
-            let full_str = folder_path.replace('\\', "/");
-            if full_str.len() > 3 && full_str.chars().nth(1) == Some(':') {
-                full_str[3..].to_string()
-            } else {
-                full_str
-            }
+    progress(&format!(
+        "音声ファイルのハッシュを計算中 ({} 件)",
+        sources.len()
+    ));
+    let hashes: Vec<Result<String>> = sources
+        .par_iter()
+        .map(|(_, source_path, _, _)| sha256_file(source_path))
+        .collect();
+
+    // FLAC への変換とタグの書き込みは、一時ファイルが増えすぎないようバッチごとに行い、
+    // バッチのエントリを書き終えたら一時ファイルを消す
+    if options.flac {
+        progress("WAV/AIFF は FLAC に変換して格納します");
+    }
+    if track_tags.is_some() {
+        progress("音声ファイルのコピーにタグを書き込んで格納します");
+    }
+    let batch_size = rayon::current_num_threads() * 2;
+    // 格納したエントリ名 → 成功したか (同じ内容のファイルは最初の1つだけ格納し、
+    // 各トラックはそのエントリの成否に従う)
+    let mut stored_entries: HashMap<String, bool> = HashMap::new();
+    let mut audio_files = Vec::new();
+    let batches = sources.chunks(batch_size).zip(hashes.chunks(batch_size));
+    for (batch_idx, (batch, batch_hashes)) in batches.enumerate() {
+        let temp_dir = if options.flac || track_tags.is_some() {
+            Some(tempfile::tempdir().context("一時フォルダの作成に失敗")?)
         } else {
-            file_name.clone()
+            None
+        };
+        let prepared = match &temp_dir {
+            Some(dir) => prepare_audio_sources(
+                batch,
+                batch_hashes,
+                options.flac,
+                track_tags,
+                base,
+                dir.path(),
+            ),
+            None => batch.iter().map(|_| PreparedAudio::default()).collect(),
         };
 
-        if source_path.exists() {
-            let entry_name = format!("files/{}", relative.replace('\\', "/"));
-            match add_file_to_rkp(writer, &entry_name, &source_path) {
-                Ok(_) => {
-                    stats.success += 1;
-                    audio_files.push(json!({
-                        "content_id": content_id,
-                        "relative_path": to_nfc(&relative),
-                    }));
-                }
+        let mut jobs: Vec<EntryJob> = Vec::new();
+        let mut job_by_entry: HashMap<String, usize> = HashMap::new();
+        let mut planned: Vec<(AudioFile, Option<usize>)> = Vec::new();
+        for (((content_id, source_path, relative, file_type), sha256), prepared) in
+            batch.iter().zip(batch_hashes).zip(prepared)
+        {
+            let sha256 = match sha256 {
+                Ok(h) => h.clone(),
                 Err(e) => {
                     progress(&format!(
                         "警告: ファイル追加失敗: {}: {}",
@@ -241,15 +1092,140 @@ fn pack_audio_files<W: io::Write + io::Seek>(
                         e
                     ));
                     stats.fail += 1;
+                    continue;
+                }
+            };
+            let (size, mtime) = file_size_and_mtime(source_path).unwrap_or_default();
+            let mut audio_file = AudioFile {
+                content_id: content_id.to_string(),
+                relative_path: to_nfc(relative),
+                entry: None,
+                sha256: Some(sha256.clone()),
+                size: Some(size),
+                mtime: Some(mtime),
+                in_base: false,
+                flac_source: None,
+                tagged: false,
+            };
+            let tagged = match prepared.tagged {
+                Some(Ok(copy)) => Some(copy),
+                Some(Err(e)) => {
+                    progress(&format!(
+                        "警告: タグを書き込めないため元のファイルを格納: {}: {:#}",
+                        source_path.display(),
+                        e
+                    ));
+                    None
+                }
+                None => None,
+            };
+
+            // タグを書き込んだコピーは、基準パックに同じ内容のコピーがあるときだけ変更なしとする。
+            // FLAC に変換するものは変換前のファイルで比べる
+            let base_sha256 = match &tagged {
+                Some(copy) => &copy.sha256,
+                None => &sha256,
+            };
+            if let Some(base_af) =
+                base.and_then(|b| b.unchanged_audio(content_id, size, mtime, base_sha256))
+            {
+                stats.unchanged += 1;
+                audio_file.entry = Some(base_af.entry_name());
+                audio_file.in_base = true;
+                if base_af.flac_source.is_some() || base_af.tagged {
+                    audio_file.sha256 = base_af.sha256.clone();
+                    audio_file.size = base_af.size;
+                    audio_file.flac_source = base_af.flac_source.clone();
+                    audio_file.tagged = base_af.tagged;
                 }
+                planned.push((audio_file, None));
+                continue;
             }
-        } else {
-            progress(&format!(
-                "警告: 音声ファイルが見つかりません: {}",
-                source_path.display()
-            ));
-            stats.skip += 1;
+
+            let mut entry_source = source_path;
+            if let Some(copy) = &tagged {
+                audio_file.sha256 = Some(copy.sha256.clone());
+                audio_file.size = Some(copy.size);
+                audio_file.tagged = true;
+                entry_source = &copy.path;
+            }
+            // FLAC に変換できたものは変換後のファイルを格納し、変換前の内容を flac_source に残す
+            match &prepared.flac {
+                Some(Ok(flac)) => {
+                    audio_file.flac_source = Some(FlacSource {
+                        sha256: base_sha256.clone(),
+                        size: audio_file.size.unwrap_or(size),
+                    });
+                    audio_file.sha256 = Some(flac.sha256.clone());
+                    audio_file.size = Some(flac.size);
+                    entry_source = &flac.path;
+                }
+                Some(Err(e)) => progress(&format!(
+                    "警告: FLAC に変換できないため元の形式で格納: {}: {:#}",
+                    source_path.display(),
+                    e
+                )),
+                None => {}
+            }
+
+            let entry_sha256 = audio_file.sha256.clone().unwrap_or_default();
+            let entry_name = content_addressed_entry_name(&entry_sha256, entry_source);
+            audio_file.entry = Some(entry_name.clone());
+            if let Some(&stored) = stored_entries.get(&entry_name) {
+                // 前のバッチで格納済み
+                stats.shared += 1;
+                if stored {
+                    planned.push((audio_file, None));
+                }
+                continue;
+            }
+            if let Some(&job_idx) = job_by_entry.get(&entry_name) {
+                stats.shared += 1;
+                planned.push((audio_file, Some(job_idx)));
+                continue;
+            }
+            let file_type = if audio_file.flac_source.is_some() {
+                Some(flac::FILE_TYPE_FLAC)
+            } else {
+                *file_type
+            };
+            job_by_entry.insert(entry_name.clone(), jobs.len());
+            planned.push((audio_file, Some(jobs.len())));
+            jobs.push(EntryJob {
+                entry_name,
+                source_path: entry_source.clone(),
+                method: options.compression.method_for(entry_source, file_type),
+            });
+        }
+
+        let results = write_entries_parallel(
+            writer,
+            &jobs,
+            options.passphrase.as_deref(),
+            batch_idx * batch_size,
+            sources.len(),
+            progress,
+        );
+        for (job, result) in jobs.iter().zip(&results) {
+            match result {
+                Ok(_) => stats.success += 1,
+                Err(e) => {
+                    progress(&format!(
+                        "警告: ファイル追加失敗: {}: {:#}",
+                        job.source_path.display(),
+                        e
+                    ));
+                    stats.fail += 1;
+                }
+            }
+            stored_entries.insert(job.entry_name.clone(), result.is_ok());
         }
+        audio_files.extend(
+            planned
+                .into_iter()
+                .filter(|(_, job_idx)| job_idx.is_none_or(|idx| results[idx].is_ok()))
+                .map(|(audio_file, _)| audio_file),
+        );
     }
 
     Ok((audio_files, stats))
@@ -258,10 +1234,14 @@ fn pack_audio_files<W: io::Write + io::Seek>(
 fn pack_content_data_files<W: io::Write + io::Seek>(
     writer: &mut ZipWriter<W>,
     content_files: &[serde_json::Value],
+    compression: PackCompression,
+    passphrase: Option<&str>,
     progress: &dyn Fn(&str),
-) -> Result<(Vec<serde_json::Value>, FileCopyStats)> {
-    let mut data_files: Vec<serde_json::Value> = Vec::new();
-    let mut stats = FileCopyStats { success: 0, skip: 0, fail: 0 };
+) -> Result<(Vec<ContentDataFile>, FileCopyStats)> {
+    let mut data_files: Vec<ContentDataFile> = Vec::new();
+    // 格納済みの相対パス
+    let mut packed: HashSet<String> = HashSet::new();
+    let mut stats = FileCopyStats::default();
     let total_data_files = content_files.len();
 
     for (idx, cf) in content_files.iter().enumerate() {
@@ -292,45 +1272,41 @@ fn pack_content_data_files<W: io::Write + io::Seek>(
 
         if source.exists() {
             let entry_name = format!("content_data/{}", pioneer_rel.replace('\\', "/"));
-            match add_file_to_rkp(writer, &entry_name, &source) {
-                Ok(_) => {
+            let relative_path = to_nfc(&pioneer_rel);
+            // 同じファイルを指す contentFile が複数あればエントリは1つにする
+            let already_packed = packed.contains(&relative_path);
+            match file_digest(&source).and_then(|digest| {
+                if !already_packed {
+                    add_file_to_rkp(
+                        writer,
+                        &entry_name,
+                        &source,
+                        compression.method_for(&source, None),
+                        passphrase,
+                    )?;
+                }
+                Ok(digest)
+            }) {
+                Ok((sha256, size)) => {
                     stats.success += 1;
-                    data_files.push(json!({
-                        "content_file_id": cf_id,
-                        "relative_path": to_nfc(&pioneer_rel),
-                    }));
+                    packed.insert(relative_path.clone());
+                    data_files.push(ContentDataFile {
+                        content_file_id: Some(cf_id.to_string()),
+                        relative_path,
+                        sha256: Some(sha256),
+                        size: Some(size),
+                        image_path: None,
+                    });
 
-                    // Artwork の兄弟ファイル (_m.jpg, _s.jpg) もパック
                     if pioneer_rel.contains("Artwork") {
-                        if let Some(parent) = source.parent() {
-                            if let Some(parent_rel) = PathBuf::from(&pioneer_rel).parent() {
-                                for suffix in &["_m", "_s"] {
-                                    let sibling_name =
-                                        format!("artwork{}.jpg", suffix);
-                                    let sibling_path = parent.join(&sibling_name);
-                                    if sibling_path.exists() {
-                                        let sibling_rel = parent_rel
-                                            .join(&sibling_name)
-                                            .to_string_lossy()
-                                            .replace('\\', "/");
-                                        let sibling_entry =
-                                            format!("content_data/{}", sibling_rel);
-                                        if add_file_to_rkp(
-                                            writer,
-                                            &sibling_entry,
-                                            &sibling_path,
-                                        )
-                                        .is_ok()
-                                        {
-                                            data_files.push(json!({
-                                                "content_file_id": null,
-                                                "relative_path": to_nfc(&sibling_rel),
-                                            }));
-                                        }
-                                    }
-                                }
-                            }
-                        }
+                        pack_artwork_siblings(
+                            writer,
+                            &source,
+                            &pioneer_rel,
+                            passphrase,
+                            &mut data_files,
+                            &mut packed,
+                        );
                     }
                 }
                 Err(e) => {
@@ -354,10 +1330,347 @@ fn pack_content_data_files<W: io::Write + io::Seek>(
     Ok((data_files, stats))
 }
 
-fn find_playlist_by_id(
+/// Artwork の兄弟ファイル (artwork_m.jpg, artwork_s.jpg など) もパック
+fn pack_artwork_siblings<W: io::Write + io::Seek>(
+    writer: &mut ZipWriter<W>,
+    source: &std::path::Path,
+    pioneer_rel: &str,
+    passphrase: Option<&str>,
+    data_files: &mut Vec<ContentDataFile>,
+    packed: &mut HashSet<String>,
+) {
+    let (Some(parent), Some(stem)) = (source.parent(), source.file_stem()) else {
+        return;
+    };
+    let Some(parent_rel) = std::path::Path::new(pioneer_rel).parent() else {
+        return;
+    };
+    for suffix in &["_m", "_s"] {
+        let sibling_name = format!("{}{}.jpg", stem.to_string_lossy(), suffix);
+        let sibling_path = parent.join(&sibling_name);
+        if !sibling_path.exists() {
+            continue;
+        }
+        let sibling_rel = parent_rel
+            .join(&sibling_name)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let sibling_rel = to_nfc(&sibling_rel);
+        if packed.contains(&sibling_rel) {
+            continue;
+        }
+        let sibling_entry = format!("content_data/{}", sibling_rel);
+        if let Ok((sha256, size)) = file_digest(&sibling_path)
+            && add_file_to_rkp(
+                writer,
+                &sibling_entry,
+                &sibling_path,
+                zip::CompressionMethod::Stored,
+                passphrase,
+            )
+            .is_ok()
+        {
+            packed.insert(sibling_rel.clone());
+            data_files.push(ContentDataFile {
+                content_file_id: None,
+                relative_path: sibling_rel,
+                sha256: Some(sha256),
+                size: Some(size),
+                image_path: None,
+            });
+        }
+    }
+}
+
+/// ImagePath でアートワークを参照するテーブルと行
+fn image_referrers(data: &PackData) -> Vec<(&'static str, &serde_json::Value)> {
+    [
+        ("djmdContent", &data.contents),
+        ("djmdAlbum", &data.albums),
+        ("djmdPlaylist", &data.playlists),
+        ("djmdPlaylist", &data.child_playlists),
+        ("djmdHotCueBanklist", &data.hot_cue_banklists),
+        ("djmdHotCueBanklist", &data.hot_cue_banklist_tree),
+    ]
+    .into_iter()
+    .flat_map(|(table, rows)| rows.iter().map(move |row| (table, row)))
+    .collect()
+}
+
+/// パックする行に対応する imageFile の行
+fn collect_image_file_rows(
     conn: &Connection,
-    playlist_id: &str,
-) -> Result<serde_json::Value> {
+    referrers: &[(&str, &serde_json::Value)],
+) -> Result<Vec<serde_json::Value>> {
+    let mut target_ids: BTreeMap<&str, HashSet<String>> = BTreeMap::new();
+    for (table, row) in referrers {
+        if let Some(id) = row["ID"].as_str() {
+            target_ids.entry(table).or_default().insert(id.to_string());
+        }
+    }
+    let mut rows = Vec::new();
+    for (table, ids) in target_ids {
+        let placeholders: Vec<&str> = ids.iter().map(|_| "?").collect();
+        let sql = format!(
+            "SELECT * FROM imageFile WHERE TableName = ? AND TargetID IN ({}) AND rb_local_deleted = 0",
+            placeholders.join(",")
+        );
+        let params: Vec<&dyn rusqlite::types::ToSql> =
+            std::iter::once(&table as &dyn rusqlite::types::ToSql)
+                .chain(ids.iter().map(|s| s as &dyn rusqlite::types::ToSql))
+                .collect();
+        rows.extend(query_table_rows(conn, &sql, &params)?);
+    }
+    Ok(rows)
+}
+
+/// ImagePath と imageFile が参照する画像をパックする。
+/// share フォルダ内の画像は PIONEER フォルダからの相対パスのまま、それ以外の場所の画像は
+/// PIONEER/Artwork/rkpack/ 以下に置き、展開時に参照元のパスを書き換える
+fn pack_image_files<W: io::Write + io::Seek>(
+    writer: &mut ZipWriter<W>,
+    referrers: &[(&str, &serde_json::Value)],
+    image_rows: &[serde_json::Value],
+    data_files: &mut Vec<ContentDataFile>,
+    compression: PackCompression,
+    passphrase: Option<&str>,
+    progress: &dyn Fn(&str),
+) -> FileCopyStats {
+    let mut stats = FileCopyStats::default();
+    let sources = image_sources(referrers, image_rows);
+    let content_artwork: HashSet<String> = data_files
+        .iter()
+        .filter(|df| df.content_file_id.is_some())
+        .map(|df| df.relative_path.clone())
+        .collect();
+    let mut packed: HashSet<String> = data_files
+        .iter()
+        .map(|df| df.relative_path.clone())
+        .collect();
+
+    for (idx, (image_path, source)) in sources.iter().enumerate() {
+        let share_rel = image_path.trim_start_matches('/').replace('\\', "/");
+        if content_artwork.contains(&share_rel) {
+            // contentFile のアートワークとして格納済み
+            continue;
+        }
+        let in_share_dir = share_rel.starts_with("PIONEER/");
+        if !source.is_file() {
+            progress(&format!(
+                "警告: 画像ファイルが見つかりません: {}",
+                source.display()
+            ));
+            stats.skip += 1;
+            continue;
+        }
+        progress(&format!(
+            "画像ファイル ({}/{}) {}",
+            idx + 1,
+            sources.len(),
+            image_path
+        ));
+
+        let result = file_digest(source).and_then(|(sha256, size)| {
+            let relative_path = if in_share_dir {
+                share_rel.clone()
+            } else {
+                match source.extension() {
+                    Some(ext) => format!(
+                        "PIONEER/Artwork/rkpack/{}.{}",
+                        sha256,
+                        ext.to_string_lossy().to_lowercase()
+                    ),
+                    None => format!("PIONEER/Artwork/rkpack/{}", sha256),
+                }
+            };
+            let relative_path = to_nfc(&relative_path);
+            if !packed.contains(&relative_path) {
+                add_file_to_rkp(
+                    writer,
+                    &format!("content_data/{}", relative_path),
+                    source,
+                    compression.method_for(source, None),
+                    passphrase,
+                )?;
+            }
+            Ok(ContentDataFile {
+                content_file_id: None,
+                relative_path,
+                sha256: Some(sha256),
+                size: Some(size),
+                image_path: Some(image_path.clone()),
+            })
+        });
+        match result {
+            Ok(data_file) => {
+                stats.success += 1;
+                let relative_path = data_file.relative_path.clone();
+                packed.insert(relative_path.clone());
+                data_files.push(data_file);
+                if in_share_dir && relative_path.contains("Artwork") {
+                    pack_artwork_siblings(
+                        writer,
+                        source,
+                        &relative_path,
+                        passphrase,
+                        data_files,
+                        &mut packed,
+                    );
+                }
+            }
+            Err(e) => {
+                progress(&format!(
+                    "警告: 画像ファイル追加失敗: {}: {}",
+                    source.display(),
+                    e
+                ));
+                stats.fail += 1;
+            }
+        }
+    }
+    stats
+}
+
+/// 参照されている画像のパスと手元のファイル (imageFile.rb_local_path があればそれを使う)
+fn image_sources(
+    referrers: &[(&str, &serde_json::Value)],
+    image_rows: &[serde_json::Value],
+) -> Vec<(String, PathBuf)> {
+    let share_dir = get_share_dir();
+    let mut image_paths: Vec<String> = Vec::new();
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut local_paths: HashMap<String, String> = HashMap::new();
+    for row in image_rows {
+        if let (Some(path), Some(local)) = (row["Path"].as_str(), row["rb_local_path"].as_str())
+            && !local.is_empty()
+        {
+            local_paths.insert(path.to_string(), local.to_string());
+        }
+    }
+    let referenced = referrers
+        .iter()
+        .filter_map(|(_, row)| row["ImagePath"].as_str())
+        .chain(image_rows.iter().filter_map(|row| row["Path"].as_str()));
+    for path in referenced {
+        if !path.is_empty() && seen.insert(path) {
+            image_paths.push(path.to_string());
+        }
+    }
+
+    image_paths
+        .into_iter()
+        .map(|image_path| {
+            let share_rel = image_path.trim_start_matches('/').replace('\\', "/");
+            let source = match local_paths.get(&image_path) {
+                Some(local) => PathBuf::from(local),
+                None if share_rel.starts_with("PIONEER/") => share_dir.join(&share_rel),
+                None => PathBuf::from(&image_path),
+            };
+            (image_path, source)
+        })
+        .collect()
+}
+
+/// 送り手のユーザー名やフォルダ構成が分かる絶対パスをパック内の相対パスに置き換え、
+/// 端末・DB 固有の値を消す。いずれも展開時に受け取り側の値で埋め直される
+fn anonymize_source_paths(
+    data: &mut PackData,
+    audio_files: &[AudioFile],
+    data_files: &mut [ContentDataFile],
+) {
+    let audio_paths: HashMap<&str, &str> = audio_files
+        .iter()
+        .map(|af| (af.content_id.as_str(), af.relative_path.as_str()))
+        .collect();
+    for content in &mut data.contents {
+        // 音声を含めない場合はファイル名だけを残す
+        let placeholder = content["ID"]
+            .as_str()
+            .and_then(|id| audio_paths.get(id))
+            .map(|path| path.to_string())
+            .or_else(|| content["FileNameL"].as_str().map(|s| s.to_string()));
+        let Some(obj) = content.as_object_mut() else {
+            continue;
+        };
+        for column in ["FolderPath", "OrgFolderPath", "rb_LocalFolderPath"] {
+            if let Some(value) = obj.get_mut(column)
+                && !value.is_null()
+            {
+                *value = placeholder
+                    .clone()
+                    .map(serde_json::Value::String)
+                    .unwrap_or_default();
+            }
+        }
+        for column in ["DeviceID", "MasterDBID", "rb_file_id"] {
+            if let Some(value) = obj.get_mut(column) {
+                *value = serde_json::Value::Null;
+            }
+        }
+    }
+
+    let data_entries: HashMap<String, String> = data_files
+        .iter()
+        .filter_map(|df| Some((df.content_file_id.clone()?, df.entry_name())))
+        .collect();
+    for content_file in &mut data.content_files {
+        let entry = content_file["ID"]
+            .as_str()
+            .and_then(|id| data_entries.get(id))
+            .cloned();
+        if let Some(obj) = content_file.as_object_mut()
+            && obj.contains_key("rb_local_path")
+        {
+            obj.insert(
+                "rb_local_path".to_string(),
+                entry.map(serde_json::Value::String).unwrap_or_default(),
+            );
+        }
+    }
+
+    // 同梱した画像の参照は展開後のパスにしておく (元のパスは残さない)。
+    // 同梱できなかった画像の参照は消す
+    let image_paths: HashMap<String, String> = data_files
+        .iter_mut()
+        .filter_map(|df| Some((df.image_path.take()?, format!("/{}", df.relative_path))))
+        .collect();
+    let packed_paths: HashSet<String> = data_files
+        .iter()
+        .map(|df| format!("/{}", df.relative_path))
+        .collect();
+    let anonymized_image_path = |path: &str| -> serde_json::Value {
+        match image_paths.get(path) {
+            Some(new_path) => serde_json::Value::String(new_path.clone()),
+            None if packed_paths.contains(path) => serde_json::Value::String(path.to_string()),
+            None => serde_json::Value::Null,
+        }
+    };
+    let referrers = data
+        .contents
+        .iter_mut()
+        .chain(&mut data.albums)
+        .chain(&mut data.playlists)
+        .chain(&mut data.child_playlists)
+        .chain(&mut data.hot_cue_banklists)
+        .chain(&mut data.hot_cue_banklist_tree);
+    for row in referrers {
+        if let Some(path) = row["ImagePath"].as_str().filter(|p| !p.is_empty()) {
+            row["ImagePath"] = anonymized_image_path(path);
+        }
+    }
+    for row in &mut data.image_files {
+        if let Some(path) = row["Path"].as_str().filter(|p| !p.is_empty()) {
+            row["Path"] = anonymized_image_path(path);
+        }
+        if let Some(obj) = row.as_object_mut()
+            && obj.contains_key("rb_local_path")
+        {
+            obj.insert("rb_local_path".to_string(), serde_json::Value::Null);
+        }
+    }
+}
+
+fn find_playlist_by_id(conn: &Connection, playlist_id: &str) -> Result<serde_json::Value> {
     let playlists = query_table_rows(
         conn,
         "SELECT * FROM djmdPlaylist WHERE ID = ? AND rb_local_deleted = 0",
@@ -370,110 +1683,962 @@ fn find_playlist_by_id(
     Ok(playlists.into_iter().next().unwrap())
 }
 
+/// テーブルを JSON Lines (1行に1レコード) のエントリとして書く。行は for_each_row から1行ずつ受け取る。
+/// 差分パックでは基準と同じ行を省き、基準と結合した後の行 ID の並びを table_ids に残す
+fn write_table<W: io::Write + io::Seek>(
+    writer: &mut ZipWriter<W>,
+    table: &str,
+    file_options: FileOptions<'_, ()>,
+    base: Option<&BasePack>,
+    table_ids: &mut BTreeMap<String, Vec<String>>,
+    for_each_row: impl FnOnce(&mut dyn FnMut(Row) -> Result<()>) -> Result<()>,
+) -> Result<TableFile> {
+    let entry = format!("tables/{}.jsonl", table);
+    writer.start_file(entry.as_str(), file_options)?;
+    let mut hasher = Sha256::new();
+    let mut rows = 0;
+    let mut size = 0;
+    let mut ids = Vec::new();
+    for_each_row(&mut |row| {
+        if let Some(base) = base {
+            if let Some(id) = row.get("ID").and_then(|v| v.as_str()) {
+                ids.push(id.to_string());
+            }
+            if base.is_unchanged_row(table, &row)? {
+                return Ok(());
+            }
+        }
+        let mut line = serde_json::to_vec(&row)?;
+        line.push(b'\n');
+        hasher.update(&line);
+        writer.write_all(&line)?;
+        rows += 1;
+        size += line.len() as u64;
+        Ok(())
+    })
+    .with_context(|| format!("{} の書き込みに失敗", entry))?;
+    if base.is_some() {
+        table_ids.insert(table.to_string(), ids);
+    }
+    Ok(TableFile {
+        entry,
+        rows,
+        size,
+        sha256: hex::encode(hasher.finalize()),
+    })
+}
+
+/// zip のローカルヘッダ・セントラルディレクトリ・エントリ名などエントリ1つあたりの目安
+const ENTRY_OVERHEAD: u64 = 256;
+
+/// pack.json に書く音声・データファイル1件あたりの目安
+const MANIFEST_FILE_ENTRY_SIZE: u64 = 256;
+
+/// djmdCue の行を Serato 形式のホットキューにする。メモリーキューと削除済みの行は None
+fn hot_cue(row: &serde_json::Value) -> Option<HotCue> {
+    if row["rb_local_deleted"].as_i64() == Some(1) {
+        return None;
+    }
+    // Kind: 0=メモリーキュー, 1-3=ホットキュー A-C, 5-9=ホットキュー D-H (4 は使われない)
+    let kind = row["Kind"]
+        .as_i64()
+        .filter(|k| matches!(k, 1..=3 | 5..=9))?;
+    let start = row["InMsec"].as_i64().filter(|&ms| ms >= 0)?;
+    let end = row["OutMsec"].as_i64().filter(|&ms| ms > start);
+    Some(HotCue {
+        index: if kind < 4 { kind - 1 } else { kind - 2 } as u8,
+        start_msec: u32::try_from(start).ok()?,
+        end_msec: end.and_then(|ms| u32::try_from(ms).ok()),
+        name: row["Comment"].as_str().unwrap_or_default().to_string(),
+    })
+}
+
+/// 音声ファイルのコピーに書き込むタグ (ContentID → タグ)
+fn collect_track_tags(conn: &Connection, data: &PackData) -> Result<HashMap<String, TrackTags>> {
+    let names = |rows: &[serde_json::Value], column: &str| -> HashMap<String, String> {
+        rows.iter()
+            .filter_map(|row| {
+                Some((
+                    row["ID"].as_str()?.to_string(),
+                    row[column].as_str()?.to_string(),
+                ))
+            })
+            .collect()
+    };
+    let artists = names(&data.artists, "Name");
+    let albums = names(&data.albums, "Name");
+    let genres = names(&data.genres, "Name");
+    let labels = names(&data.labels, "Name");
+    let keys = names(&data.keys, "ScaleName");
+    let non_empty = |s: &str| {
+        Some(s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+    };
+    let text = |value: &serde_json::Value| value.as_str().and_then(non_empty);
+    let name_of = |map: &HashMap<String, String>, id: &serde_json::Value| {
+        id.as_str()
+            .and_then(|id| map.get(id))
+            .and_then(|name| non_empty(name))
+    };
+
+    let mut cues: HashMap<String, Vec<HotCue>> = HashMap::new();
+    for_each_row_by_content_ids(conn, "djmdCue", &data.content_ids, |row| {
+        if let (Some(content_id), Some(cue)) = (row["ContentID"].as_str(), hot_cue(&row)) {
+            cues.entry(content_id.to_string()).or_default().push(cue);
+        }
+        Ok(())
+    })?;
+
+    let mut track_tags = HashMap::new();
+    for content in &data.contents {
+        let Some(content_id) = content["ID"].as_str() else {
+            continue;
+        };
+        let mut cues = cues.remove(content_id).unwrap_or_default();
+        // 同じパッドに複数ある場合は最初の1つだけ
+        cues.sort_by_key(|cue| cue.index);
+        cues.dedup_by_key(|cue| cue.index);
+        let tags = TrackTags {
+            title: text(&content["Title"]),
+            artist: name_of(&artists, &content["ArtistID"]),
+            album: name_of(&albums, &content["AlbumID"]),
+            genre: name_of(&genres, &content["GenreID"]),
+            key: name_of(&keys, &content["KeyID"]),
+            // BPM は 100 倍の整数
+            bpm: content["BPM"]
+                .as_f64()
+                .filter(|&bpm| bpm > 0.0)
+                .map(|bpm| bpm / 100.0),
+            comment: text(&content["Commnt"]),
+            rating: content["Rating"]
+                .as_i64()
+                .filter(|r| (1..=5).contains(r))
+                .map(|r| r as u8),
+            label: name_of(&labels, &content["LabelID"]),
+            isrc: text(&content["ISRC"]),
+            cues,
+        };
+        track_tags.insert(content_id.to_string(), tags);
+    }
+    Ok(track_tags)
+}
+
+/// トラック ID → パックに記録された音声ファイルの SHA-256 とサイズ
+fn expected_audio(audio_files: &[AudioFile]) -> HashMap<String, ExpectedAudio> {
+    audio_files
+        .iter()
+        .filter_map(|af| {
+            let expected = match &af.flac_source {
+                Some(source) => ExpectedAudio {
+                    sha256: source.sha256.clone(),
+                    size: source.size,
+                },
+                None => ExpectedAudio {
+                    sha256: af.sha256.clone()?,
+                    size: af.size?,
+                },
+            };
+            Some((af.content_id.clone(), expected))
+        })
+        .collect()
+}
+
+/// FolderPath のファイルがないトラックを search_roots から探し、見つかった場所に置き換える
+fn relocate_audio(
+    data: &mut PackData,
+    options: &PackOptions,
+    base: Option<&BasePack>,
+    progress: &dyn Fn(&str),
+) -> Result<Vec<Relocation>> {
+    if options.search_roots.is_empty() || options.no_audio {
+        return Ok(Vec::new());
+    }
+    let mut index = SearchIndex::build(&options.search_roots, progress)?;
+    let expected = match (&options.hash_pack, base) {
+        (Some(path), _) => {
+            let mut archive = open_rkp(std::path::Path::new(path), options.passphrase.as_deref())
+                .with_context(|| format!("以前の .rkp を開けません: {}", path))?;
+            expected_audio(&read_pack_json(&mut archive)?.audio_files)
+        }
+        (None, Some(base)) if options.search_by_hash => expected_audio(&base.pack_data.audio_files),
+        _ => HashMap::new(),
+    };
+    Ok(relocate_missing_audio(
+        &mut data.contents,
+        &mut index,
+        &expected,
+        progress,
+    ))
+}
+
+fn estimate_pack(
+    conn: &Connection,
+    mut data: PackData,
+    options: &PackOptions,
+    progress: &dyn Fn(&str),
+) -> Result<PackEstimate> {
+    let mut estimate = PackEstimate::default();
+    // ハッシュで照合するときだけ基準パックを読む (見積もり自体は基準を考慮しない)
+    let base = match &options.base {
+        Some(path) if options.search_by_hash => {
+            Some(BasePack::load(path, options.passphrase.as_deref())?)
+        }
+        _ => None,
+    };
+    estimate.relocated = relocate_audio(&mut data, options, base.as_ref(), progress)?;
+    let mut table_bytes = 0u64;
+    let mut entries = 1u64; // pack.json
+
+    let with_data_files = !options.no_audio || options.with_analysis;
+    if with_data_files {
+        data.image_files = collect_image_file_rows(conn, &image_referrers(&data))?;
+    }
+    for (table, rows) in data.tables() {
+        estimate
+            .table_rows
+            .insert(table.to_string(), rows.len() as u64);
+        for row in rows {
+            table_bytes += serde_json::to_vec(row)?.len() as u64 + 1;
+        }
+        entries += 1;
+    }
+    for &table in STREAMED_TABLES {
+        let mut count = 0u64;
+        for_each_row_by_content_ids(conn, table, &data.content_ids, &mut |row| {
+            count += 1;
+            table_bytes += serde_json::to_vec(&row)?.len() as u64 + 1;
+            Ok(())
+        })?;
+        estimate.table_rows.insert(table.to_string(), count);
+        entries += 1;
+    }
+
+    let mut titles: HashMap<&str, String> = HashMap::new();
+    for content in &data.contents {
+        let id = content["ID"].as_str().unwrap_or("?");
+        let title = format!("{} (ID: {})", content["Title"].as_str().unwrap_or("?"), id);
+        if content["AnalysisDataPath"]
+            .as_str()
+            .is_none_or(|p| p.is_empty())
+        {
+            estimate.unanalyzed.push(title.clone());
+        }
+        titles.insert(id, title);
+    }
+
+    if !options.no_audio {
+        let mut seen: HashSet<&str> = HashSet::new();
+        for content in &data.contents {
+            let title = &titles[content["ID"].as_str().unwrap_or("?")];
+            let folder_path = content["FolderPath"].as_str().unwrap_or("");
+            match fs::metadata(folder_path) {
+                Ok(meta) if meta.is_file() => {
+                    if seen.insert(folder_path) {
+                        estimate.audio_files += 1;
+                        estimate.audio_bytes += meta.len();
+                    }
+                }
+                _ => estimate.missing_audio.push(MissingFile {
+                    label: title.clone(),
+                    path: folder_path.to_string(),
+                }),
+            }
+        }
+    }
+
+    if with_data_files {
+        let mut content_file_paths: HashSet<String> = HashSet::new();
+        for cf in &data.content_files {
+            let local_path = cf["rb_local_path"].as_str().unwrap_or("");
+            match fs::metadata(local_path) {
+                Ok(meta) if meta.is_file() => {
+                    estimate.data_files += 1;
+                    estimate.data_bytes += meta.len();
+                    if let Some(path) = cf["Path"].as_str() {
+                        content_file_paths.insert(path.trim_start_matches('/').to_string());
+                    }
+                }
+                _ => estimate.missing_data_files.push(MissingFile {
+                    label: cf["ContentID"]
+                        .as_str()
+                        .and_then(|id| titles.get(id))
+                        .cloned()
+                        .unwrap_or_else(|| "?".to_string()),
+                    path: local_path.to_string(),
+                }),
+            }
+        }
+        for (image_path, source) in image_sources(&image_referrers(&data), &data.image_files) {
+            let share_rel = image_path.trim_start_matches('/').replace('\\', "/");
+            if content_file_paths.contains(&share_rel) {
+                continue;
+            }
+            match fs::metadata(&source) {
+                Ok(meta) if meta.is_file() => {
+                    estimate.data_files += 1;
+                    estimate.data_bytes += meta.len();
+                }
+                _ => estimate.missing_data_files.push(MissingFile {
+                    label: image_path,
+                    path: source.to_string_lossy().to_string(),
+                }),
+            }
+        }
+    }
+
+    let file_entries = (estimate.audio_files + estimate.data_files) as u64;
+    entries += file_entries;
+    let top_level_rows = [
+        &data.playlists,
+        &data.histories,
+        &data.hot_cue_banklist_tree,
+        &data.sampler_tree,
+        &data.related_tracks_tree,
+    ];
+    let mut manifest_bytes = file_entries * MANIFEST_FILE_ENTRY_SIZE;
+    for rows in top_level_rows {
+        manifest_bytes += serde_json::to_vec(rows)?.len() as u64;
+    }
+    estimate.estimated_size = estimate.audio_bytes
+        + estimate.data_bytes
+        + table_bytes
+        + manifest_bytes
+        + entries * ENTRY_OVERHEAD;
+    Ok(estimate)
+}
+
 fn do_pack(
     conn: &Connection,
     output: &str,
-    playlist: serde_json::Value,
-    keep_structure: bool,
+    mut data: PackData,
+    options: &PackOptions,
     progress: &dyn Fn(&str),
 ) -> Result<()> {
-    let data = collect_pack_data(conn, playlist, progress)?;
+    if options.dry_run {
+        progress("見積もり (--dry-run: .rkp は作成しません)");
+        for line in estimate_pack(conn, data, options, progress)?.summary_lines() {
+            progress(&line);
+        }
+        return Ok(());
+    }
 
     let output_path = PathBuf::from(output);
     if let Some(parent) = output_path.parent()
-        && !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent).with_context(|| {
-                format!("出力ディレクトリの作成に失敗: {}", parent.display())
-            })?;
-        }
-    let rkp_file = fs::File::create(&output_path)
-        .with_context(|| format!(".rkp ファイルの作成に失敗: {}", output_path.display()))?;
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("出力ディレクトリの作成に失敗: {}", parent.display()))?;
+    }
+    let base = options
+        .base
+        .as_deref()
+        .map(|path| BasePack::load(path, options.passphrase.as_deref()))
+        .transpose()?;
+    let signing_identity = options
+        .sign_key
+        .as_deref()
+        .map(load_signing_key)
+        .transpose()?;
+    relocate_audio(&mut data, options, base.as_ref(), progress)?;
+
+    let rkp_file = VolumeWriter::create(&output_path, options.split_size)?;
     let mut writer = ZipWriter::new(rkp_file);
 
-    let (audio_files, audio_stats) =
-        pack_audio_files(&mut writer, &data.contents, keep_structure, progress)?;
-    let (content_data_files, data_stats) =
-        pack_content_data_files(&mut writer, &data.content_files, progress)?;
-
-    let pack_data = json!({
-        "version": 1,
-        "playlist": data.playlist,
-        "tables": {
-            "djmdSongPlaylist": data.song_playlists,
-            "djmdContent": data.contents,
-            "djmdArtist": data.artists,
-            "djmdAlbum": data.albums,
-            "djmdGenre": data.genres,
-            "djmdKey": data.keys,
-            "djmdLabel": data.labels,
-            "djmdColor": data.colors,
-            "djmdCue": data.cues,
-            "djmdActiveCensor": data.active_censors,
-            "djmdMixerParam": data.mixer_params,
-            "djmdMyTag": data.my_tags,
-            "djmdSongMyTag": data.song_my_tags,
-            "djmdSongTagList": data.song_tag_lists,
-            "djmdHotCueBanklist": data.hot_cue_banklists,
-            "djmdSongHotCueBanklist": data.song_hot_cue_banklists,
-            "hotCueBanklistCue": data.hot_cue_banklist_cues,
-            "contentCue": data.content_cues,
-            "contentActiveCensor": data.content_active_censors,
-            "contentFile": data.content_files,
+    let (audio_files, audio_stats) = if options.no_audio {
+        (Vec::new(), FileCopyStats::default())
+    } else {
+        let track_tags = if options.embed_tags {
+            Some(collect_track_tags(conn, &data)?)
+        } else {
+            None
+        };
+        pack_audio_files(
+            &mut writer,
+            &data.contents,
+            options,
+            track_tags.as_ref(),
+            base.as_ref(),
+            progress,
+        )?
+    };
+    let with_data_files = !options.no_audio || options.with_analysis;
+    let (mut content_data_files, mut data_stats) = if with_data_files {
+        pack_content_data_files(
+            &mut writer,
+            &data.content_files,
+            options.compression,
+            options.passphrase.as_deref(),
+            progress,
+        )?
+    } else {
+        (Vec::new(), FileCopyStats::default())
+    };
+    if with_data_files {
+        data.image_files = collect_image_file_rows(conn, &image_referrers(&data))?;
+        let image_stats = pack_image_files(
+            &mut writer,
+            &image_referrers(&data),
+            &data.image_files,
+            &mut content_data_files,
+            options.compression,
+            options.passphrase.as_deref(),
+            progress,
+        );
+        data_stats.success += image_stats.success;
+        data_stats.skip += image_stats.skip;
+        data_stats.fail += image_stats.fail;
+    }
+    if !options.keep_source_paths {
+        anonymize_source_paths(&mut data, &audio_files, &mut content_data_files);
+    }
+
+    let file_options = entry_options(options.compression.method(), options.passphrase.as_deref());
+    let mut table_ids = BTreeMap::new();
+    let mut table_files = BTreeMap::new();
+    for (table, rows) in data.tables() {
+        let file = write_table(
+            &mut writer,
+            table,
+            file_options,
+            base.as_ref(),
+            &mut table_ids,
+            |emit| rows.iter().cloned().try_for_each(emit),
+        )?;
+        table_files.insert(table.to_string(), file);
+    }
+    // キュー等の行数の多いテーブルは DB から読みながら書く
+    for &table in STREAMED_TABLES {
+        let file = write_table(
+            &mut writer,
+            table,
+            file_options,
+            base.as_ref(),
+            &mut table_ids,
+            |emit| for_each_row_by_content_ids(conn, table, &data.content_ids, emit),
+        )?;
+        table_files.insert(table.to_string(), file);
+    }
+
+    let manifest = PackManifest {
+        version: PACK_VERSION,
+        metadata: PackMetadata {
+            pack_id: Some(uuid::Uuid::new_v4().to_string()),
+            created_at: Some(chrono::Local::now().to_rfc3339()),
+            generator: Some(format!("rkpack {}", env!("CARGO_PKG_VERSION"))),
+            lite: options.no_audio,
+            base: base.as_ref().map(|base| PackBase {
+                pack_id: base.pack_id.clone(),
+                file_name: base.file_name.clone(),
+                table_ids,
+            }),
         },
-        "audio_files": audio_files,
-        "content_data_files": content_data_files,
-    });
+        playlists: data.playlists,
+        histories: data.histories,
+        hot_cue_banklists: data.hot_cue_banklist_tree,
+        samplers: data.sampler_tree,
+        related_tracks: data.related_tracks_tree,
+        tables: Default::default(),
+        table_files,
+        audio_files,
+        content_data_files,
+    };
 
-    let options =
-        SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
-    writer.start_file("pack.json", options)?;
-    let json_bytes = serde_json::to_vec_pretty(&pack_data)
-        .context("pack.json のシリアライズに失敗")?;
+    writer.start_file("pack.json", file_options)?;
+    let json_bytes =
+        serde_json::to_vec_pretty(&manifest).context("pack.json のシリアライズに失敗")?;
     writer.write_all(&json_bytes)?;
+    if let Some(identity) = &signing_identity {
+        let signature = sign_manifest(identity, &json_bytes);
+        writer.start_file(SIGNATURE_ENTRY, file_options)?;
+        writer.write_all(&serde_json::to_vec_pretty(&signature)?)?;
+    }
 
-    writer.finish()?;
+    let volumes = writer.finish()?.finish()?;
 
     progress(&format!("パック完了: {}", output_path.display()));
-    progress(&format!(
-        "音声ファイル: 成功={}, スキップ={}, 失敗={}",
-        audio_stats.success, audio_stats.skip, audio_stats.fail
-    ));
+    if let Some(identity) = &signing_identity {
+        progress(&format!("署名: {}", identity.name));
+    }
+    if options.split_size.is_some() {
+        progress(&format!(
+            "分割: {} 個のボリューム ({} 〜 {})",
+            volumes.len(),
+            volumes
+                .first()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            volumes
+                .last()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default()
+        ));
+    }
+    if options.no_audio {
+        progress("音声ファイル: なし (音声なしパック)");
+    } else {
+        progress(&format!(
+            "音声ファイル: 成功={}, スキップ={}, 失敗={}, 同一内容で共有={}",
+            audio_stats.success, audio_stats.skip, audio_stats.fail, audio_stats.shared
+        ));
+    }
+    if let Some(base) = &base {
+        progress(&format!(
+            "差分パック: 基準={}, 基準と同一の音声ファイル={}",
+            base.file_name, audio_stats.unchanged
+        ));
+    }
     progress(&format!(
         "データファイル(artwork/分析): 成功={}, スキップ={}, 失敗={}",
         data_stats.success, data_stats.skip, data_stats.fail
     ));
-    if let Some(tables) = pack_data["tables"].as_object() {
-        let mut table_summary = String::from("テーブルデータ:");
-        for (name, rows) in tables {
-            if let Some(arr) = rows.as_array()
-                && !arr.is_empty() {
-                    table_summary.push_str(&format!(" {}={}行", name, arr.len()));
-                }
+    let mut table_summary = String::from("テーブルデータ:");
+    for (name, file) in &manifest.table_files {
+        if file.rows > 0 {
+            table_summary.push_str(&format!(" {}={}行", name, file.rows));
         }
-        progress(&table_summary);
     }
+    progress(&table_summary);
 
     Ok(())
 }
 
-pub fn pack_playlist(
+/// 名前・IDで指定した複数のプレイリストを1つの .rkp にパックする
+pub fn pack_playlists(
+    conn: &Connection,
+    output: &str,
+    playlist_names: &[String],
+    playlist_ids: &[String],
+    options: &PackOptions,
+    progress: &dyn Fn(&str),
+) -> Result<()> {
+    let data = collect_playlists_pack_data(conn, playlist_names, playlist_ids, options, progress)?;
+    do_pack(conn, output, data, options, progress)
+}
+
+/// pack_playlists でパックする内容を見積もる (GUI で保存先を選ぶ前に表示する)
+pub fn estimate_playlists(
+    conn: &Connection,
+    playlist_names: &[String],
+    playlist_ids: &[String],
+    options: &PackOptions,
+    progress: &dyn Fn(&str),
+) -> Result<PackEstimate> {
+    let data = collect_playlists_pack_data(conn, playlist_names, playlist_ids, options, progress)?;
+    estimate_pack(conn, data, options, progress)
+}
+
+fn collect_playlists_pack_data(
+    conn: &Connection,
+    playlist_names: &[String],
+    playlist_ids: &[String],
+    options: &PackOptions,
+    progress: &dyn Fn(&str),
+) -> Result<PackData> {
+    let mut playlists = Vec::new();
+    for name in playlist_names {
+        playlists.push(find_playlist(conn, name, progress)?);
+    }
+    for id in playlist_ids {
+        playlists.push(find_playlist_by_id(conn, id)?);
+    }
+    if playlists.is_empty() {
+        anyhow::bail!("パックするプレイリストを指定してください");
+    }
+    collect_pack_data(conn, playlists, options, progress)
+}
+
+/// フィルタ条件に一致するトラックを、新しいプレイリストとして .rkp にパックする
+pub fn pack_filtered_tracks(
     conn: &Connection,
     output: &str,
+    filter: &TrackFilter,
     playlist_name: &str,
-    keep_structure: bool,
+    options: &PackOptions,
+    progress: &dyn Fn(&str),
+) -> Result<()> {
+    const FILTER_PLAYLIST_ID: &str = "filter";
+
+    let node = filter.to_smart_node(conn)?;
+    let now: String = conn.query_row(
+        "SELECT strftime('%Y-%m-%d %H:%M:%f +00:00', 'now')",
+        [],
+        |row| row.get(0),
+    )?;
+    // 条件をスマートプレイリストとして評価させ、固定リストに変換してパックする
+    let playlist = json!({
+        "ID": FILTER_PLAYLIST_ID,
+        "Seq": 1,
+        "Name": playlist_name,
+        "ImagePath": serde_json::Value::Null,
+        "Attribute": 4,
+        "ParentID": "root",
+        "SmartList": smart_list_to_xml(&node, FILTER_PLAYLIST_ID),
+        "UUID": serde_json::Value::Null,
+        "rb_data_status": 0,
+        "rb_local_data_status": 0,
+        "rb_local_deleted": 0,
+        "rb_local_synced": 0,
+        "usn": serde_json::Value::Null,
+        "rb_local_usn": serde_json::Value::Null,
+        "created_at": now,
+        "updated_at": now,
+    });
+    let options = PackOptions {
+        keep_smart_lists: false,
+        ..options.clone()
+    };
+    let data = collect_pack_data(conn, vec![playlist], &options, progress)?;
+    do_pack(conn, output, data, &options, progress)
+}
+
+/// ID または日付 (YYYY-MM-DD) で再生履歴のセッションを探す
+fn find_histories(conn: &Connection, key: &str) -> Result<Vec<serde_json::Value>> {
+    let by_id = query_table_rows(
+        conn,
+        "SELECT * FROM djmdHistory WHERE ID = ? AND rb_local_deleted = 0",
+        &[&key],
+    )?;
+    if !by_id.is_empty() {
+        return Ok(by_id);
+    }
+    let by_date = query_table_rows(
+        conn,
+        "SELECT * FROM djmdHistory \
+         WHERE (Name = ?1 OR DateCreated LIKE ?1 || '%') \
+         AND COALESCE(Attribute, 0) != 1 AND rb_local_deleted = 0 \
+         ORDER BY Seq",
+        &[&key],
+    )?;
+    if by_date.is_empty() {
+        anyhow::bail!("再生履歴 '{}' が見つかりません", key);
+    }
+    Ok(by_date)
+}
+
+/// セッションの親フォルダをルート側から順にすべて取得する
+fn collect_history_folders(
+    conn: &Connection,
+    sessions: &[serde_json::Value],
+) -> Result<Vec<serde_json::Value>> {
+    let mut folders = Vec::new();
+    let mut seen = HashSet::new();
+    for session in sessions {
+        let mut chain = Vec::new();
+        let mut parent_id = session["ParentID"].as_str().map(|s| s.to_string());
+        while let Some(pid) = parent_id.filter(|p| p != "root" && !p.is_empty()) {
+            // 循環参照があっても無限ループしないように
+            if !seen.insert(pid.clone()) {
+                break;
+            }
+            let Some(folder) = query_table_rows(
+                conn,
+                "SELECT * FROM djmdHistory WHERE ID = ? AND rb_local_deleted = 0",
+                &[&pid],
+            )?
+            .into_iter()
+            .next() else {
+                break;
+            };
+            parent_id = folder["ParentID"].as_str().map(|s| s.to_string());
+            chain.push(folder);
+        }
+        chain.reverse();
+        folders.extend(chain);
+    }
+    Ok(folders)
+}
+
+fn collect_history_pack_data(
+    conn: &Connection,
+    history_keys: &[String],
+    progress: &dyn Fn(&str),
+) -> Result<PackData> {
+    let mut histories = Vec::new();
+    let mut seen = HashSet::new();
+    for key in history_keys {
+        for history in find_histories(conn, key)? {
+            let history_id = history["ID"].as_str().unwrap_or("?").to_string();
+            if seen.insert(history_id.clone()) {
+                progress(&format!(
+                    "再生履歴: {} (ID: {})",
+                    history["Name"].as_str().unwrap_or("?"),
+                    history_id
+                ));
+                histories.push(history);
+            }
+        }
+    }
+
+    let history_folders = collect_history_folders(conn, &histories)?;
+
+    let mut song_histories = Vec::new();
+    for history in &histories {
+        let history_id = history["ID"].as_str().unwrap_or("");
+        song_histories.extend(query_table_rows(
+            conn,
+            "SELECT * FROM djmdSongHistory WHERE HistoryID = ? AND rb_local_deleted = 0 ORDER BY TrackNo",
+            &[&history_id],
+        )?);
+    }
+
+    let content_ids = collect_ids_from_column(&song_histories, "ContentID");
+    progress(&format!("トラック数: {}", content_ids.len()));
+
+    Ok(PackData {
+        histories,
+        history_folders,
+        song_histories,
+        ..collect_content_data(conn, &content_ids)?
+    })
+}
+
+/// ID または日付で指定した再生履歴を .rkp にパックする
+pub fn pack_histories(
+    conn: &Connection,
+    output: &str,
+    history_keys: &[String],
+    options: &PackOptions,
+    progress: &dyn Fn(&str),
+) -> Result<()> {
+    if history_keys.is_empty() {
+        anyhow::bail!("パックする再生履歴を指定してください");
+    }
+    let data = collect_history_pack_data(conn, history_keys, progress)?;
+    do_pack(conn, output, data, options, progress)
+}
+
+/// 名前で指定してツリーごとパックできるリスト (ホットキューバンクリスト・サンプラー・関連トラック)
+struct ListTreeKind {
+    table: &'static str,
+    song_table: &'static str,
+    song_fk: &'static str,
+    label: &'static str,
+}
+
+const HOT_CUE_BANKLIST: ListTreeKind = ListTreeKind {
+    table: "djmdHotCueBanklist",
+    song_table: "djmdSongHotCueBanklist",
+    song_fk: "HotCueBanklistID",
+    label: "ホットキューバンクリスト",
+};
+
+const SAMPLER: ListTreeKind = ListTreeKind {
+    table: "djmdSampler",
+    song_table: "djmdSongSampler",
+    song_fk: "SamplerID",
+    label: "サンプラー",
+};
+
+const RELATED_TRACKS: ListTreeKind = ListTreeKind {
+    table: "djmdRelatedTracks",
+    song_table: "djmdSongRelatedTracks",
+    song_fk: "RelatedTracksID",
+    label: "関連トラック",
+};
+
+/// 名前で指定したリストと配下を親→子の順に取得し、(リスト, エントリ) を返す
+fn collect_list_tree(
+    conn: &Connection,
+    kind: &ListTreeKind,
+    names: &[String],
+    progress: &dyn Fn(&str),
+) -> Result<(Vec<serde_json::Value>, Vec<serde_json::Value>)> {
+    let mut tree = Vec::new();
+    let mut seen = HashSet::new();
+    for name in names {
+        let lists = query_table_rows(
+            conn,
+            &format!(
+                "SELECT * FROM `{}` WHERE Name = ? AND rb_local_deleted = 0",
+                kind.table
+            ),
+            &[name],
+        )?;
+        let list = match lists.len() {
+            0 => anyhow::bail!("{} '{}' が見つかりません", kind.label, name),
+            1 => lists.into_iter().next().unwrap(),
+            n => anyhow::bail!(
+                "{}名 '{}' が一意ではありません ({} 件)",
+                kind.label,
+                name,
+                n
+            ),
+        };
+        let list_id = list["ID"].as_str().unwrap_or("").to_string();
+        if !seen.insert(list_id.clone()) {
+            continue;
+        }
+        progress(&format!("{}: {} (ID: {})", kind.label, name, list_id));
+        let descendants = collect_descendants(conn, kind.table, &list_id)?;
+        if !descendants.is_empty() {
+            progress(&format!("配下のフォルダ/リスト数: {}", descendants.len()));
+        }
+        tree.push(list);
+        for child in descendants {
+            // 他の指定の配下にあるものは1回だけ含める
+            if seen.insert(child["ID"].as_str().unwrap_or("").to_string()) {
+                tree.push(child);
+            }
+        }
+    }
+
+    let tree_ids = collect_ids_from_column(&tree, "ID");
+    let songs = query_by_ids(conn, kind.song_table, kind.song_fk, &tree_ids)?;
+    Ok((tree, songs))
+}
+
+fn collect_list_tree_pack_data(
+    conn: &Connection,
+    kind: &ListTreeKind,
+    names: &[String],
+    progress: &dyn Fn(&str),
+) -> Result<PackData> {
+    let (tree, songs) = collect_list_tree(conn, kind, names, progress)?;
+
+    // リストに含まれるトラック (バンクリストの場合はキューの取得元)
+    let content_ids = collect_ids_from_column(&songs, "ContentID");
+    progress(&format!("トラック数: {}", content_ids.len()));
+    let data = collect_content_data(conn, &content_ids)?;
+
+    Ok(match kind.table {
+        "djmdHotCueBanklist" => {
+            let tree_ids = collect_ids_from_column(&tree, "ID");
+            PackData {
+                hot_cue_banklist_cues: query_by_ids(
+                    conn,
+                    "hotCueBanklistCue",
+                    "HotCueBanklistID",
+                    &tree_ids,
+                )?,
+                hot_cue_banklist_tree: tree,
+                song_hot_cue_banklists: songs,
+                hot_cue_banklists: Vec::new(),
+                ..data
+            }
+        }
+        "djmdSampler" => PackData {
+            sampler_tree: tree,
+            song_samplers: songs,
+            ..data
+        },
+        _ => {
+            // Criteria で参照されている MyTag も移行する
+            let packed_my_tag_ids = collect_ids_from_column(&data.my_tags, "ID");
+            let criteria_my_tag_ids: HashSet<String> = tree
+                .iter()
+                .filter_map(|row| row["Criteria"].as_str())
+                .filter_map(|criteria| parse_smart_list(criteria).ok())
+                .flat_map(|node| referenced_my_tag_ids(&node))
+                .filter(|id| !packed_my_tag_ids.contains(id))
+                .collect();
+            let mut my_tags = data.my_tags;
+            my_tags.extend(query_by_ids(conn, "djmdMyTag", "ID", &criteria_my_tag_ids)?);
+            PackData {
+                related_tracks_tree: tree,
+                song_related_tracks: songs,
+                my_tags,
+                ..data
+            }
+        }
+    })
+}
+
+fn pack_list_tree(
+    conn: &Connection,
+    output: &str,
+    kind: &ListTreeKind,
+    names: &[String],
+    options: &PackOptions,
+    progress: &dyn Fn(&str),
+) -> Result<()> {
+    if names.is_empty() {
+        anyhow::bail!("パックする{}を指定してください", kind.label);
+    }
+    let data = collect_list_tree_pack_data(conn, kind, names, progress)?;
+    do_pack(conn, output, data, options, progress)
+}
+
+/// 名前で指定したホットキューバンクリストを、キューの取得元トラックごと .rkp にパックする
+pub fn pack_hot_cue_banklists(
+    conn: &Connection,
+    output: &str,
+    banklist_names: &[String],
+    options: &PackOptions,
+    progress: &dyn Fn(&str),
+) -> Result<()> {
+    pack_list_tree(
+        conn,
+        output,
+        &HOT_CUE_BANKLIST,
+        banklist_names,
+        options,
+        progress,
+    )
+}
+
+/// 名前で指定したサンプラーリストを .rkp にパックする
+pub fn pack_samplers(
+    conn: &Connection,
+    output: &str,
+    sampler_names: &[String],
+    options: &PackOptions,
     progress: &dyn Fn(&str),
 ) -> Result<()> {
-    let playlist = find_playlist(conn, playlist_name, progress)?;
-    do_pack(conn, output, playlist, keep_structure, progress)
+    pack_list_tree(conn, output, &SAMPLER, sampler_names, options, progress)
 }
 
-pub fn pack_playlist_by_id(
+/// 名前で指定した関連トラックリストを、条件 (Criteria) ごと .rkp にパックする
+pub fn pack_related_tracks(
     conn: &Connection,
     output: &str,
-    playlist_id: &str,
-    keep_structure: bool,
+    list_names: &[String],
+    options: &PackOptions,
     progress: &dyn Fn(&str),
 ) -> Result<()> {
-    let playlist = find_playlist_by_id(conn, playlist_id)?;
-    do_pack(conn, output, playlist, keep_structure, progress)
+    pack_list_tree(conn, output, &RELATED_TRACKS, list_names, options, progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue_row(kind: i64) -> serde_json::Value {
+        serde_json::json!({
+            "Kind": kind,
+            "InMsec": 1000,
+            "OutMsec": -1,
+            "Comment": "Drop",
+            "rb_local_deleted": 0,
+        })
+    }
+
+    #[test]
+    fn hot_cue_maps_kind_to_pad() {
+        let pads = [
+            (0, None),
+            (1, Some(0)),
+            (3, Some(2)),
+            (4, None),
+            (5, Some(3)),
+            (8, Some(6)),
+            (9, Some(7)),
+            (10, None),
+        ];
+        for (kind, pad) in pads {
+            let index = hot_cue(&cue_row(kind)).map(|cue| cue.index);
+            assert_eq!(index, pad, "Kind {}", kind);
+        }
+
+        // パッド H のホットループ
+        let mut row = cue_row(9);
+        row["OutMsec"] = serde_json::json!(5000);
+        let cue = hot_cue(&row).unwrap();
+        assert_eq!(
+            (cue.index, cue.start_msec, cue.end_msec),
+            (7, 1000, Some(5000))
+        );
+        row["rb_local_deleted"] = serde_json::json!(1);
+        assert!(hot_cue(&row).is_none());
+    }
 }
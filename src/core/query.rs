@@ -34,13 +34,29 @@ pub fn query_table_rows(
     sql: &str,
     params: &[&dyn rusqlite::types::ToSql],
 ) -> Result<Vec<serde_json::Value>> {
+    let mut result = Vec::new();
+    for_each_table_row(conn, sql, params, |row| {
+        result.push(row);
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+/// クエリ結果を1行ずつ JSON オブジェクトにして f に渡す (結果をメモリに溜めない)
+pub(crate) fn for_each_table_row(
+    conn: &Connection,
+    sql: &str,
+    params: &[&dyn rusqlite::types::ToSql],
+    mut f: impl FnMut(serde_json::Value) -> Result<()>,
+) -> Result<()> {
     let mut stmt = conn.prepare(sql)?;
     let column_count = stmt.column_count();
     let column_names: Vec<String> = (0..column_count)
         .map(|i| stmt.column_name(i).unwrap().to_string())
         .collect();
 
-    let rows = stmt.query_map(params, |row| {
+    let mut rows = stmt.query(params)?;
+    while let Some(row) = rows.next()? {
         let mut map = serde_json::Map::new();
         for (i, name) in column_names.iter().enumerate() {
             let val: Value = row.get(i)?;
@@ -55,14 +71,9 @@ pub fn query_table_rows(
             };
             map.insert(name.clone(), json_val);
         }
-        Ok(serde_json::Value::Object(map))
-    })?;
-
-    let mut result = Vec::new();
-    for r in rows {
-        result.push(r?);
+        f(serde_json::Value::Object(map))?;
     }
-    Ok(result)
+    Ok(())
 }
 
 pub fn format_create_table(sql: &str) -> String {
@@ -113,8 +124,8 @@ pub fn format_create_table(sql: &str) -> String {
 }
 
 pub fn list_tables(conn: &Connection) -> Result<()> {
-    let mut stmt = conn
-        .prepare("SELECT name, sql FROM sqlite_master WHERE type='table' ORDER BY name")?;
+    let mut stmt =
+        conn.prepare("SELECT name, sql FROM sqlite_master WHERE type='table' ORDER BY name")?;
     let tables: Vec<(String, String)> = stmt
         .query_map([], |row| {
             let name: String = row.get(0)?;
@@ -172,20 +183,62 @@ pub fn list_playlists(conn: &Connection) -> Result<()> {
         let name = p["Name"].as_str().unwrap_or("(no name)");
         let attr = p["Attribute"].as_i64().unwrap_or(0);
         let track_count = p["TrackCount"].as_i64().unwrap_or(0);
-        let kind = if attr == 0 { "フォルダ" } else { "リスト" };
+        let kind = if attr == 1 {
+            "フォルダ"
+        } else {
+            "リスト"
+        };
         tracing::info!("{:<8} {:<6} {:<6} {}", id, kind, track_count, name);
     }
     tracing::info!("\n合計 {} プレイリスト", playlists.len());
     Ok(())
 }
 
+pub fn list_histories(conn: &Connection) -> Result<()> {
+    let histories = query_table_rows(
+        conn,
+        "SELECT h.ID, h.Name, h.Attribute, h.DateCreated, \
+         (SELECT COUNT(*) FROM djmdSongHistory sh WHERE sh.HistoryID = h.ID AND sh.rb_local_deleted = 0) as TrackCount \
+         FROM djmdHistory h WHERE h.rb_local_deleted = 0 ORDER BY h.DateCreated, h.Seq",
+        &[],
+    )?;
+
+    tracing::info!(
+        "{:<8} {:<6} {:<6} {:<12} 名前",
+        "ID",
+        "種別",
+        "曲数",
+        "日付"
+    );
+    tracing::info!("{}", "-".repeat(60));
+    for h in &histories {
+        let id = h["ID"].as_str().unwrap_or("");
+        let name = h["Name"].as_str().unwrap_or("(no name)");
+        let attr = h["Attribute"].as_i64().unwrap_or(0);
+        let date = h["DateCreated"].as_str().unwrap_or("");
+        let track_count = h["TrackCount"].as_i64().unwrap_or(0);
+        let kind = if attr == 1 { "フォルダ" } else { "履歴" };
+        tracing::info!(
+            "{:<8} {:<6} {:<6} {:<12} {}",
+            id,
+            kind,
+            track_count,
+            date,
+            name
+        );
+    }
+    tracing::info!("\n合計 {} 件", histories.len());
+    Ok(())
+}
+
 pub(crate) fn collect_ids_from_column(rows: &[serde_json::Value], column: &str) -> HashSet<String> {
     let mut ids = HashSet::new();
     for row in rows {
         if let Some(serde_json::Value::String(id)) = row.get(column)
-            && !id.is_empty() {
-                ids.insert(id.clone());
-            }
+            && !id.is_empty()
+        {
+            ids.insert(id.clone());
+        }
     }
     ids
 }
@@ -222,9 +275,33 @@ pub(crate) fn query_by_content_ids(
     query_by_ids(conn, table, "ContentID", content_ids)
 }
 
+/// query_by_content_ids と同じ行を、メモリに溜めずに1行ずつ f に渡す
+pub(crate) fn for_each_row_by_content_ids(
+    conn: &Connection,
+    table: &str,
+    content_ids: &HashSet<String>,
+    f: impl FnMut(serde_json::Value) -> Result<()>,
+) -> Result<()> {
+    if content_ids.is_empty() {
+        return Ok(());
+    }
+    let placeholders: Vec<String> = content_ids.iter().map(|_| "?".to_string()).collect();
+    let sql = format!(
+        "SELECT * FROM `{}` WHERE `ContentID` IN ({})",
+        table,
+        placeholders.join(",")
+    );
+    let params: Vec<&dyn rusqlite::types::ToSql> = content_ids
+        .iter()
+        .map(|s| s as &dyn rusqlite::types::ToSql)
+        .collect();
+    for_each_table_row(conn, &sql, &params, f)
+}
+
 pub struct PlaylistInfo {
     pub id: String,
     pub name: String,
+    pub parent_id: String,
     pub attribute: i64,
     pub track_count: i64,
 }
@@ -241,7 +318,7 @@ pub struct TrackInfo {
 pub fn get_playlists(conn: &Connection) -> Result<Vec<PlaylistInfo>> {
     let rows = query_table_rows(
         conn,
-        "SELECT p.ID, p.Name, p.Attribute, \
+        "SELECT p.ID, p.Name, p.ParentID, p.Attribute, \
          (SELECT COUNT(*) FROM djmdSongPlaylist sp WHERE sp.PlaylistID = p.ID AND sp.rb_local_deleted = 0) as TrackCount \
          FROM djmdPlaylist p WHERE p.rb_local_deleted = 0 ORDER BY p.Seq",
         &[],
@@ -252,6 +329,7 @@ pub fn get_playlists(conn: &Connection) -> Result<Vec<PlaylistInfo>> {
         playlists.push(PlaylistInfo {
             id: row["ID"].as_str().unwrap_or("").to_string(),
             name: row["Name"].as_str().unwrap_or("(no name)").to_string(),
+            parent_id: row["ParentID"].as_str().unwrap_or("root").to_string(),
             attribute: row["Attribute"].as_i64().unwrap_or(0),
             track_count: row["TrackCount"].as_i64().unwrap_or(0),
         });
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::db::to_nfc;
+use super::pack::sha256_file;
+
+/// 見つからない音声ファイルを探すフォルダ (--search-root) のファイル一覧
+pub(crate) struct SearchIndex {
+    /// NFC 正規化したファイル名 → (パス, サイズ)
+    by_name: HashMap<String, Vec<(PathBuf, u64)>>,
+    /// サイズ → パス (ハッシュで照合するときの候補)
+    by_size: HashMap<u64, Vec<PathBuf>>,
+    /// 計算済みの SHA-256
+    hashes: HashMap<PathBuf, Option<String>>,
+}
+
+/// search root で見つけた移動先
+#[derive(Clone, Debug)]
+pub struct Relocation {
+    /// トラック名
+    pub label: String,
+    /// DB に記録されている FolderPath
+    pub from: String,
+    pub to: String,
+}
+
+/// 以前のパックに記録された音声ファイルの内容
+pub(crate) struct ExpectedAudio {
+    pub(crate) sha256: String,
+    pub(crate) size: u64,
+}
+
+impl SearchIndex {
+    /// フォルダ以下のファイルを再帰的に集める (シンボリックリンクのフォルダはたどらない)
+    pub(crate) fn build(roots: &[String], progress: &dyn Fn(&str)) -> Result<Self> {
+        let mut index = Self {
+            by_name: HashMap::new(),
+            by_size: HashMap::new(),
+            hashes: HashMap::new(),
+        };
+        for root in roots {
+            progress(&format!("音声ファイルを探すフォルダを走査中: {}", root));
+            let root = Path::new(root);
+            if !root.is_dir() {
+                anyhow::bail!(
+                    "--search-root のフォルダが見つかりません: {}",
+                    root.display()
+                );
+            }
+            index.add_dir(root)?;
+        }
+        let files: usize = index.by_size.values().map(|paths| paths.len()).sum();
+        progress(&format!("走査したファイル: {} 件", files));
+        Ok(index)
+    }
+
+    fn add_dir(&mut self, dir: &Path) -> Result<()> {
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("フォルダを開けません: {}", dir.display()))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                // 読めないサブフォルダ (権限がない等) は飛ばして走査を続ける
+                if let Err(e) = self.add_dir(&path) {
+                    tracing::warn!("{:#}", e);
+                }
+                continue;
+            }
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let name = to_nfc(&entry.file_name().to_string_lossy());
+            self.by_name
+                .entry(name)
+                .or_default()
+                .push((path.clone(), metadata.len()));
+            self.by_size.entry(metadata.len()).or_default().push(path);
+        }
+        Ok(())
+    }
+
+    fn sha256(&mut self, path: &Path) -> Option<String> {
+        self.hashes
+            .entry(path.to_path_buf())
+            .or_insert_with(|| sha256_file(path).ok())
+            .clone()
+    }
+
+    /// ファイル名とサイズ (分かる場合) が一致するファイルを探す。候補が複数あれば見つからない扱い
+    fn find_by_name(&self, name: &str, size: Option<u64>) -> Result<PathBuf, usize> {
+        let candidates: Vec<&PathBuf> = self
+            .by_name
+            .get(&to_nfc(name))
+            .into_iter()
+            .flatten()
+            .filter(|(_, file_size)| size.is_none_or(|s| s == *file_size))
+            .map(|(path, _)| path)
+            .collect();
+        match candidates.as_slice() {
+            [path] => Ok((*path).clone()),
+            _ => Err(candidates.len()),
+        }
+    }
+
+    /// サイズと SHA-256 が一致するファイルを探す (名前が変わったファイルも見つかる)
+    fn find_by_hash(&mut self, expected: &ExpectedAudio) -> Option<PathBuf> {
+        let candidates = self.by_size.get(&expected.size)?.clone();
+        candidates
+            .into_iter()
+            .find(|path| self.sha256(path).as_deref() == Some(expected.sha256.as_str()))
+    }
+}
+
+/// FolderPath のファイルがないトラックを search root から探し、見つかれば FolderPath を置き換える。
+/// expected に以前のパックの SHA-256 があるトラックはまずハッシュで照合し、
+/// 見つからなければファイル名とサイズで照合する
+pub(crate) fn relocate_missing_audio(
+    contents: &mut [serde_json::Value],
+    index: &mut SearchIndex,
+    expected: &HashMap<String, ExpectedAudio>,
+    progress: &dyn Fn(&str),
+) -> Vec<Relocation> {
+    let mut relocations = Vec::new();
+    for content in contents.iter_mut() {
+        let Some(folder_path) = content["FolderPath"].as_str().map(|s| s.to_string()) else {
+            continue;
+        };
+        if folder_path.is_empty() || Path::new(&folder_path).exists() {
+            continue;
+        }
+        let content_id = content["ID"].as_str().unwrap_or("?").to_string();
+        let label = format!(
+            "{} (ID: {})",
+            content["Title"].as_str().unwrap_or("?"),
+            content_id
+        );
+
+        // ハッシュが一致しなければ (タグを書き換えた等) ファイル名とサイズで探す
+        let found = expected
+            .get(&content_id)
+            .and_then(|expected| index.find_by_hash(expected));
+        let found = found.or_else(|| {
+            // Windows の区切り文字のパスも扱えるよう、どちらの区切りでも名前を取り出す
+            let name = folder_path.rsplit(['/', '\\']).next().unwrap_or_default();
+            let size = content["FileSize"].as_u64().filter(|&s| s > 0);
+            match index.find_by_name(name, size) {
+                Ok(path) => Some(path),
+                Err(0) => None,
+                Err(count) => {
+                    progress(&format!(
+                        "警告: 移動先の候補が複数あるため選べません ({} 件): {}: {}",
+                        count, label, folder_path
+                    ));
+                    None
+                }
+            }
+        });
+        let Some(found) = found else {
+            continue;
+        };
+
+        let to = found.to_string_lossy().to_string();
+        progress(&format!(
+            "移動先を検出: {}: {} → {}",
+            label, folder_path, to
+        ));
+        content["FolderPath"] = serde_json::Value::String(to.clone());
+        relocations.push(Relocation {
+            label,
+            from: folder_path,
+            to,
+        });
+    }
+    if !relocations.is_empty() {
+        progress(&format!(
+            "{} 件のトラックを --search-root で見つけました。rekordbox 側でもファイルの場所を修正してください",
+            relocations.len()
+        ));
+    }
+    relocations
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::volume::RkpArchive;
+
+/// 署名を格納する .rkp 内のエントリ名 (pack.json のバイト列に対する署名)
+pub(crate) const SIGNATURE_ENTRY: &str = "pack.sig";
+
+/// パックの署名に使う秘密鍵と署名者名
+pub(crate) struct SigningIdentity {
+    pub(crate) name: String,
+    key: SigningKey,
+}
+
+/// 信頼する公開鍵
+pub(crate) struct TrustedKey {
+    name: String,
+    key: VerifyingKey,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SignatureStatus {
+    /// 署名なし
+    Unsigned,
+    /// 署名が pack.json と一致しない (改ざんされている)
+    Invalid,
+    /// 署名は正しいが、信頼する鍵に登録されていない
+    Untrusted,
+    /// 信頼する鍵による正しい署名
+    Trusted,
+}
+
+/// パックの署名の確認結果
+#[derive(Clone, Debug)]
+pub struct PackSignature {
+    pub status: SignatureStatus,
+    /// 署名者名 (信頼する鍵なら登録名、それ以外はパックに書かれた名前)
+    pub signer: Option<String>,
+    /// 公開鍵の指紋 (SHA-256 の先頭16桁)
+    pub fingerprint: Option<String>,
+}
+
+impl PackSignature {
+    fn unsigned() -> Self {
+        Self {
+            status: SignatureStatus::Unsigned,
+            signer: None,
+            fingerprint: None,
+        }
+    }
+}
+
+impl fmt::Display for PackSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let signer = self.signer.as_deref().unwrap_or("?");
+        let fingerprint = self.fingerprint.as_deref().unwrap_or("?");
+        match self.status {
+            SignatureStatus::Unsigned => write!(f, "署名なし"),
+            SignatureStatus::Invalid => {
+                write!(
+                    f,
+                    "署名が一致しません (改ざんの可能性: 署名者 {} [{}])",
+                    signer, fingerprint
+                )
+            }
+            SignatureStatus::Untrusted => {
+                write!(f, "未登録の鍵による署名: {} [{}]", signer, fingerprint)
+            }
+            SignatureStatus::Trusted => write!(f, "署名済み: {} [{}]", signer, fingerprint),
+        }
+    }
+}
+
+fn fingerprint(key: &VerifyingKey) -> String {
+    hex::encode(&Sha256::digest(key.as_bytes())[..8])
+}
+
+fn public_key_path(secret_path: &Path) -> PathBuf {
+    let mut name = secret_path.as_os_str().to_os_string();
+    name.push(".pub");
+    PathBuf::from(name)
+}
+
+/// 信頼する公開鍵 (.pub) を置くフォルダ
+fn trusted_keys_dir() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rkpack")
+        .join("trusted_keys")
+}
+
+/// 署名用の鍵ペアを作る。秘密鍵を output に、公開鍵を output.pub に書く
+pub fn generate_key(output: &str, name: &str, progress: &dyn Fn(&str)) -> Result<()> {
+    let secret_path = PathBuf::from(output);
+    if secret_path.exists() {
+        anyhow::bail!("鍵ファイルが既に存在します: {}", secret_path.display());
+    }
+    let mut seed = [0u8; 32];
+    getrandom::fill(&mut seed).map_err(|e| anyhow::anyhow!("乱数の生成に失敗: {}", e))?;
+    let key = SigningKey::from_bytes(&seed);
+    let public_key = hex::encode(key.verifying_key().as_bytes());
+
+    let secret = json!({
+        "name": name,
+        "secret_key": hex::encode(key.to_bytes()),
+        "public_key": public_key,
+    });
+    fs::write(&secret_path, serde_json::to_vec_pretty(&secret)?)
+        .with_context(|| format!("鍵ファイルの作成に失敗: {}", secret_path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&secret_path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    let public_path = public_key_path(&secret_path);
+    let public = json!({
+        "name": name,
+        "public_key": public_key,
+    });
+    fs::write(&public_path, serde_json::to_vec_pretty(&public)?)
+        .with_context(|| format!("公開鍵ファイルの作成に失敗: {}", public_path.display()))?;
+
+    progress(&format!("秘密鍵: {}", secret_path.display()));
+    progress(&format!(
+        "公開鍵: {} [{}] (受け取る側の {} に置くか、--trusted-keys で指定してください)",
+        public_path.display(),
+        fingerprint(&key.verifying_key()),
+        trusted_keys_dir().display()
+    ));
+    Ok(())
+}
+
+fn decode_key_bytes(value: &serde_json::Value, field: &str, path: &Path) -> Result<[u8; 32]> {
+    let hex_str = value[field]
+        .as_str()
+        .with_context(|| format!("鍵ファイルに {} がありません: {}", field, path.display()))?;
+    hex::decode(hex_str)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .with_context(|| format!("鍵ファイルの {} が不正です: {}", field, path.display()))
+}
+
+fn read_key_file(path: &Path) -> Result<serde_json::Value> {
+    let bytes =
+        fs::read(path).with_context(|| format!("鍵ファイルを開けません: {}", path.display()))?;
+    serde_json::from_slice(&bytes)
+        .with_context(|| format!("鍵ファイルの解析に失敗: {}", path.display()))
+}
+
+pub(crate) fn load_signing_key(path: &str) -> Result<SigningIdentity> {
+    let path = Path::new(path);
+    let value = read_key_file(path)?;
+    let key = SigningKey::from_bytes(&decode_key_bytes(&value, "secret_key", path)?);
+    Ok(SigningIdentity {
+        name: value["name"].as_str().unwrap_or_default().to_string(),
+        key,
+    })
+}
+
+fn load_public_key(path: &Path) -> Result<TrustedKey> {
+    let value = read_key_file(path)?;
+    let key = VerifyingKey::from_bytes(&decode_key_bytes(&value, "public_key", path)?)
+        .with_context(|| format!("公開鍵が不正です: {}", path.display()))?;
+    Ok(TrustedKey {
+        name: value["name"].as_str().unwrap_or_default().to_string(),
+        key,
+    })
+}
+
+/// 信頼する公開鍵を読む。既定のフォルダの .pub に加え、指定されたファイル・フォルダも読む
+pub(crate) fn load_trusted_keys(paths: &[String]) -> Result<Vec<TrustedKey>> {
+    let mut keys = Vec::new();
+    let default_dir = trusted_keys_dir();
+    if default_dir.is_dir() {
+        keys.extend(load_public_keys_in(&default_dir)?);
+    }
+    for path in paths.iter().map(PathBuf::from) {
+        if path.is_dir() {
+            keys.extend(load_public_keys_in(&path)?);
+        } else {
+            keys.push(load_public_key(&path)?);
+        }
+    }
+    Ok(keys)
+}
+
+fn load_public_keys_in(dir: &Path) -> Result<Vec<TrustedKey>> {
+    let mut keys = Vec::new();
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("フォルダを開けません: {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "pub") {
+            keys.push(load_public_key(&path)?);
+        }
+    }
+    Ok(keys)
+}
+
+/// pack.json のバイト列に署名し、pack.sig の内容を返す
+pub(crate) fn sign_manifest(identity: &SigningIdentity, manifest: &[u8]) -> serde_json::Value {
+    let signature = identity.key.sign(manifest);
+    json!({
+        "signer": identity.name,
+        "public_key": hex::encode(identity.key.verifying_key().as_bytes()),
+        "signature": hex::encode(signature.to_bytes()),
+    })
+}
+
+/// pack.sig を pack.json と照合し、署名者が信頼する鍵かを確かめる
+pub(crate) fn read_pack_signature(
+    archive: &mut RkpArchive,
+    trusted: &[TrustedKey],
+) -> Result<PackSignature> {
+    if !archive.contains(SIGNATURE_ENTRY) {
+        return Ok(PackSignature::unsigned());
+    }
+    let sig_data: serde_json::Value = serde_json::from_reader(archive.by_name(SIGNATURE_ENTRY)?)
+        .context("pack.sig の解析に失敗")?;
+    let mut manifest = Vec::new();
+    archive.by_name("pack.json")?.read_to_end(&mut manifest)?;
+
+    let claimed_signer = sig_data["signer"].as_str().map(|s| s.to_string());
+    let key = sig_data["public_key"]
+        .as_str()
+        .and_then(|s| hex::decode(s).ok())
+        .and_then(|bytes| bytes.try_into().ok())
+        .and_then(|bytes: [u8; 32]| VerifyingKey::from_bytes(&bytes).ok());
+    let signature = sig_data["signature"]
+        .as_str()
+        .and_then(|s| hex::decode(s).ok())
+        .and_then(|bytes| Signature::from_slice(&bytes).ok());
+    let (Some(key), Some(signature)) = (key, signature) else {
+        return Ok(PackSignature {
+            status: SignatureStatus::Invalid,
+            signer: claimed_signer,
+            fingerprint: None,
+        });
+    };
+
+    let fingerprint = Some(fingerprint(&key));
+    // 弱い公開鍵や正規形でない署名 (書き換えても検証を通るもの) は受け付けない
+    if key.verify_strict(&manifest, &signature).is_err() {
+        return Ok(PackSignature {
+            status: SignatureStatus::Invalid,
+            signer: claimed_signer,
+            fingerprint,
+        });
+    }
+    Ok(match trusted.iter().find(|t| t.key == key) {
+        Some(trusted_key) => PackSignature {
+            status: SignatureStatus::Trusted,
+            signer: Some(trusted_key.name.clone()),
+            fingerprint,
+        },
+        None => PackSignature {
+            status: SignatureStatus::Untrusted,
+            signer: claimed_signer,
+            fingerprint,
+        },
+    })
+}
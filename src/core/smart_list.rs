@@ -0,0 +1,828 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use super::id_mapping::IdMap;
+
+/// SmartList XML の要素 (`NODE` / `CONDITION`)。属性の並び順を保持する
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct XmlElement {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<XmlElement>,
+}
+
+impl XmlElement {
+    fn attr(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn set_attr(&mut self, key: &str, value: String) {
+        if let Some(slot) = self.attributes.iter_mut().find(|(k, _)| k == key) {
+            slot.1 = value;
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum LogicalOperator {
+    All,
+    Any,
+}
+
+/// スマートプレイリストの条件1件
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SmartCondition {
+    pub property: String,
+    pub operator: i64,
+    pub value_unit: String,
+    pub value_left: String,
+    pub value_right: String,
+}
+
+/// 条件のグループ。`NODE` は入れ子にできる
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SmartNode {
+    pub logical_operator: LogicalOperator,
+    pub conditions: Vec<SmartCondition>,
+    pub children: Vec<SmartNode>,
+}
+
+// rekordbox の SmartList の Operator 値
+pub(crate) const OP_EQUAL: i64 = 1;
+pub(crate) const OP_NOT_EQUAL: i64 = 2;
+pub(crate) const OP_GREATER: i64 = 3;
+pub(crate) const OP_LESS: i64 = 4;
+pub(crate) const OP_IN_RANGE: i64 = 5;
+pub(crate) const OP_IN_LAST: i64 = 6;
+pub(crate) const OP_NOT_IN_LAST: i64 = 7;
+pub(crate) const OP_CONTAINS: i64 = 8;
+pub(crate) const OP_NOT_CONTAINS: i64 = 9;
+pub(crate) const OP_STARTS_WITH: i64 = 10;
+pub(crate) const OP_ENDS_WITH: i64 = 11;
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn parse_attributes(s: &str) -> Result<Vec<(String, String)>> {
+    let mut attributes = Vec::new();
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let eq = rest.find('=').context("SmartList の属性が不正です")?;
+        let key = rest[..eq].trim().to_string();
+        let after = rest[eq + 1..].trim_start();
+        let quote = after
+            .chars()
+            .next()
+            .context("SmartList の属性値がありません")?;
+        if quote != '"' && quote != '\'' {
+            anyhow::bail!("SmartList の属性値が引用符で囲まれていません: {}", key);
+        }
+        let end = after[1..]
+            .find(quote)
+            .context("SmartList の属性値が閉じられていません")?;
+        attributes.push((key, unescape_xml(&after[1..end + 1])));
+        rest = after[end + 2..].trim_start();
+    }
+    Ok(attributes)
+}
+
+/// SmartList 程度の単純な XML (属性と子要素のみ) を解析する
+pub(crate) fn parse_xml(xml: &str) -> Result<XmlElement> {
+    let mut stack: Vec<XmlElement> = Vec::new();
+    let mut root = None;
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..]
+            .find('>')
+            .map(|e| start + e)
+            .context("SmartList のタグが閉じられていません")?;
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        if tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        if let Some(name) = tag.strip_prefix('/') {
+            let element = stack.pop().context("SmartList の終了タグが不正です")?;
+            if element.name != name.trim() {
+                anyhow::bail!("SmartList の終了タグが一致しません: {}", name);
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(element),
+                None => root = Some(element),
+            }
+            continue;
+        }
+
+        let self_closing = tag.ends_with('/');
+        let body = tag.trim_end_matches('/');
+        let (name, attrs) = match body.find(char::is_whitespace) {
+            Some(i) => (&body[..i], &body[i..]),
+            None => (body, ""),
+        };
+        let element = XmlElement {
+            name: name.to_string(),
+            attributes: parse_attributes(attrs)?,
+            children: Vec::new(),
+        };
+        if self_closing {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(element),
+                None => root = Some(element),
+            }
+        } else {
+            stack.push(element);
+        }
+    }
+
+    if !stack.is_empty() {
+        anyhow::bail!("SmartList の要素が閉じられていません");
+    }
+    root.context("SmartList が空です")
+}
+
+pub(crate) fn write_xml(element: &XmlElement) -> String {
+    let mut out = format!("<{}", element.name);
+    for (key, value) in &element.attributes {
+        out.push_str(&format!(" {}=\"{}\"", key, escape_xml(value)));
+    }
+    if element.children.is_empty() {
+        out.push_str("/>");
+    } else {
+        out.push('>');
+        for child in &element.children {
+            out.push_str(&write_xml(child));
+        }
+        out.push_str(&format!("</{}>", element.name));
+    }
+    out
+}
+
+fn node_from_element(element: &XmlElement) -> Result<SmartNode> {
+    let logical_operator = match element.attr("LogicalOperator") {
+        Some("2") => LogicalOperator::Any,
+        _ => LogicalOperator::All,
+    };
+    let mut node = SmartNode {
+        logical_operator,
+        conditions: Vec::new(),
+        children: Vec::new(),
+    };
+    for child in &element.children {
+        match child.name.as_str() {
+            "CONDITION" => node.conditions.push(SmartCondition {
+                property: child.attr("PropertyName").unwrap_or_default().to_string(),
+                operator: child
+                    .attr("Operator")
+                    .and_then(|v| v.parse().ok())
+                    .context("SmartList の Operator が不正です")?,
+                value_unit: child.attr("ValueUnit").unwrap_or_default().to_string(),
+                value_left: child.attr("ValueLeft").unwrap_or_default().to_string(),
+                value_right: child.attr("ValueRight").unwrap_or_default().to_string(),
+            }),
+            "NODE" => node.children.push(node_from_element(child)?),
+            _ => {}
+        }
+    }
+    Ok(node)
+}
+
+fn node_to_element(node: &SmartNode, node_id: &str) -> XmlElement {
+    let logical_operator = match node.logical_operator {
+        LogicalOperator::All => "1",
+        LogicalOperator::Any => "2",
+    };
+    let mut children: Vec<XmlElement> = node
+        .conditions
+        .iter()
+        .map(|cond| XmlElement {
+            name: "CONDITION".to_string(),
+            attributes: vec![
+                ("PropertyName".to_string(), cond.property.clone()),
+                ("Operator".to_string(), cond.operator.to_string()),
+                ("ValueUnit".to_string(), cond.value_unit.clone()),
+                ("ValueLeft".to_string(), cond.value_left.clone()),
+                ("ValueRight".to_string(), cond.value_right.clone()),
+            ],
+            children: Vec::new(),
+        })
+        .collect();
+    children.extend(
+        node.children
+            .iter()
+            .map(|child| node_to_element(child, "0")),
+    );
+    XmlElement {
+        name: "NODE".to_string(),
+        attributes: vec![
+            ("Id".to_string(), node_id.to_string()),
+            ("LogicalOperator".to_string(), logical_operator.to_string()),
+            ("AutomaticUpdate".to_string(), "0".to_string()),
+        ],
+        children,
+    }
+}
+
+/// 条件を SmartList XML に書き出す
+pub(crate) fn smart_list_to_xml(node: &SmartNode, playlist_id: &str) -> String {
+    write_xml(&node_to_element(node, playlist_id))
+}
+
+pub(crate) fn parse_smart_list(xml: &str) -> Result<SmartNode> {
+    let root = parse_xml(xml)?;
+    if root.name != "NODE" {
+        anyhow::bail!(
+            "SmartList のルート要素が NODE ではありません: {}",
+            root.name
+        );
+    }
+    node_from_element(&root)
+}
+
+const CONTENT_JOINS: &str = "\
+    LEFT JOIN djmdArtist ar ON ar.ID = c.ArtistID \
+    LEFT JOIN djmdArtist oar ON oar.ID = c.OrgArtistID \
+    LEFT JOIN djmdArtist rmx ON rmx.ID = c.RemixerID \
+    LEFT JOIN djmdArtist cmp ON cmp.ID = c.ComposerID \
+    LEFT JOIN djmdAlbum al ON al.ID = c.AlbumID \
+    LEFT JOIN djmdArtist aar ON aar.ID = al.AlbumArtistID \
+    LEFT JOIN djmdGenre g ON g.ID = c.GenreID \
+    LEFT JOIN djmdKey k ON k.ID = c.KeyID \
+    LEFT JOIN djmdLabel lb ON lb.ID = c.LabelID";
+
+enum PropertyKind {
+    Text(&'static str),
+    Number(&'static str),
+    Date(&'static str),
+    MyTag,
+}
+
+fn property_kind(property: &str) -> Option<PropertyKind> {
+    use PropertyKind::*;
+    Some(match property {
+        "artist" => Text("ar.Name"),
+        "originalArtist" => Text("oar.Name"),
+        "remixedBy" => Text("rmx.Name"),
+        "producer" | "composer" => Text("cmp.Name"),
+        "album" => Text("al.Name"),
+        "albumArtist" => Text("aar.Name"),
+        "genre" => Text("g.Name"),
+        "key" => Text("k.ScaleName"),
+        "label" => Text("lb.Name"),
+        "comments" => Text("c.Commnt"),
+        "name" | "title" => Text("c.Title"),
+        "mixName" => Text("c.Subtitle"),
+        "fileName" => Text("c.FileNameL"),
+        "bpm" => Number("(c.BPM / 100.0)"),
+        "rating" => Number("c.Rating"),
+        "year" => Number("c.ReleaseYear"),
+        "duration" => Number("c.Length"),
+        "counter" => Number("c.DJPlayCount"),
+        "color" => Number("CAST(c.ColorID AS INTEGER)"),
+        "stockDate" => Date("c.StockDate"),
+        "dateCreated" => Date("c.DateCreated"),
+        "dateReleased" => Date("c.ReleaseDate"),
+        "myTag" => MyTag,
+        _ => return None,
+    })
+}
+
+fn date_offset_modifier(cond: &SmartCondition) -> Result<String> {
+    let amount: i64 = cond
+        .value_left
+        .trim()
+        .parse()
+        .with_context(|| format!("期間の値が不正です: {}", cond.value_left))?;
+    Ok(match cond.value_unit.as_str() {
+        "week" => format!("-{} days", amount * 7),
+        "month" => format!("-{} months", amount),
+        "year" => format!("-{} years", amount),
+        _ => format!("-{} days", amount),
+    })
+}
+
+fn number_param(value: &str) -> Result<rusqlite::types::Value> {
+    let v: f64 = value
+        .trim()
+        .parse()
+        .with_context(|| format!("数値条件の値が不正です: {}", value))?;
+    Ok(rusqlite::types::Value::Real(v))
+}
+
+fn condition_sql(
+    cond: &SmartCondition,
+    params: &mut Vec<rusqlite::types::Value>,
+) -> Result<String> {
+    use rusqlite::types::Value;
+
+    let kind = property_kind(&cond.property)
+        .with_context(|| format!("未対応のスマートプレイリスト条件: {}", cond.property))?;
+    let unsupported = || anyhow::anyhow!("未対応の演算子 {} ({})", cond.operator, cond.property);
+
+    let sql = match kind {
+        PropertyKind::Text(col) => {
+            let col = format!("COALESCE({}, '')", col);
+            let (op, pattern) = match cond.operator {
+                OP_EQUAL => ("=", cond.value_left.clone()),
+                OP_NOT_EQUAL => ("<>", cond.value_left.clone()),
+                OP_CONTAINS => ("LIKE", format!("%{}%", cond.value_left)),
+                OP_NOT_CONTAINS => ("NOT LIKE", format!("%{}%", cond.value_left)),
+                OP_STARTS_WITH => ("LIKE", format!("{}%", cond.value_left)),
+                OP_ENDS_WITH => ("LIKE", format!("%{}", cond.value_left)),
+                _ => return Err(unsupported()),
+            };
+            params.push(Value::Text(pattern));
+            format!("{} {} ? COLLATE NOCASE", col, op)
+        }
+        PropertyKind::Number(col) => match cond.operator {
+            OP_EQUAL | OP_NOT_EQUAL | OP_GREATER | OP_LESS => {
+                params.push(number_param(&cond.value_left)?);
+                let op = match cond.operator {
+                    OP_EQUAL => "=",
+                    OP_NOT_EQUAL => "<>",
+                    OP_GREATER => ">",
+                    _ => "<",
+                };
+                format!("{} {} ?", col, op)
+            }
+            OP_IN_RANGE => {
+                params.push(number_param(&cond.value_left)?);
+                params.push(number_param(&cond.value_right)?);
+                format!("{} BETWEEN ? AND ?", col)
+            }
+            _ => return Err(unsupported()),
+        },
+        PropertyKind::Date(col) => match cond.operator {
+            OP_EQUAL | OP_NOT_EQUAL | OP_GREATER | OP_LESS => {
+                params.push(Value::Text(cond.value_left.clone()));
+                let op = match cond.operator {
+                    OP_EQUAL => "=",
+                    OP_NOT_EQUAL => "<>",
+                    OP_GREATER => ">",
+                    _ => "<",
+                };
+                format!("date({}) {} date(?)", col, op)
+            }
+            OP_IN_RANGE => {
+                params.push(Value::Text(cond.value_left.clone()));
+                params.push(Value::Text(cond.value_right.clone()));
+                format!("date({}) BETWEEN date(?) AND date(?)", col)
+            }
+            OP_IN_LAST => {
+                params.push(Value::Text(date_offset_modifier(cond)?));
+                format!("date({}) >= date('now', ?)", col)
+            }
+            OP_NOT_IN_LAST => {
+                params.push(Value::Text(date_offset_modifier(cond)?));
+                format!("date({}) < date('now', ?)", col)
+            }
+            _ => return Err(unsupported()),
+        },
+        PropertyKind::MyTag => {
+            let exists = "EXISTS (SELECT 1 FROM djmdSongMyTag smt \
+                 WHERE smt.ContentID = c.ID AND smt.MyTagID = ? AND smt.rb_local_deleted = 0)";
+            params.push(Value::Text(cond.value_left.clone()));
+            match cond.operator {
+                OP_EQUAL => exists.to_string(),
+                OP_NOT_EQUAL => format!("NOT {}", exists),
+                _ => return Err(unsupported()),
+            }
+        }
+    };
+    Ok(sql)
+}
+
+fn node_sql(node: &SmartNode, params: &mut Vec<rusqlite::types::Value>) -> Result<String> {
+    let mut parts = Vec::new();
+    for cond in &node.conditions {
+        parts.push(condition_sql(cond, params)?);
+    }
+    for child in &node.children {
+        parts.push(node_sql(child, params)?);
+    }
+    if parts.is_empty() {
+        return Ok("1".to_string());
+    }
+    let joiner = match node.logical_operator {
+        LogicalOperator::All => " AND ",
+        LogicalOperator::Any => " OR ",
+    };
+    Ok(format!("({})", parts.join(joiner)))
+}
+
+/// 条件に一致する djmdContent の ID を返す
+pub(crate) fn evaluate_smart_list(conn: &Connection, node: &SmartNode) -> Result<Vec<String>> {
+    let mut params = Vec::new();
+    let where_clause = node_sql(node, &mut params)?;
+    let sql = format!(
+        "SELECT c.ID FROM djmdContent c {} WHERE c.rb_local_deleted = 0 AND {} ORDER BY CAST(c.ID AS INTEGER)",
+        CONTENT_JOINS, where_clause
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let ids = stmt
+        .query_map(rusqlite::params_from_iter(params), |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ids)
+}
+
+/// SmartList 内の ID (NODE の Id = リスト自身の ID, myTag 条件の値) を移行先の ID に書き換える
+pub(crate) fn remap_smart_list(xml: &str, id_map: &IdMap, list_table: &str) -> Result<String> {
+    fn remap(element: &mut XmlElement, id_map: &IdMap, list_table: &str) {
+        let empty = HashMap::new();
+        let list_map = id_map.get(list_table).unwrap_or(&empty);
+        let my_tag_map = id_map.get("djmdMyTag").unwrap_or(&empty);
+
+        if element.name == "NODE"
+            && let Some(new_id) = element.attr("Id").and_then(|id| list_map.get(id))
+        {
+            element.set_attr("Id", new_id.clone());
+        }
+        if element.name == "CONDITION"
+            && element.attr("PropertyName") == Some("myTag")
+            && let Some(new_id) = element.attr("ValueLeft").and_then(|id| my_tag_map.get(id))
+        {
+            element.set_attr("ValueLeft", new_id.clone());
+        }
+        for child in element.children.iter_mut() {
+            remap(child, id_map, list_table);
+        }
+    }
+
+    let mut root = parse_xml(xml)?;
+    remap(&mut root, id_map, list_table);
+    Ok(write_xml(&root))
+}
+
+/// SmartList 内で参照されている MyTag の ID を集める
+pub(crate) fn referenced_my_tag_ids(node: &SmartNode) -> Vec<String> {
+    let mut ids: Vec<String> = node
+        .conditions
+        .iter()
+        .filter(|c| c.property == "myTag")
+        .map(|c| c.value_left.clone())
+        .collect();
+    for child in &node.children {
+        ids.extend(referenced_my_tag_ids(child));
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = concat!(
+        r#"<NODE Id="123" LogicalOperator="1" AutomaticUpdate="0">"#,
+        r#"<CONDITION PropertyName="genre" Operator="1" ValueUnit="" ValueLeft="Drum &amp; Bass" ValueRight=""/>"#,
+        r#"<NODE Id="0" LogicalOperator="2" AutomaticUpdate="0">"#,
+        r#"<CONDITION PropertyName="bpm" Operator="5" ValueUnit="" ValueLeft="170" ValueRight="175"/>"#,
+        r#"<CONDITION PropertyName="myTag" Operator="1" ValueUnit="" ValueLeft="42" ValueRight=""/>"#,
+        r#"</NODE></NODE>"#
+    );
+
+    fn condition(property: &str, operator: i64, left: &str, right: &str) -> SmartCondition {
+        SmartCondition {
+            property: property.to_string(),
+            operator,
+            value_unit: String::new(),
+            value_left: left.to_string(),
+            value_right: right.to_string(),
+        }
+    }
+
+    fn node(logical_operator: LogicalOperator, conditions: Vec<SmartCondition>) -> SmartNode {
+        SmartNode {
+            logical_operator,
+            conditions,
+            children: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_nested_smart_list() {
+        let xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", SAMPLE);
+        let parsed = parse_smart_list(&xml).unwrap();
+        assert_eq!(parsed.logical_operator, LogicalOperator::All);
+        assert_eq!(
+            parsed.conditions,
+            vec![condition("genre", OP_EQUAL, "Drum & Bass", "")]
+        );
+        assert_eq!(parsed.children.len(), 1);
+        let child = &parsed.children[0];
+        assert_eq!(child.logical_operator, LogicalOperator::Any);
+        assert_eq!(
+            child.conditions,
+            vec![
+                condition("bpm", OP_IN_RANGE, "170", "175"),
+                condition("myTag", OP_EQUAL, "42", ""),
+            ]
+        );
+        assert_eq!(referenced_my_tag_ids(&parsed), vec!["42".to_string()]);
+    }
+
+    #[test]
+    fn serializes_and_parses_again() {
+        let parsed = parse_smart_list(SAMPLE).unwrap();
+        let xml = smart_list_to_xml(&parsed, "123");
+        assert_eq!(xml, SAMPLE);
+        assert_eq!(parse_smart_list(&xml).unwrap(), parsed);
+    }
+
+    #[test]
+    fn escapes_and_unescapes_entities() {
+        let element = XmlElement {
+            name: "CONDITION".to_string(),
+            attributes: vec![
+                ("ValueLeft".to_string(), r#"<a & "b"> it's"#.to_string()),
+                ("ValueRight".to_string(), "&lt;".to_string()),
+            ],
+            children: Vec::new(),
+        };
+        let xml = write_xml(&element);
+        assert_eq!(
+            xml,
+            r#"<CONDITION ValueLeft="&lt;a &amp; &quot;b&quot;&gt; it's" ValueRight="&amp;lt;"/>"#
+        );
+        assert_eq!(parse_xml(&xml).unwrap(), element);
+
+        let single_quoted = parse_xml(r#"<CONDITION ValueLeft='it&apos;s "q"'/>"#).unwrap();
+        assert_eq!(single_quoted.attr("ValueLeft"), Some(r#"it's "q""#));
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        for xml in [
+            "",
+            "<NODE Id=\"1\">",
+            "<NODE Id=\"1\"></CONDITION>",
+            "<NODE Id=1/>",
+            "<NODE Id=\"1/>",
+            "<NODE Id=\"1\"",
+        ] {
+            assert!(parse_xml(xml).is_err(), "{}", xml);
+        }
+        assert!(parse_smart_list("<CONDITION PropertyName=\"genre\"/>").is_err());
+        assert!(parse_smart_list("<NODE><CONDITION Operator=\"x\"/></NODE>").is_err());
+    }
+
+    #[test]
+    fn remaps_list_and_my_tag_ids() {
+        let id_map: IdMap = HashMap::from([
+            (
+                "djmdPlaylist".to_string(),
+                HashMap::from([("123".to_string(), "900".to_string())]),
+            ),
+            (
+                "djmdMyTag".to_string(),
+                HashMap::from([("42".to_string(), "7".to_string())]),
+            ),
+        ]);
+        let remapped = remap_smart_list(SAMPLE, &id_map, "djmdPlaylist").unwrap();
+        assert_eq!(
+            remapped,
+            SAMPLE
+                .replace("Id=\"123\"", "Id=\"900\"")
+                .replace("ValueLeft=\"42\"", "ValueLeft=\"7\"")
+        );
+    }
+
+    /// 評価に使う列だけを持つ DB。トラック 1 と 2 は値がすべて異なり、3 は削除済み
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE djmdContent (ID TEXT, Title TEXT, Subtitle TEXT, FileNameL TEXT,
+                 Commnt TEXT, BPM INTEGER, Rating INTEGER, ReleaseYear INTEGER, Length INTEGER,
+                 DJPlayCount INTEGER, ColorID TEXT, StockDate TEXT, DateCreated TEXT,
+                 ReleaseDate TEXT, ArtistID TEXT, OrgArtistID TEXT, RemixerID TEXT,
+                 ComposerID TEXT, AlbumID TEXT, GenreID TEXT, KeyID TEXT, LabelID TEXT,
+                 rb_local_deleted INTEGER);
+             CREATE TABLE djmdArtist (ID TEXT, Name TEXT);
+             CREATE TABLE djmdAlbum (ID TEXT, Name TEXT, AlbumArtistID TEXT);
+             CREATE TABLE djmdGenre (ID TEXT, Name TEXT);
+             CREATE TABLE djmdKey (ID TEXT, ScaleName TEXT);
+             CREATE TABLE djmdLabel (ID TEXT, Name TEXT);
+             CREATE TABLE djmdSongMyTag (ContentID TEXT, MyTagID TEXT, rb_local_deleted INTEGER);
+             INSERT INTO djmdArtist VALUES
+                 ('1', 'Alpha Artist One'), ('2', 'Alpha Original One'),
+                 ('3', 'Alpha Remixer One'), ('4', 'Alpha Composer One'),
+                 ('5', 'Alpha AlbumArtist One'),
+                 ('11', 'Omega Artist'), ('12', 'Omega Original'), ('13', 'Omega Remixer'),
+                 ('14', 'Omega Composer'), ('15', 'Omega AlbumArtist');
+             INSERT INTO djmdAlbum VALUES ('1', 'Alpha Album One', '5'), ('11', 'Omega Album', '15');
+             INSERT INTO djmdGenre VALUES ('1', 'Alpha Genre One'), ('11', 'Omega Genre');
+             INSERT INTO djmdKey VALUES ('1', 'Alpha Key One'), ('11', 'Omega Key');
+             INSERT INTO djmdLabel VALUES ('1', 'Alpha Label One'), ('11', 'Omega Label');
+             INSERT INTO djmdContent VALUES
+                 ('1', 'Alpha Title One', 'Alpha Mix One', 'Alpha File One', 'Alpha Comment One',
+                  12800, 3, 2019, 300, 5, '1', '2000-01-01', '2000-01-01', '2000-01-01',
+                  '1', '2', '3', '4', '1', '1', '1', '1', 0),
+                 ('2', 'Omega Title', 'Omega Mix', 'Omega File', 'Omega Comment',
+                  14000, 5, 2023, 420, 9, '2', date('now', '-1 day'), date('now', '-1 day'),
+                  date('now', '-1 day'), '11', '12', '13', '14', '11', '11', '11', '11', 0),
+                 ('3', 'Alpha Title One', 'Alpha Mix One', 'Alpha File One', 'Alpha Comment One',
+                  12800, 3, 2019, 300, 5, '1', '2000-01-01', '2000-01-01', '2000-01-01',
+                  '1', '2', '3', '4', '1', '1', '1', '1', 1);
+             INSERT INTO djmdSongMyTag VALUES ('1', '10', 0), ('3', '10', 0), ('2', '10', 1);",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn matches(conn: &Connection, cond: SmartCondition) -> Vec<String> {
+        let label = format!("{} {}", cond.property, cond.operator);
+        evaluate_smart_list(conn, &node(LogicalOperator::All, vec![cond]))
+            .unwrap_or_else(|e| panic!("{}: {:#}", label, e))
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn evaluates_text_properties() {
+        let conn = test_db();
+        for (property, value) in [
+            ("artist", "Alpha Artist One"),
+            ("originalArtist", "Alpha Original One"),
+            ("remixedBy", "Alpha Remixer One"),
+            ("producer", "Alpha Composer One"),
+            ("composer", "Alpha Composer One"),
+            ("album", "Alpha Album One"),
+            ("albumArtist", "Alpha AlbumArtist One"),
+            ("genre", "Alpha Genre One"),
+            ("key", "Alpha Key One"),
+            ("label", "Alpha Label One"),
+            ("comments", "Alpha Comment One"),
+            ("name", "Alpha Title One"),
+            ("title", "Alpha Title One"),
+            ("mixName", "Alpha Mix One"),
+            ("fileName", "Alpha File One"),
+        ] {
+            let middle = &value[2..value.len() - 2];
+            for (operator, left, expected) in [
+                (OP_EQUAL, value.to_string(), ids(&["1"])),
+                (OP_EQUAL, value.to_uppercase(), ids(&["1"])),
+                (OP_NOT_EQUAL, value.to_string(), ids(&["2"])),
+                (OP_CONTAINS, middle.to_string(), ids(&["1"])),
+                (OP_NOT_CONTAINS, middle.to_string(), ids(&["2"])),
+                (OP_STARTS_WITH, "Alpha".to_string(), ids(&["1"])),
+                (OP_STARTS_WITH, "One".to_string(), ids(&[])),
+                (OP_ENDS_WITH, "One".to_string(), ids(&["1"])),
+                (OP_ENDS_WITH, "Alpha".to_string(), ids(&[])),
+            ] {
+                assert_eq!(
+                    matches(&conn, condition(property, operator, &left, "")),
+                    expected,
+                    "{} {} {}",
+                    property,
+                    operator,
+                    left
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn evaluates_number_properties() {
+        let conn = test_db();
+        for (property, low, high) in [
+            ("bpm", "128", "140"),
+            ("rating", "3", "5"),
+            ("year", "2019", "2023"),
+            ("duration", "300", "420"),
+            ("counter", "5", "9"),
+            ("color", "1", "2"),
+        ] {
+            for (operator, left, right, expected) in [
+                (OP_EQUAL, low, "", ids(&["1"])),
+                (OP_NOT_EQUAL, low, "", ids(&["2"])),
+                (OP_GREATER, low, "", ids(&["2"])),
+                (OP_LESS, high, "", ids(&["1"])),
+                (OP_IN_RANGE, low, low, ids(&["1"])),
+                (OP_IN_RANGE, low, high, ids(&["1", "2"])),
+            ] {
+                assert_eq!(
+                    matches(&conn, condition(property, operator, left, right)),
+                    expected,
+                    "{} {} {}..{}",
+                    property,
+                    operator,
+                    left,
+                    right
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn evaluates_date_properties() {
+        let conn = test_db();
+        for property in ["stockDate", "dateCreated", "dateReleased"] {
+            for (operator, unit, left, right, expected) in [
+                (OP_EQUAL, "", "2000-01-01", "", ids(&["1"])),
+                (OP_NOT_EQUAL, "", "2000-01-01", "", ids(&["2"])),
+                (OP_GREATER, "", "2000-01-01", "", ids(&["2"])),
+                (OP_LESS, "", "2001-01-01", "", ids(&["1"])),
+                (OP_IN_RANGE, "", "1999-12-31", "2000-01-02", ids(&["1"])),
+                (OP_IN_LAST, "day", "3", "", ids(&["2"])),
+                (OP_IN_LAST, "week", "1", "", ids(&["2"])),
+                (OP_IN_LAST, "month", "1", "", ids(&["2"])),
+                (OP_IN_LAST, "year", "1", "", ids(&["2"])),
+                (OP_NOT_IN_LAST, "day", "3", "", ids(&["1"])),
+                (OP_NOT_IN_LAST, "year", "1", "", ids(&["1"])),
+            ] {
+                let cond = SmartCondition {
+                    value_unit: unit.to_string(),
+                    ..condition(property, operator, left, right)
+                };
+                assert_eq!(
+                    matches(&conn, cond),
+                    expected,
+                    "{} {} {} {}",
+                    property,
+                    operator,
+                    unit,
+                    left
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn evaluates_my_tag() {
+        let conn = test_db();
+        assert_eq!(
+            matches(&conn, condition("myTag", OP_EQUAL, "10", "")),
+            ids(&["1"])
+        );
+        assert_eq!(
+            matches(&conn, condition("myTag", OP_NOT_EQUAL, "10", "")),
+            ids(&["2"])
+        );
+    }
+
+    #[test]
+    fn combines_conditions_and_nodes() {
+        let conn = test_db();
+        let alpha = condition("title", OP_STARTS_WITH, "Alpha", "");
+        let omega = condition("title", OP_STARTS_WITH, "Omega", "");
+        let any = node(LogicalOperator::Any, vec![alpha.clone(), omega.clone()]);
+        assert_eq!(evaluate_smart_list(&conn, &any).unwrap(), ids(&["1", "2"]));
+        let all = node(LogicalOperator::All, vec![alpha.clone(), omega]);
+        assert_eq!(evaluate_smart_list(&conn, &all).unwrap(), ids(&[]));
+
+        let nested = SmartNode {
+            children: vec![any],
+            ..node(
+                LogicalOperator::All,
+                vec![condition("bpm", OP_GREATER, "130", "")],
+            )
+        };
+        assert_eq!(evaluate_smart_list(&conn, &nested).unwrap(), ids(&["2"]));
+        let empty = node(LogicalOperator::All, Vec::new());
+        assert_eq!(
+            evaluate_smart_list(&conn, &empty).unwrap(),
+            ids(&["1", "2"])
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_conditions() {
+        let conn = test_db();
+        for cond in [
+            condition("unknownProperty", OP_EQUAL, "x", ""),
+            condition("title", OP_GREATER, "x", ""),
+            condition("title", OP_IN_LAST, "1", ""),
+            condition("bpm", OP_CONTAINS, "128", ""),
+            condition("bpm", OP_IN_LAST, "1", ""),
+            condition("bpm", OP_EQUAL, "fast", ""),
+            condition("stockDate", OP_STARTS_WITH, "2000", ""),
+            condition("stockDate", OP_IN_LAST, "soon", ""),
+            condition("myTag", OP_CONTAINS, "10", ""),
+        ] {
+            let label = format!("{} {}", cond.property, cond.operator);
+            let smart = node(LogicalOperator::All, vec![cond]);
+            assert!(evaluate_smart_list(&conn, &smart).is_err(), "{}", label);
+        }
+    }
+}
@@ -0,0 +1,1065 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use base64::Engine as _;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use id3::TagLike as _;
+use id3::frame::{Comment, EncapsulatedObject, Popularimeter};
+
+use super::flac::{FILE_TYPE_FLAC, MAX_METADATA_BLOCK_LEN, write_metadata_header};
+
+/// パックする音声ファイルのコピーに書き込むタグ (djmdContent と関連テーブルから作る)
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrackTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub key: Option<String>,
+    pub bpm: Option<f64>,
+    pub comment: Option<String>,
+    /// 星の数 (1-5)
+    pub rating: Option<u8>,
+    pub label: Option<String>,
+    pub isrc: Option<String>,
+    /// Serato Markers2 として書くホットキュー・ホットループ
+    pub cues: Vec<HotCue>,
+}
+
+/// ホットキュー (end_msec があればループ)
+#[derive(Clone, Debug)]
+pub(crate) struct HotCue {
+    /// 0 始まりのパッド番号 (A=0)
+    pub index: u8,
+    pub start_msec: u32,
+    pub end_msec: Option<u32>,
+    pub name: String,
+}
+
+/// Serato DJ のホットキューの既定の色 (パッド番号順)
+const SERATO_CUE_COLORS: [[u8; 3]; 8] = [
+    [0xCC, 0x00, 0x00],
+    [0xCC, 0x88, 0x00],
+    [0x00, 0x00, 0xCC],
+    [0xCC, 0xCC, 0x00],
+    [0x00, 0xCC, 0x00],
+    [0xCC, 0x00, 0xCC],
+    [0x00, 0xCC, 0xCC],
+    [0x88, 0x00, 0xCC],
+];
+
+/// Serato のループの色 (固定)
+const SERATO_LOOP_COLOR: [u8; 4] = [0x00, 0x27, 0xAA, 0xE1];
+
+/// Serato が GEOB を読むときの最小サイズ (足りない分は 0 で埋める)
+const SERATO_MARKERS2_MIN_LEN: usize = 470;
+
+/// Vorbis コメント・MP4 の freeform に入れる Serato Markers2 の前置き (GEOB のヘッダー相当)
+const SERATO_MARKERS2_PREFIX: &[u8] = b"application/octet-stream\0\0Serato Markers2\0";
+
+/// Windows Media Player の POPM の値 (星1-5)
+const POPM_RATINGS: [u8; 5] = [1, 64, 128, 196, 255];
+const POPM_USER: &str = "Windows Media Player 9 Series";
+
+/// rekordbox の FileType
+const FILE_TYPE_MP3: i64 = 1;
+const FILE_TYPE_M4A: i64 = 4;
+const FILE_TYPE_WAV: i64 = 11;
+const FILE_TYPE_AIFF: i64 = 12;
+
+#[derive(Clone, Copy)]
+enum TagFormat {
+    /// MP3・WAV・AIFF
+    Id3,
+    Flac,
+    Mp4,
+}
+
+fn tag_format(path: &Path, file_type: Option<i64>) -> Option<TagFormat> {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("mp3" | "wav" | "aif" | "aiff" | "aifc") => Some(TagFormat::Id3),
+        Some("flac") => Some(TagFormat::Flac),
+        Some("m4a" | "mp4" | "aac" | "alac") => Some(TagFormat::Mp4),
+        _ => match file_type {
+            Some(FILE_TYPE_MP3 | FILE_TYPE_WAV | FILE_TYPE_AIFF) => Some(TagFormat::Id3),
+            Some(FILE_TYPE_M4A) => Some(TagFormat::Mp4),
+            Some(FILE_TYPE_FLAC) => Some(TagFormat::Flac),
+            _ => None,
+        },
+    }
+}
+
+/// source のコピーを dest に作り、タグを書き込む (source は変更しない)。
+/// MP3・WAV・AIFF は ID3v2、FLAC は Vorbis コメント、M4A は iTunes 形式のタグに書く
+pub(crate) fn write_tagged_copy(
+    source: &Path,
+    dest: &Path,
+    file_type: Option<i64>,
+    tags: &TrackTags,
+) -> Result<()> {
+    let format = tag_format(source, file_type).context("タグを書き込めない形式です")?;
+    match format {
+        TagFormat::Id3 => {
+            fs::copy(source, dest)
+                .with_context(|| format!("ファイルのコピーに失敗: {}", dest.display()))?;
+            write_id3(dest, tags)
+        }
+        TagFormat::Flac => write_flac(source, dest, tags),
+        TagFormat::Mp4 => write_mp4(source, dest, tags),
+    }
+}
+
+// --- Serato Markers2 ---
+
+/// Serato Markers2 のエントリ列 (base64 にする前)
+fn serato_markers2_entries(cues: &[HotCue]) -> Vec<u8> {
+    let mut out = vec![0x01, 0x01];
+    for cue in cues {
+        let mut body = vec![0x00, cue.index];
+        body.extend_from_slice(&cue.start_msec.to_be_bytes());
+        let name = match cue.end_msec {
+            Some(end_msec) => {
+                body.extend_from_slice(&end_msec.to_be_bytes());
+                body.extend_from_slice(&[0xFF; 4]);
+                body.extend_from_slice(&SERATO_LOOP_COLOR);
+                // 不明な1バイトとロックしない
+                body.extend_from_slice(&[0x00, 0x00]);
+                "LOOP"
+            }
+            None => {
+                body.push(0x00);
+                body.extend_from_slice(&SERATO_CUE_COLORS[cue.index as usize % 8]);
+                body.extend_from_slice(&[0x00, 0x00]);
+                "CUE"
+            }
+        };
+        body.extend_from_slice(cue.name.as_bytes());
+        body.push(0x00);
+
+        out.extend_from_slice(name.as_bytes());
+        out.push(0x00);
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(&body);
+    }
+    out.push(0x00);
+    out
+}
+
+/// GEOB "Serato Markers2" の中身。エントリ列を 72 文字ごとに改行した base64 にする
+fn serato_markers2(cues: &[HotCue]) -> Vec<u8> {
+    let encoded = STANDARD_NO_PAD.encode(serato_markers2_entries(cues));
+    let mut out = vec![0x01, 0x01];
+    for (i, line) in encoded.as_bytes().chunks(72).enumerate() {
+        if i > 0 {
+            out.push(b'\n');
+        }
+        out.extend_from_slice(line);
+    }
+    if out.len() < SERATO_MARKERS2_MIN_LEN {
+        out.resize(SERATO_MARKERS2_MIN_LEN, 0);
+    }
+    out
+}
+
+/// Vorbis コメント・MP4 用の Serato Markers2 (GEOB のヘッダーごと base64 にする)
+fn serato_markers2_base64(cues: &[HotCue]) -> String {
+    let mut data = SERATO_MARKERS2_PREFIX.to_vec();
+    data.extend_from_slice(&serato_markers2(cues));
+    STANDARD.encode(data)
+}
+
+fn format_bpm(bpm: f64) -> String {
+    format!("{}", (bpm * 100.0).round() / 100.0)
+}
+
+// --- ID3v2 (MP3・WAV・AIFF) ---
+
+/// ID3v2 タグを書き込む (WAV・AIFF はチャンクとして書かれる)
+fn write_id3(path: &Path, tags: &TrackTags) -> Result<()> {
+    // 一部が壊れているタグは読めた部分を残す
+    let existing = id3::Tag::read_from_path(path);
+    let mut tag = id3::no_tag_ok(id3::partial_tag_ok(existing))
+        .context("ID3 タグを読めません")?
+        .unwrap_or_default();
+
+    let texts = [
+        ("TIT2", &tags.title),
+        ("TPE1", &tags.artist),
+        ("TALB", &tags.album),
+        ("TCON", &tags.genre),
+        ("TKEY", &tags.key),
+        ("TPUB", &tags.label),
+        ("TSRC", &tags.isrc),
+    ];
+    for (id, value) in texts {
+        if let Some(value) = value {
+            tag.set_text(id, value.as_str());
+        }
+    }
+    if let Some(bpm) = tags.bpm {
+        tag.set_text("TBPM", format_bpm(bpm));
+    }
+    if let Some(comment) = &tags.comment {
+        tag.add_frame(Comment {
+            lang: "eng".to_string(),
+            description: String::new(),
+            text: comment.clone(),
+        });
+    }
+    if let Some(rating) = tags.rating {
+        tag.add_frame(Popularimeter {
+            user: POPM_USER.to_string(),
+            rating: POPM_RATINGS[(rating.clamp(1, 5) - 1) as usize],
+            counter: 0,
+        });
+    }
+    if !tags.cues.is_empty() {
+        tag.add_frame(EncapsulatedObject {
+            mime_type: "application/octet-stream".to_string(),
+            filename: String::new(),
+            description: "Serato Markers2".to_string(),
+            data: serato_markers2(&tags.cues),
+        });
+    }
+
+    tag.write_to_path(path, id3::Version::Id3v24)
+        .context("ID3 タグを書き込めません")
+}
+
+// --- Vorbis コメント (FLAC) ---
+
+/// METADATA_BLOCK の種類
+const FLAC_VORBIS_COMMENT: u8 = 4;
+
+fn vorbis_comments(tags: &TrackTags) -> Vec<(&'static str, String)> {
+    let mut comments = Vec::new();
+    let texts = [
+        ("TITLE", &tags.title),
+        ("ARTIST", &tags.artist),
+        ("ALBUM", &tags.album),
+        ("GENRE", &tags.genre),
+        ("INITIALKEY", &tags.key),
+        ("COMMENT", &tags.comment),
+        ("LABEL", &tags.label),
+        ("ISRC", &tags.isrc),
+    ];
+    for (key, value) in texts {
+        if let Some(value) = value {
+            comments.push((key, value.clone()));
+        }
+    }
+    if let Some(bpm) = tags.bpm {
+        comments.push(("BPM", format_bpm(bpm)));
+    }
+    // 0-100 (foobar2000 等と同じ)
+    if let Some(rating) = tags.rating {
+        comments.push(("RATING", (rating.clamp(1, 5) as u32 * 20).to_string()));
+    }
+    if !tags.cues.is_empty() {
+        comments.push(("SERATO_MARKERS_V2", serato_markers2_base64(&tags.cues)));
+    }
+    comments
+}
+
+/// 既存の VORBIS_COMMENT ブロックの中身から、書き込むキーと同じコメントを除いたもの
+fn parse_vorbis_comment(body: &[u8]) -> Option<(Vec<u8>, Vec<Vec<u8>>)> {
+    let read_u32 = |pos: usize| -> Option<usize> {
+        let bytes = body.get(pos..pos + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+    };
+    let vendor_len = read_u32(0)?;
+    let vendor = body.get(4..4 + vendor_len)?.to_vec();
+    let count = read_u32(4 + vendor_len)?;
+    let mut pos = 8 + vendor_len;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = read_u32(pos)?;
+        comments.push(body.get(pos + 4..pos + 4 + len)?.to_vec());
+        pos += 4 + len;
+    }
+    Some((vendor, comments))
+}
+
+fn write_flac(source: &Path, dest: &Path, tags: &TrackTags) -> Result<()> {
+    let mut input = BufReader::new(
+        fs::File::open(source)
+            .with_context(|| format!("ファイルを開けません: {}", source.display()))?,
+    );
+    let mut marker = [0u8; 4];
+    input.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        anyhow::bail!("FLAC ではありません");
+    }
+    let mut blocks: Vec<(u8, Vec<u8>)> = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        input
+            .read_exact(&mut header)
+            .context("FLAC のメタデータが途中で切れています")?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut body = vec![0u8; len];
+        input
+            .read_exact(&mut body)
+            .context("FLAC のメタデータが途中で切れています")?;
+        blocks.push((header[0] & 0x7F, body));
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+
+    let new_comments = vorbis_comments(tags);
+    let existing = blocks
+        .iter()
+        .position(|(block_type, _)| *block_type == FLAC_VORBIS_COMMENT);
+    let (vendor, mut comments) = match existing {
+        Some(idx) => {
+            parse_vorbis_comment(&blocks[idx].1).context("FLAC の Vorbis コメントが壊れています")?
+        }
+        None => (b"rkpack".to_vec(), Vec::new()),
+    };
+    comments.retain(|comment| {
+        let key = comment.split(|&b| b == b'=').next().unwrap_or_default();
+        !new_comments
+            .iter()
+            .any(|(new_key, _)| new_key.as_bytes().eq_ignore_ascii_case(key))
+    });
+    comments.extend(
+        new_comments
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value).into_bytes()),
+    );
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    body.extend_from_slice(&vendor);
+    body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in &comments {
+        body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        body.extend_from_slice(comment);
+    }
+    if body.len() > MAX_METADATA_BLOCK_LEN {
+        anyhow::bail!("Vorbis コメントが大きすぎます");
+    }
+    match existing {
+        Some(idx) => blocks[idx].1 = body,
+        // STREAMINFO の直後に入れる
+        None => blocks.insert(1.min(blocks.len()), (FLAC_VORBIS_COMMENT, body)),
+    }
+
+    let mut out = BufWriter::new(
+        fs::File::create(dest)
+            .with_context(|| format!("ファイルの作成に失敗: {}", dest.display()))?,
+    );
+    out.write_all(b"fLaC")?;
+    for (i, (block_type, body)) in blocks.iter().enumerate() {
+        write_metadata_header(&mut out, *block_type, i + 1 == blocks.len(), body.len())?;
+        out.write_all(body)?;
+    }
+    io::copy(&mut input, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+// --- MP4 (M4A) ---
+
+/// MP4 のボックスの位置 (start・end はヘッダーを含む範囲)
+#[derive(Clone, Copy)]
+struct Mp4Box {
+    kind: [u8; 4],
+    start: usize,
+    body_start: usize,
+    end: usize,
+}
+
+/// data に並ぶボックスを読む
+fn mp4_boxes(data: &[u8]) -> Result<Vec<Mp4Box>> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + 8)
+            .context("MP4 のボックスが壊れています")?;
+        let kind: [u8; 4] = header[4..8].try_into()?;
+        let (size, header_len) = match u32::from_be_bytes(header[0..4].try_into()?) {
+            0 => (data.len() - pos, 8),
+            1 => {
+                let large = data
+                    .get(pos + 8..pos + 16)
+                    .context("MP4 のボックスが壊れています")?;
+                (u64::from_be_bytes(large.try_into()?) as usize, 16)
+            }
+            size => (size as usize, 8),
+        };
+        if size < header_len || pos + size > data.len() {
+            anyhow::bail!("MP4 のボックスが壊れています");
+        }
+        boxes.push(Mp4Box {
+            kind,
+            start: pos,
+            body_start: pos + header_len,
+            end: pos + size,
+        });
+        pos += size;
+    }
+    Ok(boxes)
+}
+
+/// ファイルの最上位のボックス (中身は読まない)
+fn mp4_top_level_boxes<R: Read + Seek>(r: &mut R) -> Result<Vec<(Mp4Box, u64)>> {
+    let file_len = r.seek(SeekFrom::End(0))?;
+    let mut boxes = Vec::new();
+    let mut pos = 0u64;
+    while pos < file_len {
+        r.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 16];
+        r.read_exact(&mut header[..8])
+            .context("MP4 のボックスが壊れています")?;
+        let kind: [u8; 4] = header[4..8].try_into()?;
+        let (size, header_len) = match u32::from_be_bytes(header[0..4].try_into()?) {
+            0 => (file_len - pos, 8),
+            1 => {
+                r.read_exact(&mut header[8..16])
+                    .context("MP4 のボックスが壊れています")?;
+                (u64::from_be_bytes(header[8..16].try_into()?), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_len || pos + size > file_len {
+            anyhow::bail!("MP4 のボックスが壊れています");
+        }
+        let mp4_box = Mp4Box {
+            kind,
+            start: 0,
+            body_start: header_len as usize,
+            end: size as usize,
+        };
+        boxes.push((mp4_box, pos));
+        pos += size;
+    }
+    Ok(boxes)
+}
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out
+}
+
+/// ilst の data ボックス (type 1=UTF-8, 21=整数)
+fn mp4_data(data_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut body = data_type.to_be_bytes().to_vec();
+    body.extend_from_slice(&[0; 4]);
+    body.extend_from_slice(payload);
+    mp4_box(b"data", &body)
+}
+
+/// ---- (freeform) の mean・name
+fn mp4_full_box_text(kind: &[u8; 4], text: &str) -> Vec<u8> {
+    let mut body = vec![0; 4];
+    body.extend_from_slice(text.as_bytes());
+    mp4_box(kind, &body)
+}
+
+/// ilst に入れる項目。freeform は (mean, name) で識別する
+enum Mp4Item {
+    Standard([u8; 4], Vec<u8>),
+    Freeform(&'static str, &'static str, String),
+}
+
+impl Mp4Item {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Standard(kind, data) => mp4_box(kind, data),
+            Self::Freeform(mean, name, value) => {
+                let mut body = mp4_full_box_text(b"mean", mean);
+                body.extend(mp4_full_box_text(b"name", name));
+                body.extend(mp4_data(1, value.as_bytes()));
+                mp4_box(b"----", &body)
+            }
+        }
+    }
+}
+
+fn mp4_items(tags: &TrackTags) -> Vec<Mp4Item> {
+    let mut items = Vec::new();
+    let texts = [
+        (b"\xA9nam", &tags.title),
+        (b"\xA9ART", &tags.artist),
+        (b"\xA9alb", &tags.album),
+        (b"\xA9gen", &tags.genre),
+        (b"\xA9cmt", &tags.comment),
+    ];
+    for (kind, value) in texts {
+        if let Some(value) = value {
+            items.push(Mp4Item::Standard(*kind, mp4_data(1, value.as_bytes())));
+        }
+    }
+    if let Some(bpm) = tags.bpm {
+        let tempo = bpm.round().clamp(0.0, u16::MAX as f64) as u16;
+        items.push(Mp4Item::Standard(
+            *b"tmpo",
+            mp4_data(21, &tempo.to_be_bytes()),
+        ));
+    }
+    let freeform = [
+        ("initialkey", &tags.key),
+        ("LABEL", &tags.label),
+        ("ISRC", &tags.isrc),
+    ];
+    for (name, value) in freeform {
+        if let Some(value) = value {
+            items.push(Mp4Item::Freeform("com.apple.iTunes", name, value.clone()));
+        }
+    }
+    if let Some(rating) = tags.rating {
+        let value = (rating.clamp(1, 5) as u32 * 20).to_string();
+        items.push(Mp4Item::Freeform("com.apple.iTunes", "RATING", value));
+    }
+    if !tags.cues.is_empty() {
+        let value = serato_markers2_base64(&tags.cues);
+        items.push(Mp4Item::Freeform("com.serato.dj", "markersv2", value));
+    }
+    items
+}
+
+/// freeform の項目の name
+fn mp4_freeform_name(item: &[u8]) -> Option<String> {
+    let boxes = mp4_boxes(item).ok()?;
+    let name = boxes.iter().find(|b| &b.kind == b"name")?;
+    let text = item.get(name.body_start + 4..name.end)?;
+    Some(String::from_utf8_lossy(text).to_string())
+}
+
+fn rebuild_ilst(ilst: Option<&[u8]>, items: &[Mp4Item]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    if let Some(ilst) = ilst {
+        for item in mp4_boxes(ilst)? {
+            let replaced = items.iter().any(|new| match new {
+                Mp4Item::Standard(kind, _) => &item.kind == kind,
+                Mp4Item::Freeform(_, name, _) => {
+                    &item.kind == b"----"
+                        && mp4_freeform_name(&ilst[item.body_start..item.end])
+                            .is_some_and(|n| n.eq_ignore_ascii_case(name))
+                }
+            });
+            if !replaced {
+                body.extend_from_slice(&ilst[item.start..item.end]);
+            }
+        }
+    }
+    for item in items {
+        body.extend(item.encode());
+    }
+    Ok(mp4_box(b"ilst", &body))
+}
+
+/// iTunes 形式のメタデータの hdlr
+fn mp4_metadata_handler() -> Vec<u8> {
+    let mut body = vec![0; 8];
+    body.extend_from_slice(b"mdirappl");
+    body.extend_from_slice(&[0; 9]);
+    mp4_box(b"hdlr", &body)
+}
+
+fn rebuild_meta(meta: Option<&[u8]>, items: &[Mp4Item]) -> Result<Vec<u8>> {
+    let mut body = vec![0; 4];
+    let mut has_handler = false;
+    let mut ilst = None;
+    if let Some(meta) = meta {
+        // QuickTime 形式の meta には version・flags がない
+        let children = if meta.get(4..8) == Some(b"hdlr") {
+            meta
+        } else {
+            meta.get(4..).context("MP4 の meta が壊れています")?
+        };
+        for child in mp4_boxes(children)? {
+            match &child.kind {
+                b"ilst" => ilst = Some(&children[child.body_start..child.end]),
+                kind => {
+                    has_handler |= kind == b"hdlr";
+                    body.extend_from_slice(&children[child.start..child.end]);
+                }
+            }
+        }
+    }
+    if !has_handler {
+        body.splice(4..4, mp4_metadata_handler());
+    }
+    body.extend(rebuild_ilst(ilst, items)?);
+    Ok(mp4_box(b"meta", &body))
+}
+
+fn rebuild_udta(udta: Option<&[u8]>, items: &[Mp4Item]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut meta = None;
+    if let Some(udta) = udta {
+        for child in mp4_boxes(udta)? {
+            if &child.kind == b"meta" && meta.is_none() {
+                meta = Some(&udta[child.body_start..child.end]);
+            } else {
+                body.extend_from_slice(&udta[child.start..child.end]);
+            }
+        }
+    }
+    body.extend(rebuild_meta(meta, items)?);
+    Ok(mp4_box(b"udta", &body))
+}
+
+fn rebuild_moov(moov_body: &[u8], items: &[Mp4Item]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut udta = None;
+    for child in mp4_boxes(moov_body)? {
+        if &child.kind == b"udta" && udta.is_none() {
+            udta = Some(&moov_body[child.body_start..child.end]);
+        } else {
+            body.extend_from_slice(&moov_body[child.start..child.end]);
+        }
+    }
+    body.extend(rebuild_udta(udta, items)?);
+    if body.len() + 8 > u32::MAX as usize {
+        anyhow::bail!("MP4 の moov が大きすぎます");
+    }
+    Ok(mp4_box(b"moov", &body))
+}
+
+/// moov より後ろを指すチャンクの位置 (stco・co64) を delta だけずらす
+fn shift_chunk_offsets(data: &mut [u8], threshold: u64, delta: i64) -> Result<()> {
+    for child in mp4_boxes(data)? {
+        let body = &mut data[child.body_start..child.end];
+        match &child.kind {
+            b"moov" | b"trak" | b"mdia" | b"minf" | b"stbl" => {
+                shift_chunk_offsets(body, threshold, delta)?
+            }
+            b"stco" | b"co64" => {
+                let width = if &child.kind == b"stco" { 4 } else { 8 };
+                let count = body
+                    .get(4..8)
+                    .map(|b| u32::from_be_bytes(b.try_into().unwrap_or_default()) as usize)
+                    .context("MP4 の stco が壊れています")?;
+                let entries = body
+                    .get_mut(8..8 + count * width)
+                    .context("MP4 の stco が壊れています")?;
+                for entry in entries.chunks_exact_mut(width) {
+                    let offset = if width == 4 {
+                        u32::from_be_bytes(entry.try_into()?) as u64
+                    } else {
+                        u64::from_be_bytes(entry.try_into()?)
+                    };
+                    if offset < threshold {
+                        continue;
+                    }
+                    let shifted = offset
+                        .checked_add_signed(delta)
+                        .context("MP4 のチャンク位置が不正です")?;
+                    if width == 4 {
+                        let shifted = u32::try_from(shifted)
+                            .context("MP4 のチャンク位置が 4GB を超えます")?;
+                        entry.copy_from_slice(&shifted.to_be_bytes());
+                    } else {
+                        entry.copy_from_slice(&shifted.to_be_bytes());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn write_mp4(source: &Path, dest: &Path, tags: &TrackTags) -> Result<()> {
+    let mut input = BufReader::new(
+        fs::File::open(source)
+            .with_context(|| format!("ファイルを開けません: {}", source.display()))?,
+    );
+    let top_level = mp4_top_level_boxes(&mut input)?;
+    if top_level.iter().any(|(b, _)| &b.kind == b"moof") {
+        anyhow::bail!("断片化された MP4 には未対応です");
+    }
+    let &(moov, moov_pos) = top_level
+        .iter()
+        .find(|(b, _)| &b.kind == b"moov")
+        .context("MP4 に moov がありません")?;
+
+    let mut moov_body = vec![0u8; moov.end - moov.body_start];
+    input.seek(SeekFrom::Start(moov_pos + moov.body_start as u64))?;
+    input.read_exact(&mut moov_body)?;
+    let mut new_moov = rebuild_moov(&moov_body, &mp4_items(tags))?;
+    let moov_end = moov_pos + moov.end as u64;
+    let delta = new_moov.len() as i64 - moov.end as i64;
+    if delta != 0 {
+        shift_chunk_offsets(&mut new_moov, moov_end, delta)?;
+    }
+
+    let mut out = BufWriter::new(
+        fs::File::create(dest)
+            .with_context(|| format!("ファイルの作成に失敗: {}", dest.display()))?,
+    );
+    input.seek(SeekFrom::Start(0))?;
+    io::copy(&mut (&mut input).take(moov_pos), &mut out)?;
+    out.write_all(&new_moov)?;
+    input.seek(SeekFrom::Start(moov_end))?;
+    io::copy(&mut input, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(index: u8, start_msec: u32, end_msec: Option<u32>, name: &str) -> HotCue {
+        HotCue {
+            index,
+            start_msec,
+            end_msec,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn serato_markers2_entries_layout() {
+        let cues = [cue(1, 1234, None, "Drop"), cue(0, 1000, Some(5000), "")];
+        let mut expected = vec![0x01, 0x01];
+        expected.extend_from_slice(b"CUE\0");
+        expected.extend_from_slice(&17u32.to_be_bytes());
+        expected.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x04, 0xD2, 0x00]);
+        expected.extend_from_slice(&[0xCC, 0x88, 0x00, 0x00, 0x00]);
+        expected.extend_from_slice(b"Drop\0");
+        expected.extend_from_slice(b"LOOP\0");
+        expected.extend_from_slice(&21u32.to_be_bytes());
+        expected.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x03, 0xE8]);
+        expected.extend_from_slice(&[0x00, 0x00, 0x13, 0x88, 0xFF, 0xFF, 0xFF, 0xFF]);
+        expected.extend_from_slice(&[0x00, 0x27, 0xAA, 0xE1, 0x00, 0x00, 0x00]);
+        expected.push(0x00);
+        assert_eq!(serato_markers2_entries(&cues), expected);
+        assert_eq!(serato_markers2_entries(&[]), vec![0x01, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn serato_markers2_wraps_and_pads_base64() {
+        let cues: Vec<HotCue> = (0..8)
+            .map(|i| cue(i, i as u32 * 1000, None, "cue name"))
+            .collect();
+        let markers = serato_markers2(&cues);
+        assert_eq!(&markers[..2], &[0x01, 0x01]);
+        assert!(markers.len() >= SERATO_MARKERS2_MIN_LEN);
+        let text: Vec<u8> = markers[2..]
+            .iter()
+            .copied()
+            .take_while(|&b| b != 0)
+            .collect();
+        assert!(markers[2 + text.len()..].iter().all(|&b| b == 0));
+        let lines: Vec<&[u8]> = text.split(|&b| b == b'\n').collect();
+        assert!(lines.len() > 1);
+        assert!(lines[..lines.len() - 1].iter().all(|line| line.len() == 72));
+        assert_eq!(
+            STANDARD_NO_PAD.decode(lines.concat()).unwrap(),
+            serato_markers2_entries(&cues)
+        );
+
+        let data = STANDARD.decode(serato_markers2_base64(&cues)).unwrap();
+        assert_eq!(
+            &data[..SERATO_MARKERS2_PREFIX.len()],
+            SERATO_MARKERS2_PREFIX
+        );
+        assert_eq!(&data[SERATO_MARKERS2_PREFIX.len()..], markers);
+    }
+
+    // --- FLAC ---
+
+    const FLAC_PADDING: u8 = 1;
+
+    fn flac_file(blocks: &[(u8, Vec<u8>)], frames: &[u8]) -> Vec<u8> {
+        let mut out = b"fLaC".to_vec();
+        for (i, (block_type, body)) in blocks.iter().enumerate() {
+            write_metadata_header(&mut out, *block_type, i + 1 == blocks.len(), body.len())
+                .unwrap();
+            out.extend_from_slice(body);
+        }
+        out.extend_from_slice(frames);
+        out
+    }
+
+    /// メタデータブロック (種類・最後か・中身)
+    type FlacBlock = (u8, bool, Vec<u8>);
+
+    /// メタデータブロックと音声フレーム
+    fn flac_blocks(data: &[u8]) -> (Vec<FlacBlock>, Vec<u8>) {
+        assert_eq!(&data[..4], b"fLaC");
+        let mut blocks = Vec::new();
+        let mut pos = 4;
+        loop {
+            let header = &data[pos..pos + 4];
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            let last = header[0] & 0x80 != 0;
+            blocks.push((
+                header[0] & 0x7F,
+                last,
+                data[pos + 4..pos + 4 + len].to_vec(),
+            ));
+            pos += 4 + len;
+            if last {
+                return (blocks, data[pos..].to_vec());
+            }
+        }
+    }
+
+    fn vorbis_comment_block(vendor: &[u8], comments: &[&str]) -> Vec<u8> {
+        let mut body = (vendor.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(vendor);
+        body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            body.extend_from_slice(comment.as_bytes());
+        }
+        body
+    }
+
+    fn write_flac_bytes(source_bytes: &[u8], tags: &TrackTags) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.flac");
+        let dest = dir.path().join("dest.flac");
+        fs::write(&source, source_bytes).unwrap();
+        write_flac(&source, &dest, tags).unwrap();
+        fs::read(&dest).unwrap()
+    }
+
+    #[test]
+    fn write_flac_replaces_matching_vorbis_comments() {
+        let stream_info = (0..34).collect::<Vec<u8>>();
+        let frames = b"\xFF\xF8frame data".to_vec();
+        let source = flac_file(
+            &[
+                (0, stream_info.clone()),
+                (
+                    FLAC_VORBIS_COMMENT,
+                    vorbis_comment_block(b"encoder", &["title=Old", "ALBUMARTIST=Keep"]),
+                ),
+                (FLAC_PADDING, vec![0; 16]),
+            ],
+            &frames,
+        );
+        let tags = TrackTags {
+            title: Some("New".to_string()),
+            bpm: Some(128.0),
+            ..Default::default()
+        };
+
+        let (blocks, rest) = flac_blocks(&write_flac_bytes(&source, &tags));
+        assert_eq!(rest, frames);
+        assert_eq!(
+            blocks,
+            vec![
+                (0, false, stream_info),
+                (
+                    FLAC_VORBIS_COMMENT,
+                    false,
+                    vorbis_comment_block(b"encoder", &["ALBUMARTIST=Keep", "TITLE=New", "BPM=128"]),
+                ),
+                (FLAC_PADDING, true, vec![0; 16]),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_flac_inserts_vorbis_comment_after_stream_info() {
+        let stream_info = vec![7; 34];
+        let frames = b"\xFF\xF8frame data".to_vec();
+        let source = flac_file(&[(0, stream_info.clone())], &frames);
+        let tags = TrackTags {
+            artist: Some("Artist".to_string()),
+            rating: Some(4),
+            ..Default::default()
+        };
+
+        let (blocks, rest) = flac_blocks(&write_flac_bytes(&source, &tags));
+        assert_eq!(rest, frames);
+        assert_eq!(
+            blocks,
+            vec![
+                (0, false, stream_info),
+                (
+                    FLAC_VORBIS_COMMENT,
+                    true,
+                    vorbis_comment_block(b"rkpack", &["ARTIST=Artist", "RATING=80"]),
+                ),
+            ]
+        );
+    }
+
+    // --- MP4 ---
+
+    fn chunk_offset_box(kind: &[u8; 4], offsets: &[u64]) -> Vec<u8> {
+        let mut body = vec![0; 4];
+        body.extend_from_slice(&(offsets.len() as u32).to_be_bytes());
+        for &offset in offsets {
+            if kind == b"stco" {
+                body.extend_from_slice(&(offset as u32).to_be_bytes());
+            } else {
+                body.extend_from_slice(&offset.to_be_bytes());
+            }
+        }
+        mp4_box(kind, &body)
+    }
+
+    fn trak(chunk_offsets: Vec<u8>) -> Vec<u8> {
+        let stbl = mp4_box(b"stbl", &chunk_offsets);
+        let minf = mp4_box(b"minf", &stbl);
+        let mdia = mp4_box(b"mdia", &minf);
+        mp4_box(b"trak", &mdia)
+    }
+
+    /// stco・co64 の値を出てくる順に集める
+    fn chunk_offsets(data: &[u8]) -> Vec<u64> {
+        let mut offsets = Vec::new();
+        for child in mp4_boxes(data).unwrap() {
+            let body = &data[child.body_start..child.end];
+            match &child.kind {
+                b"moov" | b"trak" | b"mdia" | b"minf" | b"stbl" => {
+                    offsets.extend(chunk_offsets(body))
+                }
+                b"stco" => offsets.extend(
+                    body[8..]
+                        .chunks_exact(4)
+                        .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as u64),
+                ),
+                b"co64" => offsets.extend(
+                    body[8..]
+                        .chunks_exact(8)
+                        .map(|b| u64::from_be_bytes(b.try_into().unwrap())),
+                ),
+                _ => {}
+            }
+        }
+        offsets
+    }
+
+    /// data の中で最初の kind のボックスの中身
+    fn child_body<'a>(data: &'a [u8], kind: &[u8; 4]) -> &'a [u8] {
+        let child = mp4_boxes(data)
+            .unwrap()
+            .into_iter()
+            .find(|b| &b.kind == kind)
+            .unwrap();
+        &data[child.body_start..child.end]
+    }
+
+    #[test]
+    fn shift_chunk_offsets_moves_only_offsets_after_threshold() {
+        let mut moov = mp4_box(
+            b"moov",
+            &[
+                trak(chunk_offset_box(b"stco", &[100, 200, 300])),
+                trak(chunk_offset_box(b"co64", &[100, 5_000_000_000])),
+            ]
+            .concat(),
+        );
+        shift_chunk_offsets(&mut moov, 200, 50).unwrap();
+        assert_eq!(
+            chunk_offsets(&moov),
+            vec![100, 250, 350, 100, 5_000_000_050]
+        );
+        shift_chunk_offsets(&mut moov, 200, -50).unwrap();
+        assert_eq!(
+            chunk_offsets(&moov),
+            vec![100, 200, 300, 100, 5_000_000_000]
+        );
+
+        // stco に入らない位置はエラー
+        let mut moov = mp4_box(
+            b"moov",
+            &trak(chunk_offset_box(b"stco", &[u32::MAX as u64])),
+        );
+        assert!(shift_chunk_offsets(&mut moov, 0, 1).is_err());
+    }
+
+    /// moov が mdat の前・後ろにある M4A にタグを書き込み、チャンク位置が同じ音声を指すか確かめる
+    fn assert_write_mp4_keeps_chunks(moov_first: bool) {
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0isom");
+        let mdat = mp4_box(b"mdat", b"AAAABBBBCCCC");
+        let existing_meta = {
+            let mut body = vec![0; 4];
+            body.extend(mp4_metadata_handler());
+            let old_title = mp4_box(b"\xA9nam", &mp4_data(1, b"Old"));
+            let year = mp4_box(b"\xA9day", &mp4_data(1, b"2020"));
+            body.extend(mp4_box(b"ilst", &[old_title, year].concat()));
+            mp4_box(b"meta", &body)
+        };
+        let moov = |offsets: &[u64]| {
+            let body = [
+                trak(chunk_offset_box(b"stco", &offsets[..2])),
+                trak(chunk_offset_box(b"co64", &offsets[2..])),
+                mp4_box(b"udta", &existing_meta),
+            ]
+            .concat();
+            mp4_box(b"moov", &body)
+        };
+        let moov_len = moov(&[0, 0, 0]).len() as u64;
+        let mdat_body = ftyp.len() as u64 + 8 + if moov_first { moov_len } else { 0 };
+        let offsets = [mdat_body, mdat_body + 4, mdat_body + 8];
+        let source_bytes = if moov_first {
+            [ftyp, moov(&offsets), mdat].concat()
+        } else {
+            [ftyp, mdat, moov(&offsets)].concat()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.m4a");
+        let dest = dir.path().join("dest.m4a");
+        fs::write(&source, &source_bytes).unwrap();
+        let tags = TrackTags {
+            title: Some("New Title".to_string()),
+            key: Some("8A".to_string()),
+            cues: vec![cue(0, 500, None, "")],
+            ..Default::default()
+        };
+        write_mp4(&source, &dest, &tags).unwrap();
+        let out = fs::read(&dest).unwrap();
+
+        let new_moov = mp4_boxes(&out)
+            .unwrap()
+            .into_iter()
+            .find(|b| &b.kind == b"moov")
+            .unwrap();
+        let new_offsets = chunk_offsets(&out[new_moov.start..new_moov.end]);
+        assert_eq!(new_offsets.len(), 3);
+        for (offset, expected) in new_offsets.iter().zip([b"AAAA", b"BBBB", b"CCCC"]) {
+            let offset = *offset as usize;
+            assert_eq!(&out[offset..offset + 4], expected);
+        }
+        if !moov_first {
+            assert_eq!(new_offsets, offsets);
+        }
+
+        let meta = child_body(
+            child_body(&out[new_moov.start..new_moov.end], b"moov"),
+            b"udta",
+        );
+        let meta = child_body(meta, b"meta");
+        let ilst = child_body(&meta[4..], b"ilst");
+        let items = mp4_boxes(ilst).unwrap();
+        let kinds: Vec<&[u8; 4]> = items.iter().map(|b| &b.kind).collect();
+        assert_eq!(kinds, vec![b"\xA9day", b"\xA9nam", b"----", b"----"]);
+        assert_eq!(
+            &child_body(child_body(ilst, b"\xA9nam"), b"data")[8..],
+            b"New Title"
+        );
+    }
+
+    #[test]
+    fn write_mp4_shifts_offsets_when_moov_is_before_mdat() {
+        assert_write_mp4_keeps_chunks(true);
+    }
+
+    #[test]
+    fn write_mp4_keeps_offsets_when_moov_is_after_mdat() {
+        assert_write_mp4_keeps_chunks(false);
+    }
+}
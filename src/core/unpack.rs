@@ -1,17 +1,26 @@
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io;
+use std::io::{self, Read};
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
+use rayon::prelude::*;
 use rusqlite::{Connection, params};
-use zip::ZipArchive;
 
 use super::db::get_actual_path_on_disk;
+use super::flac;
 use super::id_mapping::{
-    IdMap, apply_mapping, find_existing_master_id, get_max_numeric_id, insert_row,
-    master_table_name_column, remap_json_blob,
+    IdMap, UuidMap, apply_mapping, find_existing_master_id, get_max_numeric_id, insert_row,
+    master_table_name_column, remap_json_blob, row_exists, update_row,
 };
+use super::manifest::{
+    AudioFile, BaseTables, ContentDataFile, PackManifest, Row, STREAMED_TABLES, TableReader, Tables,
+};
+use super::query::collect_ids_from_column;
+use super::sign::{PackSignature, load_trusted_keys, read_pack_signature};
+use super::smart_list::remap_smart_list;
+use super::verify::ensure_pack_valid;
+use super::volume::{RkpArchive, open_rkp};
 
 #[derive(Clone, PartialEq)]
 pub enum DuplicateDecision {
@@ -41,7 +50,13 @@ pub struct UnpackTrackPreview {
 
 pub struct UnpackPreviewData {
     pub rkp_path: String,
-    pub playlist_name: String,
+    pub playlist_names: Vec<String>,
+    /// パックに含まれる再生履歴の名前
+    pub history_names: Vec<String>,
+    /// 音声なしパック (手元の既存トラックにのみ適用できる)
+    pub lite: bool,
+    /// pack.json の署名の確認結果
+    pub signature: PackSignature,
     pub tracks: Vec<UnpackTrackPreview>,
 }
 
@@ -51,6 +66,24 @@ pub struct UnpackDecisions {
     pub existing_content_map: HashMap<String, String>,
 }
 
+/// アンパック時のオプション
+#[derive(Clone, Default)]
+pub struct UnpackOptions {
+    /// プレイリストを作成する親フォルダの ID (省略時はルート)
+    pub parent_id: Option<String>,
+    /// 再生履歴を djmdHistory ではなく通常のプレイリストとして作成する
+    pub histories_as_playlists: bool,
+    /// 差分パックの基準の .rkp。基準をこの DB にインポート済みならその記録を優先する
+    /// (省略時は同じフォルダの基準パックを使う)
+    pub base_path: Option<String>,
+    /// 暗号化パックのパスフレーズ
+    pub passphrase: Option<String>,
+    /// 元の DB の UUID をそのまま使う (既定では挿入する行ごとに新しい UUID を振り、uuidIDMap に登録する)
+    pub keep_uuids: bool,
+    /// FLAC に変換してパックされた音声を元の WAV/AIFF に戻さず、FLAC のまま配置する
+    pub keep_flac: bool,
+}
+
 #[derive(Clone)]
 pub struct DuplicateInfo {
     pub existing_title: String,
@@ -62,13 +95,14 @@ pub struct DuplicateInfo {
 }
 
 pub(crate) fn extract_rkp_entry(
-    archive: &mut ZipArchive<fs::File>,
+    archive: &mut RkpArchive,
     name: &str,
     dest: &std::path::Path,
 ) -> Result<()> {
-    let mut entry = archive
-        .by_name(name)
-        .with_context(|| format!(".rkp 内にエントリが見つかりません: {}", name))?;
+    if !archive.contains(name) {
+        anyhow::bail!(".rkp 内にエントリが見つかりません: {}", name);
+    }
+    let mut entry = archive.by_name(name)?;
     if let Some(parent) = dest.parent() {
         fs::create_dir_all(parent)?;
     }
@@ -78,24 +112,436 @@ pub(crate) fn extract_rkp_entry(
     Ok(())
 }
 
-fn load_pack_data(archive: &mut ZipArchive<fs::File>) -> Result<serde_json::Value> {
-    let entry = archive
-        .by_name("pack.json")
-        .context(".rkp 内に pack.json が見つかりません")?;
-    let pack_data: serde_json::Value =
-        serde_json::from_reader(entry).context("pack.json の解析に失敗")?;
+/// pack.json を読む。古いバージョンのパックも現在の形式で返す。
+/// STREAMED_TABLES 以外のテーブルは tables に読み込み、STREAMED_TABLES は TableReader で1行ずつ読む
+pub(crate) fn load_pack_data(archive: &mut RkpArchive) -> Result<PackManifest> {
+    let mut pack_data = read_pack_json(archive)?;
+    pack_data.load_tables(archive, |table| !STREAMED_TABLES.contains(&table))?;
+    Ok(pack_data)
+}
 
-    let version = pack_data["version"].as_i64().unwrap_or(0);
-    if version != 1 {
-        anyhow::bail!("未対応のパックバージョン: {}", version);
+/// pack.json だけを読む (JSON Lines のテーブルは読み込まない)
+pub(crate) fn read_pack_json(archive: &mut RkpArchive) -> Result<PackManifest> {
+    if !archive.contains("pack.json") {
+        anyhow::bail!(".rkp 内に pack.json が見つかりません");
     }
+    let mut bytes = Vec::new();
+    archive.by_name("pack.json")?.read_to_end(&mut bytes)?;
+    PackManifest::from_slice(&bytes)
+}
 
-    Ok(pack_data)
+/// 基準の .rkp を開く
+fn open_base_tables(path: &std::path::Path, passphrase: Option<&str>) -> Result<BaseTables> {
+    let mut archive = open_rkp(path, passphrase)?;
+    let pack_data = read_pack_json(&mut archive)?;
+    Ok(BaseTables::new(pack_data, archive))
+}
+
+/// 基準の .rkp と結合する場合、TableReader が基準の行を読むための BaseTables
+pub(crate) fn delta_base_tables(
+    delta_base: &Option<DeltaBase>,
+    passphrase: Option<&str>,
+) -> Result<Option<BaseTables>> {
+    match delta_base {
+        Some(DeltaBase::Archive(path)) => Ok(Some(open_base_tables(path, passphrase)?)),
+        _ => Ok(None),
+    }
+}
+
+/// 差分パックの基準
+pub(crate) enum DeltaBase {
+    /// 基準の .rkp から音声ファイルを取り出す
+    Archive(PathBuf),
+    /// 基準はインポート済み (テーブル → パック内の行 ID → DB に作成した行 ID)。
+    /// 差分の行だけを作成済みの行に適用する
+    Imported(IdMap),
+}
+
+/// インポートの記録 (差分パックを基準なしで取り込むために使う)。
+/// パック内の行 ID と DB に作成した行 ID の対応だけを残し、行の内容は残さない
+fn import_record_path(pack_id: &str) -> PathBuf {
+    import_records_dir().join(format!("{}.json", pack_id))
+}
+
+fn import_records_dir() -> PathBuf {
+    let base = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+    base.join("rkpack").join("imports")
+}
+
+/// 以前の形式の記録 (復号した pack.json とテーブルの JSON Lines を平文で持っていたもの) を削除する。
+/// 暗号化パックの中身が残らないようにするため、見つけ次第消す
+fn purge_legacy_import_records(progress: &dyn Fn(&str)) {
+    let Ok(entries) = fs::read_dir(import_records_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else if path.extension().is_some_and(|ext| ext == "json")
+            && fs::read(&path)
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok())
+                .is_some_and(|record| record.get("pack").is_some())
+        {
+            fs::remove_file(&path)
+        } else {
+            continue;
+        };
+        if let Err(e) = result {
+            progress(&format!(
+                "警告: 以前の形式のインポート記録を削除できません: {}: {}",
+                path.display(),
+                e
+            ));
+        }
+    }
+}
+
+/// インポートの記録を読み、DB に残っている行の ID だけを返す。
+/// 別の DB に取り込んだ記録や古い形式の記録は None
+fn load_import_record(conn: &Connection, pack_id: &str) -> Option<IdMap> {
+    let bytes = fs::read(import_record_path(pack_id)).ok()?;
+    let record: serde_json::Value = serde_json::from_slice(&bytes).ok()?;
+    if record.get("db_path").and_then(|v| v.as_str()) != conn.path() {
+        return None;
+    }
+    let ids: IdMap = serde_json::from_value(record.get("ids")?.clone()).ok()?;
+    Some(existing_ids(conn, &ids))
+}
+
+/// パックを取り込んだ記録を残す。差分パックの基準として後で参照する。
+/// 行の内容は残さないので、暗号化パックでも中身が平文で残ることはない
+fn save_import_record(conn: &Connection, pack_id: &str, id_map: &IdMap, progress: &dyn Fn(&str)) {
+    purge_legacy_import_records(progress);
+    let record = serde_json::json!({
+        "pack_id": pack_id,
+        "db_path": conn.path(),
+        "ids": existing_ids(conn, id_map),
+    });
+
+    let path = import_record_path(pack_id);
+    let result = (|| -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, serde_json::to_vec(&record)?)?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        progress(&format!(
+            "警告: インポート記録の保存に失敗: {}: {}",
+            path.display(),
+            e
+        ));
+    }
+}
+
+/// ID の対応のうち、対応先の行が DB に残っているもの
+fn existing_ids(conn: &Connection, id_map: &IdMap) -> IdMap {
+    let mut result = IdMap::new();
+    for (table, table_map) in id_map {
+        let new_ids: Vec<&String> = table_map.values().collect();
+        let mut existing: HashSet<String> = HashSet::new();
+        for chunk in new_ids.chunks(500) {
+            let placeholders: Vec<&str> = chunk.iter().map(|_| "?").collect();
+            let sql = format!(
+                "SELECT ID FROM `{}` WHERE ID IN ({}) AND rb_local_deleted = 0",
+                table,
+                placeholders.join(",")
+            );
+            let Ok(mut stmt) = conn.prepare(&sql) else {
+                break;
+            };
+            if let Ok(rows) = stmt.query_map(rusqlite::params_from_iter(chunk), |row| {
+                row.get::<_, String>(0)
+            }) {
+                existing.extend(rows.flatten());
+            }
+        }
+        let kept: HashMap<String, String> = table_map
+            .iter()
+            .filter(|(_, new_id)| existing.contains(*new_id))
+            .map(|(old_id, new_id)| (old_id.clone(), new_id.clone()))
+            .collect();
+        if !kept.is_empty() {
+            result.insert(table.clone(), kept);
+        }
+    }
+    result
+}
+
+/// インポートの記録を残すパック ID (差分パックは基準のパック ID)
+fn record_pack_id(pack_data: &PackManifest) -> Option<&str> {
+    match &pack_data.metadata.base {
+        Some(base) => base.pack_id.as_deref(),
+        None => pack_data.metadata.pack_id.as_deref(),
+    }
+}
+
+/// 差分パックに基準パックの行を補い、完全なパックデータにする。
+/// 読み込み済みのテーブルはテーブルごとに基準の行を1行ずつ読んで補う。
+/// STREAMED_TABLES は読み込まず、TableReader が読むときに基準の行を補う
+fn merge_delta(base: &mut BaseTables, delta: &mut PackManifest) -> Result<()> {
+    let Some(pack_base) = delta.metadata.base.clone() else {
+        return Ok(());
+    };
+    for (table, ids) in &pack_base.table_ids {
+        if STREAMED_TABLES.contains(&table.as_str()) {
+            continue;
+        }
+        let delta_rows = delta.tables.remove(table).unwrap_or_default();
+        let mut changed = HashMap::new();
+        let mut added = Vec::new();
+        for row in delta_rows {
+            match row.get("ID").and_then(|v| v.as_str()) {
+                Some(id) => {
+                    changed.insert(id.to_string(), row);
+                }
+                None => added.push(row),
+            }
+        }
+        let wanted: HashSet<&str> = ids.iter().map(|id| id.as_str()).collect();
+        let mut unchanged = HashMap::new();
+        base.for_each(table, |row| {
+            if let Some(id) = row.get("ID").and_then(|v| v.as_str())
+                && wanted.contains(id)
+                && !changed.contains_key(id)
+            {
+                unchanged.insert(id.to_string(), row.clone());
+            }
+            Ok(())
+        })?;
+        let merged: Vec<Row> = ids
+            .iter()
+            .filter_map(|id| changed.remove(id).or_else(|| unchanged.remove(id)))
+            .chain(added)
+            .collect();
+        delta.tables.insert(table.clone(), merged);
+    }
+    Ok(())
+}
+
+/// 差分パックなら基準を探す。
+/// 基準をこの DB に取り込んだ記録があれば、差分の行だけを作成済みの行に適用する (パックデータは差分のまま)。
+/// 記録がなければ指定された .rkp → 差分パックと同じフォルダの .rkp の順に探し、完全なパックデータに戻す。
+/// .rkp と結合する場合も STREAMED_TABLES は読み込まず、TableReader が基準の行を補う
+pub(crate) fn resolve_delta_base(
+    conn: &Connection,
+    pack_path: &str,
+    pack_data: &mut PackManifest,
+    base_path: Option<&str>,
+    passphrase: Option<&str>,
+    progress: &dyn Fn(&str),
+) -> Result<Option<DeltaBase>> {
+    let Some(base_info) = pack_data.metadata.base.clone() else {
+        return Ok(None);
+    };
+    let base_id = base_info.pack_id.as_deref();
+    let base_file_name = base_info.file_name.as_str();
+
+    // 基準を取り込み済みなら結合し直さない (プレイリスト等を二重に作らない)
+    if let Some(id) = base_id
+        && let Some(ids) = load_import_record(conn, id)
+    {
+        progress(&format!(
+            "差分パック: インポート済みの基準 {} に適用します",
+            base_file_name
+        ));
+        return Ok(Some(DeltaBase::Imported(ids)));
+    }
+
+    let matches_base = |base: &BaseTables| {
+        base_id.is_none_or(|id| base.pack_data().metadata.pack_id.as_deref() == Some(id))
+    };
+
+    if let Some(path) = base_path {
+        let mut base_data = open_base_tables(std::path::Path::new(path), passphrase)?;
+        if !matches_base(&base_data) {
+            anyhow::bail!(
+                "指定された .rkp はこの差分パックの基準ではありません: {}",
+                path
+            );
+        }
+        progress(&format!("差分パック: 基準 {} と結合します", path));
+        merge_delta(&mut base_data, pack_data)?;
+        return Ok(Some(DeltaBase::Archive(PathBuf::from(path))));
+    }
+
+    let sibling = PathBuf::from(pack_path)
+        .parent()
+        .map(|dir| dir.join(base_file_name))
+        .filter(|p| !base_file_name.is_empty() && p.is_file());
+    if let Some(path) = sibling
+        && let Ok(mut base_data) = open_base_tables(&path, passphrase)
+        && base_id.is_some()
+        && matches_base(&base_data)
+    {
+        progress(&format!("差分パック: 基準 {} と結合します", path.display()));
+        merge_delta(&mut base_data, pack_data)?;
+        return Ok(Some(DeltaBase::Archive(path)));
+    }
+
+    anyhow::bail!(
+        "差分パックの基準 {} が見つかりません。基準の .rkp を指定してください",
+        base_file_name
+    )
+}
+
+/// インポート済みの基準に適用する場合の、作成済みの行の ID
+fn imported_ids(delta_base: &Option<DeltaBase>) -> IdMap {
+    match delta_base {
+        Some(DeltaBase::Imported(ids)) => ids.clone(),
+        _ => IdMap::new(),
+    }
+}
+
+/// 基準のインポートで登録済みのトラック (パック内 ContentID → DB の ContentID)
+fn imported_base_contents(delta_base: &Option<DeltaBase>) -> HashMap<String, String> {
+    match delta_base {
+        Some(DeltaBase::Imported(ids)) => ids.get("djmdContent").cloned().unwrap_or_default(),
+        _ => HashMap::new(),
+    }
+}
+
+/// 音声が基準パック側にあり、作成済みのトラックの音声をそのまま使うトラック
+fn imported_base_audio(
+    pack_data: &PackManifest,
+    base_contents: &HashMap<String, String>,
+) -> HashSet<String> {
+    pack_data
+        .audio_files
+        .iter()
+        .filter(|af| af.in_base && base_contents.contains_key(&af.content_id))
+        .map(|af| af.content_id.clone())
+        .collect()
+}
+
+/// 作成済みの行を ID の対応に加える (パック内の行が作成済みの行を更新し、変更のない行を参照できるようにする)
+fn add_imported_ids(id_map: &mut IdMap, imported: &IdMap) {
+    for (table, ids) in imported {
+        id_map.entry(table.clone()).or_default().extend(ids.clone());
+    }
+}
+
+/// 基準から取り除かれた行を削除するテーブル (テーブル, 持ち主を指す列, 持ち主のテーブル)。
+/// リストのエントリとトラックごとのデータで、トラック・マスタ・プレイリスト自体は削除しない
+const REMOVABLE_TABLES: &[(&str, &str, &str)] = &[
+    ("djmdSongPlaylist", "PlaylistID", "djmdPlaylist"),
+    ("djmdSongHistory", "HistoryID", "djmdHistory"),
+    ("djmdSongSampler", "SamplerID", "djmdSampler"),
+    (
+        "djmdSongRelatedTracks",
+        "RelatedTracksID",
+        "djmdRelatedTracks",
+    ),
+    (
+        "djmdSongHotCueBanklist",
+        "HotCueBanklistID",
+        "djmdHotCueBanklist",
+    ),
+    (
+        "hotCueBanklistCue",
+        "HotCueBanklistID",
+        "djmdHotCueBanklist",
+    ),
+    ("djmdCue", "ContentID", "djmdContent"),
+    ("djmdActiveCensor", "ContentID", "djmdContent"),
+    ("djmdMixerParam", "ContentID", "djmdContent"),
+    ("djmdSongMyTag", "ContentID", "djmdContent"),
+    ("djmdSongTagList", "ContentID", "djmdContent"),
+    ("contentCue", "ContentID", "djmdContent"),
+    ("contentActiveCensor", "ContentID", "djmdContent"),
+];
+
+/// インポート済みの基準に適用するとき、差分パックで基準から取り除かれた行を削除する。削除した行数を返す。
+/// 持ち主 (プレイリストやトラック) がパックから外れた行は、持ち主とともに DB に残す
+fn delete_removed_rows(tx: &Connection, pack_data: &PackManifest, imported: &IdMap) -> Result<u32> {
+    let Some(base) = &pack_data.metadata.base else {
+        return Ok(0);
+    };
+    // パックに残っている持ち主の DB の ID
+    let packed_owners = |owner_table: &str| -> HashSet<&str> {
+        let top_level_ids = [
+            ("playlists", "djmdPlaylist"),
+            ("histories", "djmdHistory"),
+            ("hot_cue_banklists", "djmdHotCueBanklist"),
+            ("samplers", "djmdSampler"),
+            ("related_tracks", "djmdRelatedTracks"),
+        ]
+        .into_iter()
+        .filter(|&(_, table)| table == owner_table)
+        .flat_map(|(key, _)| pack_data.top_level_rows(key))
+        .filter_map(|row| row.get("ID")?.as_str());
+        let table_ids = base
+            .table_ids
+            .get(owner_table)
+            .into_iter()
+            .flatten()
+            .map(|id| id.as_str());
+        top_level_ids
+            .chain(table_ids)
+            .filter_map(|id| imported.get(owner_table)?.get(id))
+            .map(|id| id.as_str())
+            .collect()
+    };
+
+    let mut deleted = 0;
+    for &(table, owner_column, owner_table) in REMOVABLE_TABLES {
+        let (Some(ids), Some(created)) = (base.table_ids.get(table), imported.get(table)) else {
+            continue;
+        };
+        let kept: HashSet<&str> = ids.iter().map(|id| id.as_str()).collect();
+        let owners = packed_owners(owner_table);
+        for (old_id, new_id) in created {
+            if kept.contains(old_id.as_str()) {
+                continue;
+            }
+            let owner: Option<String> = tx
+                .query_row(
+                    &format!("SELECT `{}` FROM `{}` WHERE ID = ?", owner_column, table),
+                    params![new_id],
+                    |row| row.get(0),
+                )
+                .unwrap_or(None);
+            if !owner.is_some_and(|owner| owners.contains(owner.as_str())) {
+                continue;
+            }
+            deleted += tx.execute(
+                &format!("DELETE FROM `{}` WHERE ID = ?", table),
+                params![new_id],
+            )? as u32;
+        }
+    }
+    Ok(deleted)
+}
+
+/// パック内の ContentID → (メモリーキュー数, ホットキュー数)
+type CueCounts = HashMap<String, (usize, usize)>;
+
+/// 重複の判定結果: (スキップする ContentID, 更新する ContentID, パック内 ContentID → 既存 ContentID)
+type DuplicateDecisions = (HashSet<String>, HashSet<String>, HashMap<String, String>);
+
+fn count_pack_cues(reader: &mut TableReader) -> Result<CueCounts> {
+    let mut counts = CueCounts::new();
+    reader.for_each("djmdCue", |cue| {
+        if let Some(cid) = cue.get("ContentID").and_then(|v| v.as_str()) {
+            let count = counts.entry(cid.to_string()).or_default();
+            if cue.get("Kind").and_then(|v| v.as_i64()) == Some(0) {
+                count.0 += 1;
+            } else {
+                count.1 += 1;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(counts)
 }
 
 fn build_duplicate_info(
     conn: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
+    tables: &Tables,
+    cue_counts: &CueCounts,
     pack_cid: &str,
     existing_cid: &str,
 ) -> DuplicateInfo {
@@ -125,7 +571,6 @@ fn build_duplicate_info(
 
     let new_title = tables
         .get("djmdContent")
-        .and_then(|v| v.as_array())
         .and_then(|arr| {
             arr.iter()
                 .find(|c| c.get("ID").and_then(|v| v.as_str()) == Some(pack_cid))
@@ -134,25 +579,8 @@ fn build_duplicate_info(
         .unwrap_or("")
         .to_string();
 
-    let cues_for_content: Vec<&serde_json::Value> = tables
-        .get("djmdCue")
-        .and_then(|v| v.as_array())
-        .map(|arr| {
-            arr.iter()
-                .filter(|c| c.get("ContentID").and_then(|v| v.as_str()) == Some(pack_cid))
-                .collect()
-        })
-        .unwrap_or_default();
-
-    let new_memory_cue_count = cues_for_content
-        .iter()
-        .filter(|c| c.get("Kind").and_then(|v| v.as_i64()) == Some(0))
-        .count();
-
-    let new_hot_cue_count = cues_for_content
-        .iter()
-        .filter(|c| c.get("Kind").and_then(|v| v.as_i64()) != Some(0))
-        .count();
+    let (new_memory_cue_count, new_hot_cue_count) =
+        cue_counts.get(pack_cid).copied().unwrap_or_default();
 
     DuplicateInfo {
         existing_title,
@@ -164,22 +592,26 @@ fn build_duplicate_info(
     }
 }
 
+/// `preset` のトラック (インポート済みの基準のトラック) は確認せず既存トラックに対応付ける。
+/// これらは行ごとに更新するため、関連データを置き換える更新の対象には含めない
 fn detect_duplicate_contents(
     conn: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
+    tables: &Tables,
+    cue_counts: &CueCounts,
+    preset: &HashMap<String, String>,
     progress: &dyn Fn(&str),
     confirm: &dyn Fn(&DuplicateInfo) -> bool,
-) -> Result<(HashSet<String>, HashSet<String>, HashMap<String, String>)> {
+) -> Result<DuplicateDecisions> {
     let mut skipped_content_ids: HashSet<String> = HashSet::new();
     let mut update_content_ids: HashSet<String> = HashSet::new();
-    let mut existing_content_map: HashMap<String, String> = HashMap::new();
+    let mut existing_content_map: HashMap<String, String> = preset.clone();
 
-    if let Some(content_files) = tables.get("contentFile").and_then(|v| v.as_array()) {
+    if let Some(content_files) = tables.get("contentFile") {
         for cf in content_files {
             let hash = cf.get("Hash").and_then(|h| h.as_str());
             let pack_content_id = cf.get("ContentID").and_then(|c| c.as_str());
             if let (Some(hash), Some(pack_cid)) = (hash, pack_content_id) {
-                if hash.is_empty() {
+                if hash.is_empty() || preset.contains_key(pack_cid) {
                     continue;
                 }
                 let existing: Option<String> = conn
@@ -195,7 +627,8 @@ fn detect_duplicate_contents(
                         pack_cid, hash, existing_cid
                     ));
 
-                    let info = build_duplicate_info(conn, tables, pack_cid, &existing_cid);
+                    let info =
+                        build_duplicate_info(conn, tables, cue_counts, pack_cid, &existing_cid);
                     if confirm(&info) {
                         update_content_ids.insert(pack_cid.to_string());
                     } else {
@@ -207,14 +640,17 @@ fn detect_duplicate_contents(
         }
     }
 
-    Ok((skipped_content_ids, update_content_ids, existing_content_map))
+    Ok((
+        skipped_content_ids,
+        update_content_ids,
+        existing_content_map,
+    ))
 }
 
 fn delete_related_rows_for_content(conn: &Connection, content_id: &str) -> Result<()> {
     let banklist_ids: Vec<String> = {
-        let mut stmt = conn.prepare(
-            "SELECT HotCueBanklistID FROM djmdSongHotCueBanklist WHERE ContentID = ?",
-        )?;
+        let mut stmt = conn
+            .prepare("SELECT HotCueBanklistID FROM djmdSongHotCueBanklist WHERE ContentID = ?")?;
         stmt.query_map(params![content_id], |row| row.get(0))?
             .filter_map(|r| r.ok())
             .collect()
@@ -245,6 +681,240 @@ fn delete_related_rows_for_content(conn: &Connection, content_id: &str) -> Resul
     Ok(())
 }
 
+/// 音声なしパックのトラックを、受け取り側が既に持っているトラックに照合する。
+/// contentFile.Hash → ISRC → タイトル+アーティスト+長さ (±1秒) の順に探す
+fn match_lite_contents(
+    conn: &Connection,
+    tables: &Tables,
+    progress: &dyn Fn(&str),
+) -> Result<HashMap<String, String>> {
+    let rows = |table: &str| tables.get(table).map(|a| a.as_slice()).unwrap_or_default();
+    let artist_names: HashMap<&str, &str> = rows("djmdArtist")
+        .iter()
+        .filter_map(|a| Some((a.get("ID")?.as_str()?, a.get("Name")?.as_str()?)))
+        .collect();
+
+    let mut matched: HashMap<String, String> = HashMap::new();
+    for content in rows("djmdContent") {
+        let Some(pack_cid) = content.get("ID").and_then(|v| v.as_str()) else {
+            continue;
+        };
+        let title = content.get("Title").and_then(|v| v.as_str()).unwrap_or("");
+
+        let by_hash = rows("contentFile")
+            .iter()
+            .filter(|cf| cf.get("ContentID").and_then(|v| v.as_str()) == Some(pack_cid))
+            .filter_map(|cf| cf.get("Hash").and_then(|v| v.as_str()))
+            .filter(|hash| !hash.is_empty())
+            .find_map(|hash| {
+                conn.query_row(
+                    "SELECT ContentID FROM contentFile WHERE Hash = ? AND rb_local_deleted = 0 LIMIT 1",
+                    params![hash],
+                    |row| row.get::<_, String>(0),
+                )
+                .ok()
+            })
+            .map(|cid| (cid, "Hash"));
+
+        let by_isrc = || {
+            let isrc = content
+                .get("ISRC")
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())?;
+            conn.query_row(
+                "SELECT ID FROM djmdContent WHERE ISRC = ? AND rb_local_deleted = 0 LIMIT 1",
+                params![isrc],
+                |row| row.get::<_, String>(0),
+            )
+            .ok()
+            .map(|cid| (cid, "ISRC"))
+        };
+
+        let by_title = || {
+            let length = content.get("Length").and_then(|v| v.as_i64())?;
+            let artist = content
+                .get("ArtistID")
+                .and_then(|v| v.as_str())
+                .and_then(|id| artist_names.get(id).copied())
+                .unwrap_or("");
+            conn.query_row(
+                "SELECT c.ID FROM djmdContent c \
+                 LEFT JOIN djmdArtist a ON a.ID = c.ArtistID \
+                 WHERE c.Title = ? AND IFNULL(a.Name, '') = ? AND ABS(c.Length - ?) <= 1 \
+                 AND c.rb_local_deleted = 0 LIMIT 1",
+                params![title, artist, length],
+                |row| row.get::<_, String>(0),
+            )
+            .ok()
+            .map(|cid| (cid, "タイトル+アーティスト+長さ"))
+        };
+
+        match by_hash.or_else(by_isrc).or_else(by_title) {
+            Some((existing_cid, method)) => {
+                progress(&format!(
+                    "トラック照合: {} → 既存 ContentID {} ({})",
+                    title, existing_cid, method
+                ));
+                matched.insert(pack_cid.to_string(), existing_cid);
+            }
+            None => {
+                progress(&format!(
+                    "警告: 手元に該当するトラックがありません: {}",
+                    title
+                ));
+            }
+        }
+    }
+
+    Ok(matched)
+}
+
+/// 照合できなかったトラックとそれを参照する行をパックデータから取り除き、取り除いた ContentID を返す。
+/// 音声がないため新規トラックとしては登録できない。JSON Lines のテーブルは TableReader で読むときに除く
+fn drop_unmatched_contents(
+    pack_data: &mut PackManifest,
+    matched: &HashMap<String, String>,
+) -> HashSet<String> {
+    let mut dropped = HashSet::new();
+    if let Some(contents) = pack_data.tables.get_mut("djmdContent") {
+        contents.retain(|c| {
+            let Some(id) = c.get("ID").and_then(|v| v.as_str()) else {
+                return false;
+            };
+            if matched.contains_key(id) {
+                return true;
+            }
+            dropped.insert(id.to_string());
+            false
+        });
+    }
+
+    let mut dropped_content_files: HashSet<String> = HashSet::new();
+    for (table, rows) in pack_data.tables.iter_mut() {
+        rows.retain(|row| {
+            let keep = row
+                .get("ContentID")
+                .and_then(|v| v.as_str())
+                .is_none_or(|cid| matched.contains_key(cid));
+            if !keep
+                && table == "contentFile"
+                && let Some(id) = row.get("ID").and_then(|v| v.as_str())
+            {
+                dropped_content_files.insert(id.to_string());
+            }
+            keep
+        });
+    }
+
+    pack_data.content_data_files.retain(|df| {
+        df.content_file_id
+            .as_ref()
+            .is_none_or(|id| !dropped_content_files.contains(id))
+    });
+
+    dropped
+}
+
+/// 分析データファイルが同梱されているトラック (手元の分析データを置き換える)
+fn contents_with_packed_analysis(pack_data: &PackManifest) -> HashSet<String> {
+    let packed_files: HashSet<&str> = pack_data
+        .content_data_files
+        .iter()
+        .filter_map(|df| df.content_file_id.as_deref())
+        .collect();
+    pack_data
+        .rows("contentFile")
+        .iter()
+        .filter(|cf| {
+            cf.get("ID")
+                .and_then(|v| v.as_str())
+                .is_some_and(|id| packed_files.contains(id))
+        })
+        .filter_map(|cf| cf.get("ContentID").and_then(|v| v.as_str()))
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// 音声なしパックで更新する既存トラックにレーティングを反映し、
+/// 分析データが同梱されていれば既存の contentFile を置き換える準備をする
+fn apply_lite_content_updates(
+    tx: &Connection,
+    tables: &Tables,
+    update_content_ids: &HashSet<String>,
+    existing_content_map: &HashMap<String, String>,
+    analysis_content_ids: &HashSet<String>,
+) -> Result<()> {
+    let Some(contents) = tables.get("djmdContent") else {
+        return Ok(());
+    };
+    for content in contents {
+        let Some(pack_cid) = content.get("ID").and_then(|v| v.as_str()) else {
+            continue;
+        };
+        if !update_content_ids.contains(pack_cid) {
+            continue;
+        }
+        let Some(existing_cid) = existing_content_map.get(pack_cid) else {
+            continue;
+        };
+        if let Some(rating) = content.get("Rating").and_then(|v| v.as_i64()) {
+            tx.execute(
+                "UPDATE djmdContent SET Rating = ? WHERE ID = ?",
+                params![rating, existing_cid],
+            )?;
+        }
+        if analysis_content_ids.contains(pack_cid) {
+            let analysis_path = content.get("AnalysisDataPath").and_then(|v| v.as_str());
+            tx.execute(
+                "UPDATE djmdContent SET AnalysisDataPath = ? WHERE ID = ?",
+                params![analysis_path, existing_cid],
+            )?;
+            tx.execute(
+                "DELETE FROM contentFile WHERE ContentID = ?",
+                params![existing_cid],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// 同梱した画像を指す ImagePath / imageFile.Path を、展開先の PIONEER フォルダからのパスに書き換える
+fn rewrite_image_paths(pack_data: &mut PackManifest) {
+    let new_paths: HashMap<String, String> = pack_data
+        .content_data_files
+        .iter()
+        .filter_map(|df| Some((df.image_path.clone()?, format!("/{}", df.relative_path))))
+        .collect();
+    if new_paths.is_empty() {
+        return;
+    }
+    let rewrite = |row: &mut Row, column: &str| {
+        if let Some(new_path) = row
+            .get(column)
+            .and_then(|v| v.as_str())
+            .and_then(|path| new_paths.get(path))
+        {
+            row[column] = serde_json::Value::String(new_path.clone());
+        }
+    };
+
+    for row in pack_data
+        .playlists
+        .iter_mut()
+        .chain(pack_data.hot_cue_banklists.iter_mut())
+    {
+        rewrite(row, "ImagePath");
+    }
+    for &table in IMAGE_PATH_TABLES {
+        for row in pack_data.tables.get_mut(table).into_iter().flatten() {
+            rewrite(row, "ImagePath");
+        }
+    }
+    for row in pack_data.tables.get_mut("imageFile").into_iter().flatten() {
+        rewrite(row, "Path");
+    }
+}
+
 const MASTER_TABLES: &[&str] = &[
     "djmdArtist",
     "djmdAlbum",
@@ -256,6 +926,17 @@ const MASTER_TABLES: &[&str] = &[
     "djmdHotCueBanklist",
 ];
 
+/// 単独でパックされるリスト (pack.json のキー, リストのテーブル, エントリのテーブル)
+const LIST_TREES: &[(&str, &str, Option<&str>)] = &[
+    ("hot_cue_banklists", "djmdHotCueBanklist", None),
+    ("samplers", "djmdSampler", Some("djmdSongSampler")),
+    (
+        "related_tracks",
+        "djmdRelatedTracks",
+        Some("djmdSongRelatedTracks"),
+    ),
+];
+
 const RELATED_TABLES: &[&str] = &[
     "djmdCue",
     "djmdActiveCensor",
@@ -267,15 +948,20 @@ const RELATED_TABLES: &[&str] = &[
     "contentCue",
     "contentActiveCensor",
     "contentFile",
+    "imageFile",
 ];
 
-fn build_master_id_map(
-    conn: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
-    id_map: &mut IdMap,
-) -> Result<()> {
+/// ImagePath でアートワークを参照するテーブル
+const IMAGE_PATH_TABLES: &[&str] = &[
+    "djmdContent",
+    "djmdAlbum",
+    "djmdPlaylist",
+    "djmdHotCueBanklist",
+];
+
+fn build_master_id_map(conn: &Connection, tables: &Tables, id_map: &mut IdMap) -> Result<()> {
     for &table in MASTER_TABLES {
-        let rows = match tables.get(table).and_then(|v| v.as_array()) {
+        let rows = match tables.get(table) {
             Some(r) => r,
             None => continue,
         };
@@ -290,12 +976,10 @@ fn build_master_id_map(
             };
 
             if let Some(name_col) = name_col {
-                let name_val = row
-                    .get(name_col)
-                    .and_then(|v| match v {
-                        serde_json::Value::String(s) => Some(s.as_str()),
-                        _ => None,
-                    });
+                let name_val = row.get(name_col).and_then(|v| match v {
+                    serde_json::Value::String(s) => Some(s.as_str()),
+                    _ => None,
+                });
                 let existing_id = if table == "djmdColor" {
                     if let Some(code) = row.get("ColorCode").and_then(|v| v.as_i64()) {
                         let sql = format!(
@@ -331,16 +1015,12 @@ fn build_master_id_map(
 
 fn build_content_id_map(
     conn: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
+    tables: &Tables,
     existing_content_map: &HashMap<String, String>,
     id_map: &mut IdMap,
 ) -> Result<()> {
     let content_table = "djmdContent";
-    let rows = tables
-        .get(content_table)
-        .and_then(|v| v.as_array())
-        .unwrap_or(&Vec::new())
-        .clone();
+    let rows = tables.get(content_table).unwrap_or(&Vec::new()).clone();
     let mut max_id = get_max_numeric_id(conn, content_table)?;
     let mut table_map = HashMap::new();
 
@@ -364,44 +1044,75 @@ fn build_content_id_map(
 
 fn build_related_id_maps(
     conn: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
-    pack_data: &serde_json::Value,
+    tables: &Tables,
+    pack_data: &PackManifest,
+    reader: &mut TableReader,
     id_map: &mut IdMap,
 ) -> Result<()> {
     // Playlist ID map
     {
         let mut max_id = get_max_numeric_id(conn, "djmdPlaylist")?;
         let mut table_map = HashMap::new();
-        if let Some(playlist) = pack_data.get("playlist")
-            && let Some(old_id) = playlist.get("ID").and_then(|v| v.as_str()) {
+        let roots = Some(&pack_data.playlists);
+        let children = tables.get("djmdPlaylist");
+        for playlist in roots.into_iter().chain(children).flatten() {
+            if let Some(old_id) = playlist.get("ID").and_then(|v| v.as_str()) {
                 max_id += 1;
                 table_map.insert(old_id.to_string(), max_id.to_string());
             }
+        }
         id_map.insert("djmdPlaylist".to_string(), table_map);
     }
 
-    // Related tables + djmdSongPlaylist
+    build_history_id_map(conn, tables, pack_data, id_map)?;
+
+    // 単独でパックされたリストは名前で既存のものに合流させず新規作成する
+    for &(key, table, _) in LIST_TREES {
+        let rows = pack_data.top_level_rows(key);
+        if rows.is_empty() {
+            continue;
+        }
+        let table_map = id_map.entry(table.to_string()).or_default();
+        let mut max_id = table_map
+            .values()
+            .filter_map(|id| id.parse::<i64>().ok())
+            .chain(std::iter::once(get_max_numeric_id(conn, table)?))
+            .max()
+            .unwrap_or(0);
+        for row in rows {
+            if let Some(old_id) = row.get("ID").and_then(|v| v.as_str()) {
+                max_id += 1;
+                table_map.insert(old_id.to_string(), max_id.to_string());
+            }
+        }
+    }
+
+    // Related tables + リストのエントリ
     let all_id_tables: Vec<&str> = RELATED_TABLES
         .iter()
         .copied()
-        .chain(std::iter::once("djmdSongPlaylist"))
+        .chain(["djmdSongPlaylist", "djmdSongHistory"])
+        .chain(
+            LIST_TREES
+                .iter()
+                .filter_map(|&(_, _, song_table)| song_table),
+        )
         .collect();
+    let table_names = reader.table_names();
     for &table in &all_id_tables {
-        let rows = match tables.get(table).and_then(|v| v.as_array()) {
-            Some(r) => r,
-            None => continue,
-        };
+        if !table_names.contains(table) {
+            continue;
+        }
         let mut max_id = get_max_numeric_id(conn, table)?;
         let mut table_map = HashMap::new();
 
-        for row in rows {
-            let old_id = match row.get("ID").and_then(|v| v.as_str()) {
-                Some(id) => id.to_string(),
-                None => continue,
-            };
-            max_id += 1;
-            table_map.insert(old_id, max_id.to_string());
-        }
+        reader.for_each(table, |row| {
+            if let Some(old_id) = row.get("ID").and_then(|v| v.as_str()) {
+                max_id += 1;
+                table_map.insert(old_id.to_string(), max_id.to_string());
+            }
+            Ok(())
+        })?;
 
         id_map.insert(table.to_string(), table_map);
     }
@@ -409,83 +1120,328 @@ fn build_related_id_maps(
     Ok(())
 }
 
-fn extract_audio_files(
-    archive: &mut ZipArchive<fs::File>,
-    pack_data: &serde_json::Value,
-    dest_dir: &str,
-    skipped_content_ids: &HashSet<String>,
-    progress: &dyn Fn(&str),
-) -> Result<HashMap<String, String>> {
-    let mut audio_actual_paths: HashMap<String, String> = HashMap::new();
-    let mut file_copy_success = 0u32;
-    let mut file_copy_skip = 0u32;
-    let mut file_copy_fail = 0u32;
+/// 再生履歴の親フォルダは同じ親・同じ名前の既存フォルダがあれば再利用する
+fn build_history_id_map(
+    conn: &Connection,
+    tables: &Tables,
+    pack_data: &PackManifest,
+    id_map: &mut IdMap,
+) -> Result<()> {
+    let mut max_id = get_max_numeric_id(conn, "djmdHistory")?;
+    let mut table_map: HashMap<String, String> = HashMap::new();
 
-    if let Some(audio_files) = pack_data.get("audio_files").and_then(|v| v.as_array()) {
-        let dest_path = PathBuf::from(dest_dir);
-        let _ = fs::create_dir_all(&dest_path);
-        let total_audio = audio_files.len();
-        for (idx, af) in audio_files.iter().enumerate() {
-            let content_id = af
-                .get("content_id")
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
-            let relative_path = match af.get("relative_path").and_then(|v| v.as_str()) {
-                Some(p) => p,
-                None => continue,
+    // フォルダはルート側から順に並んでいる
+    if let Some(folders) = tables.get("djmdHistory") {
+        for folder in folders {
+            let Some(old_id) = folder.get("ID").and_then(|v| v.as_str()) else {
+                continue;
             };
+            let name = folder.get("Name").and_then(|v| v.as_str()).unwrap_or("");
+            let old_parent = folder
+                .get("ParentID")
+                .and_then(|v| v.as_str())
+                .unwrap_or("root");
+            let parent = table_map
+                .get(old_parent)
+                .map(|s| s.as_str())
+                .unwrap_or("root");
+            let existing: Option<String> = conn
+                .query_row(
+                    "SELECT ID FROM djmdHistory WHERE Name = ? AND ParentID = ? \
+                     AND COALESCE(Attribute, 0) = 1 AND rb_local_deleted = 0 LIMIT 1",
+                    params![name, parent],
+                    |row| row.get(0),
+                )
+                .ok();
+            let new_id = existing.unwrap_or_else(|| {
+                max_id += 1;
+                max_id.to_string()
+            });
+            table_map.insert(old_id.to_string(), new_id);
+        }
+    }
 
-            if skipped_content_ids.contains(content_id) {
-                file_copy_skip += 1;
-                continue;
-            }
+    for history in &pack_data.histories {
+        if let Some(old_id) = history.get("ID").and_then(|v| v.as_str()) {
+            max_id += 1;
+            table_map.insert(old_id.to_string(), max_id.to_string());
+        }
+    }
+
+    id_map.insert("djmdHistory".to_string(), table_map);
+    Ok(())
+}
+
+/// 再生履歴をルート直下の通常のプレイリストに変換する
+fn convert_histories_to_playlists(pack_data: &mut PackManifest) {
+    if pack_data.histories.is_empty() {
+        return;
+    }
+    let histories = std::mem::take(&mut pack_data.histories);
+    pack_data.tables.remove("djmdHistory");
+    let song_histories = pack_data
+        .tables
+        .remove("djmdSongHistory")
+        .unwrap_or_default();
+    pack_data.table_files.remove("djmdHistory");
+    pack_data.table_files.remove("djmdSongHistory");
+
+    // 同じパック内のプレイリスト ID と衝突しないように接頭辞を付ける
+    let converted_id = |id: Option<&serde_json::Value>| {
+        serde_json::Value::String(format!(
+            "history-{}",
+            id.and_then(|v| v.as_str()).unwrap_or("")
+        ))
+    };
+
+    let mut playlists = Vec::new();
+    for history in &histories {
+        playlists.push(serde_json::json!({
+            "ID": converted_id(history.get("ID")),
+            "Seq": history.get("Seq").cloned().unwrap_or_default(),
+            "Name": history.get("Name").cloned().unwrap_or_default(),
+            "ImagePath": serde_json::Value::Null,
+            "Attribute": 0,
+            "ParentID": "root",
+            "SmartList": serde_json::Value::Null,
+            "UUID": history.get("UUID").cloned().unwrap_or_default(),
+            "rb_data_status": 0,
+            "rb_local_data_status": 0,
+            "rb_local_deleted": 0,
+            "rb_local_synced": 0,
+            "usn": serde_json::Value::Null,
+            "rb_local_usn": serde_json::Value::Null,
+            "created_at": history.get("created_at").cloned().unwrap_or_default(),
+            "updated_at": history.get("updated_at").cloned().unwrap_or_default(),
+        }));
+    }
+
+    let song_playlists = pack_data
+        .tables
+        .entry("djmdSongPlaylist".to_string())
+        .or_default();
+    for song in &song_histories {
+        let mut row = song.clone();
+        if let Some(row_obj) = row.as_object_mut() {
+            let history_id = row_obj.remove("HistoryID");
+            row_obj.insert("ID".to_string(), converted_id(song.get("ID")));
+            row_obj.insert("PlaylistID".to_string(), converted_id(history_id.as_ref()));
+        }
+        song_playlists.push(row);
+    }
+
+    pack_data.playlists.extend(playlists);
+}
+
+/// 展開するエントリ1つ分
+struct ExtractJob {
+    entry_name: String,
+    /// 差分パックの基準パックから取り出す
+    in_base: bool,
+    target: PathBuf,
+    /// FLAC のエントリを元の WAV/AIFF に戻して展開する (値は元のファイルの SHA-256)
+    restore_sha256: Option<String>,
+}
+
+/// 展開した音声ファイル
+#[derive(Clone)]
+struct ExtractedAudio {
+    path: String,
+    /// 元のファイルと内容が違う (タグを書き込んだ・FLAC のまま置いた) 場合の実際のサイズ
+    file_size: Option<u64>,
+    /// FLAC のまま配置した場合のビットレート (取得できなければ 0)
+    kept_flac: Option<i64>,
+}
+
+/// エントリを並列に展開する。各ワーカーは自分用に .rkp を開き直す。
+/// 結果は jobs と同じ順に返す
+fn extract_entries_parallel(
+    pack_path: &std::path::Path,
+    base_path: Option<&std::path::Path>,
+    passphrase: Option<&str>,
+    jobs: &[ExtractJob],
+) -> Vec<Result<()>> {
+    jobs.par_iter()
+        .map_init(
+            || {
+                (
+                    open_rkp(pack_path, passphrase),
+                    base_path.map(|p| open_rkp(p, passphrase)),
+                )
+            },
+            |(archive, base_archive), job| {
+                let source = if job.in_base {
+                    base_archive
+                        .as_mut()
+                        .context("差分パックの基準パックがありません")?
+                } else {
+                    archive
+                };
+                let source = source.as_mut().map_err(|e| anyhow::anyhow!("{:#}", e))?;
+                let Some(sha256) = &job.restore_sha256 else {
+                    return extract_rkp_entry(source, &job.entry_name, &job.target);
+                };
+                if !source.contains(&job.entry_name) {
+                    anyhow::bail!(".rkp 内にエントリが見つかりません: {}", job.entry_name);
+                }
+                if let Some(parent) = job.target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                flac::restore_to_file(source.by_name(&job.entry_name)?, &job.target, sha256)
+            },
+        )
+        .collect()
+}
 
-            let entry_name = format!("files/{}", relative_path.replace('\\', "/"));
-            let file_name = std::path::Path::new(relative_path)
-                .file_name()
-                .map(|n| n.to_string_lossy().to_string())
+fn extract_audio_files(
+    pack_path: &std::path::Path,
+    base_path: Option<&std::path::Path>,
+    options: &UnpackOptions,
+    audio_files: &[AudioFile],
+    dest_dir: &str,
+    skipped_content_ids: &HashSet<String>,
+    progress: &dyn Fn(&str),
+) -> Result<HashMap<String, ExtractedAudio>> {
+    let keep_flac = options.keep_flac;
+    let mut audio_actual_paths: HashMap<String, ExtractedAudio> = HashMap::new();
+    let mut file_copy_success = 0u32;
+    let mut file_copy_skip = 0u32;
+    let mut file_copy_fail = 0u32;
+
+    let dest_path = PathBuf::from(dest_dir);
+    let _ = fs::create_dir_all(&dest_path);
+
+    // 配置先を先に決めてから並列に展開する。
+    // 同じエントリ (同じ内容) を参照するトラックは1つのファイルを共有する
+    let mut jobs: Vec<ExtractJob> = Vec::new();
+    // 展開したファイルが元のファイルと違うか (タグを書き込んだコピー・FLAC のまま置くもの)
+    let mut job_modified: Vec<bool> = Vec::new();
+    let mut job_by_entry: HashMap<String, usize> = HashMap::new();
+    let mut content_jobs: Vec<(String, usize)> = Vec::new();
+    let mut planned_targets: HashSet<PathBuf> = HashSet::new();
+    for af in audio_files {
+        let content_id = af.content_id.as_str();
+        let relative_path = af.relative_path.as_str();
+
+        if skipped_content_ids.contains(content_id) {
+            file_copy_skip += 1;
+            continue;
+        }
+
+        let entry_name = af.entry_name();
+        if let Some(&job_idx) = job_by_entry.get(&entry_name) {
+            content_jobs.push((content_id.to_string(), job_idx));
+            continue;
+        }
+        let mut file_name = std::path::Path::new(relative_path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        // FLAC のまま置く場合は拡張子を .flac にする
+        let restore_sha256 = af.flac_source.as_ref().map(|source| source.sha256.clone());
+        if keep_flac && restore_sha256.is_some() {
+            file_name = std::path::Path::new(&file_name)
+                .with_extension("flac")
+                .to_string_lossy()
+                .to_string();
+        }
+
+        let mut target = dest_path.join(&file_name);
+
+        if target.exists() || planned_targets.contains(&target) {
+            let stem = std::path::Path::new(&file_name)
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let ext = std::path::Path::new(&file_name)
+                .extension()
+                .map(|s| format!(".{}", s.to_string_lossy()))
                 .unwrap_or_default();
+            let new_name = format!("{}_{}{}", stem, content_id, ext);
+            target = dest_path.join(&new_name);
+            progress(&format!(
+                "ファイル名重複のためリネーム: {} → {}",
+                file_name, new_name
+            ));
+        }
+        planned_targets.insert(target.clone());
 
+        // 差分パックで変更のない音声は基準パックから取り出す
+        let in_base = af.in_base;
+        if in_base && base_path.is_none() {
             progress(&format!(
-                "音声ファイル展開 ({}/{}) {}",
-                idx + 1,
-                total_audio,
-                file_name
+                "警告: 基準パックの音声ファイルを取り出せません: {}",
+                entry_name
             ));
+            file_copy_fail += 1;
+            continue;
+        }
+
+        // FLAC から戻した WAV/AIFF も、タグを書き込んだものは元のファイルと違う (ID3 タグが入っている)
+        let restore_sha256 = restore_sha256.filter(|_| !keep_flac);
+        job_modified.push(af.tagged || (restore_sha256.is_none() && af.flac_source.is_some()));
+        job_by_entry.insert(entry_name.clone(), jobs.len());
+        content_jobs.push((content_id.to_string(), jobs.len()));
+        jobs.push(ExtractJob {
+            entry_name,
+            in_base,
+            target,
+            restore_sha256,
+        });
+    }
+
+    progress(&format!("音声ファイル展開中 ({} 件)", jobs.len()));
+    let results =
+        extract_entries_parallel(pack_path, base_path, options.passphrase.as_deref(), &jobs);
 
-            let mut target = dest_path.join(&file_name);
-
-            if target.exists() {
-                let stem = std::path::Path::new(&file_name)
-                    .file_stem()
-                    .map(|s| s.to_string_lossy().to_string())
-                    .unwrap_or_default();
-                let ext = std::path::Path::new(&file_name)
-                    .extension()
-                    .map(|s| format!(".{}", s.to_string_lossy()))
-                    .unwrap_or_default();
-                let new_name = format!("{}_{}{}", stem, content_id, ext);
-                target = dest_path.join(&new_name);
+    let total_audio = jobs.len();
+    let mut actual_paths: Vec<Option<ExtractedAudio>> = Vec::with_capacity(total_audio);
+    for (idx, (job, result)) in jobs.iter().zip(results).enumerate() {
+        match result {
+            Ok(_) => {
                 progress(&format!(
-                    "ファイル名重複のためリネーム: {} → {}",
-                    file_name, new_name
+                    "音声ファイル展開 ({}/{}) {}",
+                    idx + 1,
+                    total_audio,
+                    job.target
+                        .file_name()
+                        .map(|n| n.to_string_lossy())
+                        .unwrap_or_default()
                 ));
+                let actual = get_actual_path_on_disk(&job.target);
+                let file_size = job_modified[idx]
+                    .then(|| fs::metadata(&job.target).ok().map(|m| m.len()))
+                    .flatten();
+                let kept_flac = (keep_flac
+                    && job
+                        .target
+                        .extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("flac")))
+                .then(|| kept_flac_bit_rate(&job.target));
+                actual_paths.push(Some(ExtractedAudio {
+                    path: actual.to_string_lossy().replace('\\', "/"),
+                    file_size,
+                    kept_flac,
+                }));
             }
-
-            match extract_rkp_entry(archive, &entry_name, &target) {
-                Ok(_) => {
-                    file_copy_success += 1;
-                    let actual = get_actual_path_on_disk(&target);
-                    let actual_str = actual.to_string_lossy().replace('\\', "/");
-                    audio_actual_paths.insert(content_id.to_string(), actual_str);
-                }
-                Err(e) => {
-                    progress(&format!("警告: 音声ファイル展開失敗: {}: {}", entry_name, e));
-                    file_copy_fail += 1;
-                }
+            Err(e) => {
+                progress(&format!(
+                    "警告: 音声ファイル展開失敗: {}: {:#}",
+                    job.entry_name, e
+                ));
+                actual_paths.push(None);
             }
         }
     }
+    for (content_id, job_idx) in content_jobs {
+        match &actual_paths[job_idx] {
+            Some(extracted) => {
+                file_copy_success += 1;
+                audio_actual_paths.insert(content_id, extracted.clone());
+            }
+            None => file_copy_fail += 1,
+        }
+    }
     progress(&format!(
         "音声ファイル配置: 成功={}, スキップ={}, 失敗={}",
         file_copy_success, file_copy_skip, file_copy_fail
@@ -494,9 +1450,22 @@ fn extract_audio_files(
     Ok(audio_actual_paths)
 }
 
+/// FLAC のまま配置したファイルのビットレート (djmdContent の BitRate に入れる)
+fn kept_flac_bit_rate(path: &std::path::Path) -> i64 {
+    flac::bit_rate(path).unwrap_or_else(|e| {
+        tracing::warn!(
+            "FLAC のビットレートを取得できません: {}: {:#}",
+            path.display(),
+            e
+        );
+        0
+    })
+}
+
 fn extract_data_files(
-    archive: &mut ZipArchive<fs::File>,
-    pack_data: &serde_json::Value,
+    pack_path: &std::path::Path,
+    passphrase: Option<&str>,
+    data_files: &[ContentDataFile],
     share_dir: &std::path::Path,
     progress: &dyn Fn(&str),
 ) -> Result<HashMap<String, String>> {
@@ -505,53 +1474,57 @@ fn extract_data_files(
     let data_file_skip = 0u32;
     let mut data_file_fail = 0u32;
 
-    if let Some(data_files) = pack_data
-        .get("content_data_files")
-        .and_then(|v| v.as_array())
-    {
-        let total_data = data_files.len();
-        for (idx, df) in data_files.iter().enumerate() {
-            let cf_id = df
-                .get("content_file_id")
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
-            let relative_path = match df.get("relative_path").and_then(|v| v.as_str()) {
-                Some(p) => p,
-                None => continue,
-            };
-
-            progress(&format!(
-                "データファイル展開 ({}/{}) {}",
-                idx + 1,
-                total_data,
-                relative_path
-            ));
-
-            let entry_name = format!("content_data/{}", relative_path.replace('\\', "/"));
-            let native_rel = relative_path.replace('/', std::path::MAIN_SEPARATOR_STR);
-            let target = share_dir.join(&native_rel);
+    let mut jobs: Vec<ExtractJob> = Vec::new();
+    // ジョブごとの、展開したファイルを指す contentFile の ID
+    let mut job_file_ids: Vec<Vec<String>> = Vec::new();
+    let mut scheduled: HashMap<&str, usize> = HashMap::new();
+    for df in data_files {
+        // 複数の参照元から同じ画像を指している場合は1回だけ展開する
+        if let Some(&job_idx) = scheduled.get(df.relative_path.as_str()) {
+            job_file_ids[job_idx].extend(df.content_file_id.clone());
+            continue;
+        }
+        scheduled.insert(df.relative_path.as_str(), jobs.len());
+        jobs.push(ExtractJob {
+            entry_name: df.entry_name(),
+            in_base: false,
+            target: pack_relative_path(share_dir, &df.relative_path)?,
+            restore_sha256: None,
+        });
+        job_file_ids.push(df.content_file_id.iter().cloned().collect());
+    }
 
-            match extract_rkp_entry(archive, &entry_name, &target) {
-                Ok(_) => {
-                    data_file_success += 1;
-                    let actual = get_actual_path_on_disk(&target);
-                    let actual_str = actual.to_string_lossy().to_string();
-                    data_actual_paths.insert(cf_id.to_string(), actual_str);
-                }
-                Err(e) => {
-                    progress(&format!(
-                        "警告: データファイル展開失敗: {}: {}",
-                        entry_name, e
-                    ));
-                    data_file_fail += 1;
+    let results = extract_entries_parallel(pack_path, None, passphrase, &jobs);
+    let total_data = jobs.len();
+    for (idx, ((job, cf_ids), result)) in jobs.iter().zip(job_file_ids).zip(results).enumerate() {
+        progress(&format!(
+            "データファイル展開 ({}/{}) {}",
+            idx + 1,
+            total_data,
+            job.entry_name.trim_start_matches("content_data/")
+        ));
+        match result {
+            Ok(_) => {
+                data_file_success += 1;
+                let actual = get_actual_path_on_disk(&job.target);
+                let actual_str = actual.to_string_lossy().to_string();
+                for cf_id in cf_ids {
+                    data_actual_paths.insert(cf_id, actual_str.clone());
                 }
             }
+            Err(e) => {
+                progress(&format!(
+                    "警告: データファイル展開失敗: {}: {:#}",
+                    job.entry_name, e
+                ));
+                data_file_fail += 1;
+            }
         }
-        progress(&format!(
-            "データファイル配置: 成功={}, スキップ={}, 失敗={}",
-            data_file_success, data_file_skip, data_file_fail
-        ));
     }
+    progress(&format!(
+        "データファイル配置: 成功={}, スキップ={}, 失敗={}",
+        data_file_success, data_file_skip, data_file_fail
+    ));
 
     Ok(data_actual_paths)
 }
@@ -572,20 +1545,18 @@ fn get_target_db_info(conn: &Connection) -> (Option<String>, Option<String>) {
     (target_dbid, target_device_id)
 }
 
-fn get_share_dir() -> PathBuf {
+pub(crate) fn get_share_dir() -> PathBuf {
     if cfg!(target_os = "macos") {
         let home = std::env::var("HOME").unwrap_or_default();
         let candidates = [
-            PathBuf::from(&home)
-                .join("Library/Application Support/Pioneer/rekordbox/share"),
+            PathBuf::from(&home).join("Library/Application Support/Pioneer/rekordbox/share"),
             PathBuf::from(&home).join("Library/Pioneer/rekordbox/share"),
         ];
         candidates
             .into_iter()
             .find(|p| p.exists())
             .unwrap_or_else(|| {
-                PathBuf::from(&home)
-                    .join("Library/Application Support/Pioneer/rekordbox/share")
+                PathBuf::from(&home).join("Library/Application Support/Pioneer/rekordbox/share")
             })
     } else if cfg!(target_os = "windows") {
         let appdata = std::env::var("APPDATA").unwrap_or_default();
@@ -599,16 +1570,41 @@ fn get_share_dir() -> PathBuf {
     }
 }
 
+/// 行を挿入する。インポート済みの基準に適用する場合は、作成済みの行 (同じ ID) を更新する
+fn write_row(tx: &Connection, table: &str, row: &Row) -> Result<()> {
+    let exists = row
+        .get("ID")
+        .and_then(|v| v.as_str())
+        .is_some_and(|id| row_exists(tx, table, id));
+    if exists {
+        update_row(tx, table, row)
+    } else {
+        insert_row(tx, table, row)
+    }
+}
+
+/// 音声を取り出し直さずに更新する作成済みのトラックで、手元のファイルに合わせて残す列
+const KEPT_CONTENT_COLUMNS: &[&str] = &[
+    "FolderPath",
+    "OrgFolderPath",
+    "rb_LocalFolderPath",
+    "FileNameL",
+    "FileSize",
+    "FileType",
+    "BitRate",
+];
+
 fn insert_master_tables(
     tx: &Connection,
     conn: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
+    tables: &Tables,
     id_map: &IdMap,
+    uuids: &UuidMap,
     inserted_count: &mut u32,
     skipped_count: &mut u32,
 ) -> Result<()> {
     for &table in MASTER_TABLES {
-        let rows = match tables.get(table).and_then(|v| v.as_array()) {
+        let rows = match tables.get(table) {
             Some(r) => r,
             None => continue,
         };
@@ -617,26 +1613,24 @@ fn insert_master_tables(
                 Some(id) => id,
                 None => continue,
             };
-            let mapped_row = apply_mapping(row, table, id_map);
-            let new_id = mapped_row
-                .get("ID")
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
+            let mapped_row = apply_mapping(row, table, id_map, uuids);
+            let new_id = mapped_row.get("ID").and_then(|v| v.as_str()).unwrap_or("");
             let old_id_str = old_id.to_string();
             if let Some(table_map) = id_map.get(table)
-                && let Some(mapped_id) = table_map.get(&old_id_str) {
-                    let exists: bool = conn
-                        .query_row(
-                            &format!("SELECT 1 FROM `{}` WHERE ID = ?", table),
-                            params![mapped_id],
-                            |_| Ok(true),
-                        )
-                        .unwrap_or(false);
-                    if exists {
-                        *skipped_count += 1;
-                        continue;
-                    }
+                && let Some(mapped_id) = table_map.get(&old_id_str)
+            {
+                let exists: bool = conn
+                    .query_row(
+                        &format!("SELECT 1 FROM `{}` WHERE ID = ?", table),
+                        params![mapped_id],
+                        |_| Ok(true),
+                    )
+                    .unwrap_or(false);
+                if exists {
+                    *skipped_count += 1;
+                    continue;
                 }
+            }
             insert_row(tx, table, &mapped_row)
                 .with_context(|| format!("{} への挿入に失敗 (ID: {})", table, new_id))?;
             *inserted_count += 1;
@@ -645,31 +1639,37 @@ fn insert_master_tables(
     Ok(())
 }
 
+/// DB への挿入で共通に使う接続・ID 対応表と、挿入・スキップした行数
+struct InsertContext<'a> {
+    tx: &'a Connection,
+    id_map: &'a IdMap,
+    uuids: &'a UuidMap,
+    inserted_count: u32,
+    skipped_count: u32,
+}
+
 fn insert_content_rows(
-    tx: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
-    id_map: &IdMap,
+    ctx: &mut InsertContext,
+    tables: &Tables,
     skipped_content_ids: &HashSet<String>,
-    audio_actual_paths: &HashMap<String, String>,
+    audio_actual_paths: &HashMap<String, ExtractedAudio>,
     dest_dir: &str,
     target_dbid: &Option<String>,
     target_device_id: &Option<String>,
-    inserted_count: &mut u32,
-    skipped_count: &mut u32,
 ) -> Result<()> {
     let content_table = "djmdContent";
-    if let Some(rows) = tables.get(content_table).and_then(|v| v.as_array()) {
+    if let Some(rows) = tables.get(content_table) {
         for row in rows {
             let old_id = match row.get("ID").and_then(|v| v.as_str()) {
                 Some(id) => id.to_string(),
                 None => continue,
             };
             if skipped_content_ids.contains(&old_id) {
-                *skipped_count += 1;
+                ctx.skipped_count += 1;
                 continue;
             }
 
-            let mut mapped_row = apply_mapping(row, content_table, id_map);
+            let mut mapped_row = apply_mapping(row, content_table, ctx.id_map, ctx.uuids);
 
             if let Some(obj) = mapped_row.as_object_mut() {
                 if let Some(dbid) = target_dbid {
@@ -687,72 +1687,170 @@ fn insert_content_rows(
             }
 
             if let Some(obj) = mapped_row.as_object_mut() {
-                if let Some(actual_path) = audio_actual_paths.get(&old_id) {
-                    obj.insert(
-                        "FolderPath".to_string(),
-                        serde_json::Value::String(actual_path.clone()),
-                    );
-                    obj.insert(
-                        "rb_LocalFolderPath".to_string(),
-                        serde_json::Value::String(actual_path.clone()),
-                    );
-                } else {
-                    let dest_normalized = dest_dir.replace('\\', "/");
-                    let dest_with_slash = if dest_normalized.ends_with('/') {
-                        dest_normalized.clone()
-                    } else {
-                        format!("{}/", dest_normalized)
-                    };
+                let extracted = audio_actual_paths.get(&old_id);
+                let folder_path = match extracted {
+                    Some(extracted) => extracted.path.clone(),
+                    None => {
+                        let dest_normalized = dest_dir.replace('\\', "/");
+                        if dest_normalized.ends_with('/') {
+                            dest_normalized
+                        } else {
+                            format!("{}/", dest_normalized)
+                        }
+                    }
+                };
+                // OrgFolderPath は送り手の元のパス (またはパック内の相対パス) なので展開先に合わせる
+                if obj.get("OrgFolderPath").is_some_and(|v| !v.is_null()) {
                     obj.insert(
-                        "FolderPath".to_string(),
-                        serde_json::Value::String(dest_with_slash.clone()),
+                        "OrgFolderPath".to_string(),
+                        serde_json::Value::String(folder_path.clone()),
                     );
+                }
+                obj.insert(
+                    "FolderPath".to_string(),
+                    serde_json::Value::String(folder_path.clone()),
+                );
+                obj.insert(
+                    "rb_LocalFolderPath".to_string(),
+                    serde_json::Value::String(folder_path.clone()),
+                );
+                // タグを書き込んだコピーや FLAC のまま配置したトラックは、
+                // ファイルのサイズ (FLAC は種類・名前・ビットレートも) を合わせる
+                if let Some(file_size) = extracted.and_then(|e| e.file_size) {
+                    obj.insert("FileSize".to_string(), serde_json::json!(file_size));
+                }
+                if let Some(bit_rate) = extracted.and_then(|e| e.kept_flac) {
+                    let file_name = folder_path.rsplit('/').next().unwrap_or_default();
                     obj.insert(
-                        "rb_LocalFolderPath".to_string(),
-                        serde_json::Value::String(dest_with_slash),
+                        "FileType".to_string(),
+                        serde_json::json!(flac::FILE_TYPE_FLAC),
                     );
+                    obj.insert("FileNameL".to_string(), serde_json::json!(file_name));
+                    if bit_rate > 0 {
+                        obj.insert("BitRate".to_string(), serde_json::json!(bit_rate));
+                    }
                 }
             }
 
-            insert_row(tx, content_table, &mapped_row)
-                .with_context(|| {
-                    format!("djmdContent への挿入に失敗 (old ID: {})", old_id)
-                })?;
-            *inserted_count += 1;
+            let new_id = mapped_row
+                .get("ID")
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string();
+            if row_exists(ctx.tx, content_table, &new_id) {
+                // インポート済みの基準のトラックは、音声を取り出し直していなければ配置先を変えない
+                if !audio_actual_paths.contains_key(&old_id)
+                    && let Some(obj) = mapped_row.as_object_mut()
+                {
+                    for column in KEPT_CONTENT_COLUMNS {
+                        obj.remove(*column);
+                    }
+                }
+                update_row(ctx.tx, content_table, &mapped_row)
+                    .with_context(|| format!("djmdContent の更新に失敗 (old ID: {})", old_id))?;
+            } else {
+                insert_row(ctx.tx, content_table, &mapped_row)
+                    .with_context(|| format!("djmdContent への挿入に失敗 (old ID: {})", old_id))?;
+            }
+            ctx.inserted_count += 1;
         }
     }
     Ok(())
 }
 
-fn insert_related_tables(
-    tx: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
+/// pack.json に書かれた '/' 区切りの相対パスを dir 以下のパスにする。
+/// `..`・ルート・ドライブを含むパスは dir の外を指しうるのでエラーにする
+fn pack_relative_path(dir: &std::path::Path, rel_path: &str) -> Result<PathBuf> {
+    let native_rel = rel_path.replace('/', std::path::MAIN_SEPARATOR_STR);
+    let rel = std::path::Path::new(&native_rel);
+    let normal = rel
+        .components()
+        .all(|c| matches!(c, std::path::Component::Normal(_)));
+    if !normal || rel.as_os_str().is_empty() {
+        anyhow::bail!("パック内のパスが不正です: {}", rel_path);
+    }
+    Ok(dir.join(rel))
+}
+
+/// PIONEER フォルダからのパスに対応する、手元の share フォルダ内のパス
+fn share_local_path(share_dir: &std::path::Path, rel_path: &str) -> Result<String> {
+    let path = pack_relative_path(share_dir, rel_path.trim_start_matches('/'))?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// imageFile の行が指す対象の新しい ID。対象がパックにない・重複トラックとしてスキップする・
+/// 既に画像が登録されている場合は None (行を挿入しない)
+fn image_file_target(
+    conn: &Connection,
+    row: &Row,
     id_map: &IdMap,
     skipped_content_ids: &HashSet<String>,
     update_content_ids: &HashSet<String>,
+) -> Option<String> {
+    let table = row.get("TableName").and_then(|v| v.as_str())?;
+    let old_target = row.get("TargetID").and_then(|v| v.as_str())?;
+    if table == "djmdContent"
+        && (skipped_content_ids.contains(old_target) || update_content_ids.contains(old_target))
+    {
+        return None;
+    }
+    let target = id_map.get(table)?.get(old_target)?;
+    let exists = conn
+        .query_row(
+            "SELECT 1 FROM imageFile WHERE TableName = ? AND TargetID = ? AND rb_local_deleted = 0",
+            params![table, target],
+            |_| Ok(()),
+        )
+        .is_ok();
+    (!exists).then(|| target.clone())
+}
+
+fn insert_related_tables(
+    ctx: &mut InsertContext,
+    reader: &mut TableReader,
+    skipped_content_ids: &HashSet<String>,
+    update_content_ids: &HashSet<String>,
     data_actual_paths: &HashMap<String, String>,
     share_dir: &std::path::Path,
-    inserted_count: &mut u32,
-    skipped_count: &mut u32,
+    banklist_tree_ids: &HashSet<String>,
 ) -> Result<()> {
     for &table in RELATED_TABLES {
-        let rows = match tables.get(table).and_then(|v| v.as_array()) {
-            Some(r) => r,
-            None => continue,
-        };
-        for row in rows {
-            if let Some(cid) = row.get("ContentID").and_then(|v| v.as_str()) {
+        reader.for_each(table, |row| {
+            // 新規作成するバンクリストのエントリは重複トラックでも既存トラックを指して挿入する
+            let in_banklist_tree = row
+                .get("HotCueBanklistID")
+                .and_then(|v| v.as_str())
+                .is_some_and(|id| banklist_tree_ids.contains(id));
+            if let Some(cid) = row.get("ContentID").and_then(|v| v.as_str())
+                && !in_banklist_tree
+            {
                 if skipped_content_ids.contains(cid) {
-                    *skipped_count += 1;
-                    continue;
+                    ctx.skipped_count += 1;
+                    return Ok(());
                 }
                 if table == "contentFile" && update_content_ids.contains(cid) {
-                    *skipped_count += 1;
-                    continue;
+                    ctx.skipped_count += 1;
+                    return Ok(());
                 }
             }
-            let mut mapped_row = apply_mapping(row, table, id_map);
-
+            let image_target = if table == "imageFile" {
+                match image_file_target(
+                    ctx.tx,
+                    row,
+                    ctx.id_map,
+                    skipped_content_ids,
+                    update_content_ids,
+                ) {
+                    Some(target) => Some(target),
+                    None => {
+                        ctx.skipped_count += 1;
+                        return Ok(());
+                    }
+                }
+            } else {
+                None
+            };
+            let mut mapped_row = apply_mapping(row, table, ctx.id_map, ctx.uuids);
             if table == "contentFile" {
                 let cf_id = row.get("ID").and_then(|v| v.as_str()).unwrap_or("");
                 if let Some(actual_path) = data_actual_paths.get(cf_id) {
@@ -767,65 +1865,432 @@ fn insert_related_tables(
                         .get("Path")
                         .and_then(|v| v.as_str())
                         .map(|s| s.to_string())
-                    {
-                        let rel_trimmed = rel_path.trim_start_matches('/');
-                        let native_rel = rel_trimmed.replace('/', std::path::MAIN_SEPARATOR_STR);
-                        let new_local = share_dir.join(&native_rel);
-                        let new_local_str = new_local.to_string_lossy().to_string();
-                        obj.insert(
-                            "rb_local_path".to_string(),
-                            serde_json::Value::String(new_local_str),
-                        );
-                    }
+                {
+                    obj.insert(
+                        "rb_local_path".to_string(),
+                        serde_json::Value::String(share_local_path(share_dir, &rel_path)?),
+                    );
+                }
+            }
+            if let Some(target) = image_target
+                && let Some(obj) = mapped_row.as_object_mut()
+            {
+                if let Some(target_uuid) = obj
+                    .get("TableName")
+                    .and_then(|v| v.as_str())
+                    .and_then(|table| ctx.uuids.get(table, &target))
+                {
+                    obj.insert(
+                        "TargetUUID".to_string(),
+                        serde_json::Value::String(target_uuid.to_string()),
+                    );
+                }
+                obj.insert("TargetID".to_string(), serde_json::Value::String(target));
+                let local_path = obj
+                    .get("Path")
+                    .and_then(|v| v.as_str())
+                    .map(|path| share_local_path(share_dir, path))
+                    .transpose()?;
+                obj.insert(
+                    "rb_local_path".to_string(),
+                    local_path
+                        .map(serde_json::Value::String)
+                        .unwrap_or_default(),
+                );
             }
 
-            remap_json_blob(&mut mapped_row, table, id_map);
+            remap_json_blob(&mut mapped_row, table, ctx.id_map);
 
-            let new_id = mapped_row
-                .get("ID")
-                .and_then(|v| v.as_str())
-                .unwrap_or("?");
-            insert_row(tx, table, &mapped_row)
+            let new_id = mapped_row.get("ID").and_then(|v| v.as_str()).unwrap_or("?");
+            write_row(ctx.tx, table, &mapped_row)
                 .with_context(|| format!("{} への挿入に失敗 (ID: {})", table, new_id))?;
+            ctx.inserted_count += 1;
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// 親の配下で次に使う Seq
+fn next_seq(conn: &Connection, table: &str, parent_id: &str) -> i64 {
+    let max_seq: Option<i64> = conn
+        .query_row(
+            &format!(
+                "SELECT MAX(Seq) FROM `{}` WHERE ParentID = ? AND rb_local_deleted = 0",
+                table
+            ),
+            params![parent_id],
+            |row| row.get(0),
+        )
+        .unwrap_or(None);
+    max_seq.unwrap_or(0) + 1
+}
+
+/// 元の Seq 順に、親ごとに 1 から振り直した Seq を返す (キーは元の ID)
+fn renumber_child_seqs<'a>(
+    rows: impl Iterator<Item = &'a serde_json::Value>,
+) -> HashMap<&'a str, i64> {
+    let mut by_seq: Vec<&serde_json::Value> = rows.collect();
+    by_seq.sort_by_key(|r| r.get("Seq").and_then(|v| v.as_i64()).unwrap_or(0));
+    let mut seq_by_parent: HashMap<&str, i64> = HashMap::new();
+    let mut new_seqs = HashMap::new();
+    for row in by_seq {
+        let (Some(id), Some(parent)) = (
+            row.get("ID").and_then(|v| v.as_str()),
+            row.get("ParentID").and_then(|v| v.as_str()),
+        ) else {
+            continue;
+        };
+        let seq = seq_by_parent.entry(parent).or_insert(0);
+        *seq += 1;
+        new_seqs.insert(id, *seq);
+    }
+    new_seqs
+}
+
+fn resolve_parent_playlist(conn: &Connection, parent_id: Option<&str>) -> Result<String> {
+    let Some(parent_id) = parent_id.filter(|p| *p != "root") else {
+        return Ok("root".to_string());
+    };
+    let exists: bool = conn
+        .query_row(
+            "SELECT 1 FROM djmdPlaylist WHERE ID = ? AND rb_local_deleted = 0",
+            params![parent_id],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+    if !exists {
+        anyhow::bail!("親フォルダ ID '{}' が見つかりません", parent_id);
+    }
+    Ok(parent_id.to_string())
+}
+
+/// スマートプレイリストの条件内の ID を移行先の ID に書き換える
+fn remap_playlist_smart_list(row: &mut serde_json::Value, id_map: &IdMap) -> Result<()> {
+    let Some(obj) = row.as_object_mut() else {
+        return Ok(());
+    };
+    let Some(smart_list) = obj
+        .get("SmartList")
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.trim().is_empty())
+    else {
+        return Ok(());
+    };
+    let remapped = remap_smart_list(smart_list, id_map, "djmdPlaylist")
+        .context("スマートプレイリストの条件の書き換えに失敗")?;
+    obj.insert("SmartList".to_string(), serde_json::Value::String(remapped));
+    Ok(())
+}
+
+fn insert_histories_and_songs(
+    tx: &Connection,
+    tables: &Tables,
+    pack_data: &PackManifest,
+    id_map: &IdMap,
+    uuids: &UuidMap,
+    inserted_count: &mut u32,
+) -> Result<()> {
+    let folders = tables
+        .get("djmdHistory")
+        .map(|a| a.as_slice())
+        .unwrap_or_default();
+    for row in folders.iter().chain(&pack_data.histories) {
+        let mut mapped = apply_mapping(row, "djmdHistory", id_map, uuids);
+        let new_id = mapped
+            .get("ID")
+            .and_then(|v| v.as_str())
+            .unwrap_or("?")
+            .to_string();
+        // 既存のフォルダに合流したものは挿入しない
+        let exists: bool = tx
+            .query_row(
+                "SELECT 1 FROM djmdHistory WHERE ID = ?",
+                params![new_id],
+                |_| Ok(true),
+            )
+            .unwrap_or(false);
+        if exists {
+            continue;
+        }
+        let parent_id = mapped
+            .get("ParentID")
+            .and_then(|v| v.as_str())
+            .filter(|p| id_map["djmdHistory"].values().any(|id| id == p))
+            .unwrap_or("root")
+            .to_string();
+        if let Some(obj) = mapped.as_object_mut() {
+            obj.insert(
+                "ParentID".to_string(),
+                serde_json::Value::String(parent_id.clone()),
+            );
+            obj.insert(
+                "Seq".to_string(),
+                serde_json::Value::Number(next_seq(tx, "djmdHistory", &parent_id).into()),
+            );
+        }
+        insert_row(tx, "djmdHistory", &mapped)
+            .with_context(|| format!("djmdHistory への挿入に失敗 (ID: {})", new_id))?;
+        *inserted_count += 1;
+    }
+
+    if let Some(rows) = tables.get("djmdSongHistory") {
+        for row in rows {
+            let mapped_row = apply_mapping(row, "djmdSongHistory", id_map, uuids);
+            let new_id = mapped_row.get("ID").and_then(|v| v.as_str()).unwrap_or("?");
+            write_row(tx, "djmdSongHistory", &mapped_row)
+                .with_context(|| format!("djmdSongHistory への挿入に失敗 (ID: {})", new_id))?;
             *inserted_count += 1;
         }
     }
+
     Ok(())
 }
 
+/// 単独でパックされたリストのツリーを作成する。
+/// 指定されたリストはルート直下に、配下は元の親の下に配置する
+fn insert_list_trees(
+    tx: &Connection,
+    pack_data: &PackManifest,
+    id_map: &IdMap,
+    uuids: &UuidMap,
+    inserted_count: &mut u32,
+) -> Result<()> {
+    for &(key, table, _) in LIST_TREES {
+        let rows = pack_data.top_level_rows(key);
+        insert_list_tree(tx, table, rows, id_map, uuids, inserted_count)?;
+    }
+    Ok(())
+}
+
+/// 単独でパックされたリストのエントリを挿入する。トラックの挿入後に呼ぶ。
+/// 重複としてスキップしたトラックと照合できずに除いたトラックのエントリは挿入しない。
+/// バンクリストのエントリは RELATED_TABLES として挿入される
+fn insert_list_songs(
+    tx: &Connection,
+    tables: &Tables,
+    id_map: &IdMap,
+    uuids: &UuidMap,
+    skipped_content_ids: &HashSet<String>,
+    inserted_count: &mut u32,
+    skipped_count: &mut u32,
+) -> Result<()> {
+    let content_map = id_map.get("djmdContent");
+    for song_table in LIST_TREES
+        .iter()
+        .filter_map(|&(_, _, song_table)| song_table)
+    {
+        let Some(songs) = tables.get(song_table) else {
+            continue;
+        };
+        for row in songs {
+            let cid = row.get("ContentID").and_then(|v| v.as_str());
+            let inserted_content = cid.is_some_and(|cid| {
+                !skipped_content_ids.contains(cid)
+                    && content_map.is_some_and(|map| map.contains_key(cid))
+            });
+            if !inserted_content {
+                *skipped_count += 1;
+                continue;
+            }
+            let mapped_row = apply_mapping(row, song_table, id_map, uuids);
+            let new_id = mapped_row.get("ID").and_then(|v| v.as_str()).unwrap_or("?");
+            write_row(tx, song_table, &mapped_row)
+                .with_context(|| format!("{} への挿入に失敗 (ID: {})", song_table, new_id))?;
+            *inserted_count += 1;
+        }
+    }
+    Ok(())
+}
+
+fn insert_list_tree(
+    tx: &Connection,
+    table: &str,
+    rows: &[serde_json::Value],
+    id_map: &IdMap,
+    uuids: &UuidMap,
+    inserted_count: &mut u32,
+) -> Result<()> {
+    let tree_ids = collect_ids_from_column(rows, "ID");
+    let is_root = |row: &serde_json::Value| {
+        !row.get("ParentID")
+            .and_then(|v| v.as_str())
+            .is_some_and(|p| tree_ids.contains(p))
+    };
+    let new_seqs = renumber_child_seqs(rows.iter().filter(|r| !is_root(r)));
+
+    for row in rows {
+        let mut mapped = apply_mapping(row, table, id_map, uuids);
+        if table == "djmdRelatedTracks" {
+            remap_related_tracks_criteria(&mut mapped, id_map);
+        }
+        let new_id = mapped
+            .get("ID")
+            .and_then(|v| v.as_str())
+            .unwrap_or("?")
+            .to_string();
+        if row_exists(tx, table, &new_id) {
+            // インポート済みのリストは置き場所を変えずに更新する
+            if let Some(obj) = mapped.as_object_mut() {
+                obj.remove("ParentID");
+                obj.remove("Seq");
+            }
+            update_row(tx, table, &mapped)
+                .with_context(|| format!("{} の更新に失敗 (ID: {})", table, new_id))?;
+            continue;
+        }
+        let seq = if is_root(row) {
+            if let Some(obj) = mapped.as_object_mut() {
+                obj.insert(
+                    "ParentID".to_string(),
+                    serde_json::Value::String("root".to_string()),
+                );
+            }
+            next_seq(tx, table, "root")
+        } else {
+            row.get("ID")
+                .and_then(|v| v.as_str())
+                .and_then(|id| new_seqs.get(id))
+                .copied()
+                .unwrap_or(1)
+        };
+        if let Some(obj) = mapped.as_object_mut() {
+            obj.insert("Seq".to_string(), serde_json::Value::Number(seq.into()));
+        }
+        let new_id = mapped.get("ID").and_then(|v| v.as_str()).unwrap_or("?");
+        insert_row(tx, table, &mapped)
+            .with_context(|| format!("{} への挿入に失敗 (ID: {})", table, new_id))?;
+        *inserted_count += 1;
+    }
+    Ok(())
+}
+
+/// 関連トラックの Criteria が SmartList と同じ形式であれば ID を書き換える。
+/// 解析できない形式の場合はそのまま残す
+fn remap_related_tracks_criteria(row: &mut serde_json::Value, id_map: &IdMap) {
+    let Some(obj) = row.as_object_mut() else {
+        return;
+    };
+    let Some(criteria) = obj
+        .get("Criteria")
+        .and_then(|v| v.as_str())
+        .filter(|s| s.trim_start().starts_with('<'))
+    else {
+        return;
+    };
+    if let Ok(remapped) = remap_smart_list(criteria, id_map, "djmdRelatedTracks") {
+        obj.insert("Criteria".to_string(), serde_json::Value::String(remapped));
+    }
+}
+
 fn insert_playlist_and_songs(
     tx: &Connection,
-    tables: &serde_json::Map<String, serde_json::Value>,
-    pack_data: &serde_json::Value,
+    tables: &Tables,
+    pack_data: &PackManifest,
     id_map: &IdMap,
+    uuids: &UuidMap,
+    parent_id: &str,
     inserted_count: &mut u32,
 ) -> Result<()> {
-    if let Some(playlist) = pack_data.get("playlist") {
-        let mapped_playlist = apply_mapping(playlist, "djmdPlaylist", id_map);
-        let mut mapped = mapped_playlist.clone();
+    for playlist in &pack_data.playlists {
+        let mut mapped = apply_mapping(playlist, "djmdPlaylist", id_map, uuids);
+        remap_playlist_smart_list(&mut mapped, id_map)?;
+        let new_id = mapped
+            .get("ID")
+            .and_then(|v| v.as_str())
+            .unwrap_or("?")
+            .to_string();
+        if row_exists(tx, "djmdPlaylist", &new_id) {
+            // インポート済みのプレイリストは置き場所を変えずに更新する
+            if let Some(obj) = mapped.as_object_mut() {
+                obj.remove("ParentID");
+                obj.remove("Seq");
+            }
+            update_row(tx, "djmdPlaylist", &mapped).context("djmdPlaylist の更新に失敗")?;
+            continue;
+        }
         if let Some(obj) = mapped.as_object_mut() {
             obj.insert(
                 "ParentID".to_string(),
-                serde_json::Value::String("root".to_string()),
+                serde_json::Value::String(parent_id.to_string()),
+            );
+            obj.insert(
+                "Seq".to_string(),
+                serde_json::Value::Number(next_seq(tx, "djmdPlaylist", parent_id).into()),
             );
         }
         insert_row(tx, "djmdPlaylist", &mapped).context("djmdPlaylist への挿入に失敗")?;
         *inserted_count += 1;
     }
 
-    if let Some(rows) = tables
-        .get("djmdSongPlaylist")
-        .and_then(|v| v.as_array())
-    {
+    // 配下のフォルダ/プレイリスト (親→子の順に並んでいる)
+    if let Some(rows) = tables.get("djmdPlaylist") {
+        let mapped_id = |row: &Row| -> Option<&String> {
+            id_map.get("djmdPlaylist")?.get(row.get("ID")?.as_str()?)
+        };
+        let mapped_parent = |row: &Row| -> String {
+            let parent = row
+                .get("ParentID")
+                .and_then(|v| v.as_str())
+                .unwrap_or("root");
+            id_map
+                .get("djmdPlaylist")
+                .and_then(|m| m.get(parent))
+                .cloned()
+                .unwrap_or_else(|| parent.to_string())
+        };
+        let is_new =
+            |row: &Row| mapped_id(row).is_none_or(|id| !row_exists(tx, "djmdPlaylist", id));
+        let new_rows: Vec<&Row> = rows.iter().filter(|row| is_new(row)).collect();
+        let new_seqs = renumber_child_seqs(new_rows.iter().copied());
+        // 作成済みのフォルダに加わる行は、既にある行の後ろに並べる
+        let mut seq_offsets: HashMap<String, i64> = HashMap::new();
+        for row in &new_rows {
+            seq_offsets
+                .entry(mapped_parent(row))
+                .or_insert_with_key(|parent| next_seq(tx, "djmdPlaylist", parent) - 1);
+        }
+
         for row in rows {
-            let mapped_row = apply_mapping(row, "djmdSongPlaylist", id_map);
-            let new_id = mapped_row
+            let mut mapped = apply_mapping(row, "djmdPlaylist", id_map, uuids);
+            remap_playlist_smart_list(&mut mapped, id_map)?;
+            let new_id = mapped
                 .get("ID")
                 .and_then(|v| v.as_str())
-                .unwrap_or("?");
-            insert_row(tx, "djmdSongPlaylist", &mapped_row).with_context(|| {
-                format!("djmdSongPlaylist への挿入に失敗 (ID: {})", new_id)
-            })?;
+                .unwrap_or("?")
+                .to_string();
+            if !is_new(row) {
+                if let Some(obj) = mapped.as_object_mut() {
+                    obj.remove("Seq");
+                }
+                update_row(tx, "djmdPlaylist", &mapped)
+                    .with_context(|| format!("djmdPlaylist の更新に失敗 (ID: {})", new_id))?;
+                continue;
+            }
+            if let Some(seq) = row
+                .get("ID")
+                .and_then(|v| v.as_str())
+                .and_then(|id| new_seqs.get(id))
+                && let Some(obj) = mapped.as_object_mut()
+            {
+                let offset = seq_offsets.get(&mapped_parent(row)).copied().unwrap_or(0);
+                obj.insert(
+                    "Seq".to_string(),
+                    serde_json::Value::Number((offset + *seq).into()),
+                );
+            }
+            insert_row(tx, "djmdPlaylist", &mapped)
+                .with_context(|| format!("djmdPlaylist への挿入に失敗 (ID: {})", new_id))?;
+            *inserted_count += 1;
+        }
+    }
+
+    if let Some(rows) = tables.get("djmdSongPlaylist") {
+        for row in rows {
+            let mapped_row = apply_mapping(row, "djmdSongPlaylist", id_map, uuids);
+            let new_id = mapped_row.get("ID").and_then(|v| v.as_str()).unwrap_or("?");
+            write_row(tx, "djmdSongPlaylist", &mapped_row)
+                .with_context(|| format!("djmdSongPlaylist への挿入に失敗 (ID: {})", new_id))?;
             *inserted_count += 1;
         }
     }
@@ -833,43 +2298,153 @@ fn insert_playlist_and_songs(
     Ok(())
 }
 
-pub fn unpack_playlist(
+/// 重複トラックの扱いの決め方
+#[derive(Clone, Copy)]
+enum DuplicatePolicy<'a> {
+    /// 重複を検出してトラックごとに confirm で尋ねる (音声なしパックは手元のトラックに照合する)
+    Confirm(&'a dyn Fn(&DuplicateInfo) -> bool),
+    /// プレビューで決めた扱いに従う
+    Decided(&'a UnpackDecisions),
+}
+
+/// .rkp を DB に取り込む。playlist_names はプレビューで編集したプレイリスト名
+fn import_pack(
     conn: &Connection,
     pack_path: &str,
     dest_dir: &str,
+    playlist_names: Option<&[String]>,
+    options: &UnpackOptions,
+    policy: DuplicatePolicy,
     progress: &dyn Fn(&str),
-    confirm: &dyn Fn(&DuplicateInfo) -> bool,
 ) -> Result<()> {
+    let parent_id = resolve_parent_playlist(conn, options.parent_id.as_deref())?;
+
     let rkp_path = PathBuf::from(pack_path);
-    let rkp_file = fs::File::open(&rkp_path)
-        .with_context(|| format!(".rkp ファイルを開けません: {}", rkp_path.display()))?;
-    let mut archive = ZipArchive::new(rkp_file)
-        .with_context(|| format!(".rkp ファイルの解析に失敗: {}", rkp_path.display()))?;
+    let passphrase = options.passphrase.as_deref();
+    let mut archive = open_rkp(&rkp_path, passphrase)?;
 
-    let pack_data = load_pack_data(&mut archive)?;
+    let mut pack_data = load_pack_data(&mut archive)?;
+    let delta_base = resolve_delta_base(
+        conn,
+        pack_path,
+        &mut pack_data,
+        options.base_path.as_deref(),
+        passphrase,
+        progress,
+    )?;
+    ensure_pack_valid(&rkp_path, passphrase, &delta_base, &pack_data, progress)?;
+    let imported = imported_ids(&delta_base);
+    let base_contents = imported_base_contents(&delta_base);
+    let base_audio = imported_base_audio(&pack_data, &base_contents);
+
+    // プレビューで編集された名前は元のプレイリストの分だけ (変換した履歴は後ろに追加される)
+    if options.histories_as_playlists {
+        convert_histories_to_playlists(&mut pack_data);
+    }
 
-    let tables = pack_data["tables"]
-        .as_object()
-        .context("tables が見つかりません")?;
+    if let Some(names) = playlist_names {
+        for (playlist, name) in pack_data.playlists.iter_mut().zip(names) {
+            if let Some(obj) = playlist.as_object_mut() {
+                obj.insert("Name".to_string(), serde_json::Value::String(name.clone()));
+            }
+        }
+    }
+
+    let lite = pack_data.metadata.lite;
+    // 音声なしパックは、手元のトラックに照合できたものだけを取り込む
+    let lite_matches = match policy {
+        _ if !lite => None,
+        DuplicatePolicy::Confirm(_) => {
+            progress("音声なしパック: 手元のトラックに照合します");
+            Some(match_lite_contents(conn, &pack_data.tables, progress)?)
+        }
+        DuplicatePolicy::Decided(decisions) => Some(decisions.existing_content_map.clone()),
+    };
+    let dropped_contents = match &lite_matches {
+        Some(matched) => drop_unmatched_contents(&mut pack_data, matched),
+        None => HashSet::new(),
+    };
+    let unmatched_count = dropped_contents.len();
+    let analysis_content_ids: HashSet<String> = if lite {
+        contents_with_packed_analysis(&pack_data)
+    } else {
+        HashSet::new()
+    };
+    rewrite_image_paths(&mut pack_data);
+
+    let tables = &pack_data.tables;
+    let mut reader = TableReader::new(&pack_data, &mut archive)
+        .excluding_contents(dropped_contents)
+        .with_base(delta_base_tables(&delta_base, passphrase)?);
 
     let (skipped_content_ids, update_content_ids, existing_content_map) =
-        detect_duplicate_contents(conn, tables, progress, confirm)?;
+        match (policy, lite_matches) {
+            (DuplicatePolicy::Decided(decisions), _) => (
+                decisions.skipped_content_ids.clone(),
+                // インポート済みの基準のトラックは関連データを置き換えず、行ごとに更新する
+                decisions
+                    .update_content_ids
+                    .iter()
+                    .filter(|cid| !base_contents.contains_key(*cid))
+                    .cloned()
+                    .collect(),
+                decisions.existing_content_map.clone(),
+            ),
+            // 照合できたトラックはすべて既存トラックへの更新として扱う
+            (DuplicatePolicy::Confirm(_), Some(matched)) => {
+                (HashSet::new(), matched.keys().cloned().collect(), matched)
+            }
+            (DuplicatePolicy::Confirm(confirm), None) => {
+                let cue_counts = count_pack_cues(&mut reader)?;
+                detect_duplicate_contents(
+                    conn,
+                    tables,
+                    &cue_counts,
+                    &base_contents,
+                    progress,
+                    confirm,
+                )?
+            }
+        };
 
     let mut id_map: IdMap = HashMap::new();
     build_master_id_map(conn, tables, &mut id_map)?;
     build_content_id_map(conn, tables, &existing_content_map, &mut id_map)?;
-    build_related_id_maps(conn, tables, &pack_data, &mut id_map)?;
+    build_related_id_maps(conn, tables, &pack_data, &mut reader, &mut id_map)?;
+    add_imported_ids(&mut id_map, &imported);
+    let uuids = if options.keep_uuids {
+        UuidMap::default()
+    } else {
+        UuidMap::regenerate(conn, &id_map)?
+    };
 
     let share_dir = get_share_dir();
 
     let audio_skip_ids: HashSet<String> = skipped_content_ids
         .union(&update_content_ids)
+        .chain(&base_audio)
         .cloned()
         .collect();
-    let audio_actual_paths =
-        extract_audio_files(&mut archive, &pack_data, dest_dir, &audio_skip_ids, progress)?;
-    let data_actual_paths =
-        extract_data_files(&mut archive, &pack_data, &share_dir, progress)?;
+    let base_archive_path = match &delta_base {
+        Some(DeltaBase::Archive(base)) => Some(base.as_path()),
+        _ => None,
+    };
+    let audio_actual_paths = extract_audio_files(
+        &rkp_path,
+        base_archive_path,
+        options,
+        &pack_data.audio_files,
+        dest_dir,
+        &audio_skip_ids,
+        progress,
+    )?;
+    let data_actual_paths = extract_data_files(
+        &rkp_path,
+        passphrase,
+        &pack_data.content_data_files,
+        &share_dir,
+        progress,
+    )?;
 
     let (target_dbid, target_device_id) = get_target_db_info(conn);
 
@@ -877,6 +2452,7 @@ pub fn unpack_playlist(
 
     let tx = conn.unchecked_transaction()?;
 
+    let removed_count = delete_removed_rows(&tx, &pack_data, &imported)?;
     // 更新対象の既存関連データを削除
     for pack_cid in &update_content_ids {
         if let Some(existing_cid) = existing_content_map.get(pack_cid) {
@@ -884,57 +2460,129 @@ pub fn unpack_playlist(
             delete_related_rows_for_content(&tx, existing_cid)?;
         }
     }
+    if lite {
+        apply_lite_content_updates(
+            &tx,
+            tables,
+            &update_content_ids,
+            &existing_content_map,
+            &analysis_content_ids,
+        )?;
+    }
 
-    let mut inserted_count = 0u32;
-    let mut skipped_count = 0u32;
+    let mut ctx = InsertContext {
+        tx: &tx,
+        id_map: &id_map,
+        uuids: &uuids,
+        inserted_count: 0,
+        skipped_count: 0,
+    };
 
-    insert_master_tables(&tx, conn, tables, &id_map, &mut inserted_count, &mut skipped_count)?;
+    insert_master_tables(
+        &tx,
+        conn,
+        tables,
+        &id_map,
+        &uuids,
+        &mut ctx.inserted_count,
+        &mut ctx.skipped_count,
+    )?;
+    insert_list_trees(&tx, &pack_data, &id_map, &uuids, &mut ctx.inserted_count)?;
+    let banklist_tree_ids = collect_ids_from_column(&pack_data.hot_cue_banklists, "ID");
 
     let content_skip_ids: HashSet<String> = skipped_content_ids
         .union(&update_content_ids)
         .cloned()
         .collect();
     insert_content_rows(
-        &tx,
+        &mut ctx,
         tables,
-        &id_map,
         &content_skip_ids,
         &audio_actual_paths,
         dest_dir,
         &target_dbid,
         &target_device_id,
-        &mut inserted_count,
-        &mut skipped_count,
     )?;
-
-    insert_related_tables(
+    insert_list_songs(
         &tx,
         tables,
         &id_map,
+        &uuids,
         &skipped_content_ids,
-        &update_content_ids,
+        &mut ctx.inserted_count,
+        &mut ctx.skipped_count,
+    )?;
+
+    // 同梱の分析データで置き換えるトラックは contentFile も挿入する
+    let content_file_skip_ids: HashSet<String> = update_content_ids
+        .difference(&analysis_content_ids)
+        .cloned()
+        .collect();
+    insert_related_tables(
+        &mut ctx,
+        &mut reader,
+        &skipped_content_ids,
+        &content_file_skip_ids,
         &data_actual_paths,
         &share_dir,
-        &mut inserted_count,
-        &mut skipped_count,
+        &banklist_tree_ids,
     )?;
 
-    insert_playlist_and_songs(&tx, tables, &pack_data, &id_map, &mut inserted_count)?;
+    insert_playlist_and_songs(
+        &tx,
+        tables,
+        &pack_data,
+        &id_map,
+        &uuids,
+        &parent_id,
+        &mut ctx.inserted_count,
+    )?;
+
+    insert_histories_and_songs(
+        &tx,
+        tables,
+        &pack_data,
+        &id_map,
+        &uuids,
+        &mut ctx.inserted_count,
+    )?;
+
+    ctx.inserted_count += uuids.insert_uuid_id_map(&tx)?;
+    let InsertContext {
+        inserted_count,
+        skipped_count,
+        ..
+    } = ctx;
 
     tx.commit()?;
+    if let Some(pack_id) = record_pack_id(&pack_data) {
+        save_import_record(conn, pack_id, &id_map, progress);
+    }
 
     progress("アンパック完了!");
     progress(&format!(
         "挿入: {} 行, スキップ(重複等): {} 行",
         inserted_count, skipped_count
     ));
+    if removed_count > 0 {
+        progress(&format!(
+            "基準から取り除かれた行を削除: {} 行",
+            removed_count
+        ));
+    }
     if !skipped_content_ids.is_empty() {
         progress(&format!(
             "重複トラック(スキップ): {} 件",
             skipped_content_ids.len()
         ));
     }
-    if !update_content_ids.is_empty() {
+    if lite {
+        progress(&format!(
+            "既存トラックに適用: {} 件, 照合できずスキップ: {} 件",
+            update_content_ids.len(),
+            unmatched_count
+        ));
+    } else if !update_content_ids.is_empty() {
         progress(&format!(
             "重複トラック(更新): {} 件",
             update_content_ids.len()
@@ -944,39 +2592,75 @@ pub fn unpack_playlist(
     Ok(())
 }
 
+pub fn unpack_playlist(
+    conn: &Connection,
+    pack_path: &str,
+    dest_dir: &str,
+    options: &UnpackOptions,
+    progress: &dyn Fn(&str),
+    confirm: &dyn Fn(&DuplicateInfo) -> bool,
+) -> Result<()> {
+    import_pack(
+        conn,
+        pack_path,
+        dest_dir,
+        None,
+        options,
+        DuplicatePolicy::Confirm(confirm),
+        progress,
+    )
+}
+
 pub fn load_unpack_preview(
     conn: &Connection,
     rkp_path: &str,
+    passphrase: Option<&str>,
 ) -> Result<UnpackPreviewData> {
-    let rkp_file = fs::File::open(rkp_path)
-        .with_context(|| format!(".rkp ファイルを開けません: {}", rkp_path))?;
-    let mut archive = ZipArchive::new(rkp_file)
-        .with_context(|| format!(".rkp ファイルの解析に失敗: {}", rkp_path))?;
-
-    let pack_data = load_pack_data(&mut archive)?;
-
-    let tables = pack_data["tables"]
-        .as_object()
-        .context("tables が見つかりません")?;
-
-    let playlist_name = pack_data
-        .get("playlist")
-        .and_then(|p| p.get("Name"))
-        .and_then(|n| n.as_str())
-        .unwrap_or("(unknown)")
-        .to_string();
+    let mut archive = open_rkp(std::path::Path::new(rkp_path), passphrase)?;
 
-    let contents = tables
-        .get("djmdContent")
-        .and_then(|v| v.as_array())
-        .cloned()
-        .unwrap_or_default();
+    let mut pack_data = load_pack_data(&mut archive)?;
+    let signature = read_pack_signature(&mut archive, &load_trusted_keys(&[])?)?;
+    let delta_base = resolve_delta_base(conn, rkp_path, &mut pack_data, None, passphrase, &|_| {})?;
+    let base_contents = imported_base_contents(&delta_base);
 
-    let artists = tables
-        .get("djmdArtist")
-        .and_then(|v| v.as_array())
-        .cloned()
-        .unwrap_or_default();
+    let tables = &pack_data.tables;
+
+    let playlist_names: Vec<String> = pack_data
+        .playlists
+        .iter()
+        .map(|p| {
+            p.get("Name")
+                .and_then(|n| n.as_str())
+                .unwrap_or("(unknown)")
+                .to_string()
+        })
+        .collect();
+
+    let history_names: Vec<String> = pack_data
+        .histories
+        .iter()
+        .map(|h| {
+            h.get("Name")
+                .and_then(|n| n.as_str())
+                .unwrap_or("(unknown)")
+                .to_string()
+        })
+        .collect();
+
+    let lite = pack_data.metadata.lite;
+    let lite_matches = if lite {
+        match_lite_contents(conn, tables, &|_| {})?
+    } else {
+        HashMap::new()
+    };
+    let cue_counts = count_pack_cues(
+        &mut TableReader::new(&pack_data, &mut archive)
+            .with_base(delta_base_tables(&delta_base, passphrase)?),
+    )?;
+
+    let contents = tables.get("djmdContent").cloned().unwrap_or_default();
+
+    let artists = tables.get("djmdArtist").cloned().unwrap_or_default();
     let artist_map: HashMap<String, String> = artists
         .iter()
         .filter_map(|a| {
@@ -986,11 +2670,7 @@ pub fn load_unpack_preview(
         })
         .collect();
 
-    let albums = tables
-        .get("djmdAlbum")
-        .and_then(|v| v.as_array())
-        .cloned()
-        .unwrap_or_default();
+    let albums = tables.get("djmdAlbum").cloned().unwrap_or_default();
     let album_map: HashMap<String, String> = albums
         .iter()
         .filter_map(|a| {
@@ -1024,29 +2704,26 @@ pub fn load_unpack_preview(
         let artist = artist_map.get(artist_id).cloned().unwrap_or_default();
         let album = album_map.get(album_id).cloned().unwrap_or_default();
 
-        let cues_for_content: Vec<&serde_json::Value> = tables
-            .get("djmdCue")
-            .and_then(|v| v.as_array())
-            .map(|arr| {
-                arr.iter()
-                    .filter(|c| c.get("ContentID").and_then(|v| v.as_str()) == Some(&*pack_cid))
-                    .collect()
-            })
-            .unwrap_or_default();
-
-        let memory_cue_count = cues_for_content
-            .iter()
-            .filter(|c| c.get("Kind").and_then(|v| v.as_i64()) == Some(0))
-            .count();
-
-        let hot_cue_count = cues_for_content
-            .iter()
-            .filter(|c| c.get("Kind").and_then(|v| v.as_i64()) != Some(0))
-            .count();
+        let (memory_cue_count, hot_cue_count) =
+            cue_counts.get(&pack_cid).copied().unwrap_or_default();
 
         // Check for duplicate via contentFile Hash
         let mut duplicate = None;
-        if let Some(content_files) = tables.get("contentFile").and_then(|v| v.as_array()) {
+        if lite {
+            // 音声なしパックは照合できたトラックを更新し、それ以外は登録できないのでスキップ
+            duplicate = lite_matches
+                .get(&pack_cid)
+                .map(|existing_cid| DuplicateMatch {
+                    existing_content_id: existing_cid.clone(),
+                    info: build_duplicate_info(conn, tables, &cue_counts, &pack_cid, existing_cid),
+                });
+        } else if let Some(existing_cid) = base_contents.get(&pack_cid) {
+            // 差分パックの基準として取り込み済みのトラックは更新する
+            duplicate = Some(DuplicateMatch {
+                existing_content_id: existing_cid.clone(),
+                info: build_duplicate_info(conn, tables, &cue_counts, &pack_cid, existing_cid),
+            });
+        } else if let Some(content_files) = tables.get("contentFile") {
             for cf in content_files {
                 let cf_cid = cf.get("ContentID").and_then(|v| v.as_str());
                 let hash = cf.get("Hash").and_then(|v| v.as_str());
@@ -1060,8 +2737,13 @@ pub fn load_unpack_preview(
                             )
                             .ok();
                         if let Some(existing_cid) = existing {
-                            let info =
-                                build_duplicate_info(conn, tables, &pack_cid, &existing_cid);
+                            let info = build_duplicate_info(
+                                conn,
+                                tables,
+                                &cue_counts,
+                                &pack_cid,
+                                &existing_cid,
+                            );
                             duplicate = Some(DuplicateMatch {
                                 existing_content_id: existing_cid,
                                 info,
@@ -1072,7 +2754,13 @@ pub fn load_unpack_preview(
             }
         }
 
-        let decision = if duplicate.is_some() {
+        let decision = if lite || base_contents.contains_key(&pack_cid) {
+            if duplicate.is_some() {
+                DuplicateDecision::Update
+            } else {
+                DuplicateDecision::Skip
+            }
+        } else if duplicate.is_some() {
             DuplicateDecision::Skip
         } else {
             DuplicateDecision::New
@@ -1093,7 +2781,10 @@ pub fn load_unpack_preview(
 
     Ok(UnpackPreviewData {
         rkp_path: rkp_path.to_string(),
-        playlist_name,
+        playlist_names,
+        history_names,
+        lite,
+        signature,
         tracks,
     })
 }
@@ -1145,120 +2836,45 @@ pub fn unpack_playlist_with_decisions(
     pack_path: &str,
     dest_dir: &str,
     decisions: &UnpackDecisions,
-    playlist_name: Option<&str>,
+    playlist_names: Option<&[String]>,
+    options: &UnpackOptions,
     progress: &dyn Fn(&str),
 ) -> Result<()> {
-    let rkp_path = PathBuf::from(pack_path);
-    let rkp_file = fs::File::open(&rkp_path)
-        .with_context(|| format!(".rkp ファイルを開けません: {}", rkp_path.display()))?;
-    let mut archive = ZipArchive::new(rkp_file)
-        .with_context(|| format!(".rkp ファイルの解析に失敗: {}", rkp_path.display()))?;
-
-    let mut pack_data = load_pack_data(&mut archive)?;
-
-    if let Some(name) = playlist_name {
-        if let Some(playlist) = pack_data.get_mut("playlist") {
-            if let Some(obj) = playlist.as_object_mut() {
-                obj.insert(
-                    "Name".to_string(),
-                    serde_json::Value::String(name.to_string()),
-                );
-            }
-        }
-    }
-
-    let tables = pack_data["tables"]
-        .as_object()
-        .context("tables が見つかりません")?;
-
-    let skipped_content_ids = &decisions.skipped_content_ids;
-    let update_content_ids = &decisions.update_content_ids;
-    let existing_content_map = &decisions.existing_content_map;
-
-    let mut id_map: IdMap = HashMap::new();
-    build_master_id_map(conn, tables, &mut id_map)?;
-    build_content_id_map(conn, tables, existing_content_map, &mut id_map)?;
-    build_related_id_maps(conn, tables, &pack_data, &mut id_map)?;
-
-    let share_dir = get_share_dir();
-
-    let audio_skip_ids: HashSet<String> = skipped_content_ids
-        .union(update_content_ids)
-        .cloned()
-        .collect();
-    let audio_actual_paths =
-        extract_audio_files(&mut archive, &pack_data, dest_dir, &audio_skip_ids, progress)?;
-    let data_actual_paths =
-        extract_data_files(&mut archive, &pack_data, &share_dir, progress)?;
-
-    let (target_dbid, target_device_id) = get_target_db_info(conn);
-
-    progress("DBへの挿入を開始...");
-
-    let tx = conn.unchecked_transaction()?;
-
-    for pack_cid in update_content_ids {
-        if let Some(existing_cid) = existing_content_map.get(pack_cid) {
-            progress(&format!("既存データを削除中: ContentID {}", existing_cid));
-            delete_related_rows_for_content(&tx, existing_cid)?;
-        }
-    }
-
-    let mut inserted_count = 0u32;
-    let mut skipped_count = 0u32;
-
-    insert_master_tables(&tx, conn, tables, &id_map, &mut inserted_count, &mut skipped_count)?;
-
-    let content_skip_ids: HashSet<String> = skipped_content_ids
-        .union(update_content_ids)
-        .cloned()
-        .collect();
-    insert_content_rows(
-        &tx,
-        tables,
-        &id_map,
-        &content_skip_ids,
-        &audio_actual_paths,
+    import_pack(
+        conn,
+        pack_path,
         dest_dir,
-        &target_dbid,
-        &target_device_id,
-        &mut inserted_count,
-        &mut skipped_count,
-    )?;
-
-    insert_related_tables(
-        &tx,
-        tables,
-        &id_map,
-        skipped_content_ids,
-        update_content_ids,
-        &data_actual_paths,
-        &share_dir,
-        &mut inserted_count,
-        &mut skipped_count,
-    )?;
-
-    insert_playlist_and_songs(&tx, tables, &pack_data, &id_map, &mut inserted_count)?;
-
-    tx.commit()?;
+        playlist_names,
+        options,
+        DuplicatePolicy::Decided(decisions),
+        progress,
+    )
+}
 
-    progress("アンパック完了!");
-    progress(&format!(
-        "挿入: {} 行, スキップ(重複等): {} 行",
-        inserted_count, skipped_count
-    ));
-    if !skipped_content_ids.is_empty() {
-        progress(&format!(
-            "重複トラック(スキップ): {} 件",
-            skipped_content_ids.len()
-        ));
-    }
-    if !update_content_ids.is_empty() {
-        progress(&format!(
-            "重複トラック(更新): {} 件",
-            update_content_ids.len()
-        ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_relative_path_stays_inside_dir() {
+        let dir = std::path::Path::new("share");
+        assert_eq!(
+            pack_relative_path(dir, "PIONEER/Artwork/rkpack/a.jpg").unwrap(),
+            dir.join("PIONEER")
+                .join("Artwork")
+                .join("rkpack")
+                .join("a.jpg")
+        );
+        for rel in [
+            "",
+            "../a.jpg",
+            "PIONEER/../../a.jpg",
+            "/etc/passwd",
+            "./a.jpg",
+        ] {
+            assert!(pack_relative_path(dir, rel).is_err(), "{}", rel);
+        }
+        assert!(share_local_path(dir, "/PIONEER/USBANLZ/aa/ANLZ0000.DAT").is_ok());
+        assert!(share_local_path(dir, "/PIONEER/../../a.DAT").is_err());
     }
-
-    Ok(())
 }
@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+
+use super::id_mapping::{IdMap, fk_columns_for_table, is_attribute_master};
+use super::manifest::{PackManifest, TableReader};
+use super::sign::{
+    PackSignature, SignatureStatus, TrustedKey, load_trusted_keys, read_pack_signature,
+};
+use super::unpack::{DeltaBase, delta_base_tables, load_pack_data, resolve_delta_base};
+use super::volume::{RkpArchive, open_rkp};
+
+/// .rkp の検証結果
+pub struct VerifyReport {
+    /// 検証したエントリ数
+    pub checked_entries: usize,
+    /// 見つかった問題 (空なら正常)
+    pub problems: Vec<String>,
+    /// 取り込める問題 (パックにない属性のマスタへの参照。アンパック時は参照を外す)
+    pub warnings: Vec<String>,
+    /// pack.json の署名
+    pub signature: PackSignature,
+    /// 差分パックの基準パックの署名 (基準の .rkp と結合した場合だけ)
+    pub base_signature: Option<PackSignature>,
+}
+
+/// 検証するエントリ1つ分
+struct EntryCheck {
+    entry_name: String,
+    /// 差分パックの基準パック側のエントリ
+    in_base: bool,
+    sha256: Option<String>,
+    size: Option<u64>,
+}
+
+/// .rkp のすべてのエントリと行の参照、署名を検証する。差分パックは基準と結合してから検証する。
+/// trusted_key_paths を指定した場合は、そのいずれかの鍵で署名されていなければ問題とする
+pub fn verify_pack(
+    conn: &Connection,
+    pack_path: &str,
+    base_path: Option<&str>,
+    passphrase: Option<&str>,
+    trusted_key_paths: &[String],
+    progress: &dyn Fn(&str),
+) -> Result<VerifyReport> {
+    let trusted = load_trusted_keys(trusted_key_paths)?;
+    let mut archive = open_rkp(Path::new(pack_path), passphrase)?;
+    let mut pack_data = load_pack_data(&mut archive)?;
+    let delta_base = resolve_delta_base(
+        conn,
+        pack_path,
+        &mut pack_data,
+        base_path,
+        passphrase,
+        progress,
+    )?;
+    let mut report = check_pack(
+        Path::new(pack_path),
+        passphrase,
+        &delta_base,
+        &pack_data,
+        &trusted,
+        progress,
+    )?;
+    if !trusted_key_paths.is_empty() {
+        if report.signature.status != SignatureStatus::Trusted {
+            report.problems.push(format!(
+                "信頼する鍵で署名されていません ({})",
+                report.signature
+            ));
+        }
+        if let Some(base_signature) = &report.base_signature
+            && base_signature.status != SignatureStatus::Trusted
+        {
+            report.problems.push(format!(
+                "基準パックが信頼する鍵で署名されていません ({})",
+                base_signature
+            ));
+        }
+    }
+    Ok(report)
+}
+
+/// 検証して問題があればエラーにする (アンパックで DB に触れる前に呼ぶ)
+pub(crate) fn ensure_pack_valid(
+    pack_path: &Path,
+    passphrase: Option<&str>,
+    delta_base: &Option<DeltaBase>,
+    pack_data: &PackManifest,
+    progress: &dyn Fn(&str),
+) -> Result<()> {
+    let trusted = load_trusted_keys(&[])?;
+    let report = check_pack(
+        pack_path, passphrase, delta_base, pack_data, &trusted, progress,
+    )?;
+    // 信頼する鍵を必須にはしないが、作成者を確認できないことは伝える
+    let signatures = std::iter::once(("", &report.signature)).chain(
+        report
+            .base_signature
+            .iter()
+            .map(|signature| ("基準パック: ", signature)),
+    );
+    for (label, signature) in signatures {
+        match signature.status {
+            SignatureStatus::Trusted => progress(&format!("{}{}", label, signature)),
+            SignatureStatus::Unsigned | SignatureStatus::Untrusted => progress(&format!(
+                "警告: {}{} (作成者を確認できません)",
+                label, signature
+            )),
+            // 問題として下で報告する
+            SignatureStatus::Invalid => {}
+        }
+    }
+    for warning in &report.warnings {
+        progress(&format!("警告: {}", warning));
+    }
+    if report.problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &report.problems {
+        progress(&format!("検証エラー: {}", problem));
+    }
+    anyhow::bail!(
+        ".rkp の検証に失敗しました ({} 件の問題)。ファイルが壊れているか、途中で切れているか、改ざんされています",
+        report.problems.len()
+    )
+}
+
+fn check_pack(
+    pack_path: &Path,
+    passphrase: Option<&str>,
+    delta_base: &Option<DeltaBase>,
+    pack_data: &PackManifest,
+    trusted: &[TrustedKey],
+    progress: &dyn Fn(&str),
+) -> Result<VerifyReport> {
+    let mut archive = open_rkp(pack_path, passphrase)?;
+    let imported = match delta_base {
+        Some(DeltaBase::Imported(ids)) => Some(ids),
+        _ => None,
+    };
+    let (mut problems, mut warnings) = check_references(
+        &mut TableReader::new(pack_data, &mut archive)
+            .with_base(delta_base_tables(delta_base, passphrase)?),
+        pack_data,
+        imported,
+    )?;
+    let signature = read_pack_signature(&mut archive, trusted)?;
+    if signature.status == SignatureStatus::Invalid {
+        problems.push(format!("pack.json: {}", signature));
+    }
+
+    let base_archive_path = match delta_base {
+        Some(DeltaBase::Archive(base)) => Some(base.as_path()),
+        _ => None,
+    };
+    // 差分パックの署名は差分の pack.json だけを保証するので、基準パックの署名も確かめる
+    let base_signature = match base_archive_path {
+        Some(base) => {
+            let base_signature = read_pack_signature(&mut open_rkp(base, passphrase)?, trusted)?;
+            if base_signature.status == SignatureStatus::Invalid {
+                problems.push(format!("基準パックの pack.json: {}", base_signature));
+            }
+            Some(base_signature)
+        }
+        None => None,
+    };
+    if matches!(delta_base, Some(DeltaBase::Imported(_))) {
+        warnings.push(
+            "基準パックはインポート済みの記録と結合したため、基準パックの署名は確認していません"
+                .to_string(),
+        );
+    }
+    let checks: Vec<EntryCheck> = entry_checks(pack_data)
+        .into_iter()
+        // インポート済みの基準と結合した場合、基準側の音声は手元にない
+        .filter(|check| !check.in_base || base_archive_path.is_some())
+        .collect();
+
+    progress(&format!("エントリを検証中 ({} 件)", checks.len()));
+    let results: Vec<Result<()>> = checks
+        .par_iter()
+        .map_init(
+            || {
+                (
+                    open_rkp(pack_path, passphrase),
+                    base_archive_path.map(|p| open_rkp(p, passphrase)),
+                )
+            },
+            |(archive, base_archive), check| {
+                let source = if check.in_base {
+                    base_archive
+                        .as_mut()
+                        .context("差分パックの基準パックがありません")?
+                } else {
+                    archive
+                };
+                let source = source.as_mut().map_err(|e| anyhow::anyhow!("{:#}", e))?;
+                check_entry(source, check)
+            },
+        )
+        .collect();
+    for (check, result) in checks.iter().zip(results) {
+        if let Err(e) = result {
+            problems.push(format!("{}: {:#}", check.entry_name, e));
+        }
+    }
+
+    Ok(VerifyReport {
+        checked_entries: checks.len(),
+        problems,
+        warnings,
+        signature,
+        base_signature,
+    })
+}
+
+/// table_files・audio_files・content_data_files に記録されたエントリ (同じエントリは1回だけ)
+fn entry_checks(pack_data: &PackManifest) -> Vec<EntryCheck> {
+    let table_files = pack_data.table_files.values().map(|tf| EntryCheck {
+        entry_name: tf.entry.clone(),
+        in_base: false,
+        sha256: Some(tf.sha256.clone()),
+        size: Some(tf.size),
+    });
+    let audio_files = pack_data.audio_files.iter().map(|af| EntryCheck {
+        entry_name: af.entry_name(),
+        in_base: af.in_base,
+        sha256: af.sha256.clone(),
+        size: af.size,
+    });
+    let data_files = pack_data.content_data_files.iter().map(|df| EntryCheck {
+        entry_name: df.entry_name(),
+        in_base: false,
+        sha256: df.sha256.clone(),
+        size: df.size,
+    });
+    let mut seen = HashSet::new();
+    table_files
+        .chain(audio_files)
+        .chain(data_files)
+        .filter(|check| seen.insert(check.entry_name.clone()))
+        .collect()
+}
+
+/// エントリを最後まで読み (zip の CRC もここで検査される)、サイズと SHA-256 を照合する
+fn check_entry(archive: &mut RkpArchive, check: &EntryCheck) -> Result<()> {
+    if !archive.contains(&check.entry_name) {
+        anyhow::bail!(".rkp 内にエントリが見つかりません");
+    }
+    let mut entry = archive.by_name(&check.entry_name)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut entry, &mut hasher).context("読み込みに失敗 (破損しています)")?;
+    if let Some(expected) = check.size
+        && expected != size
+    {
+        anyhow::bail!(
+            "サイズが一致しません ({} バイト, 記録は {} バイト)",
+            size,
+            expected
+        );
+    }
+    if let Some(expected) = &check.sha256
+        && &hex::encode(hasher.finalize()) != expected
+    {
+        anyhow::bail!("SHA-256 が一致しません");
+    }
+    Ok(())
+}
+
+/// 行の参照先がパック内にあるかを調べ、(問題, 警告) を返す。
+/// インポート済みの基準に適用する場合は、作成済みの行も参照先に含める。
+/// 属性のマスタ (アーティスト等) への参照は参照を外して取り込めるので警告にする。
+/// ParentID (ルートや既存フォルダを指す) と MasterSongID (元の DB のトラックを指しうる) は対象外
+fn check_references(
+    reader: &mut TableReader,
+    pack_data: &PackManifest,
+    imported: Option<&IdMap>,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut problems = Vec::new();
+    let mut warnings = Vec::new();
+    let table_names = reader.table_names();
+    let checked_fk = |fk_col: &str| fk_col != "ParentID" && fk_col != "MasterSongID";
+
+    // 参照されるテーブルの ID だけを集める (キュー等の行の多いテーブルは参照されない)
+    let mut referenced: HashSet<&str> = table_names
+        .iter()
+        .flat_map(|table| fk_columns_for_table(table))
+        .filter(|(fk_col, _)| checked_fk(fk_col))
+        .map(|(_, ref_table)| ref_table)
+        .collect();
+    if !pack_data.audio_files.is_empty() {
+        referenced.insert("djmdContent");
+    }
+    if !pack_data.content_data_files.is_empty() {
+        referenced.insert("contentFile");
+    }
+
+    // テーブル → パック内の ID。プレイリスト等の最上位の行は tables の外にある
+    let mut ids: HashMap<String, HashSet<String>> = HashMap::new();
+    for table in table_names
+        .iter()
+        .filter(|t| referenced.contains(t.as_str()))
+    {
+        let table_ids = ids.entry(table.clone()).or_default();
+        reader.for_each(table, |row| {
+            table_ids.extend(row_id(row));
+            Ok(())
+        })?;
+    }
+    for (key, table) in [
+        ("playlists", "djmdPlaylist"),
+        ("histories", "djmdHistory"),
+        ("hot_cue_banklists", "djmdHotCueBanklist"),
+        ("samplers", "djmdSampler"),
+        ("related_tracks", "djmdRelatedTracks"),
+    ] {
+        if !referenced.contains(table) {
+            continue;
+        }
+        ids.entry(table.to_string())
+            .or_default()
+            .extend(pack_data.top_level_rows(key).iter().filter_map(row_id));
+    }
+    for (table, table_map) in imported.into_iter().flatten() {
+        if !referenced.contains(table.as_str()) {
+            continue;
+        }
+        ids.entry(table.clone())
+            .or_default()
+            .extend(table_map.keys().cloned());
+    }
+
+    for table in &table_names {
+        reader.for_each(table, |row| {
+            for (fk_col, ref_table) in fk_columns_for_table(table) {
+                if !checked_fk(fk_col) {
+                    continue;
+                }
+                let Some(value) = row.get(fk_col).and_then(|v| v.as_str()) else {
+                    continue;
+                };
+                if value.is_empty() || ids.get(ref_table).is_some_and(|set| set.contains(value)) {
+                    continue;
+                }
+                let message = format!(
+                    "{} (ID: {}) の {}={} が {} にありません",
+                    table,
+                    row.get("ID").and_then(|v| v.as_str()).unwrap_or("?"),
+                    fk_col,
+                    value,
+                    ref_table
+                );
+                if is_attribute_master(ref_table) {
+                    warnings.push(message);
+                } else {
+                    problems.push(message);
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    let empty = HashSet::new();
+    let content_ids = ids.get("djmdContent").unwrap_or(&empty);
+    for af in &pack_data.audio_files {
+        if !content_ids.contains(&af.content_id) {
+            problems.push(format!(
+                "audio_files の content_id={} が djmdContent にありません",
+                af.content_id
+            ));
+        }
+    }
+    let content_file_ids = ids.get("contentFile").unwrap_or(&empty);
+    for df in &pack_data.content_data_files {
+        if let Some(cf_id) = df.content_file_id.as_deref()
+            && !content_file_ids.contains(cf_id)
+        {
+            problems.push(format!(
+                "content_data_files の content_file_id={} が contentFile にありません",
+                cf_id
+            ));
+        }
+    }
+
+    Ok((problems, warnings))
+}
+
+fn row_id(row: &serde_json::Value) -> Option<String> {
+    row.get("ID")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+}
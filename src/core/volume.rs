@@ -0,0 +1,366 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use zip::ZipArchive;
+use zip::read::ZipFile;
+use zip::result::ZipError;
+
+/// パスフレーズの誤り。GUI で入力を促し直せるよう他のエラーと区別する
+#[derive(Debug)]
+pub enum PassphraseError {
+    /// 暗号化されたパックにパスフレーズが指定されていない
+    Required,
+    /// パスフレーズが違う
+    Wrong,
+}
+
+impl std::fmt::Display for PassphraseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Required => f.write_str("暗号化されたパックです。パスフレーズを指定してください"),
+            Self::Wrong => f.write_str("パスフレーズが違います"),
+        }
+    }
+}
+
+impl std::error::Error for PassphraseError {}
+
+/// .rkp を読むアーカイブ (分割パックは全ボリュームを1つのファイルとして読む)。
+/// 暗号化パックのエントリはパスフレーズで復号して読む
+pub(crate) struct RkpArchive {
+    zip: ZipArchive<VolumeReader>,
+    passphrase: Option<String>,
+}
+
+impl RkpArchive {
+    pub(crate) fn len(&self) -> usize {
+        self.zip.len()
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.zip.index_for_name(name).is_some()
+    }
+
+    /// エントリを読む。暗号化されていなければパスフレーズは使わない
+    pub(crate) fn by_name(&mut self, name: &str) -> Result<ZipFile<'_, VolumeReader>> {
+        let result = match &self.passphrase {
+            Some(passphrase) => self.zip.by_name_decrypt(name, passphrase.as_bytes()),
+            None => self.zip.by_name(name),
+        };
+        result.map_err(|e| match e {
+            ZipError::InvalidPassword => PassphraseError::Wrong.into(),
+            ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED) => {
+                PassphraseError::Required.into()
+            }
+            e => anyhow::Error::from(e),
+        })
+    }
+
+    /// 復号・展開せずにエントリを読む (サイズ等のメタデータ用)
+    pub(crate) fn by_index_raw(&mut self, index: usize) -> Result<ZipFile<'_, VolumeReader>> {
+        Ok(self.zip.by_index_raw(index)?)
+    }
+}
+
+/// `4G` / `700M` / `500000` 形式のサイズを解析する。K/M/G は 1000 単位
+/// (FAT32 の上限 4GiB-1 に収まるよう `4G` は 4,000,000,000 バイト)
+pub fn parse_split_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1_000,
+        "M" | "MB" => 1_000_000,
+        "G" | "GB" => 1_000_000_000,
+        _ => anyhow::bail!("分割サイズの単位が不正です: {} (例: 4G, 700M)", s),
+    };
+    let size = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .with_context(|| format!("分割サイズが不正です: {}", s))?;
+    if size < 64 * 1_000 {
+        anyhow::bail!("分割サイズが小さすぎます: {} (64K 以上)", s);
+    }
+    Ok(size)
+}
+
+/// 分割パックの N 番目 (1 始まり) のボリュームのパス: `name.rkp.001`
+fn volume_path(base: &Path, index: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_os_string();
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+/// 分割パックの索引のパス: `name.rkp.idx`
+fn index_path(base: &Path) -> PathBuf {
+    let mut name = base.as_os_str().to_os_string();
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+/// 指定されたパスが分割パックなら、ボリューム番号や .idx を除いた `name.rkp` を返す
+fn split_base_path(path: &Path) -> Option<PathBuf> {
+    let ext = path.extension()?.to_string_lossy();
+    if ext == "idx" || (ext.len() == 3 && ext.chars().all(|c| c.is_ascii_digit())) {
+        return Some(path.with_extension(""));
+    }
+    if !path.exists() && index_path(path).is_file() {
+        return Some(path.to_path_buf());
+    }
+    None
+}
+
+/// 書き込み先を一定サイズごとのボリュームに分けるライター。
+/// 分割しない場合は出力先1ファイルにそのまま書く。
+/// zip の書き込みはヘッダを後から書き戻すため、前のボリュームへの Seek にも対応する
+pub(crate) struct VolumeWriter {
+    base: PathBuf,
+    split_size: Option<u64>,
+    volumes: Vec<fs::File>,
+    pos: u64,
+    len: u64,
+}
+
+impl VolumeWriter {
+    pub(crate) fn create(path: &Path, split_size: Option<u64>) -> Result<Self> {
+        let mut writer = Self {
+            base: path.to_path_buf(),
+            split_size,
+            volumes: Vec::new(),
+            pos: 0,
+            len: 0,
+        };
+        writer.open_volume(0)?;
+        Ok(writer)
+    }
+
+    fn volume_file_path(&self, index: usize) -> PathBuf {
+        match self.split_size {
+            Some(_) => volume_path(&self.base, index + 1),
+            None => self.base.clone(),
+        }
+    }
+
+    fn open_volume(&mut self, index: usize) -> Result<()> {
+        while self.volumes.len() <= index {
+            let path = self.volume_file_path(self.volumes.len());
+            let file = fs::File::create(&path)
+                .with_context(|| format!(".rkp ファイルの作成に失敗: {}", path.display()))?;
+            self.volumes.push(file);
+        }
+        Ok(())
+    }
+
+    /// 書き込みを終え、分割した場合は索引を書く。作成したファイルのパスを返す
+    pub(crate) fn finish(mut self) -> Result<Vec<PathBuf>> {
+        for file in &mut self.volumes {
+            file.flush()?;
+        }
+        let paths: Vec<PathBuf> = (0..self.volumes.len())
+            .map(|i| self.volume_file_path(i))
+            .collect();
+        let Some(split_size) = self.split_size else {
+            return Ok(paths);
+        };
+
+        let volumes: Vec<serde_json::Value> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let size = (self.len - i as u64 * split_size).min(split_size);
+                json!({
+                    "file_name": path.file_name().map(|n| n.to_string_lossy()),
+                    "size": size,
+                })
+            })
+            .collect();
+        let index = json!({
+            "split_size": split_size,
+            "total_size": self.len,
+            "volumes": volumes,
+        });
+        let index_path = index_path(&self.base);
+        fs::write(&index_path, serde_json::to_vec_pretty(&index)?)
+            .with_context(|| format!("分割パックの索引の作成に失敗: {}", index_path.display()))?;
+
+        // 以前の分割で残った余分なボリュームは紛らわしいので消す
+        let mut stale = volume_path(&self.base, paths.len() + 1);
+        let mut n = paths.len() + 1;
+        while stale.is_file() {
+            let _ = fs::remove_file(&stale);
+            n += 1;
+            stale = volume_path(&self.base, n);
+        }
+
+        Ok(paths)
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (index, offset, room) = match self.split_size {
+            Some(size) => (
+                (self.pos / size) as usize,
+                self.pos % size,
+                (size - self.pos % size) as usize,
+            ),
+            None => (0, self.pos, buf.len()),
+        };
+        self.open_volume(index).map_err(io::Error::other)?;
+        let file = &mut self.volumes[index];
+        file.seek(SeekFrom::Start(offset))?;
+        let written = file.write(&buf[..buf.len().min(room)])?;
+        self.pos += written as u64;
+        self.len = self.len.max(self.pos);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for file in &mut self.volumes {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for VolumeWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
+
+fn seek_position(pos: SeekFrom, current: u64, len: u64) -> io::Result<u64> {
+    let new_pos = match pos {
+        SeekFrom::Start(p) => Some(p),
+        SeekFrom::Current(d) => current.checked_add_signed(d),
+        SeekFrom::End(d) => len.checked_add_signed(d),
+    };
+    new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "不正なシーク位置です"))
+}
+
+/// .rkp を読むリーダー。分割パックは各ボリュームを順につないだ1つのファイルとして読む
+pub(crate) struct VolumeReader {
+    /// (ボリューム, 先頭の位置, サイズ)
+    volumes: Vec<(fs::File, u64, u64)>,
+    pos: u64,
+    len: u64,
+}
+
+impl VolumeReader {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let Some(base) = split_base_path(path) else {
+            let file = fs::File::open(path)
+                .with_context(|| format!(".rkp ファイルを開けません: {}", path.display()))?;
+            let len = file.metadata()?.len();
+            return Ok(Self {
+                volumes: vec![(file, 0, len)],
+                pos: 0,
+                len,
+            });
+        };
+
+        let index_path = index_path(&base);
+        let bytes = fs::read(&index_path).with_context(|| {
+            format!("分割パックの索引が見つかりません: {}", index_path.display())
+        })?;
+        let index: serde_json::Value = serde_json::from_slice(&bytes)
+            .with_context(|| format!("分割パックの索引の解析に失敗: {}", index_path.display()))?;
+        let dir = base.parent().unwrap_or(Path::new(""));
+
+        let mut volumes = Vec::new();
+        let mut missing = Vec::new();
+        let mut len = 0;
+        for volume in index["volumes"].as_array().into_iter().flatten() {
+            let file_name = volume["file_name"].as_str().unwrap_or_default();
+            let size = volume["size"].as_u64().unwrap_or(0);
+            let volume_path = dir.join(file_name);
+            match fs::File::open(&volume_path) {
+                Ok(file) => {
+                    let actual = file.metadata()?.len();
+                    if actual != size {
+                        anyhow::bail!(
+                            "分割パックのボリュームのサイズが一致しません: {} ({} バイト, 索引では {} バイト)",
+                            volume_path.display(),
+                            actual,
+                            size
+                        );
+                    }
+                    volumes.push((file, len, size));
+                }
+                Err(_) => missing.push(file_name.to_string()),
+            }
+            len += size;
+        }
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "分割パックのボリュームが見つかりません: {} (すべてのボリュームを {} に置いてください)",
+                missing.join(", "),
+                dir.display()
+            );
+        }
+        if volumes.is_empty() {
+            anyhow::bail!(
+                "分割パックの索引にボリュームがありません: {}",
+                index_path.display()
+            );
+        }
+        Ok(Self {
+            volumes,
+            pos: 0,
+            len,
+        })
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((file, start, size)) = self
+            .volumes
+            .iter_mut()
+            .find(|(_, start, size)| self.pos < *start + *size)
+        else {
+            return Ok(0);
+        };
+        let offset = self.pos - *start;
+        let room = (*size - offset).min(buf.len() as u64) as usize;
+        file.seek(SeekFrom::Start(offset))?;
+        let read = file.read(&mut buf[..room])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
+
+/// .rkp を開く。分割パックは最初のボリューム (`name.rkp.001`) か索引を指定する
+pub(crate) fn open_rkp(path: &Path, passphrase: Option<&str>) -> Result<RkpArchive> {
+    let reader = VolumeReader::open(path)?;
+    let zip = ZipArchive::new(reader)
+        .with_context(|| format!(".rkp ファイルの解析に失敗: {}", path.display()))?;
+    Ok(RkpArchive {
+        zip,
+        passphrase: passphrase.map(|p| p.to_string()),
+    })
+}
+
+/// パスフレーズで暗号化されたパックか (pack.json が暗号化されているかで判断する)
+pub fn is_encrypted_pack(path: &str) -> Result<bool> {
+    let mut archive = open_rkp(Path::new(path), None)?;
+    let Some(index) = archive.zip.index_for_name("pack.json") else {
+        anyhow::bail!(".rkp 内に pack.json が見つかりません");
+    };
+    Ok(archive.by_index_raw(index)?.encrypted())
+}
@@ -8,9 +8,27 @@ use crate::core::{self, PlaylistInfo, TrackInfo};
 
 enum BgResult {
     Progress(String),
+    /// パックの見積もり。確認してから保存先を選ぶ
+    PackEstimated(Result<PackConfirm, String>),
     PackDone(Result<String, String>),
     UnpackDone(Result<String, String>),
     PreviewLoaded(Result<core::UnpackPreviewData, String>),
+    /// パスフレーズがない・違うため、入力を求め直す
+    PassphraseRejected(PathBuf, String),
+}
+
+/// 暗号化パックのパスフレーズ入力
+struct PassphrasePrompt {
+    rkp_path: PathBuf,
+    input: String,
+    error: Option<String>,
+}
+
+/// 保存先を選ぶ前に表示するパックの見積もり
+struct PackConfirm {
+    playlist_ids: Vec<String>,
+    playlist_name: String,
+    estimate: core::PackEstimate,
 }
 
 #[derive(PartialEq)]
@@ -65,10 +83,9 @@ fn setup_japanese_fonts(ctx: &egui::Context) {
         font_data.tweak.y_offset_factor = (ascender + descender) / (2.0 * units_per_em);
     }
 
-    fonts.font_data.insert(
-        "system_jp".to_owned(),
-        font_data.into(),
-    );
+    fonts
+        .font_data
+        .insert("system_jp".to_owned(), font_data.into());
     fonts
         .families
         .get_mut(&egui::FontFamily::Proportional)
@@ -103,16 +120,31 @@ struct RkpackApp {
     db_error: Option<String>,
     playlists: Vec<PlaylistInfo>,
     selected_playlist_idx: Option<usize>,
+    /// パック対象として選択中のプレイリスト (Ctrl/Cmd+クリックで複数選択)
+    selected_playlist_indices: Vec<usize>,
     tracks: Vec<TrackInfo>,
-    keep_structure: bool,
+    pack_options: core::PackOptions,
     status: String,
     bg_rx: Option<mpsc::Receiver<BgResult>>,
     busy: bool,
     screen: AppScreen,
     preview_data: Option<core::UnpackPreviewData>,
     preview_detail_idx: Option<usize>,
+    /// Unpack 先の親フォルダ ID ("root" はルート直下)
+    unpack_parent_id: String,
+    /// 再生履歴を通常のプレイリストとして作成する
+    unpack_histories_as_playlists: bool,
     /// Previous content_id_input values to detect changes for duplicate check
     prev_content_id_inputs: Vec<String>,
+    /// パック時にパスフレーズで暗号化する
+    pack_encrypt: bool,
+    pack_passphrase: String,
+    /// 暗号化パックを開くときのパスフレーズ入力
+    passphrase_prompt: Option<PassphrasePrompt>,
+    /// パック前の見積もりの確認
+    pack_confirm: Option<PackConfirm>,
+    /// プレビュー中のパックのパスフレーズ
+    unpack_passphrase: Option<String>,
 }
 
 impl RkpackApp {
@@ -122,15 +154,23 @@ impl RkpackApp {
             db_error: None,
             playlists: Vec::new(),
             selected_playlist_idx: None,
+            selected_playlist_indices: Vec::new(),
             tracks: Vec::new(),
-            keep_structure: false,
+            pack_options: core::PackOptions::default(),
             status: "起動中...".to_string(),
             bg_rx: None,
             busy: false,
             screen: AppScreen::Main,
             preview_data: None,
             preview_detail_idx: None,
+            unpack_parent_id: "root".to_string(),
+            unpack_histories_as_playlists: false,
             prev_content_id_inputs: Vec::new(),
+            pack_encrypt: false,
+            pack_passphrase: String::new(),
+            passphrase_prompt: None,
+            pack_confirm: None,
+            unpack_passphrase: None,
         };
         app.try_auto_connect();
         app
@@ -142,7 +182,8 @@ impl RkpackApp {
                 self.load_db(path);
             }
             Err(_) => {
-                self.status = "master.db が見つかりません。[Select DB...] で選択してください。".to_string();
+                self.status =
+                    "master.db が見つかりません。[Select DB...] で選択してください。".to_string();
             }
         }
     }
@@ -151,6 +192,7 @@ impl RkpackApp {
         self.playlists.clear();
         self.tracks.clear();
         self.selected_playlist_idx = None;
+        self.selected_playlist_indices.clear();
         self.db_error = None;
 
         match core::open_rekordbox_db(&path, core::DEFAULT_KEY, true) {
@@ -193,13 +235,68 @@ impl RkpackApp {
         }
     }
 
+    /// 選択中のプレイリストを見積もり、結果を確認してから保存先を選ぶ
     fn start_pack(&mut self, ctx: &egui::Context) {
-        let Some(idx) = self.selected_playlist_idx else {
+        if self.selected_playlist_indices.is_empty() {
             self.status = "プレイリストを選択してください".to_string();
             return;
+        }
+        let playlist_ids: Vec<String> = self
+            .selected_playlist_indices
+            .iter()
+            .map(|&i| self.playlists[i].id.clone())
+            .collect();
+        let first_name = &self.playlists[self.selected_playlist_indices[0]].name;
+        let playlist_name = if playlist_ids.len() > 1 {
+            format!("{} 他{}件", first_name, playlist_ids.len() - 1)
+        } else {
+            first_name.clone()
         };
-        let playlist_name = self.playlists[idx].name.clone();
-        let playlist_id = self.playlists[idx].id.clone();
+        let Some(ref db_path) = self.db_path else {
+            return;
+        };
+        if self.pack_encrypt && self.pack_passphrase.is_empty() {
+            self.status = "暗号化するパスフレーズを入力してください".to_string();
+            return;
+        }
+
+        let (tx, rx) = mpsc::channel();
+        self.bg_rx = Some(rx);
+        self.busy = true;
+        self.status = format!("見積もり中: {}...", playlist_name);
+
+        let db_path = db_path.clone();
+        let pack_options = self.pack_options.clone();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let tx_progress = tx.clone();
+            let ctx_progress = ctx.clone();
+            let progress = move |msg: &str| {
+                tracing::info!("{}", msg);
+                let _ = tx_progress.send(BgResult::Progress(msg.to_string()));
+                ctx_progress.request_repaint();
+            };
+            let result = (|| -> anyhow::Result<PackConfirm> {
+                let conn = core::open_rekordbox_db(&db_path, core::DEFAULT_KEY, true)?;
+                let estimate =
+                    core::estimate_playlists(&conn, &[], &playlist_ids, &pack_options, &progress)?;
+                Ok(PackConfirm {
+                    playlist_ids,
+                    playlist_name,
+                    estimate,
+                })
+            })();
+            let _ = tx.send(BgResult::PackEstimated(result.map_err(|e| e.to_string())));
+            ctx.request_repaint();
+        });
+    }
+
+    fn start_pack_save(&mut self, ctx: &egui::Context, confirm: PackConfirm) {
+        let PackConfirm {
+            playlist_ids,
+            playlist_name,
+            ..
+        } = confirm;
         let Some(ref db_path) = self.db_path else {
             return;
         };
@@ -221,7 +318,8 @@ impl RkpackApp {
         self.status = format!("パック中: {}...", playlist_name);
 
         let db_path = db_path.clone();
-        let keep_structure = self.keep_structure;
+        let mut pack_options = self.pack_options.clone();
+        pack_options.passphrase = self.pack_encrypt.then(|| self.pack_passphrase.clone());
         let ctx = ctx.clone();
         std::thread::spawn(move || {
             let tx_progress = tx.clone();
@@ -234,12 +332,10 @@ impl RkpackApp {
             let result = (|| -> anyhow::Result<String> {
                 let conn = core::open_rekordbox_db(&db_path, core::DEFAULT_KEY, true)?;
                 let output = save_path.to_string_lossy().to_string();
-                core::pack_playlist_by_id(&conn, &output, &playlist_id, keep_structure, &progress)?;
+                core::pack_playlists(&conn, &output, &[], &playlist_ids, &pack_options, &progress)?;
                 Ok(output)
             })();
-            let _ = tx.send(BgResult::PackDone(
-                result.map_err(|e| e.to_string()),
-            ));
+            let _ = tx.send(BgResult::PackDone(result.map_err(|e| e.to_string())));
             ctx.request_repaint();
         });
     }
@@ -253,29 +349,53 @@ impl RkpackApp {
         let rkp_path = rfd::FileDialog::new()
             .set_dialog_id("rkpack-unpack-rkp")
             .set_title("Unpack する .rkp ファイル")
-            .add_filter("rkp", &["rkp"])
+            .add_filter("rkp", &["rkp", "001"])
             .pick_file();
 
         let Some(rkp_path) = rkp_path else {
             return;
         };
 
+        match core::is_encrypted_pack(&rkp_path.to_string_lossy()) {
+            Ok(true) => {
+                self.passphrase_prompt = Some(PassphrasePrompt {
+                    rkp_path,
+                    input: String::new(),
+                    error: None,
+                });
+            }
+            Ok(false) => self.load_preview(ctx, db_path.clone(), rkp_path, None),
+            Err(e) => self.status = format!("プレビュー読み込みエラー: {}", e),
+        }
+    }
+
+    fn load_preview(
+        &mut self,
+        ctx: &egui::Context,
+        db_path: PathBuf,
+        rkp_path: PathBuf,
+        passphrase: Option<String>,
+    ) {
         let (tx, rx) = mpsc::channel();
         self.bg_rx = Some(rx);
         self.busy = true;
         self.status = format!("プレビュー読み込み中: {}...", rkp_path.display());
+        self.unpack_passphrase = passphrase.clone();
 
-        let db_path = db_path.clone();
         let ctx = ctx.clone();
         std::thread::spawn(move || {
             let result = (|| -> anyhow::Result<core::UnpackPreviewData> {
                 let conn = core::open_rekordbox_db(&db_path, core::DEFAULT_KEY, true)?;
                 let pack_str = rkp_path.to_string_lossy().to_string();
-                core::load_unpack_preview(&conn, &pack_str)
+                core::load_unpack_preview(&conn, &pack_str, passphrase.as_deref())
             })();
-            let _ = tx.send(BgResult::PreviewLoaded(
-                result.map_err(|e| e.to_string()),
-            ));
+            let message = match result {
+                Err(e) if e.downcast_ref::<core::PassphraseError>().is_some() => {
+                    BgResult::PassphraseRejected(rkp_path, e.to_string())
+                }
+                result => BgResult::PreviewLoaded(result.map_err(|e| e.to_string())),
+            };
+            let _ = tx.send(message);
             ctx.request_repaint();
         });
     }
@@ -288,21 +408,23 @@ impl RkpackApp {
             return;
         };
 
-        // プレイリスト名の重複チェック
+        // プレイリスト名の重複チェック (同じ親フォルダ内)
         if let Ok(conn) = core::open_rekordbox_db(db_path, core::DEFAULT_KEY, true) {
-            let existing: Option<String> = conn
-                .query_row(
-                    "SELECT ID FROM djmdPlaylist WHERE Name = ? AND rb_local_deleted = 0 LIMIT 1",
-                    rusqlite::params![&preview.playlist_name],
-                    |row| row.get(0),
-                )
-                .ok();
-            if existing.is_some() {
-                self.status = format!(
-                    "エラー: プレイリスト名 '{}' は既に存在します。名前を変更してください。",
-                    preview.playlist_name
-                );
-                return;
+            for name in &preview.playlist_names {
+                let existing: Option<String> = conn
+                    .query_row(
+                        "SELECT ID FROM djmdPlaylist WHERE Name = ? AND ParentID = ? AND rb_local_deleted = 0 LIMIT 1",
+                        rusqlite::params![name, &self.unpack_parent_id],
+                        |row| row.get(0),
+                    )
+                    .ok();
+                if existing.is_some() {
+                    self.status = format!(
+                        "エラー: プレイリスト名 '{}' は既に存在します。名前を変更してください。",
+                        name
+                    );
+                    return;
+                }
             }
         }
 
@@ -324,15 +446,19 @@ impl RkpackApp {
                 core::DuplicateDecision::Skip => {
                     skipped_content_ids.insert(track.pack_content_id.clone());
                     if let Some(ref dup) = track.duplicate {
-                        existing_content_map
-                            .insert(track.pack_content_id.clone(), dup.existing_content_id.clone());
+                        existing_content_map.insert(
+                            track.pack_content_id.clone(),
+                            dup.existing_content_id.clone(),
+                        );
                     }
                 }
                 core::DuplicateDecision::Update => {
                     update_content_ids.insert(track.pack_content_id.clone());
                     if let Some(ref dup) = track.duplicate {
-                        existing_content_map
-                            .insert(track.pack_content_id.clone(), dup.existing_content_id.clone());
+                        existing_content_map.insert(
+                            track.pack_content_id.clone(),
+                            dup.existing_content_id.clone(),
+                        );
                     }
                 }
                 core::DuplicateDecision::New => {}
@@ -346,7 +472,15 @@ impl RkpackApp {
         };
 
         let pack_path = preview.rkp_path.clone();
-        let playlist_name = preview.playlist_name.clone();
+        let playlist_names = preview.playlist_names.clone();
+        let unpack_options = core::UnpackOptions {
+            parent_id: Some(self.unpack_parent_id.clone()),
+            histories_as_playlists: self.unpack_histories_as_playlists,
+            base_path: None,
+            passphrase: self.unpack_passphrase.clone(),
+            keep_uuids: false,
+            keep_flac: false,
+        };
         let dest_dir = dest_dir.to_string_lossy().to_string();
 
         let (tx, rx) = mpsc::channel();
@@ -371,14 +505,13 @@ impl RkpackApp {
                     &pack_path,
                     &dest_dir,
                     &decisions,
-                    Some(&playlist_name),
+                    Some(&playlist_names),
+                    &unpack_options,
                     &progress,
                 )?;
                 Ok(pack_path)
             })();
-            let _ = tx.send(BgResult::UnpackDone(
-                result.map_err(|e| e.to_string()),
-            ));
+            let _ = tx.send(BgResult::UnpackDone(result.map_err(|e| e.to_string())));
             ctx.request_repaint();
         });
     }
@@ -392,6 +525,19 @@ impl RkpackApp {
                 BgResult::Progress(msg) => {
                     self.status = msg;
                 }
+                BgResult::PackEstimated(Ok(confirm)) => {
+                    self.busy = false;
+                    self.bg_rx = None;
+                    self.status = format!("見積もり完了: {}", confirm.playlist_name);
+                    self.pack_confirm = Some(confirm);
+                    return;
+                }
+                BgResult::PackEstimated(Err(e)) => {
+                    self.busy = false;
+                    self.bg_rx = None;
+                    self.status = format!("見積もりエラー: {}", e);
+                    return;
+                }
                 BgResult::PackDone(Ok(path)) => {
                     self.busy = false;
                     self.bg_rx = None;
@@ -426,10 +572,19 @@ impl RkpackApp {
                 BgResult::PreviewLoaded(Ok(data)) => {
                     self.busy = false;
                     self.bg_rx = None;
-                    self.prev_content_id_inputs = data.tracks.iter().map(|t| t.content_id_input.clone()).collect();
+                    self.prev_content_id_inputs = data
+                        .tracks
+                        .iter()
+                        .map(|t| t.content_id_input.clone())
+                        .collect();
                     self.status = format!(
                         "プレビュー: {} ({} トラック)",
-                        data.playlist_name,
+                        data.playlist_names
+                            .iter()
+                            .chain(&data.history_names)
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(", "),
                         data.tracks.len()
                     );
                     self.preview_data = Some(data);
@@ -442,6 +597,17 @@ impl RkpackApp {
                     self.status = format!("プレビュー読み込みエラー: {}", e);
                     return;
                 }
+                BgResult::PassphraseRejected(rkp_path, e) => {
+                    self.busy = false;
+                    self.bg_rx = None;
+                    self.status = e.clone();
+                    self.passphrase_prompt = Some(PassphrasePrompt {
+                        rkp_path,
+                        input: String::new(),
+                        error: Some(e),
+                    });
+                    return;
+                }
             }
         }
     }
@@ -461,9 +627,9 @@ impl RkpackApp {
                         .set_title("master.db を選択")
                         .add_filter("SQLite DB", &["db"])
                         .pick_file()
-                    {
-                        self.load_db(path);
-                    }
+                {
+                    self.load_db(path);
+                }
             });
             if let Some(ref err) = self.db_error {
                 ui.colored_label(egui::Color32::RED, err);
@@ -472,15 +638,55 @@ impl RkpackApp {
 
         egui::TopBottomPanel::bottom("bottom_panel").show(ctx, |ui| {
             ui.horizontal(|ui| {
-                let pack_enabled = !self.busy && self.selected_playlist_idx.is_some();
-                if ui.add_enabled(pack_enabled, egui::Button::new("Pack Selected Playlist")).clicked() {
+                let pack_enabled = !self.busy && !self.selected_playlist_indices.is_empty();
+                let pack_label = if self.selected_playlist_indices.len() > 1 {
+                    format!(
+                        "Pack {} Selected Playlists",
+                        self.selected_playlist_indices.len()
+                    )
+                } else {
+                    "Pack Selected Playlist".to_string()
+                };
+                if ui
+                    .add_enabled(pack_enabled, egui::Button::new(pack_label))
+                    .clicked()
+                {
                     self.start_pack(ctx);
                 }
                 let unpack_enabled = !self.busy && self.db_path.is_some();
-                if ui.add_enabled(unpack_enabled, egui::Button::new("Unpack .rkp File")).clicked() {
+                if ui
+                    .add_enabled(unpack_enabled, egui::Button::new("Unpack .rkp File"))
+                    .clicked()
+                {
                     self.start_unpack_preview(ctx);
                 }
-                ui.checkbox(&mut self.keep_structure, "Keep structure");
+                ui.checkbox(&mut self.pack_options.keep_structure, "Keep structure");
+                ui.checkbox(
+                    &mut self.pack_options.keep_smart_lists,
+                    "Keep smart playlists",
+                );
+                ui.checkbox(&mut self.pack_options.no_audio, "No audio");
+                ui.add_enabled(
+                    self.pack_options.no_audio,
+                    egui::Checkbox::new(&mut self.pack_options.with_analysis, "With analysis"),
+                );
+                ui.add_enabled(
+                    !self.pack_options.no_audio,
+                    egui::Checkbox::new(&mut self.pack_options.flac, "WAV/AIFF → FLAC"),
+                );
+                ui.add_enabled(
+                    !self.pack_options.no_audio,
+                    egui::Checkbox::new(&mut self.pack_options.embed_tags, "Embed tags"),
+                );
+                ui.checkbox(&mut self.pack_encrypt, "Encrypt");
+                if self.pack_encrypt {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.pack_passphrase)
+                            .password(true)
+                            .hint_text("パスフレーズ")
+                            .desired_width(120.0),
+                    );
+                }
             });
             ui.label(&self.status);
         });
@@ -499,10 +705,21 @@ impl RkpackApp {
                             continue;
                         }
                         let label = format!("{} ({})", pl.name, pl.track_count);
-                        let selected = self.selected_playlist_idx == Some(i);
-                        if ui.selectable_label(selected, &label).clicked() && !selected {
-                            self.selected_playlist_idx = Some(i);
-                            newly_selected_playlist_id = Some(pl.id.clone());
+                        let selected = self.selected_playlist_indices.contains(&i);
+                        if ui.selectable_label(selected, &label).clicked() {
+                            if ui.input(|input| input.modifiers.command) {
+                                if selected {
+                                    self.selected_playlist_indices.retain(|&x| x != i);
+                                } else {
+                                    self.selected_playlist_indices.push(i);
+                                }
+                            } else {
+                                self.selected_playlist_indices = vec![i];
+                            }
+                            if self.selected_playlist_idx != Some(i) {
+                                self.selected_playlist_idx = Some(i);
+                                newly_selected_playlist_id = Some(pl.id.clone());
+                            }
                         }
                     }
                 });
@@ -554,6 +771,90 @@ impl RkpackApp {
         });
     }
 
+    fn draw_passphrase_prompt(&mut self, ctx: &egui::Context) {
+        let Some(prompt) = self.passphrase_prompt.as_mut() else {
+            return;
+        };
+        let mut submit = false;
+        let mut cancel = false;
+        egui::Window::new("パスフレーズ")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} は暗号化されています",
+                    prompt
+                        .rkp_path
+                        .file_name()
+                        .map(|n| n.to_string_lossy())
+                        .unwrap_or_default()
+                ));
+                let response = ui.add(egui::TextEdit::singleline(&mut prompt.input).password(true));
+                response.request_focus();
+                if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    submit = true;
+                }
+                if let Some(ref error) = prompt.error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("OK").clicked() {
+                        submit = true;
+                    }
+                    if ui.button("キャンセル").clicked() {
+                        cancel = true;
+                    }
+                });
+            });
+
+        if cancel {
+            self.passphrase_prompt = None;
+        } else if submit
+            && !prompt.input.is_empty()
+            && let Some(db_path) = self.db_path.clone()
+            && let Some(prompt) = self.passphrase_prompt.take()
+        {
+            self.load_preview(ctx, db_path, prompt.rkp_path, Some(prompt.input));
+        }
+    }
+
+    fn draw_pack_confirm(&mut self, ctx: &egui::Context) {
+        let Some(confirm) = self.pack_confirm.as_ref() else {
+            return;
+        };
+        let mut submit = false;
+        let mut cancel = false;
+        egui::Window::new("パックの見積もり")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(&confirm.playlist_name);
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for line in confirm.estimate.summary_lines() {
+                            ui.label(line);
+                        }
+                    });
+                ui.horizontal(|ui| {
+                    if ui.button("保存先を選んでパック").clicked() {
+                        submit = true;
+                    }
+                    if ui.button("キャンセル").clicked() {
+                        cancel = true;
+                    }
+                });
+            });
+
+        if cancel {
+            self.pack_confirm = None;
+        } else if submit && let Some(confirm) = self.pack_confirm.take() {
+            self.start_pack_save(ctx, confirm);
+        }
+    }
+
     fn draw_unpack_preview(&mut self, ctx: &egui::Context) {
         // Collect content_id changes to trigger duplicate checks after mutable borrow ends
         let mut content_id_checks: Vec<(usize, String)> = Vec::new();
@@ -561,20 +862,71 @@ impl RkpackApp {
         let preview_name = self
             .preview_data
             .as_ref()
-            .map(|d| d.playlist_name.clone())
+            .map(|d| d.playlist_names.join(", "))
             .unwrap_or_default();
 
         egui::TopBottomPanel::top("preview_top").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.heading("アンパックプレビュー:");
                 if let Some(ref mut preview) = self.preview_data {
-                    ui.add(
-                        egui::TextEdit::singleline(&mut preview.playlist_name)
-                            .desired_width(200.0),
-                    );
+                    for name in preview.playlist_names.iter_mut() {
+                        ui.add(egui::TextEdit::singleline(name).desired_width(200.0));
+                    }
                 } else {
                     ui.label(&preview_name);
                 }
+                ui.label("親フォルダ:");
+                let folders: Vec<(&str, &str)> = self
+                    .playlists
+                    .iter()
+                    .filter(|pl| {
+                        pl.attribute == 1 || self.playlists.iter().any(|c| c.parent_id == pl.id)
+                    })
+                    .map(|pl| (pl.id.as_str(), pl.name.as_str()))
+                    .collect();
+                let selected_label = folders
+                    .iter()
+                    .find(|(id, _)| *id == self.unpack_parent_id)
+                    .map(|(_, name)| name.to_string())
+                    .unwrap_or_else(|| "(ルート)".to_string());
+                egui::ComboBox::from_id_salt("unpack_parent")
+                    .selected_text(selected_label)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut self.unpack_parent_id,
+                            "root".to_string(),
+                            "(ルート)",
+                        );
+                        for (id, name) in &folders {
+                            ui.selectable_value(&mut self.unpack_parent_id, id.to_string(), *name);
+                        }
+                    });
+                let history_names = self
+                    .preview_data
+                    .as_ref()
+                    .map(|d| d.history_names.join(", "))
+                    .unwrap_or_default();
+                if self.preview_data.as_ref().is_some_and(|d| d.lite) {
+                    ui.label(
+                        "音声なしパック: 手元のトラックにキュー・MyTag・レーティングを適用します",
+                    );
+                }
+                if let Some(signature) = self.preview_data.as_ref().map(|d| &d.signature) {
+                    let color = match signature.status {
+                        core::SignatureStatus::Trusted => egui::Color32::GREEN,
+                        core::SignatureStatus::Invalid => egui::Color32::RED,
+                        core::SignatureStatus::Untrusted => egui::Color32::YELLOW,
+                        core::SignatureStatus::Unsigned => ui.visuals().weak_text_color(),
+                    };
+                    ui.colored_label(color, signature.to_string());
+                }
+                if !history_names.is_empty() {
+                    ui.label(format!("再生履歴: {}", history_names));
+                    ui.checkbox(
+                        &mut self.unpack_histories_as_playlists,
+                        "プレイリストとして作成",
+                    );
+                }
             });
         });
 
@@ -602,6 +954,7 @@ impl RkpackApp {
         let mut detail_decision: Option<(usize, core::DuplicateDecision)> = None;
         if let Some(detail_idx) = self.preview_detail_idx {
             if let Some(ref preview) = self.preview_data {
+                let lite = preview.lite;
                 if let Some(track) = preview.tracks.get(detail_idx) {
                     let mut open = true;
                     egui::Window::new("重複トラック詳細")
@@ -613,10 +966,7 @@ impl RkpackApp {
                             if let Some(ref dup) = track.duplicate {
                                 ui.group(|ui| {
                                     ui.label("既存データ:");
-                                    ui.label(format!(
-                                        "  トラック名: {}",
-                                        dup.info.existing_title
-                                    ));
+                                    ui.label(format!("  トラック名: {}", dup.info.existing_title));
                                     ui.label(format!(
                                         "  メモリーキュー: {}個  ホットキュー: {}個",
                                         dup.info.existing_memory_cue_count,
@@ -635,13 +985,11 @@ impl RkpackApp {
                                 ui.add_space(8.0);
                                 ui.horizontal(|ui| {
                                     if ui.button("更新する").clicked() {
-                                        detail_decision = Some((
-                                            detail_idx,
-                                            core::DuplicateDecision::Update,
-                                        ));
+                                        detail_decision =
+                                            Some((detail_idx, core::DuplicateDecision::Update));
                                         close_detail = true;
                                     }
-                                    if ui.button("新規として追加").clicked() {
+                                    if !lite && ui.button("新規として追加").clicked() {
                                         detail_decision =
                                             Some((detail_idx, core::DuplicateDecision::New));
                                         close_detail = true;
@@ -743,8 +1091,7 @@ impl RkpackApp {
                                 );
                                 if response.lost_focus() || response.changed() {
                                     // Will check for changes after the loop
-                                    content_id_checks
-                                        .push((i, track.content_id_input.clone()));
+                                    content_id_checks.push((i, track.content_id_input.clone()));
                                 }
 
                                 ui.label(&track.title);
@@ -770,7 +1117,11 @@ impl RkpackApp {
                 }
             } else {
                 // Check if content_id actually changed
-                let prev = self.prev_content_id_inputs.get(idx).cloned().unwrap_or_default();
+                let prev = self
+                    .prev_content_id_inputs
+                    .get(idx)
+                    .cloned()
+                    .unwrap_or_default();
                 if value != prev {
                     // Update prev
                     if idx < self.prev_content_id_inputs.len() {
@@ -778,12 +1129,9 @@ impl RkpackApp {
                     }
                     // Do synchronous duplicate check (fast DB query)
                     if let Some(ref db_path) = self.db_path {
-                        if let Ok(conn) =
-                            core::open_rekordbox_db(db_path, core::DEFAULT_KEY, true)
+                        if let Ok(conn) = core::open_rekordbox_db(db_path, core::DEFAULT_KEY, true)
                         {
-                            if let Ok(result) =
-                                core::check_content_id_duplicate(&conn, &value)
-                            {
+                            if let Ok(result) = core::check_content_id_duplicate(&conn, &value) {
                                 if let Some(ref mut preview) = self.preview_data {
                                     if let Some(track) = preview.tracks.get_mut(idx) {
                                         if let Some(info) = result {
@@ -796,7 +1144,12 @@ impl RkpackApp {
                                             }
                                         } else {
                                             track.duplicate = None;
-                                            track.decision = core::DuplicateDecision::New;
+                                            // 音声なしパックのトラックは新規登録できない
+                                            track.decision = if preview.lite {
+                                                core::DuplicateDecision::Skip
+                                            } else {
+                                                core::DuplicateDecision::New
+                                            };
                                         }
                                     }
                                 }
@@ -827,5 +1180,7 @@ impl eframe::App for RkpackApp {
             AppScreen::Main => self.draw_main(ctx),
             AppScreen::UnpackPreview => self.draw_unpack_preview(ctx),
         }
+        self.draw_passphrase_prompt(ctx);
+        self.draw_pack_confirm(ctx);
     }
 }
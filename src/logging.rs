@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use anyhow::{Context, Result};
 use tracing_appender::non_blocking::WorkerGuard;
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
 fn log_dir() -> PathBuf {
     let base = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
@@ -20,9 +20,7 @@ pub fn init_logging() -> Result<WorkerGuard> {
     let file_appender = tracing_appender::rolling::never(&log_dir, &file_name);
     let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
 
-    let file_layer = fmt::layer()
-        .with_writer(non_blocking)
-        .with_ansi(false);
+    let file_layer = fmt::layer().with_writer(non_blocking).with_ansi(false);
 
     let stdout_layer = fmt::layer()
         .with_writer(std::io::stdout)
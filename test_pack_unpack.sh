@@ -69,16 +69,30 @@ echo "ソース: トラック数=$TRACK_COUNT, DBID=$SRC_DBID, DeviceID=$SRC_DEV
 # --- 1. Pack ---
 echo ""
 echo "--- Pack ---"
+# --dry-run は見積もりだけを表示し、.rkp を作らないこと
+$BIN --db-path "$DECRYPTED_DB" pack "$PACK_FILE" --playlist "$PLAYLIST" --dry-run 2>&1 \
+    | grep -q "推定サイズ" || fail "--dry-run で見積もりが表示されない"
+[ ! -e "$PACK_FILE" ] || fail "--dry-run で .rkp が作成された"
+pass "--dry-run 見積もり"
+
 $BIN --db-path "$DECRYPTED_DB" pack "$PACK_FILE" --playlist "$PLAYLIST"
 
 [ -f "$PACK_FILE" ] || fail ".rkp ファイルが生成されていない"
 pass ".rkp ファイル生成"
 
 # .rkp 内に hotCueBanklistCue キーが含まれること
-unzip -p "$PACK_FILE" pack.json | jq -e '.tables | has("hotCueBanklistCue")' > /dev/null \
+unzip -p "$PACK_FILE" pack.json | jq -e '.table_files | has("hotCueBanklistCue")' > /dev/null \
     || fail "hotCueBanklistCue が pack.json に含まれていない"
+unzip -l "$PACK_FILE" tables/hotCueBanklistCue.jsonl > /dev/null \
+    || fail "tables/hotCueBanklistCue.jsonl が .rkp に含まれていない"
 pass "hotCueBanklistCue in pack.json"
 
+# 元の絶対パスと端末固有の値が残っていないこと
+LEAKED=$(unzip -p "$PACK_FILE" tables/djmdContent.jsonl \
+    | jq -s '[.[] | select((.FolderPath // "" | test("^(/|[A-Za-z]:)")) or .DeviceID != null)] | length')
+[ "$LEAKED" -eq 0 ] || fail "元のパス・DeviceID が残っているトラック: $LEAKED 件"
+pass "FolderPath 匿名化済み"
+
 # --- 2. 空の移行先DBを作成 ---
 echo ""
 echo "--- 移行先 DB 準備 ---"
@@ -182,7 +196,19 @@ BAD_CF_SYNC=$(sql "$DEST_DB" "
 [ "$BAD_CF_SYNC" -eq 0 ] || fail "contentFile 同期フィールド未リセット: $BAD_CF_SYNC 件"
 pass "contentFile 同期フィールドリセット済み"
 
-# 4-12. 音声ファイル配置
+# 4-12. UUID 振り直しと uuidIDMap
+BAD_CUE_UUID=$(sql "$DEST_DB" "
+    SELECT COUNT(*) FROM djmdCue q JOIN djmdContent c ON c.ID = q.ContentID
+    WHERE q.ContentUUID IS NOT c.UUID;")
+[ "$BAD_CUE_UUID" -eq 0 ] || fail "djmdCue.ContentUUID 不一致: $BAD_CUE_UUID 件"
+UNMAPPED=$(sql "$DEST_DB" "
+    SELECT COUNT(*) FROM djmdContent c WHERE NOT EXISTS (
+        SELECT 1 FROM uuidIDMap m
+        WHERE m.TableName = 'djmdContent' AND m.TargetUUID = c.UUID AND m.CurrentID = c.ID);")
+[ "$UNMAPPED" -eq 0 ] || fail "uuidIDMap 未登録のトラック: $UNMAPPED 件"
+pass "UUID 振り直し済み・uuidIDMap 登録済み"
+
+# 4-13. 音声ファイル配置
 AUDIO_FILE_COUNT=$(find "$DEST_DIR" -type f 2>/dev/null | wc -l | tr -d ' ')
 [ "$AUDIO_FILE_COUNT" -ge 1 ] || fail "音声ファイルが配置されていない"
 pass "音声ファイル配置済み ($AUDIO_FILE_COUNT 件)"